@@ -0,0 +1,39 @@
+// A dice bot: cargo run --example bot -- <ticket>
+// Answers "!roll 2d6" in the room and "!secret" with a direct message.
+
+use anyhow::{bail, Context, Result};
+use p2p_chat::{Bot, ChatClient, Reply};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let ticket = std::env::args().nth(1).context("usage: bot <ticket>")?;
+    let bot = Bot::builder()
+        .command("roll", |command| async move {
+            Ok(match roll(&command.args) {
+                Ok(text) => Reply::room(format!("{} rolled {}", command.name, text)),
+                Err(err) => Reply::room(format!("{} (try !roll 2d6)", err)),
+            })
+        })
+        .command("secret", |_| async { Ok(Reply::dm("the dice are loaded")) })
+        .build();
+    let data_dir = std::env::temp_dir().join("iroh-chat-bot");
+    let client = ChatClient::builder().name("dicebot").data_dir(data_dir).bot(bot).spawn().await?;
+    client.join(ticket.parse()?).await?;
+    tokio::signal::ctrl_c().await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+// "NdM" rolls N dice with M sides, "dM" one
+fn roll(args: &str) -> Result<String> {
+    let (count, sides) = args.split_once('d').context("not a roll")?;
+    let count: u32 = if count.is_empty() { 1 } else { count.parse()? };
+    let sides: u32 = sides.parse()?;
+    if !(1..=100).contains(&count) || sides < 2 {
+        bail!("can't roll {}", args);
+    }
+    let rolls: Vec<u32> = (0..count).map(|_| rand::random::<u32>() % sides + 1).collect();
+    let total: u32 = rolls.iter().sum();
+    let rolls: Vec<_> = rolls.iter().map(u32::to_string).collect();
+    Ok(format!("{} = {}", rolls.join(" + "), total))
+}
@@ -0,0 +1,340 @@
+use anyhow::Result;
+use futures_lite::stream::Boxed;
+use futures_lite::{Stream, StreamExt};
+use n0_future::SinkExt;
+use p2p_chat::{ChatClient, ChatEvent};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request as Handshake, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Anything the client itself refused, e.g. sending without a room
+const CHAT_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    // Absent for notifications, which get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct SendParams {
+    text: String,
+    // Id prefix of the message to answer
+    reply_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JoinParams {
+    ticket: String,
+    // For password-protected rooms
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct InviteParams {
+    // Seconds until the invite stops working
+    expires_in: Option<u64>,
+    #[serde(default)]
+    single_use: bool,
+}
+
+#[derive(Deserialize)]
+struct PeerParams {
+    // A name or node id, as for /msg
+    peer: String,
+}
+
+#[derive(Deserialize)]
+struct RoomParams {
+    room: Option<String>,
+}
+
+// A failed call: its JSON-RPC code and message
+struct CallError(i64, String);
+
+impl From<anyhow::Error> for CallError {
+    fn from(err: anyhow::Error) -> Self {
+        CallError(CHAT_ERROR, err.to_string())
+    }
+}
+
+// Who may open a connection: whoever presents `token`, from a page on `origin` if it is a browser
+#[derive(Debug, Clone)]
+pub struct Access {
+    pub token: String,
+    // The page allowed to connect, e.g. http://localhost:3000. Connections carrying any other
+    // Origin are refused; ones without, from outside a browser, are let through.
+    pub origin: Option<String>,
+}
+
+// Accept WebSocket connections on `addr` and serve JSON-RPC over each of them until the process ends
+pub async fn serve(client: ChatClient, addr: SocketAddr, access: Access) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (client, access) = (client.clone(), access.clone());
+        tokio::spawn(async move {
+            // A client going away mid-call is nothing to report
+            let _ = connection(&client, stream, &access).await;
+        });
+    }
+}
+
+// Refuse the handshake unless it comes with our token and, from a browser, our origin. Browsers
+// can't set headers on WebSockets, so the token may also come as ?access_token=.
+struct Authorize<'a>(&'a Access);
+
+impl Callback for Authorize<'_> {
+    fn on_request(self, request: &Handshake, response: Response) -> Result<Response, ErrorResponse> {
+        let Authorize(access) = self;
+        match authorize(access, request) {
+            Ok(()) => Ok(response),
+            Err((status, reason)) => {
+                let mut refusal = ErrorResponse::new(Some(reason));
+                *refusal.status_mut() = status;
+                Err(refusal)
+            }
+        }
+    }
+}
+
+fn authorize(access: &Access, request: &Handshake) -> Result<(), (StatusCode, String)> {
+    let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok());
+    if let Some(origin) = header("origin")
+        && access.origin.as_deref() != Some(origin)
+    {
+        return Err((StatusCode::FORBIDDEN, format!("origin {} isn't allowed", origin)));
+    }
+    let bearer = header("authorization").and_then(|value| value.strip_prefix("Bearer "));
+    let query = request.uri().query().unwrap_or_default();
+    let param = query.split('&').find_map(|pair| pair.strip_prefix("access_token="));
+    match bearer.or(param) {
+        Some(token) if same(token.trim().as_bytes(), access.token.as_bytes()) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "a valid bearer token is required".to_string())),
+    }
+}
+
+// Compare without giving away through timing how much of the token was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn connection(client: &ChatClient, stream: TcpStream, access: &Access) -> Result<()> {
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, Authorize(access)).await?;
+    // Events are only pushed after a `subscribe` call
+    let mut events: Option<Boxed<ChatEvent>> = None;
+    loop {
+        let reply = tokio::select! {
+            message = ws.next() => match message.transpose()? {
+                Some(Message::Text(text)) => handle(client, &text, &mut events).await,
+                Some(Message::Close(_)) | None => return Ok(()),
+                Some(_) => continue,
+            },
+            Some(event) = next_event(&mut events) => {
+                Some(json!({ "jsonrpc": "2.0", "method": "event", "params": event }))
+            }
+        };
+        if let Some(reply) = reply {
+            ws.send(Message::text(reply.to_string())).await?;
+        }
+    }
+}
+
+// --json: every event and notice goes to stdout as a JSON object per line, and stdin takes the
+// same requests as the WebSocket API, one per line
+pub async fn run_json(
+    client: &ChatClient,
+    mut lines: mpsc::UnboundedReceiver<String>,
+    mut events: impl Stream<Item = ChatEvent> + Unpin,
+) -> Result<()> {
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    // Events are printed anyway, so a subscribe call has nothing to add
+    let mut subscription = None;
+    loop {
+        let line = tokio::select! {
+            line = stdin.next_line() => match line? {
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => match handle(client, &line, &mut subscription).await {
+                    Some(reply) => reply,
+                    None => continue,
+                },
+                None => break,
+            },
+            Some(notice) = lines.recv() => json!({ "notice": notice.trim_start_matches("> ") }),
+            Some(event) = events.next() => json!(event),
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+async fn next_event(events: &mut Option<Boxed<ChatEvent>>) -> Option<ChatEvent> {
+    match events {
+        Some(events) => events.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Run one request and build its response, if it wants one
+async fn handle(
+    client: &ChatClient,
+    text: &str,
+    events: &mut Option<Boxed<ChatEvent>>,
+) -> Option<Value> {
+    let request: Request = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(err) => return Some(error(Value::Null, CallError(PARSE_ERROR, err.to_string()))),
+    };
+    let result = match request.method.as_str() {
+        "subscribe" => {
+            *events = Some(client.events().boxed());
+            Ok(json!(true))
+        }
+        method => call(client, method, request.params).await,
+    };
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => error(id, err),
+    })
+}
+
+async fn call(client: &ChatClient, method: &str, params: Value) -> Result<Value, CallError> {
+    match method {
+        "send" => {
+            let SendParams { text, reply_to } = params_of(params)?;
+            let id = match reply_to {
+                Some(to) => {
+                    let to = client.find_message(Some(&to)).await?;
+                    client.reply(to, text).await?
+                }
+                None => client.send(text).await?,
+            };
+            Ok(json!({ "id": id.to_string() }))
+        }
+        "open" => Ok(json!({ "ticket": client.open_room().await?.to_string() })),
+        "ticket" => Ok(json!({ "ticket": client.ticket().await?.to_string() })),
+        "invite" => {
+            let InviteParams { expires_in, single_use } = params_of(params)?;
+            let ticket = client.invite(expires_in.map(Duration::from_secs), single_use).await?;
+            Ok(json!({ "ticket": ticket.to_string() }))
+        }
+        "join" => {
+            let JoinParams { ticket, password } = params_of(params)?;
+            let room = match password {
+                Some(password) => client.join_with_password(ticket.parse()?, &password).await?,
+                None => client.join(ticket.parse()?).await?,
+            };
+            Ok(json!({ "room": room.to_string() }))
+        }
+        "allow" => {
+            let (node_id, _) = client.resolve_peer(&params_of::<PeerParams>(params)?.peer).await?;
+            client.allow(node_id).await?;
+            Ok(json!(true))
+        }
+        "revoke" => {
+            let (node_id, _) = client.resolve_peer(&params_of::<PeerParams>(params)?.peer).await?;
+            client.revoke(node_id).await?;
+            Ok(json!(true))
+        }
+        "kick" | "ban" | "unban" | "appoint" | "dismiss" => {
+            let (node_id, _) = client.resolve_peer(&params_of::<PeerParams>(params)?.peer).await?;
+            match method {
+                "kick" => client.kick(node_id).await?,
+                "ban" => client.ban(node_id).await?,
+                "unban" => client.unban(node_id).await?,
+                "appoint" => client.appoint(node_id).await?,
+                _ => client.dismiss(node_id).await?,
+            }
+            Ok(json!(true))
+        }
+        "switch" => {
+            let Some(room) = params_of::<RoomParams>(params)?.room else {
+                return Err(CallError(INVALID_PARAMS, "switch needs a room".to_string()));
+            };
+            Ok(json!({ "room": client.switch(&room).await?.to_string() }))
+        }
+        "leave" => {
+            let RoomParams { room } = params_of(params)?;
+            Ok(json!({ "room": client.leave(room.as_deref()).await?.to_string() }))
+        }
+        "rooms" => {
+            let rooms = client.rooms().await;
+            let rooms: Vec<_> =
+                rooms.iter().map(|(room, active)| json!({ "room": room.to_string(), "active": active })).collect();
+            Ok(json!(rooms))
+        }
+        _ => Err(CallError(METHOD_NOT_FOUND, format!("no method {}", method))),
+    }
+}
+
+fn params_of<T: DeserializeOwned>(params: Value) -> Result<T, CallError> {
+    // Leaving out params is the same as passing none
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|err| CallError(INVALID_PARAMS, err.to_string()))
+}
+
+fn error(id: Value, CallError(code, message): CallError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access() -> Access {
+        Access { token: "secret".to_string(), origin: Some("http://localhost:3000".to_string()) }
+    }
+
+    fn handshake(uri: &str, headers: &[(&str, &str)]) -> Handshake {
+        let mut request = Handshake::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    fn status(request: &Handshake) -> StatusCode {
+        match Authorize(&access()).on_request(request, Response::new(())) {
+            Ok(response) => response.status(),
+            Err(response) => response.status(),
+        }
+    }
+
+    #[test]
+    fn needs_the_token() {
+        assert_eq!(status(&handshake("/", &[])), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&handshake("/", &[("authorization", "Bearer wrong")])), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&handshake("/", &[("authorization", "Bearer secret")])), StatusCode::OK);
+        assert_eq!(status(&handshake("/?access_token=secret", &[])), StatusCode::OK);
+        assert_eq!(status(&handshake("/?access_token=secrets", &[])), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn refuses_other_origins() {
+        let token = ("authorization", "Bearer secret");
+        let ours = handshake("/", &[token, ("origin", "http://localhost:3000")]);
+        assert_eq!(status(&ours), StatusCode::OK);
+        let theirs = handshake("/", &[token, ("origin", "https://example.com")]);
+        assert_eq!(status(&theirs), StatusCode::FORBIDDEN);
+        let without = Access { origin: None, ..access() };
+        let request = handshake("/", &[token, ("origin", "http://localhost:3000")]);
+        assert!(authorize(&without, &request).is_err());
+    }
+}
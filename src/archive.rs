@@ -0,0 +1,353 @@
+use crate::storage::{HistoryKey, Signed, Storage, StoredMessage};
+use anyhow::{bail, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+// How many matches a page of search results holds
+pub const SEARCH_PAGE: usize = 10;
+
+// How much of a matching message to show on each side of the match
+const SNIPPET_CONTEXT: usize = 30;
+
+// A stored message that matched a search, with the part of it around the match
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub topic: TopicId,
+    // The name the room's owner gave it, if any
+    pub room: Option<String>,
+    pub message: StoredMessage,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            _ => bail!("format must be \"json\" or \"md\", not {:?}", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedRoom {
+    room: String,
+    name: Option<String>,
+    exported_at: String,
+    messages: Vec<ExportedMessage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedMessage {
+    id: String,
+    sender: String,
+    name: Option<String>,
+    text: String,
+    // Milliseconds since the epoch, as sent, and the same as RFC 3339 in UTC
+    timestamp: i64,
+    time: String,
+    edited: bool,
+    deleted: bool,
+    reply_to: Option<String>,
+    // The Lamport clock and the messages this one was sent after, which place it in the room's log.
+    // Exports from before they were included import without them.
+    #[serde(default)]
+    clock: u64,
+    #[serde(default)]
+    parents: Vec<String>,
+}
+
+// The history database of a data directory, read without bringing up a node
+pub struct Archive {
+    storage: Storage,
+}
+
+impl Archive {
+    pub fn open(data_dir: &Path, key: Option<&HistoryKey>) -> Result<Self> {
+        let path = data_dir.join("history.db");
+        if !path.exists() {
+            bail!("no chat history in {}", data_dir.display());
+        }
+        Ok(Self { storage: Storage::open(&path, key)? })
+    }
+
+    // Like `open`, but starts a history database if there is none yet, for imports
+    pub fn create(data_dir: &Path, key: Option<&HistoryKey>) -> Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        Ok(Self { storage: Storage::open(&data_dir.join("history.db"), key)? })
+    }
+
+    // The room whose topic id starts with `query`, or that its owner named `query`
+    pub fn find_room(&self, query: &str) -> Result<TopicId> {
+        let mut found = Vec::new();
+        for topic in self.storage.topics()? {
+            let named = self.room_name(&topic)?.is_some_and(|name| name.eq_ignore_ascii_case(query));
+            if named || topic.to_string().starts_with(&query.to_lowercase()) {
+                found.push(topic);
+            }
+        }
+        match found.as_slice() {
+            [topic] => Ok(*topic),
+            [] => bail!("no stored room matches {:?}", query),
+            _ => bail!("{:?} matches {} rooms, give more of the room id", query, found.len()),
+        }
+    }
+
+    // Every stored message of the room, oldest first
+    pub fn messages(&self, topic: &TopicId) -> Result<Vec<StoredMessage>> {
+        self.storage.messages(topic)
+    }
+
+    pub fn export(&self, topic: &TopicId, format: ExportFormat) -> Result<String> {
+        let name = self.room_name(topic)?;
+        let messages = self.messages(topic)?;
+        match format {
+            ExportFormat::Json => export_json(topic, name, messages),
+            ExportFormat::Markdown => Ok(export_markdown(topic, name, messages)),
+        }
+    }
+
+    // Store the messages of a JSON export we don't have yet. Returns the room and how many
+    // messages were new; importing the same export twice adds nothing.
+    pub fn import(&self, json: &str) -> Result<(TopicId, usize)> {
+        let room: ExportedRoom = match serde_json::from_str(json) {
+            Ok(room) => room,
+            Err(_) if !json.trim_start().starts_with('{') => bail!("only JSON exports can be imported"),
+            Err(err) => bail!("not a history export: {}", err),
+        };
+        let topic: TopicId = room.room.parse()?;
+        let mut imported = 0;
+        for message in room.messages {
+            let stored = StoredMessage {
+                id: message.id.parse()?,
+                sender: message.sender.parse()?,
+                name: message.name,
+                text: message.text,
+                timestamp: message.timestamp,
+                edited: message.edited,
+                deleted: message.deleted,
+                reply_to: message.reply_to.map(|id| id.parse()).transpose()?,
+                clock: message.clock,
+                parents: message.parents.iter().map(|id| id.parse()).collect::<Result<_>>()?,
+            };
+            if self.storage.insert(&topic, &stored, &Signed::default())? {
+                imported += 1;
+            }
+        }
+        Ok((topic, imported))
+    }
+
+    // Page `page` (from 0) of the messages containing `query`, in `room` or everywhere
+    pub fn search(&self, query: &str, room: Option<&TopicId>, page: usize) -> Result<Vec<SearchHit>> {
+        search(&self.storage, query, room, page)
+    }
+
+    fn room_name(&self, topic: &TopicId) -> Result<Option<String>> {
+        room_name(&self.storage, topic)
+    }
+}
+
+fn room_name(storage: &Storage, topic: &TopicId) -> Result<Option<String>> {
+    let info = storage.room_info(topic)?;
+    Ok(info.map(|(_, info)| info.name).filter(|name| !name.is_empty()))
+}
+
+pub(crate) fn search(
+    storage: &Storage,
+    query: &str,
+    room: Option<&TopicId>,
+    page: usize,
+) -> Result<Vec<SearchHit>> {
+    if query.trim().is_empty() {
+        bail!("nothing to search for");
+    }
+    let mut hits = Vec::new();
+    for (topic, message) in storage.search(query, room, SEARCH_PAGE, page * SEARCH_PAGE)? {
+        let snippet = snippet(&message.text, query);
+        hits.push(SearchHit { topic, room: room_name(storage, &topic)?, message, snippet });
+    }
+    Ok(hits)
+}
+
+// The match and a little on either side of it, on one line
+fn snippet(text: &str, query: &str) -> String {
+    let chars: Vec<char> = text.chars().map(|c| if c.is_whitespace() { ' ' } else { c }).collect();
+    let query: Vec<char> = query.chars().collect();
+    let start = (0..chars.len())
+        .find(|&i| {
+            chars.len() - i >= query.len()
+                && chars[i..i + query.len()].iter().zip(&query).all(|(a, b)| a.eq_ignore_ascii_case(b))
+        })
+        .unwrap_or(0);
+    let from = start.saturating_sub(SNIPPET_CONTEXT);
+    let to = (start + query.len() + SNIPPET_CONTEXT).min(chars.len());
+    let mut out = String::new();
+    if from > 0 {
+        out.push('…');
+    }
+    out.extend(&chars[from..to]);
+    if to < chars.len() {
+        out.push('…');
+    }
+    out
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(timestamp)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn export_json(topic: &TopicId, name: Option<String>, messages: Vec<StoredMessage>) -> Result<String> {
+    let messages = messages
+        .into_iter()
+        .map(|message| ExportedMessage {
+            id: message.id.to_string(),
+            sender: message.sender.to_string(),
+            name: message.name,
+            // Retracted messages keep their place in the log, not their text
+            text: if message.deleted { String::new() } else { message.text },
+            timestamp: message.timestamp,
+            time: format_time(message.timestamp),
+            edited: message.edited,
+            deleted: message.deleted,
+            reply_to: message.reply_to.map(|id| id.to_string()),
+            clock: message.clock,
+            parents: message.parents.iter().map(|id| id.to_string()).collect(),
+        })
+        .collect();
+    let room = ExportedRoom {
+        room: topic.to_string(),
+        name,
+        exported_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        messages,
+    };
+    Ok(serde_json::to_string_pretty(&room)?)
+}
+
+// A transcript to read or publish: one list item per message, with the sender's short node id
+// next to their name, since names aren't unique. Ids are as short as the ones the UI shows.
+fn export_markdown(topic: &TopicId, name: Option<String>, messages: Vec<StoredMessage>) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# {}\n", name.as_deref().unwrap_or("Chat history"));
+    let _ = writeln!(out, "Room `{}`, exported {}\n", topic, Utc::now().format("%Y-%m-%d %H:%M UTC"));
+    for message in messages {
+        let time = DateTime::<Utc>::from_timestamp_millis(message.timestamp).unwrap_or_default();
+        let id = message.id.to_string();
+        let sender = message.sender.fmt_short();
+        let from = match message.name {
+            Some(name) => format!("**{}** (`{}`)", name, sender),
+            None => format!("`{}`", sender),
+        };
+        let reply = message.reply_to.map(|to| format!(" replying to `#{}`", &to.to_string()[..6]));
+        let text = if message.deleted {
+            "*deleted*".to_string()
+        } else if message.edited {
+            format!("{} *(edited)*", message.text)
+        } else {
+            message.text
+        };
+        let _ = writeln!(
+            out,
+            "- `#{}` {} {}{}: {}",
+            &id[..6],
+            time.format("%Y-%m-%d %H:%M"),
+            from,
+            reply.unwrap_or_default(),
+            // Keep each message a single list item
+            text.replace('\n', "\n  ")
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageId;
+    use iroh::SecretKey;
+
+    fn archive() -> Archive {
+        Archive { storage: Storage::open(Path::new(":memory:"), None).unwrap() }
+    }
+
+    fn message(text: &str, clock: u64) -> StoredMessage {
+        StoredMessage {
+            id: MessageId::random(),
+            sender: SecretKey::from_bytes(&[1; 32]).public(),
+            name: Some("alice".to_string()),
+            text: text.to_string(),
+            timestamp: 1_700_000_000_000 + clock as i64,
+            edited: false,
+            deleted: false,
+            reply_to: None,
+            clock,
+            parents: Vec::new(),
+        }
+    }
+
+    fn keep(archive: &Archive, topic: &TopicId, message: &StoredMessage) {
+        archive.storage.insert(topic, message, &Signed::default()).unwrap();
+    }
+
+    // What an import has to bring back of a message
+    fn fields(message: &StoredMessage) -> (MessageId, Option<String>, String, i64, bool, Option<MessageId>, u64) {
+        let StoredMessage { id, name, text, timestamp, deleted, reply_to, clock, .. } = message;
+        (*id, name.clone(), text.clone(), *timestamp, *deleted, *reply_to, *clock)
+    }
+
+    #[test]
+    fn searches_a_page_at_a_time() {
+        let archive = archive();
+        let (topic, other) = (TopicId::from_bytes([1; 32]), TopicId::from_bytes([2; 32]));
+        for clock in 0..25 {
+            keep(&archive, &topic, &message(&format!("Hello number {}", clock), clock));
+            keep(&archive, &topic, &message("nothing to see", clock));
+        }
+        keep(&archive, &other, &message("hello from elsewhere", 0));
+        let pages: Vec<_> = (0..3).map(|page| archive.search("hello", Some(&topic), page).unwrap()).collect();
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), [SEARCH_PAGE, SEARCH_PAGE, 5]);
+        // Newest first, each match on exactly one page
+        let clocks: Vec<_> = pages.iter().flatten().map(|hit| hit.message.clock).collect();
+        assert_eq!(clocks, (0..25).rev().collect::<Vec<_>>());
+        assert_eq!(archive.search("hello", None, 2).unwrap().len(), 6);
+        // Wildcards are matched as they are
+        assert!(archive.search("hello_", None, 0).unwrap().is_empty());
+        assert!(archive.search(" ", None, 0).is_err());
+    }
+
+    #[test]
+    fn imports_what_it_exported_once() {
+        let (from, to) = (archive(), archive());
+        let topic = TopicId::from_bytes([1; 32]);
+        let first = message("first", 1);
+        let reply = StoredMessage { reply_to: Some(first.id), parents: vec![first.id], ..message("second", 2) };
+        let retracted = message("gone", 3);
+        for message in [&first, &reply, &retracted] {
+            keep(&from, &topic, message);
+        }
+        from.storage.edit(&topic, &first.id, &first.sender, "first, edited", b"").unwrap();
+        from.storage.delete(&topic, &retracted.id, &retracted.sender, b"").unwrap();
+        let json = from.export(&topic, ExportFormat::Json).unwrap();
+        assert_eq!(to.import(&json).unwrap(), (topic, 3));
+        // Importing the same export again adds nothing
+        assert_eq!(to.import(&json).unwrap(), (topic, 0));
+        let (exported, imported) = (from.messages(&topic).unwrap(), to.messages(&topic).unwrap());
+        assert_eq!(exported.iter().map(fields).collect::<Vec<_>>(), imported.iter().map(fields).collect::<Vec<_>>());
+        assert!(imported[0].edited && imported[2].deleted && imported[2].text.is_empty());
+        assert_eq!(imported[1].parents, [first.id]);
+        assert!(to.import("# Chat history").is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use iroh::NodeId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Peers we never want to hear from, in any room or by DM, with the name they had when blocked.
+// Unlike bans this is our own choice: nobody else is told.
+#[derive(Debug, Clone, Default)]
+pub struct Blocks(Arc<Mutex<HashMap<NodeId, String>>>);
+
+impl Blocks {
+    // Returns whether the peer wasn't blocked already
+    pub fn insert(&self, node_id: NodeId, name: String) -> bool {
+        let mut blocked = self.0.lock().expect("blocks lock poisoned");
+        if blocked.contains_key(&node_id) {
+            return false;
+        }
+        blocked.insert(node_id, name);
+        true
+    }
+
+    // Returns the name the peer was blocked under, if it was
+    pub fn remove(&self, node_id: &NodeId) -> Option<String> {
+        self.0.lock().expect("blocks lock poisoned").remove(node_id)
+    }
+
+    pub fn contains(&self, node_id: &NodeId) -> bool {
+        self.0.lock().expect("blocks lock poisoned").contains_key(node_id)
+    }
+
+    // Everyone blocked, sorted by name
+    pub fn list(&self) -> Vec<(NodeId, String)> {
+        let blocked = self.0.lock().expect("blocks lock poisoned");
+        let mut blocked: Vec<_> = blocked.iter().map(|(id, name)| (*id, name.clone())).collect();
+        blocked.sort_by(|a, b| a.1.cmp(&b.1));
+        blocked
+    }
+}
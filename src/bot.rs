@@ -0,0 +1,120 @@
+use crate::message::MessageId;
+use anyhow::Result;
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+// A command someone sent to a room, e.g. "!roll 2d6"
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub room: TopicId,
+    // The message the command came in, which room replies answer
+    pub id: MessageId,
+    pub from: NodeId,
+    pub name: String,
+    // The command word without the prefix ("roll") and everything after it ("2d6")
+    pub command: String,
+    pub args: String,
+}
+
+// What a handler wants said in response
+#[derive(Debug, Clone)]
+pub enum Reply {
+    // Answer in the room, as a reply to the command
+    Room(String),
+    // Answer with a direct message to whoever sent the command
+    Direct(String),
+    Nothing,
+}
+
+impl Reply {
+    pub fn room(text: impl Into<String>) -> Self {
+        Reply::Room(text.into())
+    }
+
+    pub fn dm(text: impl Into<String>) -> Self {
+        Reply::Direct(text.into())
+    }
+}
+
+pub(crate) type Response = Pin<Box<dyn Future<Output = Result<Reply>> + Send>>;
+
+type Handler = Arc<dyn Fn(Command) -> Response + Send + Sync>;
+
+// Command handlers run for chat messages starting with the bot's prefix. Hand one to
+// `ChatClientBuilder::bot`.
+#[derive(Clone)]
+pub struct Bot {
+    prefix: String,
+    commands: Arc<BTreeMap<String, Handler>>,
+}
+
+impl fmt::Debug for Bot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bot").field("prefix", &self.prefix).field("commands", &self.commands.keys()).finish()
+    }
+}
+
+pub struct BotBuilder {
+    prefix: String,
+    commands: BTreeMap<String, Handler>,
+}
+
+impl Bot {
+    pub fn builder() -> BotBuilder {
+        BotBuilder { prefix: "!".to_string(), commands: BTreeMap::new() }
+    }
+
+    // The response to a chat message, if it is one of our commands. Unless a handler is registered
+    // for it, "help" lists the commands.
+    pub(crate) fn dispatch(
+        &self,
+        room: TopicId,
+        id: MessageId,
+        from: NodeId,
+        name: String,
+        text: &str,
+    ) -> Option<Response> {
+        let rest = text.strip_prefix(&self.prefix)?;
+        let (command, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let Some(handler) = self.commands.get(command) else {
+            if command != "help" {
+                return None;
+            }
+            let commands: Vec<_> = self.commands.keys().map(|c| format!("{}{}", self.prefix, c)).collect();
+            let help = Reply::room(format!("commands: {}", commands.join(", ")));
+            return Some(Box::pin(async move { Ok(help) }));
+        };
+        let command =
+            Command { room, id, from, name, command: command.to_string(), args: args.trim().to_string() };
+        Some(handler(command))
+    }
+}
+
+impl BotBuilder {
+    // What commands start with (defaults to "!")
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    // Run `handler` for messages like "!<name> args". Each command is handled in a task of its
+    // own, so slow handlers don't hold up the room.
+    pub fn command<F, Fut>(mut self, name: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Command) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Reply>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |command| Box::pin(handler(command)));
+        self.commands.insert(name.into(), handler);
+        self
+    }
+
+    pub fn build(self) -> Bot {
+        Bot { prefix: self.prefix, commands: Arc::new(self.commands) }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::message::MessageId;
+use anyhow::{bail, Result};
+use iroh::NodeId;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+// Encrypted messages up to this size go out as they are. iroh-gossip drops anything over 4 KiB,
+// and needs some of that for its own framing.
+pub const MAX_PAYLOAD: usize = 3584;
+
+// How much of a larger message goes into each chunk, leaving room for the chunk's own envelope
+const CHUNK_SIZE: usize = 3072;
+
+// Messages needing more chunks than this are refused, on both ends
+const MAX_CHUNKS: usize = 256;
+
+// Incomplete messages are given up on after this long without a new chunk
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How many incomplete messages are held at once from one sender, and from everyone in the room.
+// Starting another drops the one that has waited longest for a chunk.
+const MAX_PARTIALS_PER_SENDER: usize = 4;
+const MAX_PARTIALS: usize = 32;
+
+// Cut an encrypted message into chunks small enough to gossip
+pub fn split(payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let chunks: Vec<_> = payload.chunks(CHUNK_SIZE).map(|chunk| chunk.to_vec()).collect();
+    if chunks.len() > MAX_CHUNKS {
+        bail!("message too large ({} bytes, at most {})", payload.len(), MAX_CHUNKS * CHUNK_SIZE);
+    }
+    Ok(chunks)
+}
+
+// The chunks of one message received so far
+#[derive(Debug)]
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    updated: Instant,
+}
+
+// Chunked messages of one room being put back together, keyed by sender and message id
+#[derive(Debug, Default)]
+pub struct Reassembly(HashMap<(NodeId, MessageId), Partial>);
+
+impl Reassembly {
+    // Add a chunk. Returns the whole encrypted message once its last chunk is in.
+    pub fn push(
+        &mut self,
+        from: NodeId,
+        id: MessageId,
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        let (index, total) = (index as usize, total as usize);
+        if total > MAX_CHUNKS || index >= total || data.len() > CHUNK_SIZE {
+            bail!("invalid chunk {} of {}", index, total);
+        }
+        if !self.0.contains_key(&(from, id)) {
+            self.make_room(from);
+        }
+        let partial = self
+            .0
+            .entry((from, id))
+            .or_insert_with(|| Partial { chunks: vec![None; total], updated: Instant::now() });
+        if partial.chunks.len() != total {
+            bail!("chunk {} of {} doesn't match the earlier chunks", index, total);
+        }
+        partial.chunks[index] = Some(data);
+        partial.updated = Instant::now();
+        if partial.chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let partial = self.0.remove(&(from, id)).expect("partial message just seen");
+        Ok(Some(partial.chunks.into_iter().flatten().flatten().collect()))
+    }
+
+    // Drop the stalest incomplete messages until a new one from `from` fits under both caps
+    fn make_room(&mut self, from: NodeId) {
+        let stalest = |partials: &HashMap<(NodeId, MessageId), Partial>, from: Option<NodeId>| {
+            let partials = partials.iter().filter(|((sender, _), _)| from.is_none_or(|from| *sender == from));
+            partials.min_by_key(|(_, partial)| partial.updated).map(|(key, _)| *key)
+        };
+        while self.0.keys().filter(|(sender, _)| *sender == from).count() >= MAX_PARTIALS_PER_SENDER
+            && let Some(key) = stalest(&self.0, Some(from))
+        {
+            self.0.remove(&key);
+        }
+        while self.0.len() >= MAX_PARTIALS
+            && let Some(key) = stalest(&self.0, None)
+        {
+            self.0.remove(&key);
+        }
+    }
+
+    // When the next incomplete message times out
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.0.values().map(|partial| partial.updated + CHUNK_TIMEOUT).min()
+    }
+
+    // Drop the messages that timed out and return who sent them
+    pub fn expire(&mut self) -> Vec<NodeId> {
+        let mut expired = Vec::new();
+        self.0.retain(|(from, _), partial| {
+            let keep = partial.updated.elapsed() < CHUNK_TIMEOUT;
+            if !keep {
+                expired.push(*from);
+            }
+            keep
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> NodeId {
+        iroh::SecretKey::from_bytes(&[seed; 32]).public()
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let payload: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        let chunks = split(&payload).unwrap();
+        assert_eq!(chunks.len(), 4);
+        let (from, id) = (node(1), MessageId::random());
+        let mut reassembly = Reassembly::default();
+        // Chunks can arrive in any order
+        for index in [2, 0, 3] {
+            let chunk = chunks[index].clone();
+            assert_eq!(reassembly.push(from, id, index as u32, 4, chunk).unwrap(), None);
+        }
+        let whole = reassembly.push(from, id, 1, 4, chunks[1].clone()).unwrap();
+        assert_eq!(whole, Some(payload));
+        assert_eq!(reassembly.next_expiry(), None);
+    }
+
+    #[test]
+    fn refuses_oversized_and_inconsistent_chunks() {
+        assert!(split(&vec![0; CHUNK_SIZE * MAX_CHUNKS + 1]).is_err());
+        let (from, id) = (node(1), MessageId::random());
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly.push(from, id, 0, MAX_CHUNKS as u32 + 1, vec![0]).is_err());
+        assert!(reassembly.push(from, id, 2, 2, vec![0]).is_err());
+        assert!(reassembly.push(from, id, 0, 2, vec![0; CHUNK_SIZE + 1]).is_err());
+        reassembly.push(from, id, 0, 2, vec![0]).unwrap();
+        assert!(reassembly.push(from, id, 1, 3, vec![0]).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_stalled_messages() {
+        let mut reassembly = Reassembly::default();
+        reassembly.push(node(1), MessageId::random(), 0, 2, vec![0]).unwrap();
+        tokio::time::advance(CHUNK_TIMEOUT / 2).await;
+        reassembly.push(node(2), MessageId::random(), 0, 2, vec![0]).unwrap();
+        assert!(reassembly.expire().is_empty());
+        tokio::time::advance(CHUNK_TIMEOUT / 2).await;
+        assert_eq!(reassembly.expire(), vec![node(1)]);
+        assert_eq!(reassembly.next_expiry(), Some(Instant::now() + CHUNK_TIMEOUT / 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_partials_per_sender_and_in_total() {
+        let mut reassembly = Reassembly::default();
+        let ids: Vec<MessageId> = (0..MAX_PARTIALS_PER_SENDER + 1).map(|_| MessageId::random()).collect();
+        for id in &ids {
+            reassembly.push(node(1), *id, 0, 2, vec![0]).unwrap();
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(reassembly.0.len(), MAX_PARTIALS_PER_SENDER);
+        assert!(!reassembly.0.contains_key(&(node(1), ids[0])));
+        for seed in 2..=MAX_PARTIALS as u8 + 1 {
+            reassembly.push(node(seed), MessageId::random(), 0, 2, vec![0]).unwrap();
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(reassembly.0.len(), MAX_PARTIALS);
+        // The stalest are gone, which were the first sender's
+        assert!(reassembly.0.keys().all(|(from, _)| *from != node(1)));
+    }
+}
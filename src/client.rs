@@ -0,0 +1,1858 @@
+use crate::archive::{self, SearchHit, SEARCH_PAGE};
+use crate::blocks::Blocks;
+use crate::bot::Bot;
+use crate::code;
+use crate::location;
+use crate::contacts::{Contact, Contacts};
+use crate::crypto::{self, RoomKey};
+use crate::devices::{self, DevicesProtocol, LinkTicket, Setting};
+use crate::directory::{self, Listing};
+use crate::dm::{self, DmProtocol};
+use crate::files::{self, Files};
+use crate::history::{self, HistoryProtocol};
+use crate::identity;
+use crate::invite::{self, InviteProtocol};
+use crate::membership::{Membership, ModAction, Publishers, RoomInfo};
+use crate::mention;
+use crate::message::MessageId;
+use crate::migrate::{self, Move};
+use crate::names::Names;
+use crate::netcheck::{self, Diagnosis};
+use crate::pake::{self, PakeProtocol};
+use crate::ping::{self, Latency, PingProtocol};
+use crate::plugins::Plugins;
+use crate::presence::{Neighbors, Roster, Status, Statuses};
+use crate::ratelimit::RateLimit;
+use crate::rooms::RoomManager;
+use crate::storage::{self, CachedBlob, HistoryKey, Notify, Retention, Storage, StoredMessage};
+use crate::ticket::Ticket;
+use crate::upload::Throttle;
+use crate::version::Versions;
+use anyhow::{bail, Result};
+use futures_lite::{stream, Stream};
+use iroh::protocol::Router;
+use iroh::discovery::dns::DnsDiscovery;
+use iroh::discovery::pkarr::{PkarrPublisher, PkarrResolver};
+use iroh::endpoint::ConnectionType;
+use iroh::{Endpoint, NodeAddr, NodeId, RelayMode, Watcher};
+use iroh_gossip::{net::Gossip, proto::TopicId};
+use rand::random;
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+// How many events a slow subscriber may fall behind before it starts missing some
+const EVENT_CAPACITY: usize = 1024;
+
+// How long shutting down waits for the Leave messages to be sent
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(300);
+
+// The most characters a /status may have, as it goes along with every heartbeat
+const MAX_STATUS_LEN: usize = 100;
+
+// How often fetches of unknown size report progress, in bytes
+const PROGRESS_STEP: u64 = 10 * 1024 * 1024;
+
+// How long each peer given at startup gets to answer
+const PEER_DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Everything that happens in the rooms we are in, and to us directly
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatEvent {
+    // A peer announced itself in a room
+    Joined {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+    },
+    // A chat message, including the ones we send ourselves. Replies carry the id of the message
+    // they answer, and that message itself if it is in our history.
+    Message {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        #[serde(serialize_with = "hex")]
+        id: MessageId,
+        from: NodeId,
+        name: String,
+        text: String,
+        #[serde(serialize_with = "hex_opt")]
+        reply_to: Option<MessageId>,
+        #[serde(skip)]
+        quoted: Option<StoredMessage>,
+        // When it was sent by the sender's clock, in milliseconds since the Unix epoch. `skewed`
+        // if that is far from when it arrived, so the sender's clock is off.
+        timestamp: i64,
+        skewed: bool,
+    },
+    // A message from before we joined, either from our own database or fetched from a peer
+    History {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        #[serde(serialize_with = "hex")]
+        id: MessageId,
+        from: NodeId,
+        name: String,
+        text: String,
+        timestamp: i64,
+        edited: bool,
+        deleted: bool,
+        remote: bool,
+        // Asked for again after the receiver lagged
+        recovered: bool,
+        #[serde(serialize_with = "hex_opt")]
+        reply_to: Option<MessageId>,
+        #[serde(skip)]
+        quoted: Option<StoredMessage>,
+    },
+    // The author of message `id` changed its text
+    Edited {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        #[serde(serialize_with = "hex")]
+        id: MessageId,
+        text: String,
+    },
+    // The author of message `id` retracted it
+    Deleted {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        #[serde(serialize_with = "hex")]
+        id: MessageId,
+    },
+    // Someone reacted to message `id`. `counts` is every emoji on the message and how many used it.
+    Reaction {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        #[serde(serialize_with = "hex")]
+        id: MessageId,
+        from: NodeId,
+        name: String,
+        emoji: String,
+        counts: Vec<(String, usize)>,
+    },
+    // One of our messages was delivered to or read by someone. Carries everyone so far, by name.
+    Receipt {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        #[serde(serialize_with = "hex")]
+        id: MessageId,
+        delivered: Vec<String>,
+        read: Vec<String>,
+    },
+    // A file someone offered, fetchable with `ChatClient::fetch_file(id)`
+    FileOffered {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        file: String,
+        size: u64,
+        id: usize,
+    },
+    // A directory someone offered, listed with `ChatClient::list_directory(id)` and fetchable as a
+    // whole or in parts like files
+    DirectoryOffered {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        dir: String,
+        size: u64,
+        files: u64,
+        id: usize,
+    },
+    // A contact someone shared, saved to the address book with `ChatClient::save_card(id)`
+    ContactShared {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        node_id: NodeId,
+        card: String,
+        id: usize,
+    },
+    // How far a fetch of offer `id` got, in bytes, out of `size` when we know it. Sent every
+    // percent, or every PROGRESS_STEP bytes if the size is unknown.
+    FileProgress { id: usize, done: u64, size: Option<u64> },
+    DirectMessage { from: NodeId, name: String, text: String },
+    // Another device was linked to our account, here or on one of our other devices
+    DeviceLinked { device: NodeId },
+    // A peer is composing a message. Sent every few seconds while they type; consider it stale after a few more.
+    Typing {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+    },
+    // A peer left the room or shut down
+    Left {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+    },
+    // A peer announced a protocol version other than ours; expect some of its messages to be dropped
+    IncompatiblePeer {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        version: u32,
+    },
+    NeighborUp {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        node_id: NodeId,
+    },
+    NeighborDown {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        node_id: NodeId,
+    },
+    // Every neighbor in the room is gone while its peers weren't leaving, and we keep trying to
+    // reach them
+    Disconnected {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+    },
+    // A neighbor is back after that
+    Reconnected {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+    },
+    // The gossip receiver fell behind and some messages may have been lost
+    Lagged {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+    },
+    // A message that failed to decrypt or verify
+    InvalidMessage {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        via: NodeId,
+        error: String,
+    },
+    // The room's owner changed who may speak in it. `members` are the names of everyone allowed now.
+    Members {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        revision: u64,
+        members: Vec<String>,
+    },
+    // The room's owner renamed, described or set the topic of the room. Empty fields are unset.
+    RoomInfo {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        name: String,
+        description: String,
+        topic: String,
+    },
+    // The room is read-only: only `publishers`, by name and starting with the owner, may post in it
+    ReadOnly {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        publishers: Vec<String>,
+    },
+    // The room's owner moved it to a new topic and key, and we went along. `room` is the new topic,
+    // which takes the old one's place in the room list.
+    Moved {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        #[serde(serialize_with = "hex")]
+        old_room: TopicId,
+    },
+    // The room's owner `from` handed it to `owner`, who can restrict, moderate and rename it now
+    OwnerChanged {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        by: String,
+        owner: NodeId,
+        name: String,
+    },
+    // The owner or a moderator kicked, banned, unbanned, appointed or dismissed someone. `action`
+    // is "kick", "ban", "unban", "appoint" or "dismiss".
+    Moderated {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        action: String,
+        from: NodeId,
+        by: String,
+        target: NodeId,
+        name: String,
+    },
+    // A peer sent more than the rate limit allows, so its messages are dropped until it slows down.
+    // Sent once, when it goes over the limit.
+    RateLimited {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+    },
+    // A rate-limited peer slowed down; `dropped` of its messages were lost in the meantime
+    RateLimitLifted {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        dropped: usize,
+    },
+    // A peer went by another name with /nick; `old` and `name` are as we show it
+    Renamed {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        old: String,
+        name: String,
+    },
+    // A peer went away with /away, leaving `away` as its note, or came back and `away` is None
+    Away {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        away: Option<String>,
+    },
+    // A peer announced a name someone else in our rooms, or we, already use. From now on both are
+    // shown as `name#` followed by the start of their node id; `shown_as` is the peer's.
+    NameClash {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        shown_as: String,
+    },
+    // A name we pinned to one node id in the room showed up signed by another. Either its owner
+    // moved to a new device or someone is impersonating them; `/trust` accepts the new id.
+    KeyChanged {
+        #[serde(serialize_with = "hex")]
+        room: TopicId,
+        from: NodeId,
+        name: String,
+        pinned: NodeId,
+        shown_as: String,
+    },
+    // A line a plugin logged while handling a message
+    PluginLog { plugin: String, text: String },
+    // Do not disturb, set at `since`, ended by itself or with /dnd off
+    DoNotDisturbOver { since: i64 },
+    // Something went wrong in the background, e.g. saving a message or fetching history
+    Warning(String),
+}
+
+// Ids as the hex strings users see, rather than arrays of numbers
+fn hex<S: Serializer>(id: &impl fmt::Display, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+fn hex_opt<S: Serializer>(id: &Option<MessageId>, serializer: S) -> Result<S::Ok, S::Error> {
+    match id {
+        Some(id) => serializer.collect_str(id),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl ChatEvent {
+    // The room the event happened in, if any
+    pub fn room(&self) -> Option<TopicId> {
+        match self {
+            ChatEvent::Joined { room, .. }
+            | ChatEvent::Message { room, .. }
+            | ChatEvent::History { room, .. }
+            | ChatEvent::Receipt { room, .. }
+            | ChatEvent::Reaction { room, .. }
+            | ChatEvent::Edited { room, .. }
+            | ChatEvent::Deleted { room, .. }
+            | ChatEvent::FileOffered { room, .. }
+            | ChatEvent::DirectoryOffered { room, .. }
+            | ChatEvent::ContactShared { room, .. }
+            | ChatEvent::Typing { room, .. }
+            | ChatEvent::IncompatiblePeer { room, .. }
+            | ChatEvent::Left { room, .. }
+            | ChatEvent::NeighborUp { room, .. }
+            | ChatEvent::NeighborDown { room, .. }
+            | ChatEvent::Disconnected { room }
+            | ChatEvent::Reconnected { room }
+            | ChatEvent::Lagged { room }
+            | ChatEvent::Members { room, .. }
+            | ChatEvent::RoomInfo { room, .. }
+            | ChatEvent::Moved { room, .. }
+            | ChatEvent::ReadOnly { room, .. }
+            | ChatEvent::OwnerChanged { room, .. }
+            | ChatEvent::Moderated { room, .. }
+            | ChatEvent::RateLimited { room, .. }
+            | ChatEvent::Renamed { room, .. }
+            | ChatEvent::Away { room, .. }
+            | ChatEvent::NameClash { room, .. }
+            | ChatEvent::KeyChanged { room, .. }
+            | ChatEvent::RateLimitLifted { room, .. }
+            | ChatEvent::InvalidMessage { room, .. } => Some(*room),
+            ChatEvent::DirectMessage { .. }
+            | ChatEvent::DeviceLinked { .. }
+            | ChatEvent::FileProgress { .. }
+            | ChatEvent::PluginLog { .. }
+            | ChatEvent::DoNotDisturbOver { .. }
+            | ChatEvent::Warning(_) => None,
+        }
+    }
+
+    pub(crate) fn history(
+        room: TopicId,
+        stored: StoredMessage,
+        remote: bool,
+        recovered: bool,
+        quoted: Option<StoredMessage>,
+    ) -> Self {
+        ChatEvent::History {
+            room,
+            id: stored.id,
+            from: stored.sender,
+            name: stored.name.unwrap_or(stored.sender.fmt_short()),
+            text: stored.text,
+            timestamp: stored.timestamp,
+            edited: stored.edited,
+            deleted: stored.deleted,
+            remote,
+            recovered,
+            reply_to: stored.reply_to,
+            quoted,
+        }
+    }
+}
+
+// A peer that sent a heartbeat or message recently
+#[derive(Debug, Clone)]
+pub struct OnlinePeer {
+    pub node_id: NodeId,
+    pub name: String,
+    pub last_seen: Duration,
+    // The protocol version and features from the peer's Hello, if we got one
+    pub protocol: Option<(u32, Vec<String>)>,
+    // Whether the peer is away or in do-not-disturb and what it is up to, as far as it tells
+    pub status: Status,
+}
+
+// How many of a room's gossip neighbors we reach directly, and how many through a relay
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Links {
+    pub direct: usize,
+    pub relayed: usize,
+}
+
+// How the endpoint reaches one of the active room's neighbors, for /connections
+#[derive(Debug, Clone)]
+pub struct NeighborLink {
+    pub node_id: NodeId,
+    pub name: String,
+    // None once the endpoint forgot the peer
+    pub conn_type: Option<ConnectionType>,
+    // Round trip time of the path in use
+    pub latency: Option<Duration>,
+    // Since anything went either way
+    pub last_used: Option<Duration>,
+    // Bytes of the room's messages it passed us while a neighbor
+    pub received: u64,
+}
+
+// QUIC bytes through the endpoint since it started, every room and protocol together. Gossip keeps
+// its connections to itself, so this isn't known per peer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Traffic {
+    pub sent: u64,
+    pub received: u64,
+    // How much of `received` came through a relay
+    pub relayed: u64,
+}
+
+// Sending half of the event stream, handed to everything that produces events
+#[derive(Debug, Clone)]
+pub(crate) struct Events(broadcast::Sender<ChatEvent>);
+
+impl Events {
+    pub(crate) fn emit(&self, event: ChatEvent) {
+        // Nobody listening is fine, the events are just dropped
+        let _ = self.0.send(event);
+    }
+
+    pub(crate) fn warn(&self, text: String) {
+        self.emit(ChatEvent::Warning(text));
+    }
+}
+
+// Which ways the endpoint has of finding the addresses of peers we only know by node id. Parsed
+// from a comma-separated list of "dns", "pkarr" and "local", where "n0" stands for "dns,pkarr"
+// (the default), "all" for all three and "none" for none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DiscoveryMode {
+    // Looking node ids up in n0's DNS server, which serves what was published to its pkarr relay
+    pub dns: bool,
+    // n0's pkarr relay over HTTPS, where we publish our addresses. Without publishing we only look
+    // others up there.
+    pub pkarr: bool,
+    // mDNS-style announcements on the local network, which work offline
+    pub local: bool,
+}
+
+impl DiscoveryMode {
+    pub const N0: Self = Self { dns: true, pkarr: true, local: false };
+    pub const LOCAL: Self = Self { dns: false, pkarr: false, local: true };
+}
+
+impl Default for DiscoveryMode {
+    fn default() -> Self {
+        Self::N0
+    }
+}
+
+impl FromStr for DiscoveryMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mode = Self { dns: false, pkarr: false, local: false };
+        for name in s.split(',').map(str::trim) {
+            match name {
+                "n0" => (mode.dns, mode.pkarr) = (true, true),
+                "dns" => mode.dns = true,
+                "pkarr" => mode.pkarr = true,
+                "local" => mode.local = true,
+                "all" => mode = Self { dns: true, pkarr: true, local: true },
+                "none" => {}
+                _ => bail!("{:?} is not one of \"dns\", \"pkarr\", \"local\", \"n0\", \"all\" or \"none\"", name),
+            }
+        }
+        Ok(mode)
+    }
+}
+
+impl TryFrom<String> for DiscoveryMode {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChatClientBuilder {
+    name: Option<String>,
+    secret_key: Option<iroh::SecretKey>,
+    data_dir: Option<PathBuf>,
+    relay_mode: Option<RelayMode>,
+    discovery: DiscoveryMode,
+    no_publish: bool,
+    rate_limit: RateLimit,
+    bot: Option<Bot>,
+    history_key: Option<HistoryKey>,
+    mailbox: bool,
+    file_cache: Option<u64>,
+    upload_limit: Option<u64>,
+    peers: Vec<NodeAddr>,
+    bind_v4: Option<SocketAddrV4>,
+    bind_v6: Option<SocketAddrV6>,
+}
+
+impl ChatClientBuilder {
+    // The name we announce in rooms and sign direct messages with
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    // Use this key instead of the one stored in the data directory
+    pub fn secret_key(mut self, secret_key: iroh::SecretKey) -> Self {
+        self.secret_key = Some(secret_key);
+        self
+    }
+
+    // Where the identity key, history database and blob store live (defaults to ~/.config/iroh-chat)
+    pub fn data_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(path.into());
+        self
+    }
+
+    // Which relay servers to use (defaults to n0's)
+    pub fn relay_mode(mut self, relay_mode: RelayMode) -> Self {
+        self.relay_mode = Some(relay_mode);
+        self
+    }
+
+    // How to look up peers' addresses (defaults to n0's discovery service)
+    pub fn discovery(mut self, discovery: DiscoveryMode) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    // Keep our addresses out of n0's pkarr relay, so only peers with a ticket or contact card from
+    // us can find us. Local discovery always announces us, so it can't be combined with this.
+    pub fn no_publish(mut self, no_publish: bool) -> Self {
+        self.no_publish = no_publish;
+        self
+    }
+
+    // How many messages each peer may send per room (defaults to 5 a second, in bursts of up to 20).
+    // The rest are dropped until the peer slows down.
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    // Answer commands like "!roll" in every room we join
+    pub fn bot(mut self, bot: Bot) -> Self {
+        self.bot = Some(bot);
+        self
+    }
+
+    // Keep every room's history for members that were away and tell the rooms, so they catch up
+    // from us when they come back. Best on a node that stays online.
+    pub fn mailbox(mut self, mailbox: bool) -> Self {
+        self.mailbox = mailbox;
+        self
+    }
+
+    // How many bytes offered and fetched files may take in the blob store (defaults to 1 GB).
+    // Beyond that the least recently used ones are dropped.
+    pub fn file_cache(mut self, size: u64) -> Self {
+        self.file_cache = Some(size);
+        self
+    }
+
+    // Send files no faster than this many bytes a second, all downloads from us together. Chat
+    // messages aren't held back.
+    pub fn upload_limit(mut self, per_second: u64) -> Self {
+        self.upload_limit = Some(per_second);
+        self
+    }
+
+    // Dial this peer on startup, at its addresses if there are any, and bootstrap every room we join
+    // from it as well as from the ticket's or bookmark's peers. Add it again for more peers.
+    pub fn peer(mut self, addr: NodeAddr) -> Self {
+        self.peers.push(addr);
+        self
+    }
+
+    // Listen for IPv4 and IPv6 on these addresses instead of every interface's, on a random port. A
+    // port given here has to be free, so it can be opened in a firewall.
+    pub fn bind_v4(mut self, addr: SocketAddrV4) -> Self {
+        self.bind_v4 = Some(addr);
+        self
+    }
+
+    pub fn bind_v6(mut self, addr: SocketAddrV6) -> Self {
+        self.bind_v6 = Some(addr);
+        self
+    }
+
+    // Encrypt the history database with this key. An existing plain one is encrypted on first use.
+    pub fn history_key(mut self, key: HistoryKey) -> Self {
+        self.history_key = Some(key);
+        self
+    }
+
+    // Bind the endpoint and start accepting gossip, history, blob and DM connections
+    pub async fn spawn(self) -> Result<ChatClient> {
+        let data_dir = match self.data_dir {
+            Some(path) => path,
+            None => crate::default_data_dir()?,
+        };
+        let secret_key = match self.secret_key {
+            Some(key) => key,
+            None => identity::load_or_create(&data_dir.join("identity.key"))?,
+        };
+        let name = self.name.unwrap_or_else(|| "user".to_string());
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let events = Events(events);
+
+        // Open the local message history
+        let storage = Arc::new(Storage::open(&data_dir.join("history.db"), self.history_key.as_ref())?);
+        storage::prune_periodically(Arc::downgrade(&storage));
+
+        // Create Iroh endpoint with discovery
+        let mut endpoint = Endpoint::builder()
+            .secret_key(secret_key)
+            .relay_mode(self.relay_mode.unwrap_or(RelayMode::Default));
+        let discovery = self.discovery;
+        if discovery.local && self.no_publish {
+            bail!("local discovery announces us on the local network, so it can't be used without publishing");
+        }
+        if discovery.pkarr && !self.no_publish {
+            endpoint = endpoint.add_discovery(PkarrPublisher::n0_dns());
+        }
+        if discovery.pkarr && self.no_publish {
+            endpoint = endpoint.add_discovery(PkarrResolver::n0_dns());
+        }
+        if discovery.dns {
+            endpoint = endpoint.add_discovery(DnsDiscovery::n0_dns());
+        }
+        if discovery.local {
+            endpoint = endpoint.discovery_local_network();
+        }
+        if let Some(addr) = self.bind_v4 {
+            endpoint = endpoint.bind_addr_v4(addr);
+        }
+        if let Some(addr) = self.bind_v6 {
+            endpoint = endpoint.bind_addr_v6(addr);
+        }
+        let endpoint = endpoint.bind().await?;
+        // iroh picks another port when the one asked for is taken
+        let bound = endpoint.bound_sockets();
+        let asked = [self.bind_v4.map(SocketAddr::V4), self.bind_v6.map(SocketAddr::V6)];
+        for addr in asked.into_iter().flatten().filter(|addr| addr.port() != 0) {
+            if !bound.iter().any(|bound| bound.is_ipv4() == addr.is_ipv4() && bound.port() == addr.port()) {
+                endpoint.close().await;
+                bail!("could not listen on {}, is another program using the port?", addr);
+            }
+        }
+        // Those given by node id alone are left to discovery
+        for addr in self.peers.iter().filter(|addr| !addr.is_empty()) {
+            endpoint.add_node_addr(addr.clone())?;
+        }
+
+        let gossip = Gossip::builder().spawn(endpoint.clone());
+
+        // Serve our stored history to late joiners
+        let history = HistoryProtocol::new(storage.clone());
+
+        // Blob store for files shared in rooms
+        let file_cache = self.file_cache.unwrap_or(files::DEFAULT_CACHE_SIZE);
+        let throttle = self.upload_limit.map(Throttle::new);
+        let files =
+            Files::load(&data_dir.join("blobs"), endpoint.clone(), storage.clone(), file_cache, throttle).await?;
+
+        // WASM hooks for chat messages
+        let plugins = Plugins::load(&data_dir.join("plugins"))?;
+
+        // Rooms their owner moved, for us to follow once the client is up
+        let (moves, moved) = mpsc::unbounded_channel();
+
+        let mut rooms = RoomManager::new(
+            endpoint.clone(),
+            gossip.clone(),
+            history.clone(),
+            storage.clone(),
+            files.clone(),
+            name,
+            events.clone(),
+        )
+        .with_plugins(plugins.clone())
+        .with_rate_limit(self.rate_limit)
+        .with_moves(moves);
+        if let Some(bot) = self.bot {
+            rooms = rooms.with_bot(bot);
+        }
+        if self.mailbox {
+            rooms = rooms.with_mailbox();
+        }
+        let names = rooms.names_handle();
+        let active = rooms.active_handle();
+        let roster = rooms.roster();
+        let neighbors = rooms.neighbors();
+        let statuses = rooms.statuses();
+        let versions = rooms.versions();
+        let blocks = rooms.blocks();
+        let membership = rooms.membership();
+        let migrations = rooms.migrations();
+        for (node_id, name) in storage.blocked()? {
+            blocks.insert(node_id, name);
+        }
+        // Contacts can be dialed where we last saw them, even if discovery doesn't know them
+        let contacts = names.contacts();
+        for contact in storage.contacts()? {
+            if let Some(addr) = contact.addr.clone() {
+                let _ = endpoint.add_node_addr(addr);
+            }
+            contacts.insert(contact);
+        }
+        // Which accounts the devices we know of speak for, ours included once we are linked
+        let linked = names.devices();
+        for cert in storage.devices()? {
+            let device = cert.device;
+            if let Err(err) = linked.add(cert) {
+                events.warn(format!("ignoring the stored certificate of device {}: {}", device.fmt_short(), err));
+            }
+        }
+        let devices = DevicesProtocol::new(
+            endpoint.clone(),
+            linked,
+            storage.clone(),
+            contacts.clone(),
+            blocks.clone(),
+            events.clone(),
+        );
+
+        // Direct messages are delivered wherever we are
+        let dms = DmProtocol::new(names.clone(), blocks.clone(), statuses.clone(), events.clone());
+
+        let pings = PingProtocol::new(blocks.clone());
+
+        // Restricted tickets are traded for room keys with us
+        let invites = InviteProtocol::default();
+        let passwords = PakeProtocol::default();
+
+        let router = Router::builder(endpoint.clone())
+            .accept(iroh_gossip::ALPN, gossip.clone())
+            .accept(history::ALPN, history)
+            .accept(iroh_blobs::ALPN, files.protocol())
+            .accept(dm::ALPN, dms)
+            .accept(invite::ALPN, invites.clone())
+            .accept(pake::ALPN, passwords.clone())
+            .accept(migrate::ALPN, migrations)
+            .accept(devices::ALPN, devices.clone())
+            .accept(ping::ALPN, pings)
+            .spawn();
+
+        let inner = Arc::new(Inner {
+            endpoint,
+            router,
+            rooms: Mutex::new(rooms),
+            files,
+            invites,
+            passwords,
+            devices,
+            storage,
+            names,
+            contacts,
+            membership,
+            gossip,
+            active,
+            roster,
+            neighbors,
+            statuses,
+            versions,
+            blocks,
+            plugins,
+            events,
+            dnd: Default::default(),
+            search: Mutex::new(None),
+            code: Mutex::new(None),
+            publishing: self.discovery.pkarr && !self.no_publish,
+            peers: self.peers,
+        });
+        if let Err(err) = inner.devices.catch_up() {
+            inner.events.warn(format!("could not sync with your other devices: {}", err));
+        }
+        tokio::spawn(follow_moves(Arc::downgrade(&inner), moved));
+        let (endpoint, gossip, events) = (inner.endpoint.clone(), inner.gossip.clone(), inner.events.clone());
+        tokio::spawn(dial_peers(endpoint, gossip, inner.peers.clone(), events));
+        Ok(ChatClient(inner))
+    }
+}
+
+// Connect to the peers we were given at startup, so rooms find them connected already. Gossip keeps
+// the connections for the rooms that need them.
+async fn dial_peers(endpoint: Endpoint, gossip: Gossip, peers: Vec<NodeAddr>, events: Events) {
+    for peer in peers {
+        let node_id = peer.node_id;
+        let dial = tokio::time::timeout(PEER_DIAL_TIMEOUT, endpoint.connect(peer, iroh_gossip::ALPN)).await;
+        let result = match dial {
+            Ok(Ok(conn)) => gossip.handle_connection(conn).await.map_err(anyhow::Error::from),
+            Ok(Err(err)) => Err(err.into()),
+            Err(_) => Err(anyhow::anyhow!("no answer")),
+        };
+        if let Err(err) = result {
+            events.warn(format!("could not reach peer {}: {}", node_id.fmt_short(), err));
+        }
+    }
+}
+
+// Follow the rooms their owner moves for as long as the client is around
+async fn follow_moves(inner: Weak<Inner>, mut moved: mpsc::UnboundedReceiver<Move>) {
+    while let Some(moved) = moved.recv().await {
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let client = ChatClient(inner);
+        if let Err(err) = client.follow(moved).await {
+            let room = moved.from.fmt_short();
+            client.0.events.warn(format!("could not follow room {} to its new topic: {}", room, err));
+        }
+    }
+}
+
+// When do-not-disturb was set, and when it ends if it ends by itself, in milliseconds since the epoch
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DoNotDisturb {
+    since: i64,
+    until: Option<i64>,
+}
+
+struct Inner {
+    endpoint: Endpoint,
+    router: Router,
+    rooms: Mutex<RoomManager>,
+    files: Files,
+    invites: InviteProtocol,
+    passwords: PakeProtocol,
+    devices: DevicesProtocol,
+    storage: Arc<Storage>,
+    names: Names,
+    contacts: Contacts,
+    membership: Membership,
+    gossip: Gossip,
+    active: Arc<Mutex<Option<TopicId>>>,
+    roster: Roster,
+    neighbors: Neighbors,
+    statuses: Statuses,
+    versions: Versions,
+    blocks: Blocks,
+    plugins: Plugins,
+    events: Events,
+    dnd: std::sync::Mutex<Option<DoNotDisturb>>,
+    // The last search and the page of it shown last, for paging through it
+    search: Mutex<Option<(String, usize)>>,
+    // The language and lines of the code snippet being written, until it is sent
+    code: Mutex<Option<(String, Vec<String>)>>,
+    // Whether we publish our addresses to n0's pkarr relay
+    publishing: bool,
+    // Peers given by address, which every room we join is bootstrapped from too
+    peers: Vec<NodeAddr>,
+}
+
+// A running chat node: the endpoint, its protocols and the rooms it is in. Cheap to clone.
+#[derive(Clone)]
+pub struct ChatClient(Arc<Inner>);
+
+impl ChatClient {
+    pub fn builder() -> ChatClientBuilder {
+        ChatClientBuilder::default()
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.0.endpoint.node_id()
+    }
+
+    pub fn name(&self) -> String {
+        self.0.names.ours()
+    }
+
+    // Go by another name from now on, telling every room we are in. Returns the old name.
+    pub async fn nick(&self, name: &str) -> Result<String> {
+        self.0.rooms.lock().await.nick(name).await
+    }
+
+    // Go away with a note, maybe empty, or come back with None. Returns the note we had before,
+    // if we were away. Rooms show it in /who, and DMs sent to us meanwhile are answered with it.
+    pub async fn set_away(&self, note: Option<String>) -> Option<String> {
+        let status = Status { away: note, ..self.0.statuses.ours() };
+        self.0.rooms.lock().await.set_status(status).await.away
+    }
+
+    // Say what we are up to, like "in a meeting", or nothing with an empty text. Returns what we
+    // said before.
+    pub async fn set_status(&self, text: &str) -> Result<String> {
+        let text = text.trim();
+        if text.chars().count() > MAX_STATUS_LEN {
+            bail!("a status can be at most {} characters", MAX_STATUS_LEN);
+        }
+        let status = Status { text: text.to_string(), ..self.0.statuses.ours() };
+        Ok(self.0.rooms.lock().await.set_status(status).await.text)
+    }
+
+    // Go into do-not-disturb for `duration`, or until `end_dnd` without one. It is up to the
+    // frontends to keep quiet while `dnd` says so; with `public` our rooms are told as well.
+    // Setting it again keeps when it started.
+    pub async fn set_dnd(&self, duration: Option<Duration>, public: bool) {
+        let now = storage::now();
+        let dnd = {
+            let mut dnd = self.0.dnd.lock().expect("dnd lock poisoned");
+            let since = dnd.map_or(now, |dnd| dnd.since);
+            *dnd = Some(DoNotDisturb { since, until: duration.map(|duration| now + duration.as_millis() as i64) });
+            dnd.expect("just set")
+        };
+        if let Some(duration) = duration {
+            let inner = Arc::downgrade(&self.0);
+            tokio::spawn(async move {
+                tokio::time::sleep(duration).await;
+                // Unless it was ended or set anew meanwhile
+                if let Some(inner) = inner.upgrade() && *inner.dnd.lock().expect("dnd lock poisoned") == Some(dnd) {
+                    ChatClient(inner).end_dnd().await;
+                }
+            });
+        }
+        if self.0.statuses.ours().dnd != public {
+            let status = Status { dnd: public, ..self.0.statuses.ours() };
+            self.0.rooms.lock().await.set_status(status).await;
+        }
+    }
+
+    // Leave do-not-disturb, returning false if we weren't in it. Frontends hear of it with
+    // DoNotDisturbOver, to show what they held back.
+    pub async fn end_dnd(&self) -> bool {
+        let Some(dnd) = self.0.dnd.lock().expect("dnd lock poisoned").take() else {
+            return false;
+        };
+        if self.0.statuses.ours().dnd {
+            let status = Status { dnd: false, ..self.0.statuses.ours() };
+            self.0.rooms.lock().await.set_status(status).await;
+        }
+        self.0.events.emit(ChatEvent::DoNotDisturbOver { since: dnd.since });
+        true
+    }
+
+    // Whether we are in do-not-disturb
+    pub fn dnd(&self) -> bool {
+        self.0.dnd.lock().expect("dnd lock poisoned").is_some()
+    }
+
+    // The note we left with /away while we are away
+    pub fn away(&self) -> Option<String> {
+        self.0.statuses.ours().away
+    }
+
+    // Names of the plugins loaded from the data directory, in the order they run
+    pub fn plugins(&self) -> Vec<String> {
+        self.0.plugins.names()
+    }
+
+    // Subscribe to everything that happens from now on. Subscribe before joining to see the join's history replay.
+    pub fn events(&self) -> impl Stream<Item = ChatEvent> + Send + Unpin + 'static {
+        let receiver = self.0.events.0.subscribe();
+        Box::pin(stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    // Create a fresh room with us as its only member and return the ticket to invite others
+    pub async fn open_room(&self) -> Result<Ticket> {
+        self.open(None).await
+    }
+
+    // Like `open_room`, but only we and `publishers` may post in the room; everyone else reads along
+    pub async fn open_read_only_room(&self, publishers: Vec<NodeId>) -> Result<Ticket> {
+        self.open(Some(publishers)).await
+    }
+
+    async fn open(&self, publishers: Option<Vec<NodeId>>) -> Result<Ticket> {
+        let topic = TopicId::from_bytes(random::<[u8; 32]>());
+        let key = RoomKey::generate();
+        let my_addr = self.0.endpoint.node_addr().initialized().await;
+        let owner = Some(self.node_id());
+        let publishers = publishers.map(|members| Publishers::sign(self.0.endpoint.secret_key(), topic, members));
+        let ticket = Ticket { owner, ..Ticket::new(topic, vec![my_addr], key) }.with_publishers(publishers.clone());
+        self.0.rooms.lock().await.join(topic, key, vec![], owner, publishers).await?;
+        Ok(ticket)
+    }
+
+    // Enter the room derived from `name` and `secret`, which everyone using the same pair meets in.
+    // Such rooms have no owner. Without a ticket the only peers to find there are our contacts, so
+    // the returned ticket is still the easiest way in for everyone else.
+    pub async fn open_named_room(&self, name: &str, secret: &str) -> Result<Ticket> {
+        let (topic, key) = crypto::derive_room(name, secret);
+        let peers: Vec<_> = self.0.contacts.list().into_iter().filter_map(|contact| contact.addr).collect();
+        let peers = self.with_peers(peers);
+        let my_addr = self.0.endpoint.node_addr().initialized().await;
+        self.0.rooms.lock().await.join(topic, key, peers, None, None).await?;
+        Ok(Ticket::new(topic, vec![my_addr], key))
+    }
+
+    // Like `open_room`, but the ticket leaves out the room key: joiners get it from a member once
+    // they prove they know `password`
+    pub async fn open_protected_room(&self, password: &str) -> Result<Ticket> {
+        let ticket = self.open_room().await?;
+        let key = ticket.key.expect("fresh tickets carry the key");
+        self.0.passwords.add_room(ticket.topic, password.to_string(), key);
+        self.0.storage.bookmark_password(&ticket.topic, password)?;
+        self.bookmark_changed(&ticket.topic)?;
+        let protected = Ticket { owner: ticket.owner, ..Ticket::protected(ticket.topic, ticket.nodes) };
+        Ok(protected.with_publishers(ticket.publishers))
+    }
+
+    // A ticket for the active room with the addresses of everyone we know there. Tickets for
+    // protected rooms leave out the key.
+    pub async fn ticket(&self) -> Result<Ticket> {
+        let ticket = self.0.rooms.lock().await.ticket().await?;
+        match self.0.passwords.is_protected(&ticket.topic) {
+            true => {
+                let protected = Ticket { owner: ticket.owner, ..Ticket::protected(ticket.topic, ticket.nodes) };
+                Ok(protected.with_publishers(ticket.publishers))
+            }
+            false => Ok(ticket),
+        }
+    }
+
+    // A restricted ticket for the active room: it stops working after `valid_for`, or once used if
+    // `single_use`, and only while we are online to hand out the key
+    pub async fn invite(&self, valid_for: Option<Duration>, single_use: bool) -> Result<Ticket> {
+        let ticket = self.0.rooms.lock().await.ticket().await?;
+        let key = ticket.key.expect("room tickets carry the key");
+        let expires = valid_for.map(|valid_for| crate::storage::now() + valid_for.as_millis() as i64);
+        let invite = self.0.invites.issue(ticket.topic, key, expires, single_use);
+        let my_addr = self.0.endpoint.node_addr().initialized().await;
+        Ok(Ticket { key: None, invite: Some(invite), nodes: vec![my_addr], ..ticket })
+    }
+
+    // Join the room a ticket points to and make it the active room. Invites are first traded for
+    // the room key with the peer that issued them, the first one in the ticket.
+    pub async fn join(&self, ticket: Ticket) -> Result<TopicId> {
+        let key = match (ticket.key, &ticket.invite) {
+            (Some(key), _) => key,
+            (None, Some(invite)) => {
+                let Some(issuer) = ticket.nodes.first() else {
+                    bail!("the invite doesn't say who issued it");
+                };
+                invite::redeem(&self.0.endpoint, issuer.clone(), ticket.topic, invite).await?
+            }
+            (None, None) if ticket.password => bail!("this room needs a password"),
+            (None, None) => bail!("ticket has neither a room key nor an invite"),
+        };
+        let Ticket { topic, nodes, owner, publishers, .. } = ticket;
+        let nodes = self.with_peers(nodes);
+        self.0.rooms.lock().await.join(topic, key, nodes, owner, publishers).await?;
+        Ok(topic)
+    }
+
+    // Join a password-protected room, getting the key from the first member in the ticket that
+    // accepts the password. From then on we hand the key to other joiners with the password too.
+    pub async fn join_with_password(&self, ticket: Ticket, password: &str) -> Result<TopicId> {
+        if !ticket.password {
+            return self.join(ticket).await;
+        }
+        let mut result = Err(anyhow::anyhow!("the ticket lists no members to ask"));
+        for member in &ticket.nodes {
+            result = pake::exchange(&self.0.endpoint, member.clone(), ticket.topic, password).await;
+            // The members all hold the same password, so one turning us away is enough
+            match &result {
+                Ok(_) => break,
+                Err(err) if err.is::<pake::Refused>() => break,
+                Err(_) => {}
+            }
+        }
+        let key = result?;
+        let Ticket { topic, nodes, owner, publishers, .. } = ticket;
+        let nodes = self.with_peers(nodes);
+        self.0.rooms.lock().await.join(topic, key, nodes, owner, publishers).await?;
+        self.0.passwords.add_room(topic, password.to_string(), key);
+        self.0.storage.bookmark_password(&topic, password)?;
+        self.bookmark_changed(&topic)?;
+        Ok(topic)
+    }
+
+    // A room's known peers, and the ones we were given at startup that they leave out
+    fn with_peers(&self, mut nodes: Vec<NodeAddr>) -> Vec<NodeAddr> {
+        for peer in &self.0.peers {
+            if !nodes.iter().any(|node| node.node_id == peer.node_id) {
+                nodes.push(peer.clone());
+            }
+        }
+        nodes
+    }
+
+    // Join the rooms we were in when we last shut down, except those we are in already, without
+    // changing the active room. Rooms that can't be joined are reported as warnings.
+    pub async fn rejoin(&self) -> Result<Vec<TopicId>> {
+        let mut rejoined = Vec::new();
+        for bookmark in self.0.storage.bookmarks()? {
+            let mut rooms = self.0.rooms.lock().await;
+            if rooms.is_joined(&bookmark.topic) {
+                continue;
+            }
+            let active = self.active_room().await;
+            // The publishers of read-only rooms are in storage already
+            let peers = self.with_peers(bookmark.peers);
+            let joined = rooms.join(bookmark.topic, bookmark.key, peers, bookmark.owner, None).await;
+            if let Err(err) = joined {
+                self.0.events.warn(format!("could not rejoin room {}: {}", bookmark.topic.fmt_short(), err));
+                continue;
+            }
+            if let Some(password) = bookmark.password {
+                self.0.passwords.add_room(bookmark.topic, password, bookmark.key);
+            }
+            if active.is_some() {
+                *self.0.active.lock().await = active;
+            }
+            rejoined.push(bookmark.topic);
+        }
+        Ok(rejoined)
+    }
+
+    // Move the active room, which has to be one we opened, to a fresh topic and key. Members we
+    // still let in follow along; a leaked ticket and anyone revoked or banned are left behind in the
+    // old topic. Returns the new topic.
+    pub async fn rotate(&self) -> Result<TopicId> {
+        let (from, to, key) = self.0.rooms.lock().await.rotate().await?;
+        self.moved(from, to, key)?;
+        Ok(to)
+    }
+
+    // Get the new key of a room its owner moved from the owner, and move there too
+    async fn follow(&self, moved: Move) -> Result<()> {
+        let owner = self.0.endpoint.remote_info(moved.owner).map(NodeAddr::from);
+        let owner = owner.unwrap_or_else(|| moved.owner.into());
+        let key = migrate::fetch(&self.0.endpoint, owner.clone(), moved.to).await?;
+        self.0.rooms.lock().await.migrate(moved, key, owner).await?;
+        self.moved(moved.from, moved.to, key)
+    }
+
+    // Carry a room's password over to its new topic; invites for the old one are worthless now
+    fn moved(&self, from: TopicId, to: TopicId, key: RoomKey) -> Result<()> {
+        self.0.invites.remove_room(&from);
+        if let Some(password) = self.0.passwords.move_room(&from, to, key) {
+            self.0.storage.bookmark_password(&to, &password)?;
+            self.bookmark_changed(&to)?;
+        }
+        Ok(())
+    }
+
+    // Share a bookmark's new password with our other devices
+    fn bookmark_changed(&self, topic: &TopicId) -> Result<()> {
+        let bookmark = self.0.storage.find_bookmark(topic)?;
+        self.0.devices.changed(Setting::Bookmark(*topic, bookmark))
+    }
+
+    // Name the active room, which has to be one we opened. Members see it instead of the topic id.
+    pub async fn set_room_name(&self, name: &str) -> Result<()> {
+        self.0.rooms.lock().await.set_info(Some(name.to_string()), None, None).await
+    }
+
+    pub async fn describe_room(&self, description: &str) -> Result<()> {
+        self.0.rooms.lock().await.set_info(None, Some(description.to_string()), None).await
+    }
+
+    // What the active room is talking about right now
+    pub async fn set_topic(&self, topic: &str) -> Result<()> {
+        self.0.rooms.lock().await.set_info(None, None, Some(topic.to_string())).await
+    }
+
+    // List the active room in the public directory, with a ticket anyone can join with
+    pub async fn publish(&self) -> Result<()> {
+        let mut rooms = self.0.rooms.lock().await;
+        let topic_id = rooms.active().await?;
+        rooms.publish(self.0.passwords.is_protected(&topic_id)).await
+    }
+
+    // Listen to the public directory for `wait` and return the rooms announced there
+    pub async fn browse(&self, wait: Duration) -> Result<Vec<Listing>> {
+        let contacts = self.0.contacts.list().into_iter().map(|contact| contact.node_id).collect();
+        directory::browse(&self.0.gossip, contacts, wait).await
+    }
+
+    // Whether we may post in a room, which we can't in read-only rooms unless we are a publisher
+    pub fn can_post(&self, topic_id: &TopicId) -> bool {
+        self.0.membership.may_post(topic_id, &self.node_id())
+    }
+
+    // A room's name, description and topic, if its owner set any
+    pub fn room_info(&self, topic_id: &TopicId) -> Option<RoomInfo> {
+        self.0.membership.info(topic_id)
+    }
+
+    // Let a peer speak in the active room, which has to be one we opened. Once we allow or revoke
+    // anyone, members ignore everyone not on the list.
+    pub async fn allow(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.allow(node_id).await
+    }
+
+    // Take a peer off the active room's allowlist. It keeps the room key, so it can still read along.
+    pub async fn revoke(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.revoke(node_id).await
+    }
+
+    // Make members of the active room ignore a peer until it joins again. Kicks, bans and unbans
+    // take the room's owner or a moderator it appointed.
+    pub async fn kick(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.moderate(node_id, ModAction::Kick).await
+    }
+
+    // Make members of the active room ignore a peer for good, across restarts
+    pub async fn ban(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.moderate(node_id, ModAction::Ban).await
+    }
+
+    pub async fn unban(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.moderate(node_id, ModAction::Unban).await
+    }
+
+    // Hand the active room, which has to be ours, to another peer. There is no taking it back.
+    pub async fn transfer(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.transfer(node_id).await
+    }
+
+    // Let a peer kick and ban in the active room, which has to be one we opened
+    pub async fn appoint(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.appoint(node_id).await
+    }
+
+    // Take back an appointment in a room we opened
+    pub async fn dismiss(&self, node_id: NodeId) -> Result<()> {
+        self.0.rooms.lock().await.moderate(node_id, ModAction::Dismiss).await
+    }
+
+    // Send a chat message to the active room and return its id
+    pub async fn send(&self, text: impl Into<String>) -> Result<MessageId> {
+        self.0.rooms.lock().await.send(text.into()).await
+    }
+
+    // Answer a message in the active room and return the reply's id
+    pub async fn reply(&self, to: MessageId, text: impl Into<String>) -> Result<MessageId> {
+        self.0.rooms.lock().await.reply(to, text.into()).await
+    }
+
+    // The reply chain around a message of the active room, from our local history: the messages it
+    // answers back to the first one, the message itself and every reply below it. Each comes with
+    // its depth in the thread.
+    pub async fn thread(&self, id: MessageId) -> Result<Vec<(usize, StoredMessage)>> {
+        self.0.rooms.lock().await.thread(id).await
+    }
+
+    // The first page of stored messages containing `query`, in every room, newest first
+    pub async fn search(&self, query: &str) -> Result<Vec<SearchHit>> {
+        let hits = archive::search(&self.0.storage, query, None, 0)?;
+        *self.0.search.lock().await = Some((query.to_string(), 0));
+        Ok(hits)
+    }
+
+    // The latest stored messages that @mention us, newest first, a page of them at most
+    pub fn recent_mentions(&self) -> Result<Vec<SearchHit>> {
+        let query = format!("@{}", self.0.names.ours());
+        let mut found = Vec::new();
+        // The search also finds longer names starting with ours, and that is all a page may hold
+        for page in 0.. {
+            let hits = archive::search(&self.0.storage, &query, None, page)?;
+            let last = hits.len() < SEARCH_PAGE;
+            let ours = |hit: &SearchHit| hit.message.sender != self.node_id() && self.mentions_us(&hit.message.text);
+            found.extend(hits.into_iter().filter(ours));
+            if last || found.len() >= SEARCH_PAGE {
+                break;
+            }
+        }
+        found.truncate(SEARCH_PAGE);
+        Ok(found)
+    }
+
+    // Whether a text @mentions us
+    pub fn mentions_us(&self, text: &str) -> bool {
+        mention::mentions(text).into_iter().any(|range| self.is_us(&text[range.start + 1..range.end]))
+    }
+
+    // Whether an @mention, without the @, is of us
+    pub fn is_us(&self, mention: &str) -> bool {
+        self.0.names.is_us(mention)
+    }
+
+    // The next page of the last search
+    pub async fn search_more(&self) -> Result<Vec<SearchHit>> {
+        let mut last = self.0.search.lock().await;
+        let Some((query, page)) = last.as_mut() else {
+            bail!("nothing searched for yet");
+        };
+        *page += 1;
+        archive::search(&self.0.storage, query, None, *page)
+    }
+
+    // Start writing a code snippet, which takes every line given to `code_line` until it is sent
+    pub async fn start_code(&self, lang: &str) -> Result<()> {
+        code::check_lang(lang)?;
+        let mut draft = self.0.code.lock().await;
+        if let Some((lang, _)) = draft.as_ref() {
+            bail!("already writing {} code, /end sends it and /cancel drops it", lang);
+        }
+        *draft = Some((lang.to_string(), Vec::new()));
+        Ok(())
+    }
+
+    // The language of the code snippet being written and how many lines it has so far
+    pub async fn code_draft(&self) -> Option<(String, usize)> {
+        self.0.code.lock().await.as_ref().map(|(lang, lines)| (lang.clone(), lines.len()))
+    }
+
+    // Add a line to the code snippet being written, blank or not
+    pub async fn code_line(&self, line: String) {
+        if let Some((_, lines)) = self.0.code.lock().await.as_mut() {
+            lines.push(line);
+        }
+    }
+
+    // Send the code snippet to the active room. It stays the draft if that fails.
+    pub async fn send_code(&self) -> Result<MessageId> {
+        let mut draft = self.0.code.lock().await;
+        let Some((lang, lines)) = draft.as_ref() else {
+            bail!("not writing any code, /code <lang> starts a snippet");
+        };
+        if lines.iter().all(|line| line.trim().is_empty()) {
+            bail!("the snippet is empty, /cancel drops it");
+        }
+        let id = self.0.rooms.lock().await.send_code(lang.clone(), lines.join("\n")).await?;
+        *draft = None;
+        Ok(id)
+    }
+
+    // Send a place on the map to the active room
+    pub async fn send_location(&self, lat: f64, lon: f64, label: impl Into<String>) -> Result<MessageId> {
+        location::check(lat, lon)?;
+        self.0.rooms.lock().await.send_location(lat, lon, label.into()).await
+    }
+
+    // Drop the code snippet being written. Returns whether there was one.
+    pub async fn cancel_code(&self) -> bool {
+        self.0.code.lock().await.take().is_some()
+    }
+
+    // Find a message of the active room by a prefix of its id, or the latest one
+    pub async fn find_message(&self, query: Option<&str>) -> Result<MessageId> {
+        self.0.rooms.lock().await.find_message(query).await
+    }
+
+    // React to a message in the active room with an emoji
+    pub async fn react(&self, id: MessageId, emoji: impl Into<String>) -> Result<()> {
+        self.0.rooms.lock().await.react(id, emoji.into()).await
+    }
+
+    // Change the text of one of our own messages in the active room
+    pub async fn edit(&self, id: MessageId, text: impl Into<String>) -> Result<()> {
+        self.0.rooms.lock().await.edit(id, text.into()).await
+    }
+
+    // Our newest message in the active room that hasn't been deleted
+    pub async fn last_sent(&self) -> Result<MessageId> {
+        self.0.rooms.lock().await.last_sent().await
+    }
+
+    // Retract one of our own messages in the active room. Peers replace it with a tombstone.
+    pub async fn delete(&self, id: MessageId) -> Result<()> {
+        self.0.rooms.lock().await.delete(id).await
+    }
+
+    // Tell the active room we are typing. Cheap to call on every keystroke, it is throttled.
+    pub async fn typing(&self) -> Result<()> {
+        self.0.rooms.lock().await.typing().await
+    }
+
+    // Make another room active, by its position in `rooms()` or a prefix of its topic id
+    pub async fn switch(&self, room: &str) -> Result<TopicId> {
+        self.0.rooms.lock().await.switch(room).await
+    }
+
+    // The joined room a /rooms number, name or id prefix stands for
+    pub async fn find_room(&self, query: &str) -> Result<TopicId> {
+        self.0.rooms.lock().await.find(query)
+    }
+
+    // Leave the given room, or the active one
+    pub async fn leave(&self, room: Option<&str>) -> Result<TopicId> {
+        let topic_id = self.0.rooms.lock().await.leave(room).await?;
+        self.0.invites.remove_room(&topic_id);
+        self.0.passwords.remove_room(&topic_id);
+        Ok(topic_id)
+    }
+
+    // Topics in join order, with a flag for the active one
+    pub async fn rooms(&self) -> Vec<(TopicId, bool)> {
+        self.0.rooms.lock().await.list().await
+    }
+
+    // How many messages others sent to a room since we, on any of our devices, last read there
+    pub fn unread(&self, topic: &TopicId) -> Result<usize> {
+        let mut ours = self.0.names.devices().ours();
+        ours.push(self.node_id());
+        self.0.storage.unread(topic, &ours)
+    }
+
+    pub async fn active_room(&self) -> Option<TopicId> {
+        *self.0.active.lock().await
+    }
+
+    // Everyone we have seen an AboutMe from, sorted by name. Names others use too have a suffix.
+    pub async fn peers(&self) -> Vec<(NodeId, String)> {
+        let mut names = self.0.names.list();
+        names.sort_by(|a, b| a.1.cmp(&b.1));
+        names
+    }
+
+    // How much of the active room's history we keep
+    pub async fn retention(&self) -> Result<Retention> {
+        let Some(room) = self.active_room().await else {
+            bail!("not in any room, join one first");
+        };
+        self.0.storage.retention(&room)
+    }
+
+    // Keep only this much of the active room's history, pruning the rest right away. Returns how
+    // many messages that dropped.
+    pub async fn set_retention(&self, retention: Retention) -> Result<usize> {
+        let Some(room) = self.active_room().await else {
+            bail!("not in any room, join one first");
+        };
+        self.0.storage.set_retention(&room, retention)?;
+        let storage = self.0.storage.clone();
+        tokio::task::spawn_blocking(move || storage.prune()).await?
+    }
+
+    // Which messages of a room are worth a desktop notification
+    pub fn notify_rule(&self, room: &TopicId) -> Result<Notify> {
+        self.0.storage.notify(room)
+    }
+
+    // Change which messages of the active room notify us, on every device of the account
+    pub async fn set_notify(&self, notify: Notify) -> Result<()> {
+        let Some(room) = self.active_room().await else {
+            bail!("not in any room, join one first");
+        };
+        self.0.storage.set_notify(&room, notify)?;
+        let notify = Some(notify).filter(|notify| *notify != Notify::default());
+        self.0.devices.changed(Setting::Notify(room, notify))
+    }
+
+    // Who is online in the active room, most recently seen first
+    pub async fn who(&self) -> Result<Vec<OnlinePeer>> {
+        let Some(room) = self.active_room().await else {
+            bail!("not in any room, join one first");
+        };
+        let mut online: Vec<_> = self
+            .0
+            .roster
+            .online(&room)
+            .into_iter()
+            .map(|(node_id, last_seen)| OnlinePeer {
+                node_id,
+                name: self.0.names.display(&node_id),
+                last_seen,
+                protocol: self.0.versions.get(&room, &node_id),
+                status: self.0.statuses.of(&node_id),
+            })
+            .collect();
+        online.sort_by_key(|peer| peer.last_seen);
+        Ok(online)
+    }
+
+    // How we reach the active room's neighbors right now, as the endpoint sees the connections.
+    // Connections still making sure a direct path works count as relayed.
+    pub async fn links(&self) -> Links {
+        let mut links = Links::default();
+        let Some(room) = self.active_room().await else {
+            return links;
+        };
+        for node_id in self.0.neighbors.of(&room) {
+            match self.0.endpoint.conn_type(node_id).map(|mut conn_type| conn_type.get()) {
+                Some(ConnectionType::Direct(_)) => links.direct += 1,
+                Some(ConnectionType::Relay(_) | ConnectionType::Mixed(..)) => links.relayed += 1,
+                Some(ConnectionType::None) | None => {}
+            }
+        }
+        links
+    }
+
+    // What the endpoint knows about the path to each of the active room's neighbors
+    pub async fn connections(&self) -> Result<Vec<NeighborLink>> {
+        let Some(room) = self.active_room().await else {
+            bail!("not in any room, join one first");
+        };
+        let mut links: Vec<NeighborLink> = self
+            .0
+            .neighbors
+            .traffic(&room)
+            .into_iter()
+            .map(|(node_id, received)| {
+                let info = self.0.endpoint.remote_info(node_id);
+                NeighborLink {
+                    node_id,
+                    name: self.0.names.display(&node_id),
+                    conn_type: info.as_ref().map(|info| info.conn_type.clone()),
+                    latency: info.as_ref().and_then(|info| info.latency),
+                    last_used: info.and_then(|info| info.last_used),
+                    received,
+                }
+            })
+            .collect();
+        links.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(links)
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        let metrics = &self.0.endpoint.metrics().magicsock;
+        let relayed = metrics.recv_data_relay.get();
+        Traffic {
+            sent: metrics.send_data.get(),
+            received: metrics.recv_data_ipv4.get() + metrics.recv_data_ipv6.get() + relayed,
+            relayed,
+        }
+    }
+
+    // Find a peer by contact alias, display name (alice, or alice#3f2a if several use it), full
+    // node id or a prefix of a known node id. Returns the node id and how to refer to it.
+    pub async fn resolve_peer(&self, query: &str) -> Result<(NodeId, String)> {
+        if let Ok(node_id) = query.parse::<NodeId>() {
+            return Ok((node_id, self.0.names.display(&node_id)));
+        }
+        let mut matches = self.0.names.find(query);
+        if matches.is_empty() {
+            let mut known: Vec<_> = self.0.names.list().into_iter().map(|(id, _)| id).collect();
+            known.extend(self.0.contacts.list().into_iter().map(|contact| contact.node_id));
+            known.sort();
+            known.dedup();
+            matches = known.into_iter().filter(|id| id.to_string().starts_with(query)).collect();
+        }
+        match matches.as_slice() {
+            [node_id] => Ok((*node_id, self.0.names.display(node_id))),
+            [] => bail!("no known peer called {}", query),
+            _ => bail!("{} matches more than one peer, use the name#suffix /who shows or a node id", query),
+        }
+    }
+
+    // Drop everything a peer sends, in every room and by DM, until unblocked. Nobody is told, and
+    // the block is kept across restarts. Returns false if the peer was blocked already.
+    pub async fn block(&self, node_id: NodeId) -> Result<bool> {
+        let name = self.0.names.display(&node_id);
+        self.0.rooms.lock().await.block(node_id, name)
+    }
+
+    // Returns the name the peer was blocked under
+    pub async fn unblock(&self, node_id: NodeId) -> Result<String> {
+        self.0.rooms.lock().await.unblock(&node_id)
+    }
+
+    // A ticket to link another device to our account with, usable once within ten minutes
+    pub async fn link_ticket(&self) -> Result<LinkTicket> {
+        self.0.devices.ticket().await
+    }
+
+    // Make this device one of the account that issued the ticket: peers show what we send as the
+    // account's from now on. Returns the account.
+    pub async fn link(&self, ticket: LinkTicket) -> Result<NodeId> {
+        let devices = self.0.names.devices();
+        if devices.cert().is_some() {
+            bail!("this device is linked to {} already", devices.account().fmt_short());
+        }
+        if !devices.ours().is_empty() {
+            bail!("other devices are linked to this one, link from here instead");
+        }
+        let certs = devices::link(&self.0.endpoint, ticket).await?;
+        if !certs.iter().any(|cert| cert.device == self.node_id()) {
+            bail!("the account didn't certify this device");
+        }
+        for cert in certs {
+            let device = cert.device;
+            devices.add(cert.clone())?;
+            self.0.names.linked(&device);
+            self.0.storage.save_device(&cert)?;
+        }
+        self.0.rooms.lock().await.announce_device().await;
+        // Trade settings with the account's other devices
+        self.0.devices.catch_up()?;
+        Ok(devices.account())
+    }
+
+    // The account we speak for, and its other devices
+    pub fn devices(&self) -> (NodeId, Vec<NodeId>) {
+        let devices = self.0.names.devices();
+        (devices.account(), devices.ours())
+    }
+
+    // Pin the name a peer uses in the active room to its node id, e.g. after a key change warning.
+    // Returns the name.
+    pub async fn trust(&self, node_id: NodeId) -> Result<String> {
+        self.0.rooms.lock().await.trust(node_id).await
+    }
+
+    // Forget the node id a name is pinned to in the active room. Returns the node id.
+    pub async fn distrust(&self, name: &str) -> Result<NodeId> {
+        self.0.rooms.lock().await.distrust(name).await
+    }
+
+    // Remember a peer across sessions, under `alias` or the name it uses now
+    pub fn add_contact(&self, node_id: NodeId, alias: Option<String>) -> Result<Contact> {
+        let Some(alias) = alias.or_else(|| self.0.names.get(&node_id)) else {
+            bail!("{} hasn't told us its name yet, give it an alias", node_id.fmt_short());
+        };
+        let addr = self.0.endpoint.remote_info(node_id).map(Into::into);
+        self.insert_contact(Contact { node_id, alias, notes: String::new(), addr })
+    }
+
+    // Add the contact on a card someone shared, under the name on the card unless given another.
+    // The card's address lets us message it right away.
+    pub async fn save_card(&self, id: usize, alias: Option<String>) -> Result<Contact> {
+        let mut card = self.0.rooms.lock().await.card(id)?;
+        if let Some(alias) = alias {
+            card.alias = alias;
+        }
+        if let Some(addr) = card.addr.clone() {
+            self.0.endpoint.add_node_addr(addr)?;
+        }
+        self.insert_contact(card)
+    }
+
+    // Share one of our contacts with the active room: its node id, the name it goes by and where
+    // we last reached it
+    pub async fn share_contact(&self, alias: &str) -> Result<Contact> {
+        let Some(node_id) = self.0.contacts.find(alias) else {
+            bail!("no contact called {}", alias);
+        };
+        let Some(mut card) = self.contacts().into_iter().find(|contact| contact.node_id == node_id) else {
+            bail!("no contact called {}", alias);
+        };
+        // Our alias for it is ours, the card carries what it calls itself if we know
+        if let Some(name) = self.0.names.get(&node_id) {
+            card.alias = name;
+        }
+        if let Some(info) = self.0.endpoint.remote_info(node_id) {
+            card.addr = Some(info.into());
+        }
+        self.0.rooms.lock().await.share_contact(&card).await?;
+        Ok(card)
+    }
+
+    fn insert_contact(&self, contact: Contact) -> Result<Contact> {
+        let node_id = contact.node_id;
+        if node_id == self.0.endpoint.node_id() {
+            bail!("you can't add yourself as a contact");
+        }
+        if !self.0.contacts.insert(contact.clone()) {
+            bail!("{} is a contact already", self.0.names.display(&node_id));
+        }
+        self.0.storage.save_contact(&contact)?;
+        self.0.devices.changed(Setting::Contact(node_id, Some(contact.clone())))?;
+        Ok(contact)
+    }
+
+    // Returns the alias the contact had before
+    pub fn rename_contact(&self, node_id: NodeId, alias: String) -> Result<String> {
+        let old = self.0.contacts.alias(&node_id);
+        self.update_contact(node_id, |contact| contact.alias = alias)?;
+        Ok(old.unwrap_or_default())
+    }
+
+    pub fn note_contact(&self, node_id: NodeId, notes: String) -> Result<()> {
+        self.update_contact(node_id, |contact| contact.notes = notes)
+    }
+
+    fn update_contact(&self, node_id: NodeId, change: impl FnOnce(&mut Contact)) -> Result<()> {
+        let Some(contact) = self.0.contacts.update(&node_id, change) else {
+            bail!("{} isn't a contact", node_id.fmt_short());
+        };
+        self.0.storage.save_contact(&contact)?;
+        self.0.devices.changed(Setting::Contact(node_id, Some(contact)))
+    }
+
+    pub fn remove_contact(&self, node_id: NodeId) -> Result<Contact> {
+        let Some(contact) = self.0.contacts.remove(&node_id) else {
+            bail!("{} isn't a contact", node_id.fmt_short());
+        };
+        self.0.storage.remove_contact(&node_id)?;
+        self.0.devices.changed(Setting::Contact(node_id, None))?;
+        Ok(contact)
+    }
+
+    // Everyone in the address book, sorted by alias
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.0.contacts.list()
+    }
+
+    // Blocked peers and their names when blocked, sorted by name
+    pub fn blocked(&self) -> Vec<(NodeId, String)> {
+        self.0.blocks.list()
+    }
+
+    // Offer a file to the active room. Returns the file name and size.
+    pub async fn send_file(&self, path: &Path) -> Result<(String, u64)> {
+        self.0.rooms.lock().await.send_file(path).await
+    }
+
+    // Offer a file that isn't on disk, like an image from the clipboard. Returns its size.
+    pub async fn send_bytes(&self, name: impl Into<String>, bytes: Vec<u8>) -> Result<u64> {
+        self.0.rooms.lock().await.send_bytes(name.into(), bytes).await
+    }
+
+    // Offer a directory to the active room. Returns its name, size and how many files it holds.
+    pub async fn send_directory(&self, path: &Path) -> Result<(String, u64, u64)> {
+        self.0.rooms.lock().await.send_directory(path).await
+    }
+
+    // Download an offered file, or an offered directory or the file or directory `entry` in it,
+    // and return where it was saved
+    pub async fn fetch_file(&self, id: usize, entry: Option<&str>) -> Result<PathBuf> {
+        let events = self.0.events.clone();
+        let mut shown = None;
+        let progress = move |done: u64, size: Option<u64>| {
+            let step = match size {
+                Some(size) if size > 0 => done.min(size) * 100 / size,
+                _ => done / PROGRESS_STEP,
+            };
+            if shown != Some(step) {
+                shown = Some(step);
+                events.emit(ChatEvent::FileProgress { id, done, size });
+            }
+        };
+        self.0.files.fetch(id, entry, progress).await
+    }
+
+    // The files in the blob store, least recently used first, and how much space they may take
+    pub fn cached_files(&self) -> Result<(Vec<CachedBlob>, u64)> {
+        self.0.files.cached()
+    }
+
+    // Drop every file from the blob store, and return how many there were and their size
+    pub async fn clear_files(&self) -> Result<(usize, u64)> {
+        self.0.files.clear().await
+    }
+
+    // The paths of the files in an offered directory
+    pub async fn list_directory(&self, id: usize) -> Result<Vec<String>> {
+        self.0.files.entries(id).await
+    }
+
+    // The contents of an offered file, downloaded without saving it anywhere, to show images inline
+    pub async fn preview_file(&self, id: usize) -> Result<Vec<u8>> {
+        self.0.files.preview(id).await
+    }
+
+    // The whole of a long message of the active room that came with only its start, fetched from
+    // its author. History keeps the whole text from then on.
+    pub async fn read_more(&self, id: MessageId) -> Result<String> {
+        let Some(topic_id) = self.active_room().await else {
+            bail!("not in any room, join one first");
+        };
+        let text = self.0.files.fetch_text(id).await?;
+        self.0.storage.expand(&topic_id, &id, &text)?;
+        Ok(text)
+    }
+
+    // Send a private message straight to a peer, outside any room. Returns the peer's note if it
+    // is away.
+    pub async fn send_dm(&self, to: NodeId, text: &str) -> Result<Option<String>> {
+        dm::send(&self.0.endpoint, to, &self.0.names.ours(), text).await
+    }
+
+    // Time a few round trips to a peer over a connection of their own, rooms and gossip left out
+    pub async fn ping(&self, to: NodeId) -> Result<Latency> {
+        ping::ping(&self.0.endpoint, to).await
+    }
+
+    // Check how well we reach the relays and peers, and whether others can look us up. With a
+    // `helper` that is online, also see whether a connection to it gets past our NAT.
+    pub async fn diagnose(&self, helper: Option<NodeId>) -> Diagnosis {
+        netcheck::diagnose(&self.0.endpoint, self.0.publishing, helper).await
+    }
+
+    // Say goodbye to every room, then stop the endpoint and its protocols. Returns the rooms left.
+    pub async fn shutdown(&self) -> Result<Vec<TopicId>> {
+        // Remember where contacts were last reachable
+        for contact in self.0.contacts.list() {
+            let Some(info) = self.0.endpoint.remote_info(contact.node_id) else {
+                continue;
+            };
+            let addr = Some(info.into());
+            if let Some(contact) = self.0.contacts.update(&contact.node_id, |contact| contact.addr = addr) {
+                self.0.storage.save_contact(&contact)?;
+            }
+        }
+        let left = self.0.rooms.lock().await.shutdown().await;
+        // Give gossip a moment to get the goodbyes out
+        if !left.is_empty() {
+            tokio::time::sleep(SHUTDOWN_GRACE).await;
+        }
+        self.0.router.shutdown().await?;
+        Ok(left)
+    }
+}
@@ -0,0 +1,24 @@
+use anyhow::{anyhow, bail, Result};
+use arboard::{Clipboard, Error};
+use png::{BitDepth, ColorType, Encoder};
+
+// The image on the system clipboard, as PNG. arboard reads it the platform's way (X11 or Wayland
+// on Linux) and hands it over as RGBA pixels, which are encoded again here.
+pub async fn image() -> Result<Vec<u8>> {
+    let image = tokio::task::spawn_blocking(|| Clipboard::new()?.get_image()).await?;
+    let image = match image {
+        Ok(image) => image,
+        Err(Error::ContentNotAvailable) => bail!("there is no image on the clipboard"),
+        Err(Error::ClipboardNotSupported) => bail!("no clipboard to paste from here"),
+        Err(err) => bail!("could not read the clipboard: {}", err),
+    };
+    let (width, height) = (u32::try_from(image.width)?, u32::try_from(image.height)?);
+    let mut png = Vec::new();
+    let mut encoder = Encoder::new(&mut png, width, height);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.bytes).map_err(|err| anyhow!("could not encode the image: {}", err))?;
+    writer.finish()?;
+    Ok(png)
+}
@@ -0,0 +1,25 @@
+use anyhow::{bail, Result};
+
+// Code snippets travel as MessageBody::Code, but are stored and shown like a Markdown fence, so
+// history, search and exports keep the language and every line of it
+pub fn fence(lang: &str, code: &str) -> String {
+    format!("```{}\n{}\n```", lang, code)
+}
+
+// The language and code of a fenced snippet, if the text is one
+pub fn unfence(text: &str) -> Option<(&str, &str)> {
+    let (lang, rest) = text.strip_prefix("```")?.split_once('\n')?;
+    let code = rest.strip_suffix("```")?;
+    let code = code.strip_suffix('\n').unwrap_or(code);
+    check_lang(lang).ok()?;
+    Some((lang, code))
+}
+
+// Language names are a short word like rust, c++ or c#
+pub fn check_lang(lang: &str) -> Result<()> {
+    let valid = lang.chars().all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c));
+    if lang.is_empty() || lang.len() > 20 || !valid {
+        bail!("the language is a short name like rust or python");
+    }
+    Ok(())
+}
@@ -0,0 +1,756 @@
+use crate::{clipboard, parse_duration, ui, ui::Output, voice};
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use iroh::endpoint::ConnectionType;
+use iroh::NodeId;
+use p2p_chat::{
+    expand_shortcodes, format_size, ChatClient, Notify, Retention, Ticket, PROTOCOL_VERSION, SEARCH_PAGE,
+};
+use std::path::Path;
+use std::time::Duration;
+
+// Messages starting with this are actions, shown as "* alice waves" for `/me waves`
+pub const ACTION: &str = "/me ";
+
+// A slash command as /help lists it. Its arguments read like `<peer> [alias]`: without any it takes
+// none, starting with `<` it needs some and starting with `[` they are optional.
+pub struct Command {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub help: &'static str,
+}
+
+impl Command {
+    fn usage(&self) -> String {
+        match self.args.is_empty() {
+            true => format!("/{}", self.name),
+            false => format!("/{} {}", self.name, self.args),
+        }
+    }
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command { name: "help", args: "[command]", help: "List the commands, or explain one" },
+    Command { name: "quit", args: "", help: "Exit, like Esc or the end of input does" },
+    Command { name: "join", args: "<ticket> [password]", help: "Join another room; it becomes the active room" },
+    Command { name: "switch", args: "<room>", help: "Make another room active (number from /rooms, name or id)" },
+    Command { name: "split", args: "<room> | dm | off", help: "Show a room or DMs beside the active room" },
+    Command { name: "leave", args: "[room]", help: "Leave a room for good (defaults to the active one)" },
+    Command { name: "rooms", args: "", help: "List joined rooms, with how many messages you haven't read" },
+    Command { name: "room-name", args: "<name>", help: "Name a room you opened" },
+    Command { name: "describe", args: "<text>", help: "Describe a room you opened" },
+    Command { name: "topic", args: "<text>", help: "Set the topic of a room you opened" },
+    Command { name: "publish", args: "", help: "Announce the active room in the public directory" },
+    Command { name: "rotate", args: "", help: "Move a room you opened to a new topic and key" },
+    Command { name: "ticket", args: "", help: "Print a fresh ticket for the active room" },
+    Command {
+        name: "invite",
+        args: "[duration] [once] | <node-id>",
+        help: "Print an invite that expires and/or works only once, or let a peer into a room you opened",
+    },
+    Command { name: "revoke", args: "<peer>", help: "Take a peer off the allowlist of a room you opened" },
+    Command { name: "kick", args: "<peer>", help: "Make the room ignore a peer until it rejoins" },
+    Command { name: "ban", args: "<peer>", help: "Make the room ignore a peer for good" },
+    Command { name: "unban", args: "<peer>", help: "Stop ignoring a banned peer" },
+    Command { name: "mod", args: "<peer>", help: "Appoint a moderator in a room you opened" },
+    Command { name: "unmod", args: "<peer>", help: "Dismiss a moderator in a room you opened" },
+    Command { name: "transfer", args: "<peer>", help: "Hand a room you own to another peer, for good" },
+    Command { name: "block", args: "<peer>", help: "Stop hearing from a peer, in every room and by DM" },
+    Command { name: "unblock", args: "<peer>", help: "Hear from a blocked peer again" },
+    Command { name: "blocks", args: "", help: "List the peers you blocked" },
+    Command {
+        name: "contact",
+        args: "[list | add <peer> [alias] | rename <contact> <alias> | note <contact> <text> | save <id> [alias] \
+               | remove <contact>]",
+        help: "Manage your contacts",
+    },
+    Command { name: "share-contact", args: "<alias>", help: "Send the active room a card for one of your contacts" },
+    Command { name: "link", args: "[ticket]", help: "Print a ticket to link another device, or link this one" },
+    Command { name: "devices", args: "", help: "Show your account and its other devices" },
+    Command { name: "trust", args: "<peer>", help: "Pin the name a peer uses in the active room to its node id" },
+    Command { name: "distrust", args: "<name>", help: "Forget the node id a name is pinned to in the active room" },
+    Command { name: "who", args: "", help: "List who is online in the active room" },
+    Command {
+        name: "connections",
+        args: "",
+        help: "Show how each neighbor in the active room is reached: path, address, round trip, traffic",
+    },
+    Command { name: "nick", args: "<name>", help: "Go by another name from now on, in every room" },
+    Command { name: "away", args: "[note]", help: "Tell your rooms you are away, and answer DMs with the note" },
+    Command { name: "back", args: "", help: "Tell your rooms you are back" },
+    Command { name: "status", args: "[text]", help: "Say what you are up to in /who, or clear it" },
+    Command {
+        name: "dnd",
+        args: "[duration] [public] | off",
+        help: "Hold back notifications and comings and goings, showing mentions afterwards; public tells your rooms",
+    },
+    Command { name: "me", args: "<action>", help: "Say what you are doing, shown as \"* you action\"" },
+    Command { name: "react", args: "[id] <emoji>", help: "React to a message (defaults to the latest one)" },
+    Command { name: "reply", args: "<id> <text>", help: "Answer a message" },
+    Command { name: "thread", args: "<id>", help: "Print the reply chain a message is part of" },
+    Command { name: "code", args: "<lang>", help: "Write a code snippet line by line, /end sends it" },
+    Command { name: "location", args: "<lat>,<lon> [label]", help: "Send a place on the map" },
+    Command { name: "more", args: "<id>", help: "Fetch the whole of a long message" },
+    Command { name: "notify", args: "[all | mentions | off]", help: "Pick which messages of the room notify you" },
+    Command { name: "retain", args: "[30d] [10k] | off", help: "Limit the stored history of the room, or show it" },
+    Command { name: "search", args: "[text]", help: "Find stored messages; again without text for more" },
+    Command { name: "mentions", args: "", help: "List the latest messages that @mention you" },
+    Command { name: "edit", args: "<id> <text>", help: "Change the text of one of your own messages" },
+    Command { name: "delete", args: "[id]", help: "Retract one of your messages (defaults to the last one)" },
+    Command { name: "send", args: "<path>", help: "Offer a file or directory to the active room" },
+    Command { name: "paste", args: "", help: "Offer the image on the clipboard to the active room" },
+    Command {
+        name: "voice",
+        args: "record [length] | play <id>",
+        help: "Record a voice clip from the microphone and offer it to the room, or play an offered one",
+    },
+    Command { name: "get", args: "<id> [path]", help: "Download an offered file or directory, or part of one" },
+    Command { name: "ls", args: "<id>", help: "List the files of an offered directory as a tree" },
+    Command { name: "files", args: "[clear]", help: "List the files in the blob store, or drop them all" },
+    Command { name: "msg", args: "<peer> <text>", help: "Send a private message" },
+    Command { name: "ping", args: "<peer>", help: "Time how long a peer takes to answer, outside the room" },
+];
+
+fn find(name: &str) -> Result<&'static Command> {
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => Ok(command),
+        None => bail!("unknown command /{}, /help lists them", name),
+    }
+}
+
+// Run a command line without its leading `/`. Returns false once the user asked to quit.
+pub async fn run(client: &ChatClient, output: &Output, line: &str) -> Result<bool> {
+    let (name, arg) = match line.trim().split_once(' ') {
+        Some((name, arg)) => (name, Some(arg.trim())),
+        None => (line.trim(), None),
+    };
+    let command = find(name)?;
+    match (command.args.chars().next(), arg) {
+        (None, Some(_)) | (Some('<'), None) => bail!("usage: {}", command.usage()),
+        _ => {}
+    }
+    match (name, arg) {
+        ("help", None) => {
+            output.line("> Commands:");
+            for command in COMMANDS {
+                output.line(format!(">   {} - {}", command.usage(), command.help));
+            }
+        }
+        ("help", Some(name)) => {
+            let command = find(name.trim_start_matches('/'))?;
+            output.line(format!("> {}: {}", command.usage(), command.help));
+        }
+        ("quit", None) => return Ok(false),
+        ("join", Some(arg)) => join_command(client, output, arg).await?,
+        ("switch", Some(room)) => {
+            let topic_id = client.switch(room).await?;
+            output.line(format!("> Switched to room {}", topic_id.fmt_short()));
+        }
+        // The full-screen UI handles it before it gets here
+        ("split", _) => bail!("/split needs the full-screen UI"),
+        ("leave", room) => {
+            let topic_id = client.leave(room).await?;
+            output.line(format!("> Left room {}", topic_id.fmt_short()));
+        }
+        ("send", Some(path)) if Path::new(path).is_dir() => {
+            let (name, size, files) = client.send_directory(Path::new(path)).await?;
+            output.line(format!("> Offered {}/ ({} files, {}) to the room", name, files, format_size(size)));
+        }
+        ("send", Some(path)) => {
+            let (name, size) = client.send_file(Path::new(path)).await?;
+            output.line(format!("> Offered {} ({}) to the room", name, format_size(size)));
+        }
+        ("paste", None) => {
+            let image = clipboard::image().await?;
+            let name = format!("clipboard-{}.png", Local::now().format("%Y%m%d-%H%M%S"));
+            let size = client.send_bytes(name.clone(), image).await?;
+            output.line(format!("> Offered {} ({}) to the room", name, format_size(size)));
+        }
+        ("voice", Some(arg)) => voice_command(client, output, arg).await?,
+        ("get", Some(arg)) => get_command(client, output, arg).await?,
+        ("ls", Some(id)) => {
+            let id: usize = id.parse().context("usage: /ls <id>")?;
+            let client = client.clone();
+            let output = output.clone();
+            tokio::spawn(async move {
+                match client.list_directory(id).await {
+                    Ok(paths) => {
+                        output.line(format!("> Directory #{} holds {} files:", id, paths.len()));
+                        for line in ui::render_tree(&paths) {
+                            output.line(format!(">   {}", line));
+                        }
+                    }
+                    Err(err) => output.line(format!("> Error: could not list directory #{}: {}", id, err)),
+                }
+            });
+        }
+        ("msg", Some(arg)) => msg_command(client, output, arg).await?,
+        ("ping", Some(peer)) => {
+            let (to, name) = client.resolve_peer(peer).await?;
+            let client = client.clone();
+            let output = output.clone();
+            output.line(format!("> Pinging {}…", name));
+            tokio::spawn(async move {
+                match client.ping(to).await {
+                    Ok(latency) => output.line(ui::latency(&name, &latency)),
+                    Err(err) => output.line(format!("> Error: {} didn't answer: {}", name, err)),
+                }
+            });
+        }
+        // `/invite <node-id>` allows a peer into a room we opened, anything else makes a ticket
+        ("invite", Some(node_id)) if node_id.parse::<NodeId>().is_ok() => {
+            client.allow(node_id.parse()?).await?;
+        }
+        ("revoke", Some(peer)) => {
+            let (node_id, name) = client.resolve_peer(peer).await?;
+            client.revoke(node_id).await?;
+            output.line(format!("> Revoked {}, the room ignores them from now on", name));
+        }
+        ("transfer", Some(peer)) => {
+            let (node_id, _) = client.resolve_peer(peer).await?;
+            client.transfer(node_id).await?;
+        }
+        ("kick" | "ban" | "unban" | "mod" | "unmod", Some(peer)) => {
+            let (node_id, _) = client.resolve_peer(peer).await?;
+            match name {
+                "kick" => client.kick(node_id).await?,
+                "ban" => client.ban(node_id).await?,
+                "unban" => client.unban(node_id).await?,
+                "mod" => client.appoint(node_id).await?,
+                _ => client.dismiss(node_id).await?,
+            }
+        }
+        ("block", Some(peer)) => {
+            let (node_id, name) = client.resolve_peer(peer).await?;
+            match client.block(node_id).await? {
+                true => output.line(format!("> Blocked {}, you won't hear from them anywhere", name)),
+                false => output.line(format!("> {} is already blocked", name)),
+            }
+        }
+        ("share-contact", Some(alias)) => {
+            let card = client.share_contact(alias).await?;
+            output.line(format!("> Shared {} ({}) with the room", card.alias, card.node_id.fmt_short()));
+        }
+        ("contact", arg) => contact_command(client, output, arg.unwrap_or("list")).await?,
+        ("link", None) => {
+            let ticket = client.link_ticket().await?;
+            output.line(format!("> On the new device, within ten minutes: /link {}", ticket));
+        }
+        ("link", Some(ticket)) => {
+            let account = client.link(ticket.parse()?).await?;
+            output.line(format!("> Linked to account {}, what you send shows up as theirs", account.fmt_short()));
+        }
+        ("devices", None) => {
+            let (account, devices) = client.devices();
+            output.line(format!("> Account {}, this device {}", account.fmt_short(), client.node_id().fmt_short()));
+            for device in devices {
+                let role = if device == account { " (the account's own)" } else { "" };
+                output.line(format!(">   device {}{}", device.fmt_short(), role));
+            }
+        }
+        ("notify", None) => {
+            let room = client.active_room().await.context("not in any room, join one first")?;
+            output.line(format!("> Notifying you of {} in this room", client.notify_rule(&room)?));
+        }
+        ("notify", Some(rule)) => {
+            let rule: Notify = rule.parse()?;
+            client.set_notify(rule).await?;
+            output.line(format!("> Notifying you of {} in this room from now on", rule));
+        }
+        ("retain", None) => output.line(format!("> Keeping {} of this room", client.retention().await?)),
+        ("retain", Some(limits)) => {
+            let retention = parse_retention(limits)?;
+            let pruned = client.set_retention(retention).await?;
+            output.line(format!("> Keeping {} of this room, {} stored messages dropped", retention, pruned));
+        }
+        ("trust", Some(peer)) => {
+            let (node_id, shown) = client.resolve_peer(peer).await?;
+            let name = client.trust(node_id).await?;
+            output.line(format!("> Trusting {} as {} in this room", shown, name));
+        }
+        ("distrust", Some(name)) => {
+            let node_id = client.distrust(name).await?;
+            let node_id = node_id.fmt_short();
+            output.line(format!("> Forgot that {} is {}, whoever uses the name next is trusted", name, node_id));
+        }
+        ("unblock", Some(peer)) => {
+            // Blocked peers may be long gone from the rooms, so look at the block list first
+            let blocked = client.blocked();
+            let found = blocked.iter().find(|(id, name)| name == peer || id.to_string().starts_with(peer));
+            let node_id = match found {
+                Some((node_id, _)) => *node_id,
+                None => client.resolve_peer(peer).await?.0,
+            };
+            let name = client.unblock(node_id).await?;
+            output.line(format!("> Unblocked {}", name));
+        }
+        ("files", None) => files_command(client, output)?,
+        ("files", Some("clear")) => {
+            let (count, size) = client.clear_files().await?;
+            output.line(format!("> Dropped {} files, {} is freed within a minute", count, format_size(size)));
+        }
+        ("blocks", None) => {
+            let blocked = client.blocked();
+            output.line(format!("> {} blocked", blocked.len()));
+            for (node_id, name) in blocked {
+                output.line(format!("> {} ({})", name, node_id.fmt_short()));
+            }
+        }
+        ("invite", arg) => {
+            // `/invite [duration] [once]`, e.g. `/invite 1h once`
+            let (mut valid_for, mut single_use) = (None, false);
+            for word in arg.unwrap_or_default().split_whitespace() {
+                match word {
+                    "once" => single_use = true,
+                    duration => valid_for = Some(parse_duration(duration)?),
+                }
+            }
+            let ticket = client.invite(valid_for, single_use).await?;
+            output.line(format!("> Invite: {}", ticket));
+        }
+        ("ticket", None) => {
+            output.line(format!("> Ticket to join: {}", client.ticket().await?));
+        }
+        ("who", None) => who_command(client, output).await?,
+        ("connections", None) => connections_command(client, output).await?,
+        ("nick", Some(name)) => {
+            let old = client.nick(name).await?;
+            output.line(format!("> {} is now known as {}", old, client.name()));
+        }
+        ("away", note) => {
+            client.set_away(Some(note.unwrap_or_default().to_string())).await;
+            output.line(match note {
+                Some(note) => format!("> You are away: {}", note),
+                None => "> You are away".to_string(),
+            });
+        }
+        ("back", None) => {
+            if client.set_away(None).await.is_none() {
+                bail!("you weren't away");
+            }
+            output.line("> You are back".to_string());
+        }
+        ("status", text) => {
+            let text = text.unwrap_or_default();
+            let old = client.set_status(text).await?;
+            output.line(match text {
+                "" if old.is_empty() => "> You have no status to clear".to_string(),
+                "" => "> Status cleared".to_string(),
+                text => format!("> Your status: {}", text),
+            });
+        }
+        ("dnd", Some("off")) => {
+            if !client.end_dnd().await {
+                bail!("you aren't in do-not-disturb");
+            }
+        }
+        ("dnd", arg) => dnd_command(client, output, arg).await?,
+        ("me", Some(action)) => {
+            client.send(format!("{}{}", ACTION, expand_shortcodes(action))).await?;
+        }
+        ("react", Some(arg)) => {
+            // `/react <emoji>` reacts to the latest message, `/react <id> <emoji>` to a specific one
+            let (target, emoji) = match arg.split_once(' ') {
+                Some((id, emoji)) => (Some(id), emoji.trim()),
+                None => (None, arg),
+            };
+            let id = client.find_message(target).await?;
+            client.react(id, expand_shortcodes(emoji)).await?;
+        }
+        ("edit", Some(arg)) => {
+            let Some((id, text)) = arg.split_once(' ') else {
+                bail!("usage: /edit <id> <text>");
+            };
+            let id = client.find_message(Some(id)).await?;
+            client.edit(id, expand_shortcodes(text.trim())).await?;
+        }
+        ("reply", Some(arg)) => {
+            let Some((id, text)) = arg.split_once(' ') else {
+                bail!("usage: /reply <id> <text>");
+            };
+            let id = client.find_message(Some(id)).await?;
+            client.reply(id, expand_shortcodes(text.trim())).await?;
+        }
+        ("search", query) => search_command(client, output, query).await?,
+        ("mentions", None) => {
+            let hits = client.recent_mentions()?;
+            if hits.is_empty() {
+                output.line("> Nothing mentions you");
+            }
+            for hit in &hits {
+                output.line(format!("> {}", ui::render_search(hit)));
+            }
+        }
+        ("code", Some(lang)) => {
+            client.start_code(lang).await?;
+            let hint = "lines go in as they are, /end sends them, /cancel drops them";
+            output.line(format!("> Writing {} code: {}", lang, hint));
+        }
+        ("location", Some(place)) => {
+            let (lat, lon, label) = parse_location(place)?;
+            client.send_location(lat, lon, label).await?;
+        }
+        ("more", Some(id)) => {
+            let id = client.find_message(Some(id)).await?;
+            let client = client.clone();
+            let output = output.clone();
+            tokio::spawn(async move {
+                match client.read_more(id).await {
+                    Ok(text) => output.line(format!("> #{} in full: {}", id.fmt_short(), text)),
+                    Err(err) => {
+                        output.line(format!("> Error: could not fetch message #{}: {}", id.fmt_short(), err))
+                    }
+                }
+            });
+        }
+        ("thread", Some(id)) => {
+            let id = client.find_message(Some(id)).await?;
+            for (depth, message) in client.thread(id).await? {
+                output.line(ui::render_thread(depth, &message));
+            }
+        }
+        ("delete", id) => {
+            // Without an id, retract the last message we sent
+            let id = match id {
+                Some(id) => client.find_message(Some(id)).await?,
+                None => client.last_sent().await?,
+            };
+            client.delete(id).await?;
+        }
+        ("rooms", None) => rooms_command(client, output).await?,
+        ("publish", None) => {
+            client.publish().await?;
+            output.line("> Listed the room in the public directory until you leave it");
+        }
+        ("rotate", None) => {
+            client.rotate().await?;
+            output.line("> Members still allowed in follow along, use /ticket to invite anyone else again");
+        }
+        ("room-name", Some(name)) => client.set_room_name(name).await?,
+        ("describe", Some(description)) => client.describe_room(description).await?,
+        ("topic", Some(topic)) => client.set_topic(topic).await?,
+        _ => bail!("usage: {}", command.usage()),
+    }
+    Ok(true)
+}
+
+// `/join <ticket> [password]`, which joins in the background
+async fn join_command(client: &ChatClient, output: &Output, arg: &str) -> Result<()> {
+    let (ticket, password) = match arg.split_once(' ') {
+        Some((ticket, password)) => (ticket.parse::<Ticket>()?, Some(password.trim().to_string())),
+        None => (arg.parse::<Ticket>()?, None),
+    };
+    // Trading an invite or password for the key takes a round trip, which the input shouldn't wait for
+    let client = client.clone();
+    let output = output.clone();
+    output.line("> Joining the room...");
+    tokio::spawn(async move {
+        let joined = match password {
+            Some(password) => client.join_with_password(ticket, &password).await,
+            None => client.join(ticket).await,
+        };
+        match joined {
+            Ok(topic_id) => output.line(format!("> Joined room {}", topic_id.fmt_short())),
+            Err(err) => output.line(format!("> Error: could not join the room: {}", err)),
+        }
+    });
+    Ok(())
+}
+
+// `/voice record [length]` and `/voice play <id>`
+async fn voice_command(client: &ChatClient, output: &Output, arg: &str) -> Result<()> {
+    let (what, rest) = match arg.split_once(' ') {
+        Some((what, rest)) => (what, Some(rest.trim())),
+        None => (arg, None),
+    };
+    // Say so now rather than after the clip's length
+    voice::available()?;
+    let (client, output) = (client.clone(), output.clone());
+    match (what, rest) {
+        ("record", length) => {
+            let length = length.map(parse_duration).transpose()?.unwrap_or(voice::DEFAULT_LENGTH);
+            if length.is_zero() || length > voice::MAX_LENGTH {
+                bail!("clips are up to {}s long", voice::MAX_LENGTH.as_secs());
+            }
+            output.line(format!("> Recording {}s...", length.as_secs()));
+            // Recording takes as long as the clip, which the input shouldn't wait for
+            tokio::spawn(async move {
+                let now = Local::now().format("%Y%m%d-%H%M%S");
+                let name = format!("{}{}{}", voice::PREFIX, now, voice::EXTENSION);
+                let sent = match voice::record(length).await {
+                    Ok(clip) => client.send_bytes(name.clone(), clip).await,
+                    Err(err) => Err(err),
+                };
+                match sent {
+                    Ok(size) => {
+                        output.line(format!("> Offered {} ({}) to the room", name, format_size(size)))
+                    }
+                    Err(err) => output.line(format!("> Error: could not send the clip: {}", err)),
+                }
+            });
+        }
+        ("play", Some(id)) => {
+            let id: usize = id.parse().context("usage: /voice play <id>")?;
+            tokio::spawn(async move {
+                let played = match client.preview_file(id).await {
+                    Ok(clip) => voice::play(clip).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = played {
+                    output.line(format!("> Error: could not play #{}: {}", id, err));
+                }
+            });
+        }
+        _ => bail!("usage: {}", find("voice")?.usage()),
+    }
+    Ok(())
+}
+
+// `/get <id> [path]`, which downloads in the background
+async fn get_command(client: &ChatClient, output: &Output, arg: &str) -> Result<()> {
+    let (id, entry) = match arg.split_once(' ') {
+        Some((id, entry)) => (id, Some(entry.trim().to_string())),
+        None => (arg, None),
+    };
+    let id: usize = id.parse().context("usage: /get <id> [path]")?;
+    let client = client.clone();
+    let output = output.clone();
+    output.line(format!("> Fetching file #{}...", id));
+    tokio::spawn(async move {
+        match client.fetch_file(id, entry.as_deref()).await {
+            Ok(path) => output.line(format!("> Saved file #{} to {}", id, path.display())),
+            Err(err) => output.line(format!("> Error: could not fetch file #{}: {}", id, err)),
+        }
+    });
+    Ok(())
+}
+
+// `/msg <peer> <text>`
+async fn msg_command(client: &ChatClient, output: &Output, arg: &str) -> Result<()> {
+    let Some((peer, text)) = arg.split_once(' ') else {
+        bail!("usage: /msg <peer> <text>");
+    };
+    let (to, name) = client.resolve_peer(peer).await?;
+    let client = client.clone();
+    let text = expand_shortcodes(text.trim());
+    let output = output.clone();
+    tokio::spawn(async move {
+        match client.send_dm(to, &text).await {
+            Ok(away) => {
+                output.line(format!("[dm to {}] {}", name, text));
+                if away.is_some() {
+                    output.line(ui::went_away(&name, away.as_deref()));
+                }
+            }
+            Err(err) => output.line(format!("> Error: could not message {}: {}", name, err)),
+        }
+    });
+    Ok(())
+}
+
+// `/files`: what the blob store holds
+fn files_command(client: &ChatClient, output: &Output) -> Result<()> {
+    let (blobs, limit) = client.cached_files()?;
+    let total = blobs.iter().map(|blob| blob.size).sum();
+    output.line(format!("> {} files, {} of {}", blobs.len(), format_size(total), format_size(limit)));
+    // Most recently used first, the opposite of the order they are evicted in
+    for blob in blobs.iter().rev() {
+        let name = if blob.directory { format!("{}/", blob.name) } else { blob.name.clone() };
+        let how = if blob.sent { "offered" } else { "fetched" };
+        let used = chrono::DateTime::from_timestamp_millis(blob.used).unwrap_or_default();
+        let used = used.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+        output.line(format!("> {} ({}), {}, last used {}", name, format_size(blob.size), how, used));
+    }
+    Ok(())
+}
+
+// `/who`: who is online in the active room
+async fn who_command(client: &ChatClient, output: &Output) -> Result<()> {
+    let online = client.who().await?;
+    output.line(format!("> {} online", online.len()));
+    for peer in online {
+        // Only worth pointing out when it differs from ours
+        let protocol = match peer.protocol {
+            Some((version, _)) if version != PROTOCOL_VERSION => format!(", protocol {}", version),
+            _ => String::new(),
+        };
+        output.line(format!(
+            "> {}{} ({}), seen {}s ago{}",
+            peer.name,
+            ui::presence(&peer.status),
+            peer.node_id.fmt_short(),
+            peer.last_seen.as_secs(),
+            protocol
+        ));
+    }
+    Ok(())
+}
+
+// `/connections`: how we reach each neighbor, and the traffic so far
+async fn connections_command(client: &ChatClient, output: &Output) -> Result<()> {
+    let links = client.connections().await?;
+    let plural = if links.len() == 1 { "" } else { "s" };
+    output.line(format!("> {} neighbor{}", links.len(), plural));
+    let mut rows = vec![["PEER", "PATH", "ADDRESS", "RTT", "IDLE", "RECEIVED"].map(String::from).to_vec()];
+    for link in links {
+        let (path, address) = match link.conn_type {
+            Some(ConnectionType::Direct(addr)) => ("direct", addr.to_string()),
+            Some(ConnectionType::Relay(url)) => ("relay", url.to_string()),
+            // Still trying whether the direct path works
+            Some(ConnectionType::Mixed(addr, url)) => ("mixed", format!("{} and {}", addr, url)),
+            Some(ConnectionType::None) | None => ("none", "-".to_string()),
+        };
+        let rtt = link.latency.map(|rtt| format!("{} ms", rtt.as_millis())).unwrap_or("-".to_string());
+        let idle = link.last_used.map(|idle| format!("{}s", idle.as_secs())).unwrap_or("-".to_string());
+        let name = format!("{} ({})", link.name, link.node_id.fmt_short());
+        rows.push(vec![name, path.to_string(), address, rtt, idle, format_size(link.received)]);
+    }
+    for row in ui::table(&rows) {
+        output.line(format!("> {}", row));
+    }
+    let traffic = client.traffic();
+    output.line(format!(
+        "> Since starting, all rooms: {} sent, {} received ({} of it through a relay)",
+        format_size(traffic.sent),
+        format_size(traffic.received),
+        format_size(traffic.relayed)
+    ));
+    Ok(())
+}
+
+// `/dnd [duration] [public]`
+async fn dnd_command(client: &ChatClient, output: &Output, arg: Option<&str>) -> Result<()> {
+    let (mut duration, mut public) = (None, false);
+    for word in arg.unwrap_or_default().split_whitespace() {
+        match word {
+            "public" => public = true,
+            word => duration = Some((word, parse_duration(word)?)),
+        }
+    }
+    client.set_dnd(duration.map(|(_, duration)| duration), public).await;
+    let told = if public { ", and your rooms know" } else { "" };
+    output.line(match duration {
+        Some((word, _)) => format!("> Do not disturb for {}{}, /dnd off ends it sooner", word, told),
+        None => format!("> Do not disturb until /dnd off{}", told),
+    });
+    Ok(())
+}
+
+// `/search [query]`, without a query the next page of the last search
+async fn search_command(client: &ChatClient, output: &Output, query: Option<&str>) -> Result<()> {
+    let hits = match query {
+        Some(query) => client.search(query).await?,
+        None => client.search_more().await?,
+    };
+    if hits.is_empty() {
+        output.line(if query.is_some() { "> Nothing found" } else { "> No more matches" });
+    }
+    for hit in &hits {
+        output.line(format!("> {}", ui::render_search(hit)));
+    }
+    if hits.len() == SEARCH_PAGE {
+        output.line("> /search for more");
+    }
+    Ok(())
+}
+
+// `/rooms`: the rooms we are in, with their unread messages
+async fn rooms_command(client: &ChatClient, output: &Output) -> Result<()> {
+    for (i, (topic_id, active)) in client.rooms().await.into_iter().enumerate() {
+        let marker = match client.unread(&topic_id)? {
+            _ if active => " (active)".to_string(),
+            0 => String::new(),
+            unread => format!(" ({} unread)", unread),
+        };
+        let name = match client.room_info(&topic_id) {
+            Some(info) if !info.name.is_empty() => format!(" {}", info.name),
+            _ => String::new(),
+        };
+        output.line(format!("> {}. {}{}{}", i + 1, topic_id.fmt_short(), name, marker));
+    }
+    Ok(())
+}
+
+// `/contact add <peer> [alias]`, `/contact list`, `/contact rename <contact> <alias>`,
+// `/contact note <contact> <text>` and `/contact remove <contact>`
+async fn contact_command(client: &ChatClient, output: &Output, arg: &str) -> Result<()> {
+    let (action, rest) = match arg.split_once(' ') {
+        Some((action, rest)) => (action, rest.trim()),
+        None => (arg, ""),
+    };
+    let (peer, value) = match rest.split_once(' ') {
+        Some((peer, value)) => (peer, Some(value.trim().to_string())),
+        None => (rest, None),
+    };
+    match (action, value) {
+        ("list", _) => {
+            let contacts = client.contacts();
+            output.line(format!("> {} contacts", contacts.len()));
+            for contact in contacts {
+                let notes = match contact.notes.is_empty() {
+                    true => String::new(),
+                    false => format!(": {}", contact.notes),
+                };
+                output.line(format!("> {} ({}){}", contact.alias, contact.node_id.fmt_short(), notes));
+            }
+        }
+        ("add", alias) if !peer.is_empty() => {
+            let (node_id, _) = client.resolve_peer(peer).await?;
+            let contact = client.add_contact(node_id, alias)?;
+            output.line(format!("> Added {} ({}) to your contacts", contact.alias, node_id.fmt_short()));
+        }
+        ("rename", Some(alias)) => {
+            let (node_id, _) = client.resolve_peer(peer).await?;
+            let old = client.rename_contact(node_id, alias.clone())?;
+            output.line(format!("> Renamed {} to {}", old, alias));
+        }
+        ("note", Some(notes)) => {
+            let (node_id, name) = client.resolve_peer(peer).await?;
+            client.note_contact(node_id, notes)?;
+            output.line(format!("> Saved notes on {}", name));
+        }
+        ("save", alias) => {
+            let id: usize = peer.parse().context("usage: /contact save <id> [alias]")?;
+            let contact = client.save_card(id, alias).await?;
+            output.line(format!("> Added {} ({}) to your contacts", contact.alias, contact.node_id.fmt_short()));
+        }
+        ("remove", None) if !peer.is_empty() => {
+            let (node_id, _) = client.resolve_peer(peer).await?;
+            let contact = client.remove_contact(node_id)?;
+            output.line(format!("> Removed {} from your contacts", contact.alias));
+        }
+        _ => bail!("usage: {}", find("contact")?.usage()),
+    }
+    Ok(())
+}
+
+// "52.5163,13.3777 the gate", or with a space for the comma, as latitude, longitude and label
+fn parse_location(text: &str) -> Result<(f64, f64, String)> {
+    const USAGE: &str = "usage: /location <lat>,<lon> [label], like /location 52.5163,13.3777 the gate";
+    let text = text.replacen(',', " ", 1);
+    let mut parts = text.split_whitespace();
+    let lat = parts.next().and_then(|lat| lat.parse().ok()).context(USAGE)?;
+    let lon = parts.next().and_then(|lon| lon.parse().ok()).context(USAGE)?;
+    Ok((lat, lon, parts.collect::<Vec<_>>().join(" ")))
+}
+
+// `/retain` limits: an age like 30d, a number of messages like 10000 or 10k, both, or "off"
+fn parse_retention(text: &str) -> Result<Retention> {
+    let mut retention = Retention::default();
+    for word in text.split_whitespace() {
+        let count = match word.strip_suffix('k') {
+            Some(thousands) => thousands.parse::<u64>().ok().map(|count| count * 1000),
+            None => word.parse::<u64>().ok(),
+        };
+        match (word, count) {
+            ("off", _) => retention = Retention::default(),
+            (_, Some(0)) => bail!("keep at least one message"),
+            (_, Some(count)) => retention.max_messages = Some(count),
+            (_, None) => match parse_duration(word)? {
+                Duration::ZERO => bail!("keep at least a second of history"),
+                age => retention.max_age = Some(age),
+            },
+        }
+    }
+    Ok(retention)
+}
@@ -0,0 +1,43 @@
+use crate::commands::COMMANDS;
+
+// Tab completion of the word at the end of the input: a /command at its start from the registry, or
+// an @mention from the names of who is online. Returns the input completed as far as every
+// candidate agrees, and the candidates when more than one is left.
+pub fn complete(input: &str, names: &[String]) -> (String, Vec<String>) {
+    let start = input.rfind(' ').map_or(0, |i| i + 1);
+    let (head, word) = input.split_at(start);
+    let mut candidates: Vec<String> = if start == 0 && let Some(word) = word.strip_prefix('/') {
+        COMMANDS.iter().filter(|command| command.name.starts_with(word)).map(|c| format!("/{}", c.name)).collect()
+    } else if let Some(word) = word.strip_prefix('@') {
+        let word = word.to_lowercase();
+        names.iter().filter(|name| name.to_lowercase().starts_with(&word)).map(|name| format!("@{}", name)).collect()
+    } else {
+        Vec::new()
+    };
+    candidates.sort();
+    candidates.dedup();
+    match candidates.as_slice() {
+        [] => (input.to_string(), candidates),
+        [only] => (format!("{}{} ", head, only), Vec::new()),
+        // Only ever longer, should case make the shared start shorter than what was typed
+        _ => match common_prefix(&candidates) {
+            prefix if prefix.len() > word.len() => (format!("{}{}", head, prefix), candidates),
+            _ => (input.to_string(), candidates),
+        },
+    }
+}
+
+// The start all the words share, ignoring case and spelled like the first of them
+fn common_prefix(words: &[String]) -> &str {
+    let first = &words[0];
+    let len = first
+        .char_indices()
+        .find(|&(i, c)| {
+            let differs = |word: &String| word.get(i..).and_then(|rest| rest.chars().next()).is_none_or(|d| {
+                !d.eq_ignore_ascii_case(&c)
+            });
+            words[1..].iter().any(differs)
+        })
+        .map_or(first.len(), |(i, _)| i);
+    &first[..len]
+}
@@ -0,0 +1,169 @@
+use crate::{DiscoveryMode, RateLimit};
+use anyhow::{bail, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use iroh::{NodeAddr, NodeId, RelayMode, RelayUrl};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+// Defaults from config.toml in the data directory. Command line flags take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Display name announced in rooms
+    pub name: Option<String>,
+    // Secret key file to use instead of the data directory's identity.key
+    pub identity: Option<PathBuf>,
+    // "default" for n0's relays, "disabled", or the URL of a relay of our own
+    pub relay: Option<String>,
+    // "n0", "local" for the local network only, "all", "none" or a list like "dns,local"
+    pub discovery: Option<DiscoveryMode>,
+    // Set to false to keep our addresses out of discovery, like --no-publish
+    pub publish: Option<bool>,
+    // The UDP port to listen on instead of a random one, like --port
+    pub port: Option<u16>,
+    // Tickets of rooms to join on startup
+    pub rooms: Vec<String>,
+    // Always-on peers, "<node-id>" or "<node-id>@<ip:port>", dialed on startup and bootstrapped
+    // from in every room we join
+    pub bootstrap: Vec<String>,
+    // How many messages each peer may send per room before the rest are dropped
+    pub limits: Option<RateLimit>,
+    // Encrypt the history database with a "passphrase" asked for at startup, or with a key derived
+    // from the "identity" key
+    pub encrypt_history: Option<HistoryEncryption>,
+    // Act as a mailbox: members that were away catch up from us
+    pub mailbox: Option<bool>,
+    // How many megabytes offered and fetched files may take before the least recently used go
+    pub file_cache: Option<u64>,
+    // How many kilobytes a second files may be sent at, all downloads from us together
+    pub upload_limit: Option<u64>,
+    pub ui: UiConfig,
+    pub theme: ThemeConfig,
+    pub keys: KeysConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryEncryption {
+    Passphrase,
+    Identity,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    // Set to false to always use the plain line-based interface
+    pub tui: Option<bool>,
+    // Set to false to show names and code without colors, like --no-color
+    pub color: Option<bool>,
+    // Set to false for no desktop notifications
+    pub notifications: Option<bool>,
+    // The strftime format of the time in front of messages, %H:%M by default and "" for none
+    pub timestamps: Option<String>,
+    // Set to true to show those times in UTC instead of local time
+    pub utc: Option<bool>,
+    // Set to false to show emoji as their :shortcodes:, for terminals without an emoji font
+    pub emoji: Option<bool>,
+    // Set to false to show message text as it was sent instead of rendering its Markdown, like --plain
+    pub markdown: Option<bool>,
+    // Set to true for output that suits screen readers, like --accessible
+    pub accessible: Option<bool>,
+}
+
+// The colors of the frontends: a built-in theme, with any of its colors replaced. Colors are names
+// like "red" or "lightblue", "#rrggbb" or a 0-255 palette index.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    // "dark" (the default), "light" or "solarized"
+    pub name: Option<String>,
+    // Other people's messages, our own, notices, mentions of us and the times in front of messages
+    pub text: Option<String>,
+    pub own: Option<String>,
+    pub notice: Option<String>,
+    pub mention: Option<String>,
+    pub timestamp: Option<String>,
+}
+
+// Vim-like keys for the full-screen UI. With `vim` on, Esc leaves typing for a normal mode where
+// keys scroll, search and move between rooms. The other settings bind those actions to other keys,
+// written as typed, like "gt", or as "ctrl-d".
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    pub vim: Option<bool>,
+    pub down: Option<String>,
+    pub up: Option<String>,
+    pub half_page_down: Option<String>,
+    pub half_page_up: Option<String>,
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    pub search: Option<String>,
+    pub next_room: Option<String>,
+    pub previous_room: Option<String>,
+    pub insert: Option<String>,
+    pub command: Option<String>,
+    pub quit: Option<String>,
+}
+
+impl Config {
+    // Read a config file. A missing file is the same as an empty one.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("could not read {}", path.display())),
+        };
+        let config: Self =
+            toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))?;
+        if let Some(limits) = config.limits
+            && (limits.per_second <= 0.0 || limits.burst < 1.0)
+        {
+            bail!("limits need a positive per_second and a burst of at least 1 in {}", path.display());
+        }
+        // chrono only finds out when formatting, and then panics
+        if let Some(format) = &config.ui.timestamps
+            && StrftimeItems::new(format).any(|item| matches!(item, Item::Error))
+        {
+            bail!("timestamps is not a valid strftime format in {}", path.display());
+        }
+        Ok(config)
+    }
+
+    // The relay setting as the endpoint understands it, if there is one
+    pub fn relay_mode(&self) -> Result<Option<RelayMode>> {
+        let Some(relay) = &self.relay else {
+            return Ok(None);
+        };
+        let mode = match relay.as_str() {
+            "default" => RelayMode::Default,
+            "disabled" => RelayMode::Disabled,
+            url => match url.parse::<RelayUrl>() {
+                Ok(url) => RelayMode::Custom(url.into()),
+                Err(_) => bail!("relay must be \"default\", \"disabled\" or a URL, not {:?}", url),
+            },
+        };
+        Ok(Some(mode))
+    }
+
+    // The bootstrap peers, with the addresses given for them
+    pub fn bootstrap(&self) -> Result<Vec<NodeAddr>> {
+        let parse = |peer: &String| parse_peer(peer).context("invalid bootstrap peer in the config file");
+        self.bootstrap.iter().map(parse).collect()
+    }
+}
+
+// "<node-id>", found through discovery, or "<node-id>@<ip:port>"
+pub fn parse_peer(text: &str) -> Result<NodeAddr> {
+    let (node_id, addr) = match text.split_once('@') {
+        Some((node_id, addr)) => (node_id, Some(addr)),
+        None => (text, None),
+    };
+    let node_id: NodeId = node_id.parse().with_context(|| format!("{} is not a node id", node_id))?;
+    let Some(addr) = addr else {
+        return Ok(NodeAddr::new(node_id));
+    };
+    let addr: SocketAddr = addr.parse().with_context(|| format!("{} is not an address like 10.0.0.2:4433", addr))?;
+    Ok(NodeAddr::new(node_id).with_direct_addresses([addr]))
+}
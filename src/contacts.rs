@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use iroh::{NodeAddr, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// A peer we chose to remember, under a name we picked rather than the one it announces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub node_id: NodeId,
+    pub alias: String,
+    pub notes: String,
+    // Where we last reached it, so we can find it again without discovery
+    pub addr: Option<NodeAddr>,
+}
+
+// Our address book. Unlike announced names these are ours to pick and outlive the session, so
+// they take precedence wherever a peer is shown.
+#[derive(Debug, Clone, Default)]
+pub struct Contacts(Arc<Mutex<HashMap<NodeId, Contact>>>);
+
+impl Contacts {
+    // Returns whether the peer wasn't a contact already
+    pub fn insert(&self, contact: Contact) -> bool {
+        let mut contacts = self.0.lock().expect("contacts lock poisoned");
+        if contacts.contains_key(&contact.node_id) {
+            return false;
+        }
+        contacts.insert(contact.node_id, contact);
+        true
+    }
+
+    pub fn remove(&self, node_id: &NodeId) -> Option<Contact> {
+        self.0.lock().expect("contacts lock poisoned").remove(node_id)
+    }
+
+    pub fn alias(&self, node_id: &NodeId) -> Option<String> {
+        self.0.lock().expect("contacts lock poisoned").get(node_id).map(|contact| contact.alias.clone())
+    }
+
+    // The contact we call `alias`
+    pub fn find(&self, alias: &str) -> Option<NodeId> {
+        let contacts = self.0.lock().expect("contacts lock poisoned");
+        contacts.values().find(|contact| contact.alias == alias).map(|contact| contact.node_id)
+    }
+
+    // Change a contact in place and return the result, if the peer is one
+    pub fn update(&self, node_id: &NodeId, change: impl FnOnce(&mut Contact)) -> Option<Contact> {
+        let mut contacts = self.0.lock().expect("contacts lock poisoned");
+        let contact = contacts.get_mut(node_id)?;
+        change(contact);
+        Some(contact.clone())
+    }
+
+    // Everyone in the address book, sorted by alias
+    pub fn list(&self) -> Vec<Contact> {
+        let mut contacts: Vec<_> = self.0.lock().expect("contacts lock poisoned").values().cloned().collect();
+        contacts.sort_by(|a, b| a.alias.cmp(&b.alias));
+        contacts
+    }
+}
+
+// Contact cards shared in rooms this session, numbered like file offers so one can be saved by id.
+// A card is a contact under the name it goes by, with the address its sender last reached it at.
+#[derive(Debug, Clone, Default)]
+pub struct Cards(Arc<Mutex<Vec<Contact>>>);
+
+impl Cards {
+    // Remember a card and return the id to save it with
+    pub fn add(&self, card: Contact) -> usize {
+        let mut cards = self.0.lock().expect("cards lock poisoned");
+        cards.push(card);
+        cards.len()
+    }
+
+    pub fn get(&self, id: usize) -> Result<Contact> {
+        id.checked_sub(1)
+            .and_then(|i| self.0.lock().expect("cards lock poisoned").get(i).cloned())
+            .with_context(|| format!("no contact card #{}", id))
+    }
+}
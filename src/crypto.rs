@@ -0,0 +1,79 @@
+use anyhow::{anyhow, bail, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use iroh_gossip::proto::TopicId;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const NONCE_LEN: usize = 24;
+
+// Argon2id costs for deriving a room from a secret: 64 MiB of memory and 3 passes on one lane,
+// about a second on a laptop. Anyone on the topic can test guesses against its id offline, so each
+// guess has to cost that much memory too, not just time a GPU has plenty of.
+const DERIVE_MEMORY_KIB: u32 = 64 * 1024;
+const DERIVE_PASSES: u32 = 3;
+const DERIVE_LANES: u32 = 1;
+
+// Symmetric key shared by everyone holding the room's ticket
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct RoomKey([u8; 32]);
+
+impl RoomKey {
+    pub fn generate() -> Self {
+        Self(random())
+    }
+
+    // A key agreed on some other way, e.g. in a password exchange
+    pub(crate) fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    // Output is the random nonce followed by the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let cipher = XChaCha20Poly1305::new(&self.0.into());
+        let nonce: [u8; NONCE_LEN] = random();
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .expect("Encryption failed");
+        let mut out = nonce.to_vec();
+        out.extend(ciphertext);
+        out
+    }
+
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        if bytes.len() < NONCE_LEN {
+            bail!("ciphertext too short");
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let cipher = XChaCha20Poly1305::new(&self.0.into());
+        cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("decryption failed (wrong room key?)"))
+    }
+}
+
+// Keep the key itself out of debug output
+impl fmt::Debug for RoomKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RoomKey(..)")
+    }
+}
+
+// The topic and key of the room everyone with the same name and secret meets in, so a group can
+// come back to it without a ticket. The topic is visible to peers and relays, the key can't be
+// worked out from it.
+pub fn derive_room(name: &str, secret: &str) -> (TopicId, RoomKey) {
+    let input = postcard::to_stdvec(&(name, secret)).expect("Serialization failed");
+    // Salted with the name, so a guess only ever tests one room
+    let salt = blake3::derive_key("iroh-chat 2025 room salt", name.as_bytes());
+    let params = Params::new(DERIVE_MEMORY_KIB, DERIVE_PASSES, DERIVE_LANES, Some(32)).expect("valid argon2 params");
+    let mut seed = [0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(&input, &salt, &mut seed)
+        .expect("argon2 takes 32-byte salts and outputs");
+    let topic = blake3::derive_key("iroh-chat 2025 room topic", &seed);
+    let key = blake3::derive_key("iroh-chat 2025 room key", &seed);
+    (TopicId::from_bytes(topic), RoomKey(key))
+}
@@ -0,0 +1,107 @@
+#[cfg(unix)]
+use crate::ui::{self, Output};
+use anyhow::{bail, Result};
+#[cfg(unix)]
+use futures_lite::StreamExt;
+use p2p_chat::ChatClient;
+use std::path::Path;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+// Run without a frontend, serving the client on a Unix socket until interrupted. Every connection
+// gets the same line interface as --no-tui: messages and /commands in, rendered events and notices
+// out.
+#[cfg(unix)]
+pub async fn run(client: &ChatClient, path: &Path, mut lines: mpsc::UnboundedReceiver<String>) -> Result<()> {
+    if UnixStream::connect(path).await.is_ok() {
+        bail!("a daemon is already listening on {}", path.display());
+    }
+    // Left behind by a daemon that didn't shut down cleanly
+    let _ = std::fs::remove_file(path);
+    let listener = listen(path)?;
+    println!("> Listening on {}", path.display());
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let client = client.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve(&client, stream).await {
+                        println!("> Connection closed: {}", err);
+                    }
+                });
+            }
+            // Notices from startup, e.g. rooms in the config file that couldn't be joined
+            Some(line) = lines.recv() => println!("{}", line),
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn run(_client: &ChatClient, path: &Path, _lines: mpsc::UnboundedReceiver<String>) -> Result<()> {
+    bail!("can't listen on {}: the daemon needs Unix sockets", path.display());
+}
+
+// Anyone who can connect can speak as us, so the socket is bound in a directory only we can enter,
+// narrowed to 0600 there and only then moved into place. Binding it at `path` directly would leave
+// it open to everyone until its permissions were changed.
+#[cfg(unix)]
+fn listen(path: &Path) -> Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let private = parent.join(format!(".daemon-{}", std::process::id()));
+    // Left behind by an earlier daemon that had our process id
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("daemon.sock");
+    let bound = (|| -> Result<UnixListener> {
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&private);
+    bound
+}
+
+#[cfg(unix)]
+async fn serve(client: &ChatClient, stream: UnixStream) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut input = BufReader::new(read).lines();
+    let (output, mut notices) = Output::channel();
+    let mut events = client.events();
+    loop {
+        let line = tokio::select! {
+            line = input.next_line() => match line? {
+                // /quit only ends this connection, the daemon keeps running
+                Some(line) => match crate::handle_line(client, &output, line).await {
+                    true => continue,
+                    false => return Ok(()),
+                },
+                None => return Ok(()),
+            },
+            Some(line) = notices.recv() => line,
+            Some(event) = events.next() => {
+                let Some(line) = ui::render(&event, client.active_room().await) else {
+                    continue;
+                };
+                match ui::render_quote(&event) {
+                    Some(quote) => format!("{}\n{}", quote, line),
+                    None => line,
+                }
+            }
+        };
+        write.write_all(format!("{}\n", line).as_bytes()).await?;
+    }
+}
@@ -0,0 +1,517 @@
+use crate::blocks::Blocks;
+use crate::client::{ChatEvent, Events};
+use crate::contacts::{Contact, Contacts};
+use crate::storage::{self, Bookmark, Notify, Storage};
+use anyhow::{bail, Result};
+use data_encoding::BASE32_NOPAD;
+use iroh::endpoint::Connection;
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{Endpoint, NodeAddr, NodeId, SecretKey, Watcher};
+use iroh_base::Signature;
+use iroh_gossip::proto::TopicId;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+pub const ALPN: &[u8] = b"iroh-chat/devices/0";
+
+// Catching up on settings carries every contact and bookmark
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// How long a link ticket can be redeemed, in milliseconds
+const LINK_TTL: i64 = 10 * 60 * 1000;
+
+// Keep a device certificate from being passed off as any other signature of the account
+const CERT_DOMAIN: &str = "iroh-chat device";
+
+// An account's signature making `device` one of its devices. The account is the node id of the
+// device the others were linked from. Peers show what any of its devices sends as the account's,
+// and the devices of an account trust each other on the sync channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceCert {
+    pub account: NodeId,
+    pub device: NodeId,
+    signature: Signature,
+}
+
+impl DeviceCert {
+    pub fn sign(account: &SecretKey, device: NodeId) -> Self {
+        let signature = account.sign(&cert_bytes(&device));
+        Self { account: account.public(), device, signature }
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        self.account.verify(&cert_bytes(&self.device), &self.signature)?;
+        Ok(())
+    }
+}
+
+fn cert_bytes(device: &NodeId) -> Vec<u8> {
+    postcard::to_stdvec(&(CERT_DOMAIN, device)).expect("Serialization failed")
+}
+
+// What a new device needs to link itself: where the account's node is, and a token it hands out
+// once, for a few minutes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkTicket {
+    pub addr: NodeAddr,
+    token: [u8; 16],
+}
+
+impl fmt::Display for LinkTicket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = serde_json::to_vec(self).expect("Serialization failed");
+        write!(f, "{}", BASE32_NOPAD.encode(&bytes).to_lowercase())
+    }
+}
+
+impl FromStr for LinkTicket {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = BASE32_NOPAD.decode(s.to_uppercase().as_bytes())?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+// A setting of the account rather than of one device, which all its devices share. `None` is one
+// that was removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Setting {
+    Contact(NodeId, Option<Contact>),
+    // A blocked peer and the name it was blocked under
+    Blocked(NodeId, Option<String>),
+    // A room we are in. Devices we sync it to join it the next time they start.
+    Bookmark(TopicId, Option<Bookmark>),
+    // Which messages of a room notify us, None for the default
+    Notify(TopicId, Option<Notify>),
+}
+
+impl Setting {
+    // Later changes of the same setting replace it under this
+    pub fn key(&self) -> String {
+        match self {
+            Setting::Contact(node_id, _) => format!("contact:{}", node_id),
+            Setting::Blocked(node_id, _) => format!("blocked:{}", node_id),
+            Setting::Bookmark(topic, _) => format!("bookmark:{}", topic),
+            Setting::Notify(topic, _) => format!("notify:{}", topic),
+        }
+    }
+}
+
+// What the devices of an account tell each other over the sync channel: direct connections, which
+// authenticate both ends, between node ids one account certified
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SyncMessage {
+    // Every device of the account, after one was linked
+    Devices(Vec<DeviceCert>),
+    // How far we read in rooms, as the clock of the last message read. The furthest position
+    // wins, and a device that is further along answers with its own.
+    Read(Vec<(TopicId, u64)>),
+    // Settings and when they last changed. The latest change wins, and a device with later ones
+    // answers with those, and with the ones we lack if these are `everything` we have.
+    Settings { changes: Vec<(Setting, i64)>, everything: bool },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    Link { token: [u8; 16] },
+    Sync(SyncMessage),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Answer {
+    // The new device's certificate and those of the account's other devices
+    Linked(Vec<DeviceCert>),
+    Done,
+    Refused(String),
+}
+
+#[derive(Debug)]
+struct State {
+    us: NodeId,
+    // Known certificates, by device: ours, our other devices' and those peers announced
+    certs: HashMap<NodeId, DeviceCert>,
+    // Link tokens we issued, and when they expire
+    tokens: HashMap<[u8; 16], i64>,
+}
+
+// Which devices speak for which account
+#[derive(Debug, Clone)]
+pub struct Devices(Arc<Mutex<State>>);
+
+impl Devices {
+    pub fn new(us: NodeId) -> Self {
+        Self(Arc::new(Mutex::new(State { us, certs: HashMap::new(), tokens: HashMap::new() })))
+    }
+
+    // The account a node speaks for: the one that certified it, or its own
+    pub fn account_of(&self, node_id: &NodeId) -> NodeId {
+        let state = self.0.lock().expect("devices lock poisoned");
+        state.certs.get(node_id).map_or(*node_id, |cert| cert.account)
+    }
+
+    // The account we speak for
+    pub fn account(&self) -> NodeId {
+        let us = self.0.lock().expect("devices lock poisoned").us;
+        self.account_of(&us)
+    }
+
+    // Our certificate, if we were linked to an account, to announce in rooms
+    pub fn cert(&self) -> Option<DeviceCert> {
+        let state = self.0.lock().expect("devices lock poisoned");
+        state.certs.get(&state.us).cloned()
+    }
+
+    // The other devices of our account, the account's own node included
+    pub fn ours(&self) -> Vec<NodeId> {
+        let account = self.account();
+        let state = self.0.lock().expect("devices lock poisoned");
+        let mut devices: Vec<_> =
+            state.certs.values().filter(|cert| cert.account == account).map(|cert| cert.device).collect();
+        devices.push(account);
+        devices.retain(|device| *device != state.us);
+        devices.sort();
+        devices.dedup();
+        devices
+    }
+
+    pub fn is_ours(&self, node_id: &NodeId) -> bool {
+        self.ours().contains(node_id)
+    }
+
+    // Every certificate of our account's devices
+    fn certs(&self) -> Vec<DeviceCert> {
+        let account = self.account();
+        let state = self.0.lock().expect("devices lock poisoned");
+        state.certs.values().filter(|cert| cert.account == account).cloned().collect()
+    }
+
+    // Learn which account a device speaks for. Returns whether the certificate was new. A device
+    // is never moved to another account, nor an account made someone's device.
+    pub fn add(&self, cert: DeviceCert) -> Result<bool> {
+        cert.verify()?;
+        let mut state = self.0.lock().expect("devices lock poisoned");
+        if cert.account == cert.device {
+            bail!("an account can't be its own device");
+        }
+        if let Some(known) = state.certs.get(&cert.device) {
+            if known.account != cert.account {
+                bail!("{} is already a device of {}", cert.device.fmt_short(), known.account.fmt_short());
+            }
+            return Ok(false);
+        }
+        if state.certs.contains_key(&cert.account) {
+            bail!("{} is a device, not an account", cert.account.fmt_short());
+        }
+        if state.certs.values().any(|known| known.account == cert.device) {
+            bail!("{} is an account, not a device", cert.device.fmt_short());
+        }
+        state.certs.insert(cert.device, cert);
+        Ok(true)
+    }
+
+    fn issue(&self) -> [u8; 16] {
+        let token = random();
+        let mut state = self.0.lock().expect("devices lock poisoned");
+        state.tokens.insert(token, storage::now() + LINK_TTL);
+        token
+    }
+
+    fn redeem(&self, token: &[u8; 16]) -> bool {
+        let mut state = self.0.lock().expect("devices lock poisoned");
+        state.tokens.retain(|_, expires| *expires > storage::now());
+        state.tokens.remove(token).is_some()
+    }
+}
+
+// Links new devices to our account, and hears what our other devices sync to us
+#[derive(Debug, Clone)]
+pub struct DevicesProtocol {
+    endpoint: Endpoint,
+    devices: Devices,
+    storage: Arc<Storage>,
+    contacts: Contacts,
+    blocks: Blocks,
+    events: Events,
+}
+
+impl DevicesProtocol {
+    pub fn new(
+        endpoint: Endpoint,
+        devices: Devices,
+        storage: Arc<Storage>,
+        contacts: Contacts,
+        blocks: Blocks,
+        events: Events,
+    ) -> Self {
+        Self { endpoint, devices, storage, contacts, blocks, events }
+    }
+
+    // Tell our other devices where we are, for them to answer where they are further along
+    pub fn catch_up(&self) -> Result<()> {
+        if !self.devices.ours().is_empty() {
+            share(&self.endpoint, &self.devices, SyncMessage::Read(self.storage.read_positions()?));
+            let changes = self.storage.settings()?;
+            share(&self.endpoint, &self.devices, SyncMessage::Settings { changes, everything: true });
+        }
+        Ok(())
+    }
+
+    pub fn changed(&self, setting: Setting) -> Result<()> {
+        changed(&self.endpoint, &self.devices, &self.storage, setting)
+    }
+
+    // A ticket a new device can link itself to our account with
+    pub async fn ticket(&self) -> Result<LinkTicket> {
+        if self.devices.cert().is_some() {
+            bail!("this device is linked to {}, link new devices from there", self.devices.account().fmt_short());
+        }
+        let addr = self.endpoint.node_addr().initialized().await;
+        Ok(LinkTicket { addr, token: self.devices.issue() })
+    }
+
+    fn link(&self, device: NodeId, token: &[u8; 16]) -> Result<Vec<DeviceCert>> {
+        if self.devices.cert().is_some() {
+            bail!("this device is linked to another account itself");
+        }
+        if !self.devices.redeem(token) {
+            bail!("the link ticket expired or was used already");
+        }
+        let cert = DeviceCert::sign(self.endpoint.secret_key(), device);
+        self.devices.add(cert.clone())?;
+        self.storage.save_device(&cert)?;
+        Ok(self.devices.certs())
+    }
+
+    fn apply(&self, from: NodeId, message: SyncMessage) -> Result<()> {
+        match message {
+            SyncMessage::Devices(certs) => {
+                for cert in certs {
+                    let device = cert.device;
+                    if cert.account == self.devices.account() && self.devices.add(cert.clone())? {
+                        self.storage.save_device(&cert)?;
+                        self.events.emit(ChatEvent::DeviceLinked { device });
+                    }
+                }
+            }
+            SyncMessage::Read(positions) => {
+                let ours: HashMap<_, _> = self.storage.read_positions()?.into_iter().collect();
+                let mut further = Vec::new();
+                for (topic, clock) in positions {
+                    if !self.storage.set_read(&topic, clock)?
+                        && let Some(ours) = ours.get(&topic).filter(|ours| **ours > clock)
+                    {
+                        further.push((topic, *ours));
+                    }
+                }
+                if !further.is_empty() {
+                    let endpoint = self.endpoint.clone();
+                    tokio::spawn(async move { sync(&endpoint, from, SyncMessage::Read(further)).await });
+                }
+            }
+            SyncMessage::Settings { changes, everything } => {
+                let mut theirs = HashMap::new();
+                for (setting, updated) in changes {
+                    theirs.insert(setting.key(), (serde_json::to_string(&setting)?, updated));
+                    if self.storage.merge_setting(&setting, updated)? {
+                        self.adopt(setting)?;
+                    }
+                }
+                // Whatever we keep that isn't what they sent is newer
+                let mut newer = Vec::new();
+                for (setting, updated) in self.storage.settings()? {
+                    match theirs.get(&setting.key()) {
+                        Some(change) if *change == (serde_json::to_string(&setting)?, updated) => {}
+                        None if !everything => {}
+                        _ => newer.push((setting, updated)),
+                    }
+                }
+                if !newer.is_empty() {
+                    let endpoint = self.endpoint.clone();
+                    let answer = SyncMessage::Settings { changes: newer, everything: false };
+                    tokio::spawn(async move { sync(&endpoint, from, answer).await });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Take up a setting another device changed
+    fn adopt(&self, setting: Setting) -> Result<()> {
+        match setting {
+            Setting::Contact(_, Some(contact)) => {
+                if let Some(addr) = contact.addr.clone() {
+                    let _ = self.endpoint.add_node_addr(addr);
+                }
+                self.contacts.remove(&contact.node_id);
+                self.contacts.insert(contact.clone());
+                self.storage.save_contact(&contact)
+            }
+            Setting::Contact(node_id, None) => {
+                self.contacts.remove(&node_id);
+                self.storage.remove_contact(&node_id)
+            }
+            Setting::Blocked(node_id, Some(name)) => {
+                self.blocks.insert(node_id, name.clone());
+                self.storage.block(&node_id, &name)
+            }
+            Setting::Blocked(node_id, None) => {
+                self.blocks.remove(&node_id);
+                self.storage.unblock(&node_id)
+            }
+            Setting::Bookmark(topic, Some(bookmark)) => {
+                self.storage.bookmark(&topic, &bookmark.key, bookmark.owner.as_ref(), &bookmark.peers)?;
+                match bookmark.password {
+                    Some(password) => self.storage.bookmark_password(&topic, &password),
+                    None => Ok(()),
+                }
+            }
+            Setting::Bookmark(topic, None) => self.storage.remove_bookmark(&topic),
+            Setting::Notify(topic, notify) => self.storage.set_notify(&topic, notify.unwrap_or_default()),
+        }
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        let from = conn.remote_node_id()?;
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let request: Request = serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?;
+        let (answer, linked) = match request {
+            Request::Link { token } => match self.link(from, &token) {
+                Ok(certs) => (Answer::Linked(certs), true),
+                Err(err) => (Answer::Refused(err.to_string()), false),
+            },
+            Request::Sync(_) if !self.devices.is_ours(&from) => {
+                (Answer::Refused("not a device of this account".to_string()), false)
+            }
+            Request::Sync(message) => match self.apply(from, message) {
+                Ok(()) => (Answer::Done, false),
+                Err(err) => (Answer::Refused(err.to_string()), false),
+            },
+        };
+        send.write_all(&serde_json::to_vec(&answer)?).await?;
+        send.finish()?;
+        conn.closed().await;
+        if linked {
+            self.events.emit(ChatEvent::DeviceLinked { device: from });
+            // Our other devices learn of the new one too
+            let certs = self.devices.certs();
+            let others = self.devices.ours().into_iter().filter(|device| *device != from);
+            for device in others.collect::<Vec<_>>() {
+                if let Err(err) = sync(&self.endpoint, device, SyncMessage::Devices(certs.clone())).await {
+                    let device = device.fmt_short();
+                    self.events.warn(format!("could not tell device {} about the new one: {}", device, err));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for DevicesProtocol {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
+
+async fn request(endpoint: &Endpoint, to: NodeAddr, request: &Request) -> Result<Answer> {
+    let conn = endpoint.connect(to, ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&serde_json::to_vec(request)?).await?;
+    send.finish()?;
+    let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+    conn.close(0u32.into(), b"done");
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+// Trade a link ticket for our certificate and those of the account's other devices
+pub async fn link(endpoint: &Endpoint, ticket: LinkTicket) -> Result<Vec<DeviceCert>> {
+    match request(endpoint, ticket.addr, &Request::Link { token: ticket.token }).await? {
+        Answer::Linked(certs) => Ok(certs),
+        Answer::Refused(reason) => bail!("the account refused: {}", reason),
+        Answer::Done => bail!("the account didn't answer the link"),
+    }
+}
+
+// Send something to every other device of our account, in the background. Devices that are
+// offline miss it, and catch up from what the others tell them when they come back.
+pub fn share(endpoint: &Endpoint, devices: &Devices, message: SyncMessage) {
+    for device in devices.ours() {
+        let (endpoint, message) = (endpoint.clone(), message.clone());
+        tokio::spawn(async move { sync(&endpoint, device, message).await });
+    }
+}
+
+// Record a setting we changed and share it with our other devices
+pub fn changed(endpoint: &Endpoint, devices: &Devices, storage: &Storage, setting: Setting) -> Result<()> {
+    let updated = storage.stamp_setting(&setting)?;
+    share(endpoint, devices, SyncMessage::Settings { changes: vec![(setting, updated)], everything: false });
+    Ok(())
+}
+
+// Send something to another device of our account
+pub async fn sync(endpoint: &Endpoint, device: NodeId, message: SyncMessage) -> Result<()> {
+    match request(endpoint, device.into(), &Request::Sync(message)).await? {
+        Answer::Done => Ok(()),
+        Answer::Refused(reason) => bail!("the device refused: {}", reason),
+        Answer::Linked(_) => bail!("the device answered with certificates"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SecretKey {
+        SecretKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn links_devices_the_account_signed() {
+        let (account, laptop, phone) = (key(1), key(2).public(), key(3).public());
+        let devices = Devices::new(phone);
+        let cert = DeviceCert::sign(&account, laptop);
+        assert!(devices.add(cert.clone()).unwrap());
+        assert!(!devices.add(cert).unwrap());
+        assert_eq!(devices.account_of(&laptop), account.public());
+        assert_eq!(devices.account_of(&phone), phone);
+        // Linking ourselves makes the account's node and its other devices ours
+        assert!(devices.add(DeviceCert::sign(&account, phone)).unwrap());
+        assert_eq!(devices.account(), account.public());
+        assert!(devices.is_ours(&laptop) && devices.is_ours(&account.public()) && !devices.is_ours(&phone));
+    }
+
+    #[test]
+    fn refuses_forged_links() {
+        let (account, mallory, laptop) = (key(1), key(4), key(2).public());
+        let devices = Devices::new(key(3).public());
+        // Signed by someone else than the account it names, or for another device
+        let DeviceCert { signature, .. } = DeviceCert::sign(&mallory, laptop);
+        assert!(devices.add(DeviceCert { account: account.public(), device: laptop, signature }).is_err());
+        let DeviceCert { signature, .. } = DeviceCert::sign(&account, mallory.public());
+        assert!(devices.add(DeviceCert { account: account.public(), device: laptop, signature }).is_err());
+        assert_eq!(devices.account_of(&laptop), laptop);
+        // Nor can a device be moved to another account, or an account made someone's device
+        assert!(devices.add(DeviceCert::sign(&account, laptop)).unwrap());
+        assert!(devices.add(DeviceCert::sign(&mallory, laptop)).is_err());
+        assert!(devices.add(DeviceCert::sign(&mallory, account.public())).is_err());
+        assert!(devices.add(DeviceCert::sign(&account, account.public())).is_err());
+        assert_eq!(devices.account_of(&laptop), account.public());
+    }
+
+    #[test]
+    fn redeems_link_tokens_once_until_they_expire() {
+        let devices = Devices::new(key(1).public());
+        let token = devices.issue();
+        assert!(devices.redeem(&token));
+        assert!(!devices.redeem(&token));
+        assert!(!devices.redeem(&random()));
+        let expired = devices.issue();
+        devices.0.lock().unwrap().tokens.insert(expired, storage::now() - 1);
+        assert!(!devices.redeem(&expired));
+    }
+}
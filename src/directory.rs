@@ -0,0 +1,94 @@
+use crate::crypto::RoomKey;
+use crate::membership::Membership;
+use crate::message::{Message, MessageBody};
+use crate::ticket::Ticket;
+use anyhow::Result;
+use futures_lite::StreamExt;
+use iroh::{Endpoint, NodeId, Watcher};
+use iroh_gossip::api::{Event, GossipSender};
+use iroh_gossip::{net::Gossip, proto::TopicId};
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+
+// How often public rooms are announced again, for listers that just arrived
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(20);
+
+// A public room as announced in the directory
+#[derive(Debug, Clone, Serialize)]
+pub struct Listing {
+    pub from: NodeId,
+    pub name: String,
+    pub description: String,
+    pub ticket: String,
+}
+
+// Everyone shares the directory's topic and key, so anything announced there is public. The
+// envelope is still signed, so a listing can't claim to be from someone else.
+fn topic() -> TopicId {
+    TopicId::from_bytes(blake3::derive_key("iroh-chat 2025 directory topic", b""))
+}
+
+fn key() -> RoomKey {
+    RoomKey::from_bytes(blake3::derive_key("iroh-chat 2025 directory key", b""))
+}
+
+// Subscribe to the directory, bootstrapping from `peers`. Nothing we hear there matters to a
+// publisher, so the receiving half is just drained.
+pub async fn join(gossip: &Gossip, peers: Vec<NodeId>) -> Result<(GossipSender, JoinHandle<()>)> {
+    let (sender, mut receiver) = gossip.subscribe(topic(), peers).await?.split();
+    let drain = tokio::spawn(async move { while let Some(Ok(_)) = receiver.next().await {} });
+    Ok((sender, drain))
+}
+
+// Keep announcing a room until the room is left. The name and description are read again each
+// time, so they follow the owner's changes.
+pub fn announce(
+    sender: GossipSender,
+    endpoint: Endpoint,
+    membership: Membership,
+    topic_id: TopicId,
+    ticket: Ticket,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let info = membership.info(&topic_id);
+            let (name, description) = info.map(|info| (info.name, info.description)).unwrap_or_default();
+            let mut ticket = ticket.clone();
+            ticket.nodes = vec![endpoint.node_addr().initialized().await];
+            let from = endpoint.node_id();
+            let listing = MessageBody::Listing { from, name, description, ticket: ticket.to_string() };
+            let bytes = Message::new(listing).to_bytes(endpoint.secret_key(), &key());
+            // A failed announcement is retried on the next tick
+            let _ = sender.broadcast(bytes.into()).await;
+            sleep(ANNOUNCE_INTERVAL).await;
+        }
+    })
+}
+
+// Listen to the directory for `wait` and return the rooms announced in that time, one per topic
+pub async fn browse(gossip: &Gossip, peers: Vec<NodeId>, wait: Duration) -> Result<Vec<Listing>> {
+    let (_sender, mut receiver) = gossip.subscribe(topic(), peers).await?.split();
+    let deadline = Instant::now() + wait;
+    let mut found = HashMap::new();
+    while let Ok(Some(event)) = timeout_at(deadline, receiver.next()).await {
+        let Event::Received(msg) = event? else {
+            continue;
+        };
+        let Ok(message) = Message::from_bytes(&msg.content, &key()) else {
+            continue;
+        };
+        let MessageBody::Listing { from, name, description, ticket } = message.body else {
+            continue;
+        };
+        // Listings that don't carry a ticket we can use aren't worth showing
+        let Ok(parsed) = ticket.parse::<Ticket>() else {
+            continue;
+        };
+        found.insert(parsed.topic, Listing { from, name, description, ticket });
+    }
+    let mut listings: Vec<_> = found.into_values().collect();
+    listings.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(listings)
+}
@@ -0,0 +1,87 @@
+use crate::blocks::Blocks;
+use crate::client::{ChatEvent, Events};
+use crate::names::{self, Names};
+use crate::presence::Statuses;
+use anyhow::Result;
+use iroh::endpoint::{Connection, ConnectionError};
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{Endpoint, NodeId};
+use serde::{Deserialize, Serialize};
+
+pub const ALPN: &[u8] = b"iroh-chat/dm/0";
+
+const MAX_DM_SIZE: usize = 64 * 1024;
+
+// The code the receiver closes the connection with when it is away, with its note as the reason.
+// Senders that predate it only see the connection closed.
+const AWAY: u32 = 1;
+
+// The connection already authenticates the sender, so no signature is needed
+#[derive(Debug, Serialize, Deserialize)]
+struct DirectMessage {
+    name: String,
+    text: String,
+}
+
+// Accepts private messages sent straight to us instead of through a room
+#[derive(Debug, Clone)]
+pub struct DmProtocol {
+    names: Names,
+    blocks: Blocks,
+    statuses: Statuses,
+    events: Events,
+}
+
+impl DmProtocol {
+    pub fn new(names: Names, blocks: Blocks, statuses: Statuses, events: Events) -> Self {
+        Self { names, blocks, statuses, events }
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        let from = conn.remote_node_id()?;
+        let mut recv = conn.accept_uni().await?;
+        let dm: DirectMessage = serde_json::from_slice(&recv.read_to_end(MAX_DM_SIZE).await?)?;
+        // Blocked peers aren't told, their messages just go nowhere, and neither that we are away
+        if self.blocks.contains(&from) {
+            conn.close(0u32.into(), b"received");
+            return Ok(());
+        }
+        match self.statuses.ours().away {
+            Some(note) => conn.close(AWAY.into(), note.as_bytes()),
+            None => conn.close(0u32.into(), b"received"),
+        }
+        // Prefer the name we know from the rooms over whatever the sender claims. A claimed name is
+        // never shown bare, so strangers can't pass for someone we know.
+        let name = match self.names.get(&from) {
+            Some(_) => self.names.display(&from),
+            None => names::suffixed(&dm.name, &from),
+        };
+        self.events.emit(ChatEvent::DirectMessage { from, name, text: dm.text });
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for DmProtocol {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
+
+// Open a direct connection to `to` and deliver a private message. Returns the note `to` left if it
+// is away.
+pub async fn send(endpoint: &Endpoint, to: NodeId, name: &str, text: &str) -> Result<Option<String>> {
+    let conn = endpoint.connect(to, ALPN).await?;
+    let mut stream = conn.open_uni().await?;
+    let dm = DirectMessage { name: name.to_string(), text: text.to_string() };
+    stream.write_all(&serde_json::to_vec(&dm)?).await?;
+    stream.finish()?;
+    // The receiver closes the connection once it has read the message
+    match conn.closed().await {
+        ConnectionError::ApplicationClosed(close) if close.error_code == AWAY.into() => {
+            Ok(Some(String::from_utf8_lossy(&close.reason).into_owned()))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,208 @@
+use crate::complete;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// How many typed lines Up and Down go back through
+const HISTORY_LIMIT: usize = 500;
+
+// What a key did to the line being edited
+pub enum Input {
+    Line(String),
+    // Tab found several ways to go on
+    Choices(Vec<String>),
+    Quit,
+}
+
+// The line editor of the plain frontend on a terminal: moving about the line, this session's lines
+// with Up and Down, and Tab completion like the full-screen UI has
+#[derive(Default)]
+pub struct Editor {
+    line: String,
+    // A byte offset into `line`
+    cursor: usize,
+    history: Vec<String>,
+    // The history entry shown while going through it, and the line typed before that
+    browsing: Option<(usize, String)>,
+}
+
+impl Editor {
+    pub fn on_key(&mut self, key: KeyEvent, names: &[String]) -> Option<Input> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => {
+                self.cursor = 0;
+                self.browsing = None;
+                let line = std::mem::take(&mut self.line);
+                if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                    if self.history.len() > HISTORY_LIMIT {
+                        self.history.remove(0);
+                    }
+                }
+                return Some(Input::Line(line));
+            }
+            // Ctrl-C drops what was typed, and on an empty line quits like Ctrl-D does
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl && self.line.is_empty() => return Some(Input::Quit),
+            KeyCode::Char('c') if ctrl => (self.line, self.cursor) = (String::new(), 0),
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.line.len(),
+            KeyCode::Char('b') if ctrl => self.cursor = self.before(),
+            KeyCode::Char('f') if ctrl => self.cursor = self.after(),
+            KeyCode::Char('d') if ctrl => self.delete(self.cursor..self.after()),
+            KeyCode::Char('u') if ctrl => self.delete(0..self.cursor),
+            KeyCode::Char('k') if ctrl => self.delete(self.cursor..self.line.len()),
+            KeyCode::Char('w') if ctrl => self.delete(self.word_start()..self.cursor),
+            KeyCode::Char(c) if !ctrl => {
+                self.line.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+            }
+            KeyCode::Backspace => self.delete(self.before()..self.cursor),
+            KeyCode::Delete => self.delete(self.cursor..self.after()),
+            KeyCode::Left if ctrl => self.cursor = self.word_start(),
+            KeyCode::Right if ctrl => self.cursor = self.word_end(),
+            KeyCode::Left => self.cursor = self.before(),
+            KeyCode::Right => self.cursor = self.after(),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.line.len(),
+            KeyCode::Up => self.back(),
+            KeyCode::Down => self.forward(),
+            // Completing the word at the cursor, so what comes after it stays where it is
+            KeyCode::Tab => {
+                let (line, choices) = complete::complete(&self.line[..self.cursor], names);
+                if line.len() == self.cursor && !choices.is_empty() {
+                    return Some(Input::Choices(choices));
+                }
+                self.line.replace_range(..self.cursor, &line);
+                self.cursor = line.len();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn delete(&mut self, range: std::ops::Range<usize>) {
+        self.cursor = range.start;
+        self.line.replace_range(range, "");
+    }
+
+    // Where the character before and the one after the cursor start
+    fn before(&self) -> usize {
+        self.line[..self.cursor].chars().next_back().map_or(0, |c| self.cursor - c.len_utf8())
+    }
+
+    fn after(&self) -> usize {
+        self.line[self.cursor..].chars().next().map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+
+    // The start of the word the cursor is in or after, and the end of the one it is in or before
+    fn word_start(&self) -> usize {
+        let head = self.line[..self.cursor].trim_end();
+        head.rfind(' ').map_or(0, |i| i + 1)
+    }
+
+    fn word_end(&self) -> usize {
+        let tail = &self.line[self.cursor..];
+        let skipped = tail.len() - tail.trim_start().len();
+        tail[skipped..].find(' ').map_or(self.line.len(), |i| self.cursor + skipped + i)
+    }
+
+    // An older line from the history, keeping what was being typed for coming back down
+    fn back(&mut self) {
+        let index = match &self.browsing {
+            Some((0, _)) => return,
+            Some((index, _)) => index - 1,
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+        let draft = self.browsing.take().map_or_else(|| self.line.clone(), |(_, draft)| draft);
+        self.line = self.history[index].clone();
+        self.cursor = self.line.len();
+        self.browsing = Some((index, draft));
+    }
+
+    fn forward(&mut self) {
+        let Some((index, draft)) = self.browsing.take() else {
+            return;
+        };
+        self.line = match self.history.get(index + 1) {
+            Some(line) => {
+                self.browsing = Some((index + 1, draft));
+                line.clone()
+            }
+            None => draft,
+        };
+        self.cursor = self.line.len();
+    }
+
+    // The escape sequence redrawing the line, on a terminal `width` columns across. A line too long
+    // for it scrolls sideways to keep the cursor in view.
+    fn draw(&self, width: usize) -> String {
+        let room = width.saturating_sub(1).max(1);
+        let mut start = 0;
+        while self.line[start..self.cursor].width() >= room {
+            start += self.line[start..].chars().next().map_or(1, char::len_utf8);
+        }
+        let mut end = start;
+        let mut used = 0;
+        for c in self.line[start..].chars() {
+            used += c.width().unwrap_or(0);
+            if used > room {
+                break;
+            }
+            end += c.len_utf8();
+        }
+        let back = self.line[self.cursor.min(end)..end].width();
+        let left = if back > 0 { format!("\x1b[{}D", back) } else { String::new() };
+        format!("\r\x1b[K{}{}", &self.line[start..end], left)
+    }
+}
+
+// Where the plain frontend prints: straight to stdout, or above the line being edited while the
+// editor runs with the terminal in raw mode
+#[derive(Clone, Default)]
+pub struct Screen(Arc<Mutex<Option<Editor>>>);
+
+impl Screen {
+    pub fn start(&self) {
+        *self.0.lock().expect("screen lock poisoned") = Some(Editor::default());
+    }
+
+    // Back to plain printing, leaving what was typed on a line of its own
+    pub fn stop(&self) {
+        if self.0.lock().expect("screen lock poisoned").take().is_some() {
+            print!("\r\n");
+            let _ = std::io::stdout().flush();
+        }
+    }
+
+    pub fn println(&self, text: &str) {
+        let editor = self.0.lock().expect("screen lock poisoned");
+        let mut stdout = std::io::stdout().lock();
+        let _ = match editor.as_ref() {
+            // Raw mode doesn't go back to the start of the line by itself
+            Some(editor) => write!(stdout, "\r\x1b[K{}\r\n{}", text.replace('\n', "\r\n"), editor.draw(width())),
+            None => writeln!(stdout, "{}", text),
+        };
+        let _ = stdout.flush();
+    }
+
+    // Hand a key to the editor and redraw the line. A line entered stays on screen above the next.
+    pub fn key(&self, key: KeyEvent, names: &[String]) -> Option<Input> {
+        let mut editor = self.0.lock().expect("screen lock poisoned");
+        let editor = editor.as_mut()?;
+        let input = editor.on_key(key, names);
+        let mut stdout = std::io::stdout().lock();
+        if let Some(Input::Line(_)) = input {
+            let _ = write!(stdout, "\r\n");
+        }
+        let _ = write!(stdout, "{}", editor.draw(width()));
+        let _ = stdout.flush();
+        input
+    }
+}
+
+fn width() -> usize {
+    crossterm::terminal::size().map_or(80, |(columns, _)| columns as usize)
+}
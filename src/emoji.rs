@@ -0,0 +1,249 @@
+// The shortcodes understood, by their usual names. Where two name the same emoji the first is the
+// one shown when turning emoji back into shortcodes.
+const SHORTCODES: &[(&str, &str)] = &[
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("grin", "😁"),
+    ("laughing", "😆"),
+    ("joy", "😂"),
+    ("rofl", "🤣"),
+    ("sweat_smile", "😅"),
+    ("wink", "😉"),
+    ("blush", "😊"),
+    ("innocent", "😇"),
+    ("slightly_smiling_face", "🙂"),
+    ("upside_down_face", "🙃"),
+    ("heart_eyes", "😍"),
+    ("kissing_heart", "😘"),
+    ("yum", "😋"),
+    ("stuck_out_tongue", "😛"),
+    ("stuck_out_tongue_winking_eye", "😜"),
+    ("zany_face", "🤪"),
+    ("thinking", "🤔"),
+    ("neutral_face", "😐"),
+    ("expressionless", "😑"),
+    ("no_mouth", "😶"),
+    ("smirk", "😏"),
+    ("unamused", "😒"),
+    ("roll_eyes", "🙄"),
+    ("grimacing", "😬"),
+    ("relieved", "😌"),
+    ("pensive", "😔"),
+    ("sleepy", "😪"),
+    ("sleeping", "😴"),
+    ("mask", "😷"),
+    ("nerd_face", "🤓"),
+    ("sunglasses", "😎"),
+    ("confused", "😕"),
+    ("worried", "😟"),
+    ("open_mouth", "😮"),
+    ("astonished", "😲"),
+    ("flushed", "😳"),
+    ("pleading_face", "🥺"),
+    ("cry", "😢"),
+    ("sob", "😭"),
+    ("scream", "😱"),
+    ("angry", "😠"),
+    ("rage", "😡"),
+    ("partying_face", "🥳"),
+    ("exploding_head", "🤯"),
+    ("hugs", "🤗"),
+    ("shushing_face", "🤫"),
+    ("skull", "💀"),
+    ("poop", "💩"),
+    ("clown_face", "🤡"),
+    ("ghost", "👻"),
+    ("alien", "👽"),
+    ("robot", "🤖"),
+    ("see_no_evil", "🙈"),
+    ("facepalm", "🤦"),
+    ("face_palm", "🤦"),
+    ("shrug", "🤷"),
+    ("wave", "👋"),
+    ("raised_hand", "✋"),
+    ("ok_hand", "👌"),
+    ("v", "✌️"),
+    ("crossed_fingers", "🤞"),
+    ("point_up", "☝️"),
+    ("point_down", "👇"),
+    ("point_left", "👈"),
+    ("point_right", "👉"),
+    ("thumbsup", "👍"),
+    ("+1", "👍"),
+    ("thumbsdown", "👎"),
+    ("-1", "👎"),
+    ("fist", "👊"),
+    ("clap", "👏"),
+    ("raised_hands", "🙌"),
+    ("pray", "🙏"),
+    ("handshake", "🤝"),
+    ("muscle", "💪"),
+    ("eyes", "👀"),
+    ("brain", "🧠"),
+    ("heart", "❤️"),
+    ("orange_heart", "🧡"),
+    ("yellow_heart", "💛"),
+    ("green_heart", "💚"),
+    ("blue_heart", "💙"),
+    ("purple_heart", "💜"),
+    ("black_heart", "🖤"),
+    ("broken_heart", "💔"),
+    ("sparkling_heart", "💖"),
+    ("100", "💯"),
+    ("fire", "🔥"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("zap", "⚡"),
+    ("boom", "💥"),
+    ("tada", "🎉"),
+    ("confetti_ball", "🎊"),
+    ("gift", "🎁"),
+    ("trophy", "🏆"),
+    ("rocket", "🚀"),
+    ("warning", "⚠️"),
+    ("x", "❌"),
+    ("white_check_mark", "✅"),
+    ("heavy_check_mark", "✔️"),
+    ("question", "❓"),
+    ("exclamation", "❗"),
+    ("bulb", "💡"),
+    ("lock", "🔒"),
+    ("unlock", "🔓"),
+    ("key", "🔑"),
+    ("bell", "🔔"),
+    ("memo", "📝"),
+    ("calendar", "📅"),
+    ("paperclip", "📎"),
+    ("link", "🔗"),
+    ("computer", "💻"),
+    ("iphone", "📱"),
+    ("email", "📧"),
+    ("package", "📦"),
+    ("bug", "🐛"),
+    ("hammer", "🔨"),
+    ("wrench", "🔧"),
+    ("gear", "⚙️"),
+    ("hourglass", "⌛"),
+    ("alarm_clock", "⏰"),
+    ("coffee", "☕"),
+    ("beer", "🍺"),
+    ("beers", "🍻"),
+    ("pizza", "🍕"),
+    ("cake", "🍰"),
+    ("apple", "🍎"),
+    ("sunny", "☀️"),
+    ("cloud", "☁️"),
+    ("rainbow", "🌈"),
+    ("snowflake", "❄️"),
+    ("umbrella", "☔"),
+    ("earth_africa", "🌍"),
+    ("crescent_moon", "🌙"),
+    ("dog", "🐶"),
+    ("cat", "🐱"),
+    ("unicorn", "🦄"),
+    ("crab", "🦀"),
+    ("snake", "🐍"),
+    ("penguin", "🐧"),
+    ("octopus", "🐙"),
+    ("turtle", "🐢"),
+    ("rose", "🌹"),
+    ("seedling", "🌱"),
+];
+
+// Turns the `:shortcode:`s in a text into the emoji they name. Ones not in the table stay as they
+// were typed, so times like 12:30:45 come through untouched.
+pub fn expand_shortcodes(text: &str) -> String {
+    let code_char = |c: char| c.is_ascii_alphanumeric() || "_+-".contains(c);
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let len = after.find(|c: char| !code_char(c)).unwrap_or(after.len());
+        let closed = after[len..].starts_with(':');
+        let emoji = SHORTCODES.iter().find(|(code, _)| closed && *code == &after[..len]);
+        match emoji {
+            Some((_, emoji)) => {
+                out.push_str(emoji);
+                rest = &after[len + 1..];
+            }
+            // The closing colon may open the next shortcode
+            None => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// The other way around, for terminals without an emoji font: the emoji in the table shown as their
+// shortcodes. The one matching the most of what follows wins, and an emoji also matches without its
+// variation selector, so a ❤️ doesn't leave that behind and a bare ❤ is still found.
+pub fn to_shortcodes(text: &str) -> String {
+    let matched = |rest: &str, emoji: &str| {
+        let bare = emoji.trim_end_matches('\u{fe0f}');
+        [emoji, bare].into_iter().find(|emoji| rest.starts_with(emoji)).map(str::len)
+    };
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let mut found: Option<(&str, usize)> = None;
+        for (code, emoji) in SHORTCODES {
+            if let Some(len) = matched(rest, emoji) && found.is_none_or(|(_, longest)| len > longest) {
+                found = Some((code, len));
+            }
+        }
+        match found {
+            Some((code, len)) => {
+                out.push_str(&format!(":{}:", code));
+                rest = &rest[len..];
+            }
+            None => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_known_shortcodes_only() {
+        let cases = [
+            ("nice :thumbsup:", "nice 👍"),
+            (":+1::fire:", "👍🔥"),
+            // Not in the table, or not closed
+            (":nope: :fire", ":nope: :fire"),
+            ("meet at 12:30:45", "meet at 12:30:45"),
+            // A colon that opens nothing can still close something before the next one
+            ("::tada:", ":🎉"),
+            (":tada:tada:", "🎉tada:"),
+            // Around and next to multi-byte text
+            ("héllo:heart:wörld", "héllo❤️wörld"),
+            (":héart:", ":héart:"),
+            ("", ""),
+        ];
+        for (typed, expanded) in cases {
+            assert_eq!(expand_shortcodes(typed), expanded, "{:?}", typed);
+        }
+    }
+
+    #[test]
+    fn turns_emoji_back_into_shortcodes() {
+        let cases = [
+            ("nice 👍", "nice :thumbsup:"),
+            ("❤️ and a bare ❤", ":heart: and a bare :heart:"),
+            ("héllo 🎉 wörld", "héllo :tada: wörld"),
+            ("no emoji here: ✓", "no emoji here: ✓"),
+        ];
+        for (text, codes) in cases {
+            assert_eq!(to_shortcodes(text), codes, "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use anyhow::{bail, Context, Result};
+use iroh::protocol::DynProtocolHandler;
+use iroh::{Endpoint, NodeId};
+use futures_lite::StreamExt;
+use iroh_blobs::api::downloader::{DownloadProgessItem, SupportedRequest};
+use iroh_blobs::format::collection::Collection;
+use iroh_blobs::hashseq::HashSeq;
+use crate::message::MessageId;
+use crate::storage::{CachedBlob, Storage};
+use crate::upload::{Throttle, ThrottledBlobs};
+use iroh_blobs::store::fs::options::{GcConfig, Options};
+use iroh_blobs::store::fs::FsStore;
+use iroh_blobs::{BlobsProtocol, Hash, HashAndFormat};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// What image files are called, for showing them as images rather than as files
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "webp", "bmp"];
+
+// How much space offered and fetched files may take by default
+pub const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Blobs we keep are tagged with this and their hash. Evicting one drops its tag, and the store's
+// garbage collection, every GC_INTERVAL, frees what no tag keeps.
+const TAG_PREFIX: &str = "cache/";
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often a fetch is tried again after the sender went away, and how long we wait before the
+// first retry; the wait doubles every time. The store keeps the verified chunks of a partial
+// download, so each try, like a later `/get` of the same file, picks up where the last one stopped.
+const FETCH_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// A file or directory someone announced in a room, fetchable with `/get <id>`
+#[derive(Debug, Clone)]
+pub struct Offer {
+    pub from: NodeId,
+    pub hash: Hash,
+    pub name: String,
+    // Directories are collections: a hash sequence of their files, named by relative path
+    pub directory: bool,
+    // Of the file, or of all files in the directory
+    pub size: u64,
+}
+
+// Where the rest of a long message is
+#[derive(Debug, Clone, Copy)]
+struct LongText {
+    from: NodeId,
+    hash: Hash,
+    size: u64,
+}
+
+// Blob store for files we share and fetch, plus the offers we have seen. The store is a cache of
+// at most `limit` bytes that evicts the files used least recently.
+#[derive(Debug, Clone)]
+pub struct Files {
+    store: FsStore,
+    endpoint: Endpoint,
+    storage: Arc<Storage>,
+    limit: u64,
+    // Paces serving blobs, if there is an upload limit
+    throttle: Option<Throttle>,
+    offers: Arc<Mutex<Vec<Offer>>>,
+    // Long messages we only got the start of, by id
+    texts: Arc<Mutex<HashMap<MessageId, LongText>>>,
+}
+
+impl Files {
+    pub async fn load(
+        path: &Path,
+        endpoint: Endpoint,
+        storage: Arc<Storage>,
+        limit: u64,
+        throttle: Option<Throttle>,
+    ) -> Result<Self> {
+        let mut options = Options::new(path);
+        options.gc = Some(GcConfig { interval: GC_INTERVAL, add_protected: None });
+        let store = FsStore::load_with_opts(path.join("blobs.db"), options).await?;
+        let (offers, texts) = Default::default();
+        let files = Self { store, endpoint, storage, limit, throttle, offers, texts };
+        // In case the limit went down since the last run
+        files.evict().await?;
+        Ok(files)
+    }
+
+    // Serves blobs from our store to peers fetching our offers
+    pub fn protocol(&self) -> Box<dyn DynProtocolHandler> {
+        match &self.throttle {
+            Some(throttle) => ThrottledBlobs::new((*self.store).clone(), throttle.clone()).into(),
+            None => BlobsProtocol::new(&self.store, self.endpoint.clone(), None).into(),
+        }
+    }
+
+    // Hash a file into the store so peers can fetch it. Returns (hash, file name, size).
+    pub async fn import(&self, path: &Path) -> Result<(Hash, String, u64)> {
+        let path = std::path::absolute(path)?;
+        let metadata = std::fs::metadata(&path).with_context(|| format!("cannot read {}", path.display()))?;
+        if !metadata.is_file() {
+            bail!("{} is not a file", path.display());
+        }
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .context("path has no file name")?;
+        let temp = self.store.blobs().add_path(&path).temp_tag().await?;
+        self.keep(HashAndFormat::raw(*temp.hash()), &name, metadata.len(), false, true).await?;
+        Ok((*temp.hash(), name, metadata.len()))
+    }
+
+    // Put a file we only have in memory, like a pasted image, into the store. Returns its hash.
+    pub async fn import_bytes(&self, name: &str, bytes: Vec<u8>) -> Result<Hash> {
+        let size = bytes.len() as u64;
+        let temp = self.store.blobs().add_bytes(bytes).temp_tag().await?;
+        self.keep(HashAndFormat::raw(*temp.hash()), name, size, false, true).await?;
+        Ok(*temp.hash())
+    }
+
+    // Hash every file under a directory into the store as one collection, named by their paths
+    // inside it. Returns (hash, directory name, total size, number of files).
+    pub async fn import_dir(&self, path: &Path) -> Result<(Hash, String, u64, u64)> {
+        let path = std::path::absolute(path)?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .context("path has no directory name")?;
+        let mut files = Vec::new();
+        walk(&path, "", &mut files).with_context(|| format!("cannot read {}", path.display()))?;
+        if files.is_empty() {
+            bail!("{} has no files to share", path.display());
+        }
+        let (mut collection, mut size, mut temps) = (Collection::default(), 0, Vec::new());
+        for (relative, file, len) in &files {
+            let temp = self.store.blobs().add_path(file).temp_tag().await?;
+            collection.push(relative.clone(), *temp.hash());
+            temps.push(temp);
+            size += len;
+        }
+        // The collection's tag keeps the files in it too
+        let temp = collection.store(&self.store).await?;
+        self.keep(HashAndFormat::hash_seq(*temp.hash()), &name, size, true, true).await?;
+        Ok((*temp.hash(), name, size, files.len() as u64))
+    }
+
+    // Put the text of a message too long to gossip into the store, for the room to fetch
+    pub async fn import_text(&self, id: MessageId, text: &str) -> Result<Hash> {
+        let temp = self.store.blobs().add_bytes(text.as_bytes().to_vec()).temp_tag().await?;
+        let name = format!("message #{}", id.fmt_short());
+        self.keep(HashAndFormat::raw(*temp.hash()), &name, text.len() as u64, false, true).await?;
+        Ok(*temp.hash())
+    }
+
+    // Remember where the rest of a long message is
+    pub fn add_text(&self, id: MessageId, from: NodeId, hash: Hash, size: u64) {
+        self.texts.lock().expect("texts lock poisoned").insert(id, LongText { from, hash, size });
+    }
+
+    // Fetch the whole of a long message from its sender
+    pub async fn fetch_text(&self, id: MessageId) -> Result<String> {
+        let found = self.texts.lock().expect("texts lock poisoned").get(&id).copied();
+        let Some(LongText { from, hash, size }) = found else {
+            bail!("#{} is not a long message, or came before we started", id.fmt_short());
+        };
+        self.keep(HashAndFormat::raw(hash), &format!("message #{}", id.fmt_short()), size, false, false).await?;
+        self.download(hash, from, |_| {}).await?;
+        let bytes = self.store.blobs().get_bytes(hash).await?;
+        String::from_utf8(bytes.to_vec()).context("the message is not text")
+    }
+
+    // Remember an offer and return the id to fetch it with
+    pub fn add_offer(&self, offer: Offer) -> usize {
+        let mut offers = self.offers.lock().expect("offers lock poisoned");
+        offers.push(offer);
+        offers.len()
+    }
+
+    fn offer(&self, id: usize) -> Result<Offer> {
+        id.checked_sub(1)
+            .and_then(|i| self.offers.lock().expect("offers lock poisoned").get(i).cloned())
+            .with_context(|| format!("no file offer #{}", id))
+    }
+
+    // Download an offered file, or a directory or just `entry` in it, from its sender and save it
+    // in the downloads directory. Entries are files or directories inside the offered one.
+    // `progress` hears how many bytes we have so far, and how many there are if we know.
+    pub async fn fetch(
+        &self,
+        id: usize,
+        entry: Option<&str>,
+        mut progress: impl FnMut(u64, Option<u64>) + Send,
+    ) -> Result<PathBuf> {
+        let offer = self.offer(id)?;
+        let dir = dirs::download_dir().unwrap_or(std::env::current_dir()?);
+        if !offer.directory {
+            if entry.is_some() {
+                bail!("#{} is a file, not a directory", id);
+            }
+            // Kept from the start, so what we have stays for a retry
+            self.keep(HashAndFormat::raw(offer.hash), &offer.name, offer.size, false, false).await?;
+            self.download(offer.hash, offer.from, |done| progress(done, Some(offer.size))).await?;
+            let target = unique_path(&dir, &offer.name);
+            self.store.blobs().export(offer.hash, &target).await?;
+            return Ok(target);
+        }
+        // Parts of a directory count as all of it
+        let whole = HashAndFormat::hash_seq(offer.hash);
+        self.keep(whole, &offer.name, offer.size, true, false).await?;
+        let collection = self.collection(&offer).await?;
+        let Some(entry) = entry.map(|entry| entry.trim_matches('/')).filter(|entry| !entry.is_empty()) else {
+            // The whole directory in one go
+            self.download(whole, offer.from, |done| progress(done, Some(offer.size))).await?;
+            let root = unique_path(&dir, &offer.name);
+            for (path, hash) in collection.iter() {
+                self.export(*hash, &root.join(relative(path)?)).await?;
+            }
+            return Ok(root);
+        };
+        // Only the offer as a whole has a known size
+        if let Some((_, hash)) = collection.iter().find(|(path, _)| path == entry) {
+            self.download(*hash, offer.from, |done| progress(done, None)).await?;
+            let target = unique_path(&dir, entry);
+            self.export(*hash, &target).await?;
+            return Ok(target);
+        }
+        let prefix = format!("{}/", entry);
+        let inside: Vec<_> = collection
+            .iter()
+            .filter_map(|(path, hash)| Some((path.strip_prefix(&prefix)?, *hash)))
+            .collect();
+        if inside.is_empty() {
+            bail!("directory #{} has nothing called {}", id, entry);
+        }
+        let request: Vec<_> = inside.iter().map(|(_, hash)| *hash).collect();
+        self.download(request, offer.from, |done| progress(done, None)).await?;
+        let root = unique_path(&dir, entry);
+        for (path, hash) in inside {
+            self.export(hash, &root.join(relative(path)?)).await?;
+        }
+        Ok(root)
+    }
+
+    // The paths of the files in an offered directory, fetching only the collection and not them
+    pub async fn entries(&self, id: usize) -> Result<Vec<String>> {
+        let offer = self.offer(id)?;
+        if !offer.directory {
+            bail!("#{} is a file, not a directory", id);
+        }
+        let collection = self.collection(&offer).await?;
+        Ok(collection.iter().map(|(path, _)| path.clone()).collect())
+    }
+
+    async fn collection(&self, offer: &Offer) -> Result<Collection> {
+        // Listing a directory doesn't keep it, but it mustn't be collected halfway either
+        let _listing = self.store.tags().temp_tag(HashAndFormat::hash_seq(offer.hash)).await?;
+        self.download(offer.hash, offer.from, |_| {}).await?;
+        // The first hash of the sequence is the collection's metadata, which names the rest
+        let links = HashSeq::try_from(self.store.blobs().get_bytes(offer.hash).await?)?;
+        let Some(meta) = links.iter().next() else {
+            bail!("the directory offer is empty");
+        };
+        self.download(meta, offer.from, |_| {}).await?;
+        Collection::load(offer.hash, &*self.store).await
+    }
+
+    // Everything in the store, least recently used first, and how much it may hold
+    pub fn cached(&self) -> Result<(Vec<CachedBlob>, u64)> {
+        Ok((self.storage.cached_blobs()?, self.limit))
+    }
+
+    // Drop every file from the store, ours too, so peers can't fetch what we offered before
+    // either. Returns how many there were and their size; the space is free after the next GC.
+    pub async fn clear(&self) -> Result<(usize, u64)> {
+        let blobs = self.storage.cached_blobs()?;
+        // Stores from before the cache have other tags
+        self.store.tags().delete_all().await?;
+        self.storage.clear_blobs()?;
+        Ok((blobs.len(), blobs.iter().map(|blob| blob.size).sum()))
+    }
+
+    // Tag a blob so the store keeps it, and note that we used it
+    async fn keep(&self, value: HashAndFormat, name: &str, size: u64, directory: bool, sent: bool) -> Result<()> {
+        self.store.tags().set(tag(&value.hash), value).await?;
+        self.storage.cache_blob(&value.hash, name, size, directory, sent)?;
+        self.evict().await
+    }
+
+    // Drop the least recently used files until the rest fit, always keeping the newest one
+    async fn evict(&self) -> Result<()> {
+        let blobs = self.storage.cached_blobs()?;
+        let mut total: u64 = blobs.iter().map(|blob| blob.size).sum();
+        for blob in blobs.iter().take(blobs.len().saturating_sub(1)) {
+            if total <= self.limit {
+                break;
+            }
+            self.store.tags().delete(tag(&blob.hash)).await?;
+            self.storage.uncache_blob(&blob.hash)?;
+            total -= blob.size;
+        }
+        Ok(())
+    }
+
+    // Fetch from the sender, trying again a few times if it goes away halfway
+    async fn download(
+        &self,
+        request: impl SupportedRequest + Clone,
+        from: NodeId,
+        mut progress: impl FnMut(u64) + Send,
+    ) -> Result<()> {
+        let mut delay = RETRY_DELAY;
+        let mut tries = 0;
+        loop {
+            match self.try_download(request.clone(), from, &mut progress).await {
+                Ok(()) => return Ok(()),
+                Err(err) if tries == FETCH_RETRIES => return Err(err),
+                Err(_) => {
+                    tries += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    async fn try_download(
+        &self,
+        request: impl SupportedRequest,
+        from: NodeId,
+        progress: &mut (impl FnMut(u64) + Send),
+    ) -> Result<()> {
+        // The downloader stops once dropped, so it has to outlive the stream
+        let downloader = self.store.downloader(&self.endpoint);
+        let mut items = downloader.download(request, Some(from)).stream().await?;
+        while let Some(item) = items.next().await {
+            match item {
+                // Counts what we had from earlier tries too
+                DownloadProgessItem::Progress(done) => progress(done),
+                DownloadProgessItem::Error(err) => return Err(err),
+                DownloadProgessItem::DownloadError => bail!("the sender stopped serving it"),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn export(&self, hash: Hash, target: &Path) -> Result<()> {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.store.blobs().export(hash, target).await?;
+        Ok(())
+    }
+
+    // Download an offered file into the store only and return its contents, to show it. A later
+    // `/get` finds it there.
+    pub async fn preview(&self, id: usize) -> Result<Vec<u8>> {
+        let offer = self.offer(id)?;
+        self.keep(HashAndFormat::raw(offer.hash), &offer.name, offer.size, false, false).await?;
+        self.download(offer.hash, offer.from, |_| {}).await?;
+        Ok(self.store.blobs().get_bytes(offer.hash).await?.to_vec())
+    }
+}
+
+fn tag(hash: &Hash) -> String {
+    format!("{}{}", TAG_PREFIX, hash)
+}
+
+// Every regular file under `dir`, with its path relative to the shared directory, its path on disk
+// and its size. Symlinks are skipped, so nothing outside the directory is shared.
+fn walk(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf, u64)>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(&entry.path(), &format!("{}/", path), files)?;
+        } else if metadata.is_file() {
+            files.push((path, entry.path(), metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+// A path from an offered directory, which is the sender's to pick, as one that stays inside the
+// directory we save it in
+fn relative(path: &str) -> Result<PathBuf> {
+    let relative = PathBuf::from(path);
+    if path.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        bail!("the directory offer has an unsafe path {:?}", path);
+    }
+    Ok(relative)
+}
+
+// Whether a file is an image, going by its name
+pub fn is_image(name: &str) -> bool {
+    let extension = Path::new(name).extension().map(|e| e.to_string_lossy().to_lowercase());
+    extension.is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.as_str()))
+}
+
+// Only keep the final path component of the sender's name and don't overwrite existing files
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".to_string());
+    let mut target = dir.join(&name);
+    let mut n = 1;
+    while target.exists() {
+        target = dir.join(format!("{} ({})", name, n));
+        n += 1;
+    }
+    target
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", size),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+// How a long message `id` is shown and kept: its start, and how to get the rest
+pub fn long_text(id: MessageId, preview: &str, size: u64) -> String {
+    let rest = format_size(size.saturating_sub(preview.len() as u64));
+    format!("{}… [{} more, /more {}]", preview, rest, id.fmt_short())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::RelayMode;
+
+    // Whether the store still keeps the blob, and we still list it
+    async fn cached(files: &Files, hash: &Hash) -> bool {
+        let listed = files.storage.cached_blobs().unwrap().iter().any(|blob| blob.hash == *hash);
+        let tagged = files.store.tags().get(tag(hash)).await.unwrap().is_some();
+        assert_eq!(listed, tagged);
+        listed
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_files() {
+        let dir = std::env::temp_dir().join(format!("iroh-chat-test-{}", rand::random::<u64>()));
+        let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await.unwrap();
+        let storage = Arc::new(Storage::open(Path::new(":memory:"), None).unwrap());
+        let files = Files::load(&dir, endpoint.clone(), storage, 250, None).await.unwrap();
+        let mut hashes = Vec::new();
+        for (n, name) in ["a", "b", "a", "c"].into_iter().enumerate() {
+            // Uses are told apart by the millisecond
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let hash = files.import_bytes(name, vec![name.as_bytes()[0]; 100]).await.unwrap();
+            if n != 2 {
+                hashes.push(hash);
+            }
+        }
+        // Sending "a" again made "b" the one used least recently
+        let [a, b, c] = hashes[..] else { unreachable!() };
+        assert!(cached(&files, &a).await && !cached(&files, &b).await && cached(&files, &c).await);
+        // A file bigger than the whole cache pushes out the rest, but is kept itself
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let big = files.import_bytes("big", vec![0; 300]).await.unwrap();
+        assert!(!cached(&files, &a).await && !cached(&files, &c).await && cached(&files, &big).await);
+        endpoint.close().await;
+        files.store.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use data_encoding::BASE64;
+use std::io::IsTerminal;
+use std::path::Path;
+
+// Offered images up to this size are fetched to be shown, larger ones are left to `/get`
+pub const PREVIEW_LIMIT: u64 = 5 * 1024 * 1024;
+
+// How many terminal cells wide an image is shown; the height follows from its aspect ratio
+const PREVIEW_COLUMNS: usize = 40;
+
+// Kitty takes the image in pieces of at most this much base64
+const KITTY_CHUNK: usize = 4096;
+
+// The protocols terminals draw images with. Sixel terminals would need the image decoded to
+// pixels first, so they get the placeholder line like any other terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Graphics {
+    // Kitty's graphics protocol, which WezTerm and Ghostty speak too
+    Kitty,
+    // iTerm2's inline images
+    Iterm,
+}
+
+impl Graphics {
+    // What the terminal on stdout can draw, going by what it puts in the environment. Terminal
+    // multiplexers pass the variables on but not the images, so there are no images under them.
+    pub fn detect() -> Option<Self> {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        if !std::io::stdout().is_terminal() || !var("TMUX").is_empty() || var("TERM").starts_with("screen") {
+            return None;
+        }
+        let program = var("TERM_PROGRAM");
+        let kitty = var("TERM") == "xterm-kitty" || !var("KITTY_WINDOW_ID").is_empty();
+        if kitty || program == "WezTerm" || program == "ghostty" {
+            Some(Graphics::Kitty)
+        } else if program == "iTerm.app" {
+            Some(Graphics::Iterm)
+        } else {
+            None
+        }
+    }
+
+    // Whether the terminal can draw an image file with this name. Kitty takes PNG as it is, iTerm2
+    // anything macOS can read.
+    pub fn shows(self, file: &str) -> bool {
+        let extension = Path::new(file).extension().map(|e| e.to_string_lossy().to_lowercase());
+        match self {
+            Graphics::Kitty => extension.as_deref() == Some("png"),
+            Graphics::Iterm => p2p_chat::is_image(file),
+        }
+    }
+
+    // The escape sequence that draws `image` at the cursor
+    pub fn encode(self, image: &[u8]) -> String {
+        let data = BASE64.encode(image);
+        match self {
+            Graphics::Kitty => {
+                let chunks: Vec<_> = data.as_bytes().chunks(KITTY_CHUNK).collect();
+                let mut out = String::new();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let more = if i + 1 < chunks.len() { 1 } else { 0 };
+                    // Only the first piece says what the image is. Kitty answers on stdin unless
+                    // told to be quiet, which would come out as a line we typed.
+                    let control = match i {
+                        0 => format!("a=T,f=100,q=2,c={},m={}", PREVIEW_COLUMNS, more),
+                        _ => format!("m={}", more),
+                    };
+                    out.push_str(&format!("\x1b_G{};{}\x1b\\", control, String::from_utf8_lossy(chunk)));
+                }
+                out
+            }
+            Graphics::Iterm => {
+                format!("\x1b]1337;File=inline=1;size={};width={}:{}\x07", image.len(), PREVIEW_COLUMNS, data)
+            }
+        }
+    }
+}
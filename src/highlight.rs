@@ -0,0 +1,155 @@
+// Highlighting for code snippets with syntect and the syntaxes it ships with, line after line of a
+// snippet so comments and strings spanning lines are followed. Only the kind of each piece is
+// kept, which the UI colors with its theme.
+use std::sync::LazyLock;
+use syntect::easy::ScopeRegionIterator;
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxSet};
+
+// What a piece of a line is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Plain,
+    Keyword,
+    Str,
+    Number,
+    Comment,
+}
+
+// For lines without their newline, which is how snippets are split
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_nonewlines);
+
+// Which scopes make a piece which kind, checked from the innermost scope out
+static KINDS: LazyLock<Vec<(Scope, Kind)>> = LazyLock::new(|| {
+    let kinds = [
+        ("comment", Kind::Comment),
+        ("string", Kind::Str),
+        ("constant.character", Kind::Str),
+        ("constant.numeric", Kind::Number),
+        // Operators stay plain, like the rest of the punctuation
+        ("keyword.operator", Kind::Plain),
+        ("keyword", Kind::Keyword),
+        ("storage", Kind::Keyword),
+        ("constant.language", Kind::Keyword),
+    ];
+    kinds.into_iter().map(|(scope, kind)| (Scope::new(scope).expect("valid scope"), kind)).collect()
+});
+
+// Names snippets are often marked with that syntect doesn't know the language by
+const ALIASES: &[(&str, &str)] = &[
+    ("golang", "go"),
+    ("shell", "sh"),
+    ("console", "sh"),
+    ("typescript", "js"),
+    ("ts", "js"),
+    ("jsx", "js"),
+    ("tsx", "js"),
+];
+
+// The lines of one snippet, highlighted in order
+pub struct Highlighter {
+    // None for languages we don't know, which stay plain
+    state: Option<ParseState>,
+    scopes: ScopeStack,
+}
+
+impl Highlighter {
+    pub fn new(lang: &str) -> Self {
+        let lang = lang.to_lowercase();
+        let lang = ALIASES.iter().find(|(alias, _)| *alias == lang).map_or(lang.as_str(), |(_, name)| name);
+        let syntax = SYNTAXES.find_syntax_by_token(lang);
+        Self { state: syntax.map(ParseState::new), scopes: ScopeStack::new() }
+    }
+
+    // The next line of the snippet split into pieces, which together are the whole line
+    pub fn tokens<'a>(&mut self, line: &'a str) -> Vec<(Kind, &'a str)> {
+        let ops = match &mut self.state {
+            Some(state) => state.parse_line(line, &SYNTAXES),
+            None => return vec![(Kind::Plain, line)],
+        };
+        let Ok(ops) = ops else {
+            // What syntect can't parse isn't worth losing the line over
+            self.state = None;
+            return vec![(Kind::Plain, line)];
+        };
+        let mut pieces: Vec<(Kind, usize, usize)> = Vec::new();
+        let mut start = 0;
+        for (piece, op) in ScopeRegionIterator::new(&ops, line) {
+            // Only popping more scopes than were pushed fails, which a syntax shouldn't do
+            let _ = self.scopes.apply(op);
+            let (kind, end) = (self.kind(), start + piece.len());
+            match pieces.last_mut() {
+                // Runs of one kind stay one piece
+                Some((last, _, last_end)) if *last == kind => *last_end = end,
+                _ if piece.is_empty() => {}
+                _ => pieces.push((kind, start, end)),
+            }
+            start = end;
+        }
+        pieces.into_iter().map(|(kind, start, end)| (kind, &line[start..end])).collect()
+    }
+
+    // The next line with ANSI colors, for printing on a terminal
+    pub fn ansi(&mut self, line: &str) -> String {
+        self.tokens(line)
+            .into_iter()
+            .map(|(kind, piece)| match color(kind) {
+                Some(code) => format!("\x1b[{}m{}\x1b[0m", code, piece),
+                None => piece.to_string(),
+            })
+            .collect()
+    }
+
+    // The kind of piece the scopes we are in make
+    fn kind(&self) -> Kind {
+        let scopes = self.scopes.as_slice().iter().rev();
+        let mut kinds = scopes.filter_map(|scope| KINDS.iter().find(|(prefix, _)| prefix.is_prefix_of(*scope)));
+        kinds.next().map_or(Kind::Plain, |(_, kind)| *kind)
+    }
+}
+
+// The SGR color of each kind of piece
+fn color(kind: Kind) -> Option<u8> {
+    match kind {
+        Kind::Plain => None,
+        Kind::Keyword => Some(35),
+        Kind::Str => Some(32),
+        Kind::Number => Some(33),
+        Kind::Comment => Some(90),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(highlighter: &mut Highlighter, line: &str) -> Vec<Kind> {
+        let pieces = highlighter.tokens(line);
+        assert_eq!(pieces.iter().map(|(_, piece)| *piece).collect::<String>(), line);
+        pieces.into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    #[test]
+    fn picks_out_keywords_strings_and_numbers() {
+        let mut rust = Highlighter::new("rs");
+        let pieces = rust.tokens(r#"let x = "hi"; // 42"#);
+        assert!(pieces.contains(&(Kind::Keyword, "let")));
+        assert!(pieces.iter().any(|(kind, piece)| *kind == Kind::Str && piece.contains("hi")));
+        assert!(pieces.iter().any(|(kind, piece)| *kind == Kind::Comment && piece.contains("42")));
+        assert!(Highlighter::new("python").tokens("x = 42").contains(&(Kind::Number, "42")));
+    }
+
+    #[test]
+    fn follows_comments_across_lines() {
+        let mut c = Highlighter::new("c");
+        kinds(&mut c, "/* starts here");
+        assert_eq!(kinds(&mut c, "still a comment"), vec![Kind::Comment]);
+        kinds(&mut c, "ends */");
+        assert!(kinds(&mut c, "int x;").contains(&Kind::Keyword));
+    }
+
+    #[test]
+    fn leaves_unknown_languages_plain() {
+        assert_eq!(kinds(&mut Highlighter::new("klingon"), "qapla' 42"), vec![Kind::Plain]);
+        assert!(kinds(&mut Highlighter::new("golang"), "func main() {}").contains(&Kind::Keyword));
+    }
+}
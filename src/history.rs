@@ -0,0 +1,395 @@
+use crate::crypto::RoomKey;
+use crate::files;
+use crate::location;
+use crate::message::{self, Message, MessageBody, MessageId};
+use crate::storage::{Signed, Storage, StoredMessage};
+use anyhow::{bail, Context, Result};
+use iroh::endpoint::Connection;
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use iroh_blobs::Hash;
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const ALPN: &[u8] = b"iroh-chat/history/0";
+
+// Upper bounds for what we are willing to read off the wire
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+pub const MAX_LIMIT: usize = 500;
+// How many messages a request may ask for by id, which keeps it within MAX_REQUEST_SIZE
+pub const MAX_IDS: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryRequest {
+    topic: TopicId,
+    limit: usize,
+    // Ask for the first messages sent after this time instead of the last ones. Older peers
+    // don't know the field and answer with the last ones.
+    #[serde(default)]
+    since: Option<i64>,
+    // Ask for these messages, wherever they are in the log, instead
+    #[serde(default)]
+    ids: Vec<MessageId>,
+    // Answer with `Entry`s, which carry what each message was signed in, rather than with the
+    // messages as we keep them. Older peers answer the old way, which we don't take.
+    #[serde(default)]
+    signed: bool,
+}
+
+// A message as served to peers that check signatures, postcard-encoded. Only the envelopes are
+// believed, the rest is what the serving peer says.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    // The envelope the message was gossiped in, gone once it was deleted
+    envelope: Option<Vec<u8>>,
+    // Its last edit, or its deletion
+    change: Option<Vec<u8>>,
+    // Who the serving peer knows the sender as, and when it got the message
+    name: Option<String>,
+    timestamp: i64,
+    // Where a deleted message was in the log, which its envelope said
+    clock: u64,
+    parents: Vec<MessageId>,
+}
+
+// A message a peer served, as checked against its sender's signature
+#[derive(Debug)]
+pub struct Fetched {
+    pub stored: StoredMessage,
+    pub signed: Signed,
+    // For long messages, where the rest of the text is: the sender, the blob and its size
+    pub long_text: Option<(NodeId, Hash, u64)>,
+}
+
+// What a peer served in answer to one request
+#[derive(Debug, Default)]
+pub struct Page {
+    pub messages: Vec<Fetched>,
+    // How many it served that didn't check out, and were dropped
+    pub rejected: usize,
+    // Whether it served as many as it would, so there may be more
+    pub full: bool,
+    // When it got the last message it served, where the next page starts
+    pub last: Option<i64>,
+}
+
+// Serves the last stored messages of the rooms we are in to peers that ask for them.
+// Responses are encrypted with the room key, so only ticket holders can read them.
+#[derive(Debug, Clone)]
+pub struct HistoryProtocol {
+    storage: Arc<Storage>,
+    rooms: Arc<Mutex<HashMap<TopicId, RoomKey>>>,
+}
+
+impl HistoryProtocol {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        Self { storage, rooms: Default::default() }
+    }
+
+    // Start serving history for a room
+    pub fn add_room(&self, topic: TopicId, key: RoomKey) {
+        self.rooms.lock().expect("rooms lock poisoned").insert(topic, key);
+    }
+
+    pub fn remove_room(&self, topic: &TopicId) {
+        self.rooms.lock().expect("rooms lock poisoned").remove(topic);
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let request: HistoryRequest = serde_json::from_slice(&recv.read_to_end(MAX_REQUEST_SIZE).await?)?;
+        let key = self.rooms.lock().expect("rooms lock poisoned").get(&request.topic).copied();
+        let Some(key) = key else {
+            bail!("not a member of topic {}", request.topic.fmt_short());
+        };
+        let limit = request.limit.min(MAX_LIMIT);
+        let messages = match request.since {
+            // With what they were sent after, so a gap takes one request rather than one per message
+            _ if !request.ids.is_empty() => {
+                let ids = &request.ids[..request.ids.len().min(MAX_IDS)];
+                self.storage.ancestors(&request.topic, ids, MAX_LIMIT)?
+            }
+            Some(since) => self.storage.since(&request.topic, since, limit)?,
+            None => self.storage.recent(&request.topic, limit)?,
+        };
+        let bytes = match request.signed {
+            true => postcard::to_stdvec(&self.entries(&request.topic, messages)?)?,
+            false => serde_json::to_vec(&messages)?,
+        };
+        send.write_all(&key.encrypt(&bytes)).await?;
+        send.finish()?;
+        conn.closed().await;
+        Ok(())
+    }
+
+    // The messages with what they were signed in, leaving out those we kept before envelopes were
+    fn entries(&self, topic: &TopicId, messages: Vec<StoredMessage>) -> Result<Vec<Entry>> {
+        let ids: Vec<_> = messages.iter().map(|stored| stored.id).collect();
+        let mut signed = self.storage.signed(topic, &ids)?;
+        let entries = messages.into_iter().filter_map(|stored| {
+            let Signed { envelope, change } = signed.remove(&stored.id)?;
+            if envelope.is_none() && change.is_none() {
+                return None;
+            }
+            let StoredMessage { name, timestamp, clock, parents, .. } = stored;
+            Some(Entry { envelope, change, name, timestamp, clock, parents })
+        });
+        Ok(entries.collect())
+    }
+}
+
+impl ProtocolHandler for HistoryProtocol {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
+
+// Ask `peer` for the last `limit` messages it has stored for `topic`
+pub async fn fetch(
+    endpoint: &Endpoint,
+    peer: NodeAddr,
+    topic: TopicId,
+    key: &RoomKey,
+    limit: usize,
+) -> Result<Page> {
+    request(endpoint, peer, HistoryRequest { topic, limit, since: None, ids: Vec::new(), signed: true }, key).await
+}
+
+// Ask `peer` for up to MAX_LIMIT messages of `topic` sent after `since`, oldest first
+pub async fn fetch_since(
+    endpoint: &Endpoint,
+    peer: NodeAddr,
+    topic: TopicId,
+    key: &RoomKey,
+    since: i64,
+) -> Result<Page> {
+    let query = HistoryRequest { topic, limit: MAX_LIMIT, since: Some(since), ids: Vec::new(), signed: true };
+    request(endpoint, peer, query, key).await
+}
+
+// Ask `peer` for these messages of `topic`, at most MAX_IDS of them, and up to MAX_LIMIT of
+// those they were sent after. Peers that predate ids refuse requests this large.
+pub async fn fetch_ids(
+    endpoint: &Endpoint,
+    peer: NodeAddr,
+    topic: TopicId,
+    key: &RoomKey,
+    ids: Vec<MessageId>,
+) -> Result<Page> {
+    let ids: Vec<_> = ids.into_iter().take(MAX_IDS).collect();
+    let query = HistoryRequest { topic, limit: ids.len(), since: None, ids, signed: true };
+    request(endpoint, peer, query, key).await
+}
+
+async fn request(
+    endpoint: &Endpoint,
+    peer: NodeAddr,
+    request: HistoryRequest,
+    key: &RoomKey,
+) -> Result<Page> {
+    let conn = endpoint.connect(peer, ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&serde_json::to_vec(&request)?).await?;
+    send.finish()?;
+    let bytes = recv.read_to_end(MAX_RESPONSE_SIZE).await?;
+    conn.close(0u32.into(), b"done");
+    let entries: Vec<Entry> = postcard::from_bytes(&key.decrypt(&bytes)?)?;
+    let mut page = Page {
+        full: entries.len() == MAX_LIMIT,
+        last: entries.last().map(|entry| entry.timestamp),
+        ..Page::default()
+    };
+    for entry in entries {
+        match check(entry) {
+            Ok(fetched) => page.messages.push(fetched),
+            Err(_) => page.rejected += 1,
+        }
+    }
+    Ok(page)
+}
+
+// Read a served message out of its envelopes, checking their signatures the way gossiped messages
+// are checked, and that the change is its sender's edit or deletion of it
+fn check(entry: Entry) -> Result<Fetched> {
+    let Entry { envelope, change, name, timestamp, clock, parents } = entry;
+    let changed = change.as_deref().map(Message::verify).transpose()?;
+    let Some(envelope) = envelope else {
+        // All that is left of a deleted message, with its place in the log as the serving peer says
+        let Some(Message { body: MessageBody::Delete { from, target_id }, .. }) = changed else {
+            bail!("neither a message nor its deletion");
+        };
+        let stored = StoredMessage {
+            id: target_id,
+            sender: from,
+            name,
+            text: String::new(),
+            timestamp,
+            edited: false,
+            deleted: true,
+            reply_to: None,
+            clock,
+            parents,
+        };
+        return Ok(Fetched { stored, signed: Signed { envelope: None, change }, long_text: None });
+    };
+    let message = Message::verify(&envelope)?;
+    let from = message.body.from();
+    let mut long_text = None;
+    let text = match &message.body {
+        MessageBody::LongText { hash, size, preview, .. } => {
+            long_text = Some((from, *hash, *size));
+            files::long_text(message.id, preview, *size)
+        }
+        MessageBody::Location { lat, lon, .. } => {
+            location::check(*lat, *lon)?;
+            message.body.text().context("places have a text")?
+        }
+        body => body.text().context("not a chat message")?,
+    };
+    let mut stored = StoredMessage {
+        id: message.id,
+        sender: from,
+        name,
+        text,
+        // When its sender signed that they sent it, rather than when the serving peer got it
+        timestamp: message::sent(message.timestamp, timestamp).0,
+        edited: false,
+        deleted: false,
+        reply_to: message.reply_to,
+        clock: message.clock,
+        parents: message.parents,
+    };
+    let mut signed = Signed { envelope: Some(envelope), change: None };
+    if let Some(changed) = changed {
+        let id = stored.id;
+        match changed.body {
+            MessageBody::Edit { from: by, target_id, new_text } if by == from && target_id == id => {
+                stored.text = new_text;
+                stored.edited = true;
+            }
+            // Kept the way we keep deletions of our own, without the message
+            MessageBody::Delete { from: by, target_id } if by == from && target_id == id => {
+                stored.text.clear();
+                stored.deleted = true;
+                signed.envelope = None;
+                long_text = None;
+            }
+            _ => bail!("the change isn't its sender's edit or deletion of it"),
+        }
+        signed.change = change;
+    }
+    Ok(Fetched { stored, signed, long_text })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::protocol::Router;
+    use iroh::{RelayMode, SecretKey, Watcher};
+    use std::path::Path;
+
+    fn storage() -> Arc<Storage> {
+        Arc::new(Storage::open(Path::new(":memory:"), None).unwrap())
+    }
+
+    // A chat message of `secret_key`'s, kept the way it came in
+    fn keep(storage: &Storage, topic: &TopicId, secret_key: &SecretKey, text: &str) -> MessageId {
+        let body = MessageBody::Message { from: secret_key.public(), text: text.to_string() };
+        let mut message = Message::new(body);
+        message.clock = 1;
+        let stored = StoredMessage {
+            id: message.id,
+            sender: secret_key.public(),
+            name: None,
+            text: text.to_string(),
+            timestamp: 1_700_000_000_000,
+            edited: false,
+            deleted: false,
+            reply_to: None,
+            clock: 1,
+            parents: Vec::new(),
+        };
+        storage.insert(topic, &stored, &Signed::new(message.sign(secret_key))).unwrap();
+        message.id
+    }
+
+    fn served(history: &HistoryProtocol, topic: &TopicId) -> Vec<Entry> {
+        history.entries(topic, history.storage.recent(topic, MAX_LIMIT).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serves_what_was_signed_with_its_edits() {
+        let (topic, alice) = (TopicId::from_bytes([1; 32]), SecretKey::from_bytes(&[1; 32]));
+        let history = HistoryProtocol::new(storage());
+        let id = keep(&history.storage, &topic, &alice, "hello");
+        let new_text = "hello, world".to_string();
+        let edit = MessageBody::Edit { from: alice.public(), target_id: id, new_text: new_text.clone() };
+        let change = Message::new(edit).sign(&alice);
+        history.storage.edit(&topic, &id, &alice.public(), &new_text, &change).unwrap();
+        // Kept before envelopes were, so there is nothing to check it against
+        let kept = history.storage.get(&topic, &id).unwrap().unwrap();
+        let unsigned = StoredMessage { id: MessageId::random(), ..kept };
+        history.storage.insert(&topic, &unsigned, &Signed::default()).unwrap();
+        let mut entries = served(&history, &topic);
+        assert_eq!(entries.len(), 1);
+        let fetched = check(entries.remove(0)).unwrap();
+        assert_eq!((fetched.stored.id, fetched.stored.sender), (id, alice.public()));
+        assert_eq!(fetched.stored.text, "hello, world");
+        assert!(fetched.stored.edited);
+    }
+
+    #[tokio::test]
+    async fn fetches_signed_history() {
+        let (topic, alice) = (TopicId::from_bytes([1; 32]), SecretKey::from_bytes(&[1; 32]));
+        let key = RoomKey::generate();
+        let history = HistoryProtocol::new(storage());
+        let id = keep(&history.storage, &topic, &alice, "hello");
+        history.add_room(topic, key);
+        let member = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await.unwrap();
+        let router = Router::builder(member.clone()).accept(ALPN, history).spawn();
+        let addr = member.node_addr().initialized().await;
+        let joiner = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await.unwrap();
+        let page = fetch(&joiner, addr, topic, &key, 10).await.unwrap();
+        assert_eq!((page.messages.len(), page.rejected, page.full), (1, 0, false));
+        assert_eq!((page.messages[0].stored.id, page.messages[0].stored.text.as_str()), (id, "hello"));
+        router.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn refuses_tampered_envelopes_and_foreign_changes() {
+        let (topic, alice) = (TopicId::from_bytes([1; 32]), SecretKey::from_bytes(&[1; 32]));
+        let mallory = SecretKey::from_bytes(&[2; 32]);
+        let history = HistoryProtocol::new(storage());
+        let id = keep(&history.storage, &topic, &alice, "pay bob");
+        let mut entry = served(&history, &topic).remove(0);
+        let envelope = entry.envelope.as_mut().unwrap();
+        let at = envelope.windows(3).position(|bytes| bytes == b"bob").unwrap();
+        envelope[at..at + 3].copy_from_slice(b"eve");
+        assert!(check(entry).is_err());
+        // Only the sender may edit a message
+        let mut entry = served(&history, &topic).remove(0);
+        let body = MessageBody::Edit { from: mallory.public(), target_id: id, new_text: "pay eve".to_string() };
+        entry.change = Some(Message::new(body).sign(&mallory));
+        assert!(check(entry).is_err());
+    }
+
+    #[test]
+    fn deleted_messages_are_served_without_their_text() {
+        let (topic, alice) = (TopicId::from_bytes([1; 32]), SecretKey::from_bytes(&[1; 32]));
+        let history = HistoryProtocol::new(storage());
+        let id = keep(&history.storage, &topic, &alice, "oops");
+        let delete = Message::new(MessageBody::Delete { from: alice.public(), target_id: id });
+        history.storage.delete(&topic, &id, &alice.public(), &delete.sign(&alice)).unwrap();
+        let mut entries = served(&history, &topic);
+        assert!(entries[0].envelope.is_none());
+        let fetched = check(entries.remove(0)).unwrap();
+        assert_eq!((fetched.stored.id, fetched.stored.clock), (id, 1));
+        assert!(fetched.stored.deleted && fetched.stored.text.is_empty());
+        assert!(fetched.signed.envelope.is_none());
+    }
+}
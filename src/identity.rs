@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use data_encoding::HEXLOWER;
+use iroh::SecretKey;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+// Load the node's secret key, generating and saving a new one on first run
+pub fn load_or_create(path: &Path) -> Result<SecretKey> {
+    if path.exists() {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read identity from {}", path.display()))?;
+        let key = text
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid identity key in {}", path.display()))?;
+        return Ok(key);
+    }
+
+    let key = SecretKey::generate(rand::rngs::OsRng);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_private(path, HEXLOWER.encode(&key.to_bytes()).as_bytes())
+        .with_context(|| format!("failed to save identity to {}", path.display()))?;
+    Ok(key)
+}
+
+// A random secret kept in `path`, e.g. the API's bearer token, generating it on first run
+pub fn load_or_create_token(path: &Path) -> Result<String> {
+    if path.exists() {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read token from {}", path.display()))?;
+        return Ok(text.trim().to_string());
+    }
+    let token = HEXLOWER.encode(&rand::random::<[u8; 32]>());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_private(path, token.as_bytes()).with_context(|| format!("failed to save token to {}", path.display()))?;
+    Ok(token)
+}
+
+// The key file should only be readable by its owner
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(contents)?;
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use crate::crypto::RoomKey;
+use crate::storage;
+use crate::ticket::Invite;
+use anyhow::{bail, Result};
+use iroh::endpoint::Connection;
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{Endpoint, NodeAddr};
+use iroh_gossip::proto::TopicId;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const ALPN: &[u8] = b"iroh-chat/invite/0";
+
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Redeem {
+    topic: TopicId,
+    token: [u8; 16],
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Answer {
+    Key(RoomKey),
+    Refused(String),
+}
+
+// An invite we handed out, and the key it unlocks
+#[derive(Debug)]
+struct Issued {
+    topic: TopicId,
+    key: RoomKey,
+    expires: Option<i64>,
+    single_use: bool,
+}
+
+// The invites we issued, by token. Peers holding one connect to us to trade it for the room key.
+// Invites only live as long as this process and the room they are for.
+#[derive(Debug, Clone, Default)]
+pub struct InviteProtocol(Arc<Mutex<HashMap<[u8; 16], Issued>>>);
+
+impl InviteProtocol {
+    pub fn issue(&self, topic: TopicId, key: RoomKey, expires: Option<i64>, single_use: bool) -> Invite {
+        let token = random();
+        let issued = Issued { topic, key, expires, single_use };
+        self.0.lock().expect("invites lock poisoned").insert(token, issued);
+        Invite { token, expires, single_use }
+    }
+
+    pub fn remove_room(&self, topic: &TopicId) {
+        self.0.lock().expect("invites lock poisoned").retain(|_, issued| issued.topic != *topic);
+    }
+
+    fn redeem(&self, topic: TopicId, token: [u8; 16]) -> Result<RoomKey> {
+        let mut invites = self.0.lock().expect("invites lock poisoned");
+        let now = storage::now();
+        invites.retain(|_, issued| issued.expires.is_none_or(|expires| expires > now));
+        let Some(issued) = invites.get(&token).filter(|issued| issued.topic == topic) else {
+            bail!("the invite has expired or was already used");
+        };
+        let key = issued.key;
+        if issued.single_use {
+            invites.remove(&token);
+        }
+        Ok(key)
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let request: Redeem = serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?;
+        let answer = match self.redeem(request.topic, request.token) {
+            Ok(key) => Answer::Key(key),
+            Err(err) => Answer::Refused(err.to_string()),
+        };
+        send.write_all(&serde_json::to_vec(&answer)?).await?;
+        send.finish()?;
+        conn.closed().await;
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for InviteProtocol {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
+
+// Trade an invite for the room key with whoever issued it
+pub async fn redeem(endpoint: &Endpoint, issuer: NodeAddr, topic: TopicId, invite: &Invite) -> Result<RoomKey> {
+    if invite.expires.is_some_and(|expires| expires <= storage::now()) {
+        bail!("the invite has expired");
+    }
+    let conn = endpoint.connect(issuer, ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&serde_json::to_vec(&Redeem { topic, token: invite.token })?).await?;
+    send.finish()?;
+    let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+    conn.close(0u32.into(), b"done");
+    match serde_json::from_slice(&bytes)? {
+        Answer::Key(key) => Ok(key),
+        Answer::Refused(reason) => bail!("invite refused: {}", reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redeems_until_expired_or_used() {
+        let invites = InviteProtocol::default();
+        let (topic, other) = (TopicId::from_bytes([1; 32]), TopicId::from_bytes([2; 32]));
+        let key = RoomKey::generate();
+        let lasting = invites.issue(topic, key, Some(storage::now() + 60_000), false);
+        let once = invites.issue(topic, key, None, true);
+        let expired = invites.issue(topic, key, Some(storage::now() - 1), false);
+
+        let redeemed = invites.redeem(topic, lasting.token).unwrap();
+        assert!(redeemed.decrypt(&key.encrypt(b"probe")).is_ok());
+        assert!(invites.redeem(topic, lasting.token).is_ok());
+        assert!(invites.redeem(other, lasting.token).is_err());
+
+        assert!(invites.redeem(topic, once.token).is_ok());
+        assert!(invites.redeem(topic, once.token).is_err());
+
+        assert!(invites.redeem(topic, expired.token).is_err());
+        assert!(invites.redeem(topic, [0; 16]).is_err());
+
+        invites.remove_room(&topic);
+        assert!(invites.redeem(topic, lasting.token).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{bail, Context, Result};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use p2p_chat::config::KeysConfig;
+
+// What a key does in normal mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Down,
+    Up,
+    HalfPageDown,
+    HalfPageUp,
+    Top,
+    Bottom,
+    Search,
+    NextRoom,
+    PreviousRoom,
+    Insert,
+    Command,
+    Quit,
+}
+
+// A key as bindings name it: a character, maybe with Ctrl held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    c: char,
+    ctrl: bool,
+}
+
+impl Key {
+    fn of(event: &KeyEvent) -> Option<Self> {
+        let KeyCode::Char(c) = event.code else {
+            return None;
+        };
+        Some(Key { c, ctrl: event.modifiers.contains(KeyModifiers::CONTROL) })
+    }
+}
+
+// Where the keys typed so far in normal mode lead
+pub enum Lookup {
+    Action(Action),
+    // The start of a binding, waiting for the rest
+    Pending,
+    Unbound,
+}
+
+// The normal mode bindings, from the [keys] section of config.toml
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: Vec<(Vec<Key>, Action)>,
+}
+
+impl Keymap {
+    // None leaves the UI as it is without modes
+    pub fn from_config(config: &KeysConfig) -> Result<Option<Self>> {
+        if !config.vim.unwrap_or(false) {
+            return Ok(None);
+        }
+        let actions = [
+            (&config.down, "j", Action::Down, "down"),
+            (&config.up, "k", Action::Up, "up"),
+            (&config.half_page_down, "ctrl-d", Action::HalfPageDown, "half_page_down"),
+            (&config.half_page_up, "ctrl-u", Action::HalfPageUp, "half_page_up"),
+            (&config.top, "gg", Action::Top, "top"),
+            (&config.bottom, "G", Action::Bottom, "bottom"),
+            (&config.search, "/", Action::Search, "search"),
+            (&config.next_room, "gt", Action::NextRoom, "next_room"),
+            (&config.previous_room, "gT", Action::PreviousRoom, "previous_room"),
+            (&config.insert, "i", Action::Insert, "insert"),
+            (&config.command, ":", Action::Command, "command"),
+            (&config.quit, "ZZ", Action::Quit, "quit"),
+        ];
+        let mut bindings: Vec<(Vec<Key>, Action, &str)> = Vec::new();
+        for (configured, default, action, name) in actions {
+            let keys = parse(configured.as_deref().unwrap_or(default))
+                .with_context(|| format!("invalid key for keys.{}", name))?;
+            // A binding that starts another would never let that one be typed
+            let clash = bindings.iter().find(|(other, ..)| other.starts_with(&keys) || keys.starts_with(other));
+            if let Some((_, _, other)) = clash {
+                bail!("keys.{} and keys.{} can't be told apart", other, name);
+            }
+            bindings.push((keys, action, name));
+        }
+        let bindings = bindings.into_iter().map(|(keys, action, _)| (keys, action)).collect();
+        Ok(Some(Self { bindings }))
+    }
+
+    // The keys typed so far, with `event` the last of them. Keys that aren't characters are never
+    // bound.
+    pub fn lookup(&self, pending: &mut Vec<KeyEvent>, event: KeyEvent) -> Lookup {
+        pending.push(event);
+        let typed: Option<Vec<Key>> = pending.iter().map(Key::of).collect();
+        let Some(typed) = typed else {
+            pending.clear();
+            return Lookup::Unbound;
+        };
+        if let Some((_, action)) = self.bindings.iter().find(|(keys, _)| *keys == typed) {
+            pending.clear();
+            return Lookup::Action(*action);
+        }
+        if self.bindings.iter().any(|(keys, _)| keys.starts_with(&typed)) {
+            return Lookup::Pending;
+        }
+        pending.clear();
+        Lookup::Unbound
+    }
+}
+
+// "gt" is g then t, "ctrl-d" is d with Ctrl held
+fn parse(binding: &str) -> Option<Vec<Key>> {
+    if let Some(c) = binding.strip_prefix("ctrl-") {
+        let mut chars = c.chars();
+        let (Some(c), None) = (chars.next(), chars.next()) else {
+            return None;
+        };
+        return Some(vec![Key { c: c.to_ascii_lowercase(), ctrl: true }]);
+    }
+    let keys: Vec<Key> = binding.chars().map(|c| Key { c, ctrl: false }).collect();
+    (!keys.is_empty() && !binding.contains(char::is_whitespace)).then_some(keys)
+}
@@ -0,0 +1,88 @@
+// The chat engine: build a ChatClient, subscribe to its events and open or join rooms.
+// The iroh-chat binary in main.rs is a thin CLI over this.
+
+mod archive;
+mod blocks;
+mod bot;
+mod chunks;
+mod client;
+mod code;
+mod contacts;
+mod devices;
+mod directory;
+pub mod config;
+pub mod crypto;
+mod dm;
+mod emoji;
+mod files;
+mod history;
+pub mod identity;
+mod invite;
+mod location;
+mod membership;
+mod mention;
+mod message;
+mod migrate;
+mod names;
+mod netcheck;
+mod ordering;
+mod pake;
+mod ping;
+mod pins;
+mod plugins;
+mod presence;
+mod ratelimit;
+mod reactions;
+mod receipts;
+mod rooms;
+mod storage;
+mod ticket;
+mod upload;
+mod version;
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+pub use archive::{Archive, ExportFormat, SearchHit, SEARCH_PAGE};
+pub use bot::{Bot, BotBuilder, Command, Reply};
+pub use code::unfence;
+pub use client::{ChatClient, ChatClientBuilder, ChatEvent, DiscoveryMode, Links, NeighborLink, OnlinePeer, Traffic};
+pub use contacts::Contact;
+pub use devices::LinkTicket;
+pub use directory::Listing;
+pub use emoji::{expand_shortcodes, to_shortcodes};
+pub use files::{format_size, is_image};
+pub use membership::{Publishers, RoomInfo};
+pub use mention::mentions;
+pub use netcheck::{Diagnosis, Holepunch};
+pub use ping::Latency;
+pub use message::{AckKind, MessageId};
+pub use presence::Status;
+pub use ratelimit::RateLimit;
+pub use storage::{CachedBlob, HistoryKey, Notify, Retention, StoredMessage};
+pub use ticket::{Invite, Ticket, TICKET_VERSION};
+pub use version::PROTOCOL_VERSION;
+
+// How many stored messages to replay when (re)joining a room
+const HISTORY_LIMIT: usize = 50;
+
+// ~/.config/iroh-chat, home of the identity key, history database and blob store
+pub fn default_data_dir() -> Result<PathBuf> {
+    let home = dirs::home_dir().context("could not determine home directory")?;
+    Ok(home.join(".config").join("iroh-chat"))
+}
+
+// ~/.config/iroh-chat/profiles/<name>: a data directory of its own, so each profile has its own
+// identity, config and history
+pub fn profile_dir(name: &str) -> Result<PathBuf> {
+    let valid = name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || !valid {
+        bail!("profile names may only contain letters, digits, - and _");
+    }
+    Ok(default_data_dir()?.join("profiles").join(name))
+}
+
+// Whether the history database in `data_dir` is encrypted, and so needs a HistoryKey to open
+pub fn history_encrypted(data_dir: &Path) -> Result<bool> {
+    storage::is_encrypted(&data_dir.join("history.db"))
+}
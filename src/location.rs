@@ -0,0 +1,28 @@
+use anyhow::{bail, Result};
+
+// How close in the OpenStreetMap link zooms, about a few streets across
+const MAP_ZOOM: u8 = 17;
+
+// Whether the coordinates are somewhere on Earth
+pub fn check(lat: f64, lon: f64) -> Result<()> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        bail!("latitude goes from -90 to 90 and longitude from -180 to 180");
+    }
+    Ok(())
+}
+
+// A place as shown and kept in history: its label, the coordinates in degrees north or south and
+// east or west, and a link to it on OpenStreetMap
+pub fn describe(lat: f64, lon: f64, label: &str) -> String {
+    let north = if lat < 0.0 { 'S' } else { 'N' };
+    let east = if lon < 0.0 { 'W' } else { 'E' };
+    let place = format!("{:.5}° {}, {:.5}° {}", lat.abs(), north, lon.abs(), east);
+    let link = format!(
+        "https://www.openstreetmap.org/?mlat={:.5}&mlon={:.5}#map={}/{:.5}/{:.5}",
+        lat, lon, MAP_ZOOM, lat, lon
+    );
+    match label.is_empty() {
+        true => format!("📍 {} {}", place, link),
+        false => format!("📍 {} ({}) {}", label, place, link),
+    }
+}
@@ -1,189 +1,720 @@
-use anyhow::Result;
+mod api;
+mod clipboard;
+mod commands;
+mod complete;
+mod daemon;
+mod editor;
+mod graphics;
+mod highlight;
+mod keys;
+mod markdown;
+mod notify;
+mod theme;
+mod ui;
+mod voice;
+
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use data_encoding::BASE32_NOPAD;
-use futures_lite::StreamExt; 
-use iroh::{Endpoint, NodeAddr, NodeId, Watcher};
-use iroh::protocol::Router;
-use iroh_gossip::{net::Gossip, proto::TopicId};
-use iroh_gossip::api::{GossipReceiver, Event};
-use rand::random;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt;
-use std::io::{self};
-use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use iroh::{NodeAddr, NodeId, RelayMode, RelayUrl};
+use p2p_chat::config::{self, Config, HistoryEncryption};
+use p2p_chat::{
+    expand_shortcodes, identity, Archive, ChatClient, Diagnosis, DiscoveryMode, ExportFormat, HistoryKey, Ticket,
+    SEARCH_PAGE,
+};
+use std::io::IsTerminal;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use ui::Output;
 
 #[derive(Parser)]
 #[command(name = "iroh-chat")]
 struct Args {
+    // Without one, rejoin the rooms from last time
     #[command(subcommand)]
-    command: Commands,
-    #[arg(long, default_value = "user")]
-    name: String,
+    command: Option<Commands>,
+    // Display name (defaults to the config file's, then "user")
+    #[arg(long)]
+    name: Option<String>,
+    // Path to the node's secret key (defaults to ~/.config/iroh-chat/identity.key)
+    #[arg(long)]
+    identity: Option<PathBuf>,
+    // Use a separate identity, config and history kept under ~/.config/iroh-chat/profiles/<name>
+    #[arg(long)]
+    profile: Option<String>,
+    // Relay through these servers instead of n0's (repeat for several)
+    #[arg(long, conflicts_with = "no_relay")]
+    relay_url: Vec<RelayUrl>,
+    // Never relay, only connect directly
+    #[arg(long)]
+    no_relay: bool,
+    // How to find peers: "n0" (the default, the same as "dns,pkarr"), "local" for the local network,
+    // "all", "none", or a comma-separated list of "dns", "pkarr" and "local"
+    #[arg(long)]
+    discovery: Option<DiscoveryMode>,
+    // Don't publish our addresses for others to look up, only look theirs up
+    #[arg(long)]
+    no_publish: bool,
+    // Also serve JSON-RPC over WebSocket on this address, e.g. 127.0.0.1:8080
+    #[arg(long)]
+    api_listen: Option<SocketAddr>,
+    // Let --api-listen take an address other machines can reach
+    #[arg(long)]
+    api_allow_remote: bool,
+    // The web page allowed to use the API from a browser, e.g. http://localhost:3000
+    #[arg(long)]
+    api_origin: Option<String>,
+    // Print plain lines and read stdin instead of running the full-screen UI
+    #[arg(long)]
+    no_tui: bool,
+    // Show names and code without colors, as setting NO_COLOR does
+    #[arg(long)]
+    no_color: bool,
+    // Show message text as it was sent, without rendering its Markdown
+    #[arg(long)]
+    plain: bool,
+    // Plain lines for screen readers: no colors or box drawing, and every line says who it is from
+    #[arg(long)]
+    accessible: bool,
+    // Print events as JSON lines and read JSON-RPC requests from stdin, for piping into other tools
+    #[arg(long)]
+    json: bool,
+    // Don't rejoin the rooms we were in last time
+    #[arg(long)]
+    no_rejoin: bool,
+    // Keep history for members that were away, so they catch up from us when they come back
+    #[arg(long)]
+    mailbox: bool,
+    // Send files at no more than this many kilobytes a second, all downloads from us together
+    #[arg(long, value_name = "KB/S")]
+    upload_limit: Option<u64>,
+    // Reach a peer at this address without discovery, and bootstrap joined rooms from it; repeat for
+    // more peers or addresses
+    #[arg(long = "peer", value_name = "NODE-ID@IP:PORT", value_parser = config::parse_peer)]
+    peers: Vec<NodeAddr>,
+    // Listen on this IPv4 or IPv6 address rather than on every interface; give one of each for both
+    #[arg(long)]
+    bind_addr: Vec<IpAddr>,
+    // Listen on this UDP port, for IPv4 and IPv6 alike, instead of a random one
+    #[arg(long)]
+    port: Option<u16>,
+    // Only use IPv4, or only IPv6. The other is still opened, but only on the loopback interface.
+    #[arg(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+    #[arg(long)]
+    ipv6_only: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Open,
-    Join { ticket: String },
+    Open {
+        // Make the printed ticket an invite that stops working after this long, e.g. 30m or 2h
+        #[arg(long, value_parser = parse_duration)]
+        expires: Option<Duration>,
+        // Make the printed ticket an invite that works only once
+        #[arg(long)]
+        single_use: bool,
+        // Leave the room key out of the ticket; joiners need a password to get it from a member. The
+        // password is asked for, or taken from IROH_CHAT_ROOM_PASSWORD, never the command line.
+        #[arg(long, conflicts_with_all = ["expires", "single_use"])]
+        password: bool,
+        // Open the room derived from this name and --secret instead of a new one, so a group can meet
+        // again without passing tickets around
+        #[arg(long, requires = "secret", conflicts_with = "password")]
+        room: Option<String>,
+        #[arg(long, requires = "room")]
+        secret: Option<String>,
+        // Announce the room in the public directory, where `rooms list` finds it
+        #[arg(long)]
+        public: bool,
+        // Only let us, and every --publisher, post in the room
+        #[arg(long, conflicts_with_all = ["password", "room"])]
+        read_only: bool,
+        // A node id that may post in a --read-only room besides us; repeat for more
+        #[arg(long = "publisher", requires = "read_only")]
+        publishers: Vec<NodeId>,
+    },
+    Join {
+        ticket: String,
+        // Ask for the password of a protected room, or take it from IROH_CHAT_ROOM_PASSWORD
+        #[arg(long)]
+        password: bool,
+    },
+    // Run headless, controlled over a Unix socket (defaults to daemon.sock in the data directory)
+    Daemon {
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    // The public room directory
+    Rooms {
+        #[command(subcommand)]
+        command: RoomsCommand,
+    },
+    // The messages stored on this machine
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+    // Check how this machine reaches relays and peers, and suggest fixes for what doesn't work
+    Doctor {
+        // Also test holepunching against this node id, which has to be online running iroh-chat
+        #[arg(long)]
+        peer: Option<NodeId>,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum MessageBody {
-    AboutMe { from: NodeId, name: String },
-    Message { from: NodeId, text: String },
+#[derive(Subcommand)]
+enum RoomsCommand {
+    // Listen to the directory for a while and print the rooms announced there
+    List {
+        #[arg(long, value_parser = parse_duration, default_value = "15s")]
+        wait: Duration,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Message {
-    body: MessageBody,
-    nonce: [u8; 16],  // To prevent deduplication
+#[derive(Subcommand)]
+enum HistoryCommand {
+    // Write out a room's stored messages, to stdout or --output
+    Export {
+        // A room id, or the start of one, or the room's name
+        #[arg(long)]
+        room: String,
+        // "json", or "md" for a Markdown transcript
+        #[arg(long, default_value = "json")]
+        format: ExportFormat,
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    // Load a room exported with `history export --format json` on another machine
+    Import {
+        file: PathBuf,
+    },
+    // Print the stored messages containing a text, newest first
+    Search {
+        query: String,
+        // Only search this room, given like for export
+        #[arg(long)]
+        room: Option<String>,
+        // Which page of results to print, from 1
+        #[arg(long, default_value_t = 1)]
+        page: usize,
+    },
 }
 
-impl Message {
-    fn new(body: MessageBody) -> Self {
-        Self {
-            body,
-            nonce: random(),
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    // Anyone on the network who gets hold of the API token could speak as us
+    if let Some(addr) = args.api_listen
+        && !addr.ip().is_loopback()
+        && !args.api_allow_remote
+    {
+        bail!("{} isn't a loopback address; pass --api-allow-remote to serve the API on it", addr);
+    }
+    let data_dir = match &args.profile {
+        Some(profile) => p2p_chat::profile_dir(profile)?,
+        None => p2p_chat::default_data_dir()?,
+    };
+    let config = Config::load(&data_dir.join("config.toml"))?;
+    let identity_path = args.identity.clone().or(config.identity.clone());
+    let history_key = history_key(&data_dir, config.encrypt_history, identity_path.as_deref())?;
+    // Asked before the node starts, so the prompt isn't buried in its output
+    let room_password = match &args.command {
+        Some(Commands::Open { password: true, .. }) => Some(room_password(true)?),
+        Some(Commands::Join { password: true, .. }) => Some(room_password(false)?),
+        _ => None,
+    };
+    // Reading the history doesn't need the network
+    if let Some(Commands::History { command }) = &args.command {
+        return history(&data_dir, history_key.as_ref(), command);
+    }
+    let (output, lines) = Output::channel();
+
+    // Flags win over the config file
+    let name = args.name.or(config.name.clone()).unwrap_or_else(|| "user".to_string());
+    let mut builder = ChatClient::builder().name(name).data_dir(&data_dir);
+    // Load (or create) our persistent node identity
+    if let Some(path) = &identity_path {
+        builder = builder.secret_key(identity::load_or_create(path)?);
+    }
+    if let Some(key) = history_key {
+        builder = builder.history_key(key);
+    }
+    let relay_mode = if args.no_relay {
+        Some(RelayMode::Disabled)
+    } else if !args.relay_url.is_empty() {
+        Some(RelayMode::Custom(args.relay_url.into_iter().collect()))
+    } else {
+        config.relay_mode()?
+    };
+    if let Some(relay_mode) = relay_mode {
+        builder = builder.relay_mode(relay_mode);
+    }
+    if let Some(discovery) = args.discovery.or(config.discovery) {
+        builder = builder.discovery(discovery);
+    }
+    if args.no_publish || config.publish == Some(false) {
+        builder = builder.no_publish(true);
+    }
+    if let Some(limits) = config.limits {
+        builder = builder.rate_limit(limits);
+    }
+    if args.mailbox || config.mailbox.unwrap_or(false) {
+        builder = builder.mailbox(true);
+    }
+    if let Some(megabytes) = config.file_cache {
+        builder = builder.file_cache(megabytes * 1024 * 1024);
+    }
+    if let Some(kilobytes) = args.upload_limit.or(config.upload_limit) {
+        builder = builder.upload_limit(kilobytes * 1024);
+    }
+    let (bind_v4, bind_v6) = bind_addrs(&args.bind_addr, args.port.or(config.port), args.ipv4_only, args.ipv6_only)?;
+    if let Some(addr) = bind_v4 {
+        builder = builder.bind_v4(addr);
+    }
+    if let Some(addr) = bind_v6 {
+        builder = builder.bind_v6(addr);
+    }
+    for peer in config.bootstrap()?.into_iter().chain(args.peers.iter().cloned()) {
+        builder = builder.peer(peer);
+    }
+    let client = builder.spawn().await?;
+    if let Some(Commands::Rooms { command: RoomsCommand::List { wait } }) = args.command {
+        let listings = client.browse(wait).await?;
+        println!("{} public rooms", listings.len());
+        for listing in listings {
+            let name = if listing.name.is_empty() { "(unnamed)" } else { listing.name.as_str() };
+            println!("\n{} (by {})", name, listing.from.fmt_short());
+            if !listing.description.is_empty() {
+                println!("  {}", listing.description);
+            }
+            println!("  {}", listing.ticket);
+        }
+        client.shutdown().await?;
+        return Ok(());
+    }
+    if let Some(Commands::Doctor { peer }) = args.command {
+        println!("Checking the network, this takes up to a minute...");
+        doctor(&client.diagnose(peer).await);
+        client.shutdown().await?;
+        return Ok(());
+    }
+    // What others need to /invite us into a room of theirs
+    output.line(format!("> Node id: {}", client.node_id()));
+    let plugins = client.plugins();
+    if !plugins.is_empty() {
+        output.line(format!("> Plugins: {}", plugins.join(", ")));
+    }
+    // Subscribe before joining so the history replay isn't missed
+    let events = client.events();
+
+    if let Some(addr) = args.api_listen {
+        let token_path = data_dir.join("api.token");
+        let token = identity::load_or_create_token(&token_path)?;
+        output.line(format!("> API on ws://{}, token in {}", addr, token_path.display()));
+        let access = api::Access { token, origin: args.api_origin.clone() };
+        let (client, output) = (client.clone(), output.clone());
+        tokio::spawn(async move {
+            if let Err(err) = api::serve(client, addr, access).await {
+                output.line(format!("> Error: API on {} stopped: {}", addr, err));
+            }
+        });
+    }
+
+    // Rooms from the config file first, so the one on the command line ends up active
+    for ticket in &config.rooms {
+        if let Err(err) = join_ticket(&client, ticket).await {
+            output.line(format!("> Error: could not join a room from the config file: {}", err));
         }
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        serde_json::to_vec(self).expect("Serialization failed")
+    match args.command {
+        Some(Commands::Open { expires, single_use, room, secret, public, read_only, publishers, .. }) => {
+            let mut ticket = match (room_password, room.zip(secret)) {
+                (Some(password), _) => client.open_protected_room(&password).await?,
+                (None, Some((room, secret))) => client.open_named_room(&room, &secret).await?,
+                (None, None) if read_only => client.open_read_only_room(publishers).await?,
+                (None, None) => client.open_room().await?,
+            };
+            if public {
+                client.publish().await?;
+            }
+            if expires.is_some() || single_use {
+                ticket = client.invite(expires, single_use).await?;
+            }
+            output.line(format!("> Ticket to join: {}", ticket));
+        }
+        Some(Commands::Join { ticket, .. }) => match room_password {
+            Some(password) => {
+                client.join_with_password(ticket.parse()?, &password).await?;
+            }
+            None => {
+                client.join(ticket.parse()?).await?;
+            }
+        },
+        Some(Commands::Daemon { socket }) => {
+            if !args.no_rejoin {
+                rejoin_in_background(&client, &output);
+            }
+            let socket = socket.unwrap_or_else(|| data_dir.join("daemon.sock"));
+            daemon::run(&client, &socket, lines).await?;
+            client.shutdown().await?;
+            return Ok(());
+        }
+        Some(Commands::Rooms { .. } | Commands::Doctor { .. }) => unreachable!("handled before joining any room"),
+        Some(Commands::History { .. }) => unreachable!("handled before starting the node"),
+        None => {}
+    };
+
+    if !args.no_rejoin {
+        rejoin_in_background(&client, &output);
     }
 
-    fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        serde_json::from_slice(bytes).map_err(Into::into)
+    // Fall back to plain lines when we aren't attached to a terminal
+    let accessible = args.accessible || config.ui.accessible.unwrap_or(false);
+    let tui = !args.no_tui && !accessible && config.ui.tui.unwrap_or(true);
+    // https://no-color.org: any NO_COLOR that isn't empty turns colors off
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let options = ui::Options {
+        color: !args.no_color && !no_color && !accessible && config.ui.color.unwrap_or(true),
+        notify: config.ui.notifications.unwrap_or(true),
+        // An empty format shows no times
+        clock: Some(config.ui.timestamps.clone().unwrap_or_else(|| "%H:%M".to_string()))
+            .filter(|format| !format.is_empty())
+            .map(|format| ui::Clock { format, utc: config.ui.utc.unwrap_or(false) }),
+        theme: theme::Theme::from_config(&config.theme)?,
+        emoji: config.ui.emoji.unwrap_or(true),
+        markdown: !args.plain && config.ui.markdown.unwrap_or(true),
+        keymap: keys::Keymap::from_config(&config.keys)?,
+        accessible,
+    };
+    if args.json {
+        api::run_json(&client, lines, events).await?;
+    } else if !tui || !std::io::stdout().is_terminal() {
+        ui::run_plain(&client, output, lines, events, options).await?;
+    } else {
+        ui::run_tui(&client, output, lines, events, options).await?;
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Ticket {
-    topic: TopicId,
-    nodes: Vec<NodeAddr>,
+    // Say goodbye to the rooms before the endpoint goes away. The frontend is gone by now.
+    let left = client.shutdown().await?;
+    if !left.is_empty() && !args.json {
+        println!("> you left the room");
+    }
+    Ok(())
 }
 
-impl fmt::Display for Ticket {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let bytes = serde_json::to_vec(self).expect("Serialization failed");
-        let text = BASE32_NOPAD.encode(&bytes).to_lowercase();
-        write!(f, "{}", text)
+// The key the history database is encrypted with, if config.toml asks for one. A passphrase is
+// read from IROH_CHAT_PASSPHRASE or asked for on the terminal, twice when it's new.
+fn history_key(
+    data_dir: &Path,
+    encryption: Option<HistoryEncryption>,
+    identity_path: Option<&Path>,
+) -> Result<Option<HistoryKey>> {
+    let passphrase = match encryption {
+        None => return Ok(None),
+        Some(HistoryEncryption::Identity) => {
+            let path = identity_path.map(PathBuf::from).unwrap_or_else(|| data_dir.join("identity.key"));
+            return Ok(Some(HistoryKey::from_identity(&identity::load_or_create(&path)?)));
+        }
+        Some(HistoryEncryption::Passphrase) => match std::env::var("IROH_CHAT_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => {
+                let passphrase = rpassword::prompt_password("History passphrase: ")?;
+                if !p2p_chat::history_encrypted(data_dir)?
+                    && rpassword::prompt_password("Repeat it to encrypt the history: ")? != passphrase
+                {
+                    bail!("the passphrases don't match");
+                }
+                passphrase
+            }
+        },
+    };
+    if passphrase.is_empty() {
+        bail!("the history passphrase can't be empty");
     }
+    Ok(Some(HistoryKey::Passphrase(passphrase)))
 }
 
-impl FromStr for Ticket {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = BASE32_NOPAD.decode(s.to_uppercase().as_bytes())?;
-        serde_json::from_slice(&bytes).map_err(Into::into)
+// The password of a protected room, from IROH_CHAT_ROOM_PASSWORD or typed in without echo, twice
+// when it is for a new room. Passwords on the command line would show up in `ps` and shell history.
+fn room_password(new: bool) -> Result<String> {
+    let password = match std::env::var("IROH_CHAT_ROOM_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => {
+            let password = rpassword::prompt_password("Room password: ")?;
+            if new && rpassword::prompt_password("Repeat it: ")? != password {
+                bail!("the passwords don't match");
+            }
+            password
+        }
+    };
+    if password.is_empty() {
+        bail!("the room password can't be empty");
     }
+    Ok(password)
 }
 
-async fn subscribe_loop(
-    mut receiver: GossipReceiver,
-    names: Arc<Mutex<HashMap<NodeId, String>>>,
-) -> Result<()> {
-    while let Some(event) = receiver.try_next().await? {
-        match event {
-            Event::Received(msg) => {
-                let message = Message::from_bytes(&msg.content)?;
-                let mut names = names.lock().await;
-                match message.body {
-                    MessageBody::AboutMe { from, name } => {
-                        names.insert(from, name.clone());
-                        println!("> {} joined as {}", from.fmt_short(), name);
-                    }
-                    MessageBody::Message { from, text } => {
-                        let name = names.get(&from).cloned().unwrap_or(from.fmt_short());
-                        println!("{}: {}", name, text);
-                    }
-                }
+fn history(data_dir: &Path, key: Option<&HistoryKey>, command: &HistoryCommand) -> Result<()> {
+    let archive = match command {
+        HistoryCommand::Import { .. } => Archive::create(data_dir, key)?,
+        _ => Archive::open(data_dir, key)?,
+    };
+    match command {
+        HistoryCommand::Export { room, format, output } => {
+            let topic = archive.find_room(room)?;
+            let export = archive.export(&topic, *format)?;
+            match output {
+                Some(path) => std::fs::write(path, export)
+                    .with_context(|| format!("could not write {}", path.display()))?,
+                None => println!("{}", export),
             }
-            Event::NeighborUp(node_id) => {
-                println!("> Neighbor connected: {}", node_id.fmt_short());
+        }
+        HistoryCommand::Import { file } => {
+            let json = std::fs::read_to_string(file).with_context(|| format!("could not read {}", file.display()))?;
+            let (topic, imported) = archive.import(&json)?;
+            println!("Imported {} new messages into room {}", imported, topic);
+        }
+        HistoryCommand::Search { query, room, page } => {
+            let room = room.as_deref().map(|room| archive.find_room(room)).transpose()?;
+            let hits = archive.search(query, room.as_ref(), page.saturating_sub(1))?;
+            if hits.is_empty() {
+                println!("Nothing found");
             }
-            Event::NeighborDown(node_id) => {
-                println!("> Neighbor disconnected: {}", node_id.fmt_short());
+            for hit in &hits {
+                println!("{}", ui::render_search(hit));
             }
-            Event::Lagged => {
-                println!("> Warning: Message queue lagged, some messages may have been lost");
+            if hits.len() == SEARCH_PAGE {
+                println!("More with --page {}", page.max(&1) + 1);
             }
         }
     }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-
-    // Create Iroh endpoint with discovery
-    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+// What `doctor` found, then what to do about the problems among it
+fn doctor(diagnosis: &Diagnosis) {
+    let mut fixes = Vec::new();
+    println!();
+    match &diagnosis.home_relay {
+        Some(relay) => println!("Home relay:      {}", relay),
+        None => {
+            println!("Home relay:      none");
+            fixes.push("No relay answered, so peers behind other NATs can't reach you. Check that HTTPS to the \
+                relays isn't blocked, and that --no-relay or a wrong --relay-url isn't set.");
+        }
+    }
+    for (relay, latency) in &diagnosis.relays {
+        println!("                 {} answered in {} ms", relay, latency.as_millis());
+    }
+    let yes_no = |ok: bool| if ok { "yes" } else { "no" };
+    println!("UDP:             IPv4 {}, IPv6 {}", yes_no(diagnosis.udp_v4), yes_no(diagnosis.udp_v6));
+    if !diagnosis.udp_v4 && !diagnosis.udp_v6 {
+        fixes.push("UDP is blocked, so every connection goes through a relay. Allow outgoing UDP in your \
+            firewall, or try another network.");
+    }
+    let public: Vec<String> = diagnosis
+        .public_v4
+        .map(|addr| addr.to_string())
+        .into_iter()
+        .chain(diagnosis.public_v6.map(|addr| addr.to_string()))
+        .collect();
+    println!("Public address:  {}", if public.is_empty() { "unknown".to_string() } else { public.join(", ") });
+    let nat = match diagnosis.mapping_varies {
+        Some(false) => "keeps the same public port for everyone, so holepunching should work",
+        Some(true) => {
+            fixes.push("Your NAT picks a new public port for every peer (a symmetric NAT), so direct \
+                connections rarely succeed and chat goes through relays. Forwarding a UDP port on the router, \
+                or a network with IPv6, gets around it.");
+            "gives each peer a different public port (symmetric), which defeats holepunching"
+        }
+        None => "couldn't be told",
+    };
+    println!("NAT:             {}", nat);
+    if diagnosis.captive_portal == Some(true) {
+        println!("Captive portal:  yes");
+        fixes.push("This network has a captive portal. Sign in to it in a browser, then run doctor again.");
+    }
+    let local: Vec<String> = diagnosis.direct_addresses.iter().map(|addr| addr.to_string()).collect();
+    println!("Addresses:       {}", if local.is_empty() { "none".to_string() } else { local.join(", ") });
+    match &diagnosis.published {
+        Some(Ok(())) => println!("Discovery:       n0's DNS serves our address"),
+        Some(Err(err)) => {
+            println!("Discovery:       n0's DNS doesn't serve our address: {}", err);
+            fixes.push("Peers can't look you up by node id, only join with a ticket from you. Check that \
+                HTTPS to dns.iroh.link isn't blocked, or use --discovery local on a local network.");
+        }
+        None => println!("Discovery:       not publishing to n0's DNS"),
+    }
+    match &diagnosis.holepunch {
+        Some(Ok(holepunch)) => match holepunch.direct_after {
+            Some(after) => println!("Holepunching:    direct after {} ms", after.as_millis()),
+            None => {
+                let round_trip = holepunch.latency.round_trips.iter().min().copied().unwrap_or_default();
+                println!("Holepunching:    still relayed, {} ms a round trip", round_trip.as_millis());
+                fixes.push("The connection to the helper stayed on the relay. It works, but slower; if either \
+                    side has a symmetric NAT or blocks UDP, that is why.");
+            }
+        },
+        Some(Err(err)) => {
+            println!("Holepunching:    could not reach the helper: {}", err);
+            fixes.push("The helper didn't answer. Check that it is online and running iroh-chat.");
+        }
+        None => println!("Holepunching:    not tested, pass --peer <node-id> of a node that is online"),
+    }
+    println!();
+    if fixes.is_empty() {
+        println!("Everything looks fine.");
+    }
+    for fix in fixes {
+        println!("- {}", fix);
+    }
+}
 
-    // Build gossip instance (remove .await - it returns the instance directly)
-    let gossip = Gossip::builder().spawn(endpoint.clone());
+// "90s", "30m", "2h" or "7d"
+fn parse_duration(text: &str) -> Result<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().with_context(|| format!("{} is not a duration like 30m", text))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("{} is not a duration like 30m (units: s, m, h, d)", text),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
 
-    // Set up router for handling gossip protocol
-    let router = Router::builder(endpoint.clone())
-        .accept(iroh_gossip::ALPN, gossip.clone())
-        .spawn();
+// Where to listen for each address family, None for iroh's default of a random port everywhere
+fn bind_addrs(
+    addrs: &[IpAddr],
+    port: Option<u16>,
+    ipv4_only: bool,
+    ipv6_only: bool,
+) -> Result<(Option<SocketAddrV4>, Option<SocketAddrV6>)> {
+    let mut v4 = None;
+    let mut v6 = None;
+    for addr in addrs {
+        match addr {
+            IpAddr::V4(_) if v4.is_some() => bail!("--bind-addr takes at most one IPv4 address"),
+            IpAddr::V6(_) if v6.is_some() => bail!("--bind-addr takes at most one IPv6 address"),
+            IpAddr::V4(_) if ipv6_only => bail!("--bind-addr {} is an IPv4 address, but --ipv6-only is set", addr),
+            IpAddr::V6(_) if ipv4_only => bail!("--bind-addr {} is an IPv6 address, but --ipv4-only is set", addr),
+            IpAddr::V4(ip) => v4 = Some(*ip),
+            IpAddr::V6(ip) => v6 = Some(*ip),
+        }
+    }
+    // The family left out is kept off the network
+    if ipv6_only {
+        v4 = Some(Ipv4Addr::LOCALHOST);
+    }
+    if ipv4_only {
+        v6 = Some(Ipv6Addr::LOCALHOST);
+    }
+    if v4.is_none() && v6.is_none() && port.is_none() {
+        return Ok((None, None));
+    }
+    // The loopback side gets a random port, so it doesn't take the one meant for the network
+    let port_of = |loopback: bool| if loopback { 0 } else { port.unwrap_or(0) };
+    let v4 = SocketAddrV4::new(v4.unwrap_or(Ipv4Addr::UNSPECIFIED), port_of(ipv6_only));
+    let v6 = SocketAddrV6::new(v6.unwrap_or(Ipv6Addr::UNSPECIFIED), port_of(ipv4_only), 0, 0);
+    Ok((Some(v4), Some(v6)))
+}
 
-    // Generate or parse topic and peers based on role
-    let (topic_id, peers) = match args.command {
-        Commands::Open => {
-            let topic_id = TopicId::from_bytes(random::<[u8; 32]>());
-            // Get our own address without .await - node_addr() returns a Watcher
-            let my_addr = endpoint.node_addr().initialized().await;
-            let ticket = Ticket { topic: topic_id, nodes: vec![my_addr] };
-            println!("> Ticket to join: {}", ticket);
-            (topic_id, vec![])
+// Rooms from last time come back once the command line's room is up, so it doesn't wait for them
+fn rejoin_in_background(client: &ChatClient, output: &Output) {
+    let (client, output) = (client.clone(), output.clone());
+    tokio::spawn(async move {
+        match client.rejoin().await {
+            Ok(rooms) if rooms.is_empty() => {}
+            Ok(rooms) => output.line(format!("> Rejoined {} rooms from last time", rooms.len())),
+            Err(err) => output.line(format!("> Error: could not rejoin rooms: {}", err)),
         }
-        Commands::Join { ticket } => {
-            let ticket: Ticket = ticket.parse()?;
-            (ticket.topic, ticket.nodes)
+    });
+}
+
+async fn join_ticket(client: &ChatClient, ticket: &str) -> Result<()> {
+    client.join(ticket.parse::<Ticket>()?).await?;
+    Ok(())
+}
+
+// Typed lines are either /commands or messages for the active room. Returns false after /quit.
+async fn handle_line(client: &ChatClient, output: &Output, text: String) -> bool {
+    // While writing a snippet every line is code, blank ones and ones starting with / too
+    if client.code_draft().await.is_some() {
+        let result = match text.trim() {
+            "/end" => client.send_code().await.map(|_| ()),
+            "/cancel" => {
+                client.cancel_code().await;
+                output.line("> Dropped the code snippet");
+                Ok(())
+            }
+            _ => {
+                client.code_line(text).await;
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            output.line(format!("> Error: {}", err));
         }
+        return true;
+    }
+    if text.trim().is_empty() {
+        return true;
+    }
+    let result = match text.strip_prefix('/') {
+        Some(command) => commands::run(client, output, command).await,
+        None => client.send(expand_shortcodes(&text)).await.map(|_| true),
     };
+    result.unwrap_or_else(|err| {
+        output.line(format!("> Error: {}", err));
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Add known peers to the endpoint
-    for addr in &peers {
-        endpoint.add_node_addr(addr.clone())?;
+    #[test]
+    fn binds_iroh_defaults_without_flags() {
+        assert_eq!(bind_addrs(&[], None, false, false).unwrap(), (None, None));
     }
 
-    // Subscribe to topic and wait for peers to connect
-    let topic = gossip.subscribe(topic_id, peers.iter().map(|a| a.node_id).collect()).await?;
-    let (sender, receiver) = topic.split();
+    #[test]
+    fn binds_port_on_both_families() {
+        let (v4, v6) = bind_addrs(&[], Some(4433), false, false).unwrap();
+        assert_eq!(v4, Some(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 4433)));
+        assert_eq!(v6, Some(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 4433, 0, 0)));
+    }
 
-    // Brief wait for connections (helps in local testing)
-    sleep(Duration::from_secs(2)).await;
+    #[test]
+    fn binds_given_addresses() {
+        let addrs = ["192.168.1.5".parse().unwrap(), "fe80::1".parse().unwrap()];
+        let (v4, v6) = bind_addrs(&addrs, Some(4433), false, false).unwrap();
+        assert_eq!(v4, Some(SocketAddrV4::new("192.168.1.5".parse().unwrap(), 4433)));
+        assert_eq!(v6, Some(SocketAddrV6::new("fe80::1".parse().unwrap(), 4433, 0, 0)));
+        // Only one address per family
+        let two: [IpAddr; 2] = ["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        assert!(bind_addrs(&two, None, false, false).is_err());
+    }
 
-    // Broadcast "about me" message
-    let about_me = Message::new(MessageBody::AboutMe {
-        from: endpoint.node_id(),
-        name: args.name.clone(),
-    });
-    sender.broadcast(about_me.to_bytes().into()).await?;
-
-    // Spawn receiver loop
-    let names = Arc::new(Mutex::new(HashMap::new()));
-    tokio::spawn(subscribe_loop(receiver, names.clone()));
-
-    // Input loop for sending messages
-    println!("> Type messages and press enter to send...");
-    let stdin = io::stdin();
-    for line in stdin.lines() {
-        let text = line?;
-        if text.trim().is_empty() { continue; }
-        let msg = Message::new(MessageBody::Message {
-            from: endpoint.node_id(),
-            text,
-        });
-        sender.broadcast(msg.to_bytes().into()).await?;
+    #[test]
+    fn keeps_the_other_family_on_loopback() {
+        let (v4, v6) = bind_addrs(&[], Some(4433), true, false).unwrap();
+        assert_eq!(v4, Some(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 4433)));
+        assert_eq!(v6, Some(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0)));
+        let (v4, v6) = bind_addrs(&[], None, false, true).unwrap();
+        assert_eq!(v4, Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)));
+        assert_eq!(v6, Some(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)));
     }
 
-    // Shutdown
-    router.shutdown().await?;
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn refuses_addresses_of_the_excluded_family() {
+        let v4: [IpAddr; 1] = ["10.0.0.1".parse().unwrap()];
+        let v6: [IpAddr; 1] = ["::1".parse().unwrap()];
+        assert!(bind_addrs(&v4, None, false, true).is_err());
+        assert!(bind_addrs(&v6, None, true, false).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use ratatui::style::{Color, Modifier, Style};
+use std::ops::Range;
+
+// What a piece of message text is marked up as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emphasis {
+    Bold,
+    Italic,
+    Code,
+    Link,
+}
+
+impl Emphasis {
+    pub fn style(self) -> Style {
+        match self {
+            Emphasis::Bold => Style::default().add_modifier(Modifier::BOLD),
+            Emphasis::Italic => Style::default().add_modifier(Modifier::ITALIC),
+            Emphasis::Code => Style::default().fg(Color::Yellow),
+            Emphasis::Link => Style::default().add_modifier(Modifier::UNDERLINED),
+        }
+    }
+
+    // The SGR parameters of the same, for plain lines
+    pub fn sgr(self) -> &'static str {
+        match self {
+            Emphasis::Bold => "1",
+            Emphasis::Italic => "3",
+            Emphasis::Code => "33",
+            Emphasis::Link => "4",
+        }
+    }
+}
+
+// A line of message text with its Markdown taken out: what is left to show, and where it was
+// emphasized. Knows **bold**, *italics* and _italics_, `code`, [links](url), which keep their
+// address in view as "links (url)", and "- " list items, which get a bullet. Emphasis doesn't
+// nest, and a backslash keeps a marker as it is.
+pub fn render(line: &str) -> (String, Vec<(Range<usize>, Emphasis)>) {
+    let mut out = String::with_capacity(line.len());
+    let mut spans = Vec::new();
+    let mut rest = line;
+    if let Some(item) = ["- ", "* ", "+ "].into_iter().find_map(|bullet| line.strip_prefix(bullet)) {
+        out.push_str("• ");
+        rest = item;
+    }
+    let mut before = None;
+    while let Some(c) = rest.chars().next() {
+        let (shown, emphasis, len) = match c {
+            '\\' if rest[1..].starts_with(|c: char| "\\*_`[]()".contains(c)) => (&rest[1..2], None, 2),
+            '`' => match code(rest) {
+                Some((code, len)) => (code, Some(Emphasis::Code), len),
+                None => (&rest[..1], None, 1),
+            },
+            '*' | '_' => match emphasized(rest, before) {
+                Some((text, emphasis, len)) => (text, Some(emphasis), len),
+                None => (&rest[..1], None, 1),
+            },
+            '[' => match link(rest) {
+                Some((text, url, len)) => {
+                    let start = out.len();
+                    out.push_str(text);
+                    spans.push((start..out.len(), Emphasis::Link));
+                    out.push_str(&format!(" ({})", url));
+                    before = Some(')');
+                    rest = &rest[len..];
+                    continue;
+                }
+                None => (&rest[..1], None, 1),
+            },
+            c => (&rest[..c.len_utf8()], None, c.len_utf8()),
+        };
+        let start = out.len();
+        out.push_str(shown);
+        if let Some(emphasis) = emphasis {
+            spans.push((start..out.len(), emphasis));
+        }
+        before = rest[..len].chars().next_back();
+        rest = &rest[len..];
+    }
+    (out, spans)
+}
+
+// `code` at the start of `text`, opened and closed by as many backticks as it starts with
+fn code(text: &str) -> Option<(&str, usize)> {
+    let ticks = text.len() - text.trim_start_matches('`').len();
+    let fence = &text[..ticks];
+    let end = text[ticks..].find(fence)? + ticks;
+    let code = &text[ticks..end];
+    (!code.is_empty()).then_some((code, end + ticks))
+}
+
+// **bold**, __bold__, *italics* or _italics_ at the start of `text`, coming after `before`.
+// Underscores inside words, as in snake_case, don't count.
+fn emphasized(text: &str, before: Option<char>) -> Option<(&str, Emphasis, usize)> {
+    let marker = text.chars().next()?;
+    let double = text[1..].starts_with(marker);
+    let (delim, emphasis) = match double {
+        true => (&text[..2], Emphasis::Bold),
+        false => (&text[..1], Emphasis::Italic),
+    };
+    let wordy = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    if marker == '_' && wordy(before) {
+        return None;
+    }
+    let inner = &text[delim.len()..];
+    if inner.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut from = 0;
+    while let Some(at) = inner[from..].find(delim).map(|at| from + at) {
+        let after = inner[at + delim.len()..].chars().next();
+        let closes = at > 0
+            && !inner[..at].ends_with(char::is_whitespace)
+            && after != Some(marker)
+            && !(marker == '_' && wordy(after));
+        if closes {
+            return Some((&inner[..at], emphasis, at + 2 * delim.len()));
+        }
+        from = at + delim.len();
+    }
+    None
+}
+
+// [text](url) at the start of `text`
+fn link(text: &str) -> Option<(&str, &str, usize)> {
+    let close = text.find("](")?;
+    let label = &text[1..close];
+    let url_len = text[close + 2..].find(')')?;
+    let url = &text[close + 2..close + 2 + url_len];
+    let valid = !label.is_empty() && !label.contains('[') && !url.is_empty() && !url.contains(char::is_whitespace);
+    valid.then_some((label, url, close + 3 + url_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Emphasis::*;
+
+    // A line, how it is shown and the text of each emphasized part
+    type Case = (&'static str, &'static str, &'static [(&'static str, Emphasis)]);
+
+    fn check(cases: &[Case]) {
+        for (line, shown, emphasized) in cases {
+            let (out, spans) = render(line);
+            let spans: Vec<_> = spans.into_iter().map(|(range, emphasis)| (&out[range], emphasis)).collect();
+            assert_eq!((out.as_str(), spans.as_slice()), (*shown, *emphasized), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn renders_markup() {
+        check(&[
+            ("**bold** and *it* and _it_", "bold and it and it", &[("bold", Bold), ("it", Italic), ("it", Italic)]),
+            ("run `cargo test` now", "run cargo test now", &[("cargo test", Code)]),
+            ("``a ` tick``", "a ` tick", &[("a ` tick", Code)]),
+            ("see [docs](https://x.io)", "see docs (https://x.io)", &[("docs", Link)]),
+            ("- item", "• item", &[]),
+            // Left alone when never closed
+            ("**not bold", "**not bold", &[]),
+            ("`not code", "`not code", &[]),
+            ("_never closed", "_never closed", &[]),
+            ("** spaced**", "** spaced**", &[]),
+            ("[label](no end", "[label](no end", &[]),
+            // snake_case and escaped markers
+            ("snake_case_name", "snake_case_name", &[]),
+            (r"\*not it\*", "*not it*", &[]),
+            // Emphasis doesn't nest: the inner markers are shown as they are
+            ("**bold _and it_**", "bold _and it_", &[("bold _and it_", Bold)]),
+            ("`**code**`", "**code**", &[("**code**", Code)]),
+            ("*it `code` it*", "it `code` it", &[("it `code` it", Italic)]),
+        ]);
+    }
+
+    #[test]
+    fn keeps_spans_on_multi_byte_boundaries() {
+        check(&[
+            ("**héllo**", "héllo", &[("héllo", Bold)]),
+            ("日本*語*", "日本語", &[("語", Italic)]),
+            ("ü`ö`ä", "üöä", &[("ö", Code)]),
+            ("[🦀](https://rust-lang.org)🦀", "🦀 (https://rust-lang.org)🦀", &[("🦀", Link)]),
+            ("é_x_", "é_x_", &[]),
+            ("**é", "**é", &[]),
+            ("\\é", "\\é", &[]),
+            ("`", "`", &[]),
+            ("é\\", "é\\", &[]),
+        ]);
+    }
+}
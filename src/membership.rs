@@ -0,0 +1,621 @@
+use anyhow::{bail, Result};
+use iroh::{NodeId, SecretKey};
+use iroh_base::Signature;
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+// Keep a signature over an allowlist or grant from being passed off as one over anything else
+const DOMAIN: &str = "iroh-chat allowlist";
+const GRANT_DOMAIN: &str = "iroh-chat moderator";
+const INFO_DOMAIN: &str = "iroh-chat room info";
+const PUBLISHERS_DOMAIN: &str = "iroh-chat publishers";
+const TRANSFER_DOMAIN: &str = "iroh-chat ownership transfer";
+
+// Who may speak in a room, as decided by its owner. Each revision replaces the ones before, and
+// the owner's signature lets any member pass the list on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Allowlist {
+    pub revision: u64,
+    pub members: Vec<NodeId>,
+    signature: Signature,
+}
+
+impl Allowlist {
+    pub fn sign(owner: &SecretKey, topic: TopicId, revision: u64, members: Vec<NodeId>) -> Self {
+        let signature = owner.sign(&signed_bytes(&topic, revision, &members));
+        Self { revision, members, signature }
+    }
+
+    pub fn verify(&self, owner: &NodeId, topic: &TopicId) -> Result<()> {
+        owner.verify(&signed_bytes(topic, self.revision, &self.members), &self.signature)?;
+        Ok(())
+    }
+}
+
+fn signed_bytes(topic: &TopicId, revision: u64, members: &[NodeId]) -> Vec<u8> {
+    postcard::to_stdvec(&(DOMAIN, topic, revision, members)).expect("Serialization failed")
+}
+
+// The owner's word that `moderator` may kick and ban in a room. Moderators send it along with
+// everything they do, so peers can check it even if they missed the appointment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub moderator: NodeId,
+    signature: Signature,
+}
+
+impl Grant {
+    pub fn sign(owner: &SecretKey, topic: TopicId, moderator: NodeId) -> Self {
+        let signature = owner.sign(&grant_bytes(&topic, &moderator));
+        Self { moderator, signature }
+    }
+
+    pub fn verify(&self, owner: &NodeId, topic: &TopicId) -> Result<()> {
+        owner.verify(&grant_bytes(topic, &self.moderator), &self.signature)?;
+        Ok(())
+    }
+}
+
+fn grant_bytes(topic: &TopicId, moderator: &NodeId) -> Vec<u8> {
+    postcard::to_stdvec(&(GRANT_DOMAIN, topic, moderator)).expect("Serialization failed")
+}
+
+// What a room is called and about, as its owner describes it. Like allowlists, each revision
+// replaces the ones before and any member can pass it on. Empty fields are unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub revision: u64,
+    pub name: String,
+    pub description: String,
+    pub topic: String,
+    signature: Signature,
+}
+
+impl RoomInfo {
+    pub fn sign(
+        owner: &SecretKey,
+        topic_id: TopicId,
+        revision: u64,
+        name: String,
+        description: String,
+        topic: String,
+    ) -> Self {
+        let signature = owner.sign(&info_bytes(&topic_id, revision, &name, &description, &topic));
+        Self { revision, name, description, topic, signature }
+    }
+
+    pub fn verify(&self, owner: &NodeId, topic_id: &TopicId) -> Result<()> {
+        let bytes = info_bytes(topic_id, self.revision, &self.name, &self.description, &self.topic);
+        owner.verify(&bytes, &self.signature)?;
+        Ok(())
+    }
+}
+
+fn info_bytes(topic_id: &TopicId, revision: u64, name: &str, description: &str, topic: &str) -> Vec<u8> {
+    postcard::to_stdvec(&(INFO_DOMAIN, topic_id, revision, name, description, topic)).expect("Serialization failed")
+}
+
+// The only peers that may post in a read-only room, besides its owner. Set once when the room is
+// opened and carried in its tickets; the owner's signature keeps anyone from adding themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Publishers {
+    pub members: Vec<NodeId>,
+    signature: Signature,
+}
+
+impl Publishers {
+    pub fn sign(owner: &SecretKey, topic: TopicId, members: Vec<NodeId>) -> Self {
+        let signature = owner.sign(&publishers_bytes(&topic, &members));
+        Self { members, signature }
+    }
+
+    pub fn verify(&self, owner: &NodeId, topic: &TopicId) -> Result<()> {
+        owner.verify(&publishers_bytes(topic, &self.members), &self.signature)?;
+        Ok(())
+    }
+}
+
+fn publishers_bytes(topic: &TopicId, members: &[NodeId]) -> Vec<u8> {
+    postcard::to_stdvec(&(PUBLISHERS_DOMAIN, topic, members)).expect("Serialization failed")
+}
+
+// The owner handing its room to `new_owner`. `generation` counts the transfers of the room, so an
+// old transfer replayed after the room changed hands again doesn't take it back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub generation: u64,
+    pub new_owner: NodeId,
+    signature: Signature,
+}
+
+impl Transfer {
+    pub fn sign(owner: &SecretKey, topic: TopicId, generation: u64, new_owner: NodeId) -> Self {
+        let signature = owner.sign(&transfer_bytes(&topic, generation, &new_owner));
+        Self { generation, new_owner, signature }
+    }
+
+    pub fn verify(&self, owner: &NodeId, topic: &TopicId) -> Result<()> {
+        owner.verify(&transfer_bytes(topic, self.generation, &self.new_owner), &self.signature)?;
+        Ok(())
+    }
+}
+
+fn transfer_bytes(topic: &TopicId, generation: u64, new_owner: &NodeId) -> Vec<u8> {
+    postcard::to_stdvec(&(TRANSFER_DOMAIN, topic, generation, new_owner)).expect("Serialization failed")
+}
+
+// What a moderation message does to its target. postcard encodes variants by position, so new
+// ones go at the end.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ModAction {
+    // Ignore the target until it joins the room again
+    Kick,
+    // Ignore the target for good
+    Ban,
+    Unban,
+    // Make the target a moderator. Only the owner can, and the grant is its signature on it.
+    Appoint(Grant),
+    // Take back an appointment, which the grant alone can't
+    Dismiss,
+}
+
+impl ModAction {
+    // How events and the API call it
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModAction::Kick => "kick",
+            ModAction::Ban => "ban",
+            ModAction::Unban => "unban",
+            ModAction::Appoint(_) => "appoint",
+            ModAction::Dismiss => "dismiss",
+        }
+    }
+}
+
+// The moderation of a room that outlives a session. Kicks don't: they end when the peer rejoins.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Moderation {
+    pub moderators: Vec<Grant>,
+    pub dismissed: Vec<NodeId>,
+    pub banned: Vec<NodeId>,
+    // The revision of the last moderation message applied. Each one carries the next revision,
+    // signed along with the rest of it, so none can be replayed once a later one is in.
+    #[serde(default)]
+    pub revision: u64,
+}
+
+// How long a kick lasts at least, unless the peer left and came back in between
+const KICK_MIN: Duration = Duration::from_secs(10 * 60);
+
+// A peer kicked out of a room, until it rejoins
+#[derive(Debug)]
+struct Kick {
+    at: Instant,
+    // Whether it left since, by saying so or by dropping its connection to us
+    left: bool,
+}
+
+#[derive(Debug)]
+struct Room {
+    owner: NodeId,
+    list: Option<Allowlist>,
+    info: Option<RoomInfo>,
+    publishers: Option<Publishers>,
+    // The transfers we saw, oldest first, to pass on to peers whose ticket names an earlier owner
+    transfers: Vec<Transfer>,
+    moderators: HashMap<NodeId, Grant>,
+    dismissed: HashSet<NodeId>,
+    banned: HashSet<NodeId>,
+    kicked: HashMap<NodeId, Kick>,
+    revision: u64,
+}
+
+// The owners of the rooms we are in and their latest allowlists. Rooms without an owner, or whose
+// owner hasn't restricted them yet, are open to anyone with the key.
+#[derive(Debug, Clone, Default)]
+pub struct Membership(Arc<Mutex<HashMap<TopicId, Room>>>);
+
+impl Membership {
+    // Start tracking a room owned by `owner`, with the list, info and moderation we saved last time
+    pub fn add_room(
+        &self,
+        topic: TopicId,
+        owner: NodeId,
+        list: Option<Allowlist>,
+        info: Option<RoomInfo>,
+        moderation: Moderation,
+    ) {
+        let list = list.filter(|list| list.verify(&owner, &topic).is_ok());
+        let info = info.filter(|info| info.verify(&owner, &topic).is_ok());
+        let moderators = moderation
+            .moderators
+            .into_iter()
+            .filter(|grant| grant.verify(&owner, &topic).is_ok())
+            .map(|grant| (grant.moderator, grant))
+            .collect();
+        let room = Room {
+            owner,
+            list,
+            info,
+            publishers: None,
+            transfers: Vec::new(),
+            moderators,
+            dismissed: moderation.dismissed.into_iter().collect(),
+            banned: moderation.banned.into_iter().collect(),
+            kicked: HashMap::new(),
+            revision: moderation.revision,
+        };
+        self.0.lock().expect("membership lock poisoned").insert(topic, room);
+    }
+
+    pub fn remove_room(&self, topic: &TopicId) {
+        self.0.lock().expect("membership lock poisoned").remove(topic);
+    }
+
+    pub fn owner(&self, topic: &TopicId) -> Option<NodeId> {
+        self.0.lock().expect("membership lock poisoned").get(topic).map(|room| room.owner)
+    }
+
+    pub fn list(&self, topic: &TopicId) -> Option<Allowlist> {
+        self.0.lock().expect("membership lock poisoned").get(topic)?.list.clone()
+    }
+
+    pub fn info(&self, topic: &TopicId) -> Option<RoomInfo> {
+        self.0.lock().expect("membership lock poisoned").get(topic)?.info.clone()
+    }
+
+    pub fn publishers(&self, topic: &TopicId) -> Option<Publishers> {
+        self.0.lock().expect("membership lock poisoned").get(topic)?.publishers.clone()
+    }
+
+    // Whether `node_id` may post chat messages and files in `topic`: anyone can, unless the room is
+    // read-only
+    pub fn may_post(&self, topic: &TopicId, node_id: &NodeId) -> bool {
+        let rooms = self.0.lock().expect("membership lock poisoned");
+        let Some(room) = rooms.get(topic) else {
+            return true;
+        };
+        let publishers = room.publishers.as_ref();
+        room.owner == *node_id || publishers.is_none_or(|publishers| publishers.members.contains(node_id))
+    }
+
+    // Whether to listen to `node_id` in `topic`. The owner can never lock itself out.
+    pub fn allows(&self, topic: &TopicId, node_id: &NodeId) -> bool {
+        let rooms = self.0.lock().expect("membership lock poisoned");
+        let Some(room) = rooms.get(topic) else {
+            return true;
+        };
+        if room.owner == *node_id {
+            return true;
+        }
+        let listed = room.list.as_ref().is_none_or(|list| list.members.contains(node_id));
+        listed && !room.banned.contains(node_id) && !room.kicked.contains_key(node_id)
+    }
+
+    // A kicked peer left the room, by saying so or by dropping its connection
+    pub fn left(&self, topic: &TopicId, node_id: &NodeId) {
+        let mut rooms = self.0.lock().expect("membership lock poisoned");
+        if let Some(kick) = rooms.get_mut(topic).and_then(|room| room.kicked.get_mut(node_id)) {
+            kick.left = true;
+        }
+    }
+
+    // A kicked peer announced itself again, which is all a kick asks of it: once it left in
+    // between, or once the kick is old enough. Announcing itself right away doesn't undo the kick.
+    pub fn rejoined(&self, topic: &TopicId, node_id: &NodeId) {
+        if let Some(room) = self.0.lock().expect("membership lock poisoned").get_mut(topic)
+            && room.kicked.get(node_id).is_some_and(|kick| kick.left || kick.at.elapsed() >= KICK_MIN)
+        {
+            room.kicked.remove(node_id);
+        }
+    }
+
+    // The proof that `node_id` moderates `topic`, if it does
+    pub fn grant(&self, topic: &TopicId, node_id: &NodeId) -> Option<Grant> {
+        let rooms = self.0.lock().expect("membership lock poisoned");
+        let room = rooms.get(topic)?;
+        room.moderators.get(node_id).filter(|_| !room.dismissed.contains(node_id)).cloned()
+    }
+
+    pub fn moderation(&self, topic: &TopicId) -> Option<Moderation> {
+        let rooms = self.0.lock().expect("membership lock poisoned");
+        let room = rooms.get(topic)?;
+        Some(Moderation {
+            moderators: room.moderators.values().cloned().collect(),
+            dismissed: room.dismissed.iter().copied().collect(),
+            banned: room.banned.iter().copied().collect(),
+            revision: room.revision,
+        })
+    }
+
+    // The revision the next moderation message in a room has to carry
+    pub fn next_revision(&self, topic: &TopicId) -> u64 {
+        let rooms = self.0.lock().expect("membership lock poisoned");
+        rooms.get(topic).map_or(1, |room| room.revision + 1)
+    }
+
+    // Apply `action` by `from`, if the owner took it or `signed_by` proves the owner made `from` a
+    // moderator, and `revision` is newer than the last one applied. Returns whether it changed
+    // anything: replays of older moderation don't.
+    pub fn moderate(
+        &self,
+        topic: &TopicId,
+        from: NodeId,
+        action: &ModAction,
+        target: NodeId,
+        signed_by: Option<&Grant>,
+        revision: u64,
+    ) -> Result<bool> {
+        let mut rooms = self.0.lock().expect("membership lock poisoned");
+        let Some(room) = rooms.get_mut(topic) else {
+            bail!("the room has no owner, so nobody can moderate it");
+        };
+        if from != room.owner {
+            let Some(grant) = signed_by.filter(|grant| grant.moderator == from) else {
+                bail!("{} is not a moderator", from.fmt_short());
+            };
+            grant.verify(&room.owner, topic)?;
+            if room.dismissed.contains(&from) || room.banned.contains(&from) {
+                bail!("{} is no longer a moderator", from.fmt_short());
+            }
+            if matches!(action, ModAction::Appoint(_) | ModAction::Dismiss) {
+                bail!("only the owner appoints and dismisses moderators");
+            }
+            if room.moderators.contains_key(&target) {
+                bail!("only the owner can kick or ban a moderator");
+            }
+            room.moderators.entry(from).or_insert_with(|| grant.clone());
+        }
+        if target == room.owner {
+            bail!("nobody can moderate the room's owner");
+        }
+        // Checked after the authority, so a forged revision can't hold back the real ones
+        if revision <= room.revision {
+            return Ok(false);
+        }
+        room.revision = revision;
+        Ok(match action {
+            ModAction::Kick => room.kicked.insert(target, Kick { at: Instant::now(), left: false }).is_none(),
+            ModAction::Ban => room.banned.insert(target),
+            ModAction::Unban => room.banned.remove(&target),
+            ModAction::Appoint(grant) => {
+                if grant.moderator != target {
+                    bail!("the grant is for someone else");
+                }
+                grant.verify(&room.owner, topic)?;
+                let dismissed = room.dismissed.remove(&target);
+                room.moderators.insert(target, grant.clone()).is_none() || dismissed
+            }
+            ModAction::Dismiss => {
+                room.moderators.remove(&target);
+                room.dismissed.insert(target)
+            }
+        })
+    }
+
+    // Take a list someone passed on if the owner signed it and it is newer than ours. Returns
+    // whether it replaced ours. Rooms we don't know an owner for can't check it, so ignore it.
+    pub fn update(&self, topic: &TopicId, list: Allowlist) -> Result<bool> {
+        let mut rooms = self.0.lock().expect("membership lock poisoned");
+        let Some(room) = rooms.get_mut(topic) else {
+            return Ok(false);
+        };
+        // Checked before the signature: members may still pass on lists the previous owner signed
+        if room.list.as_ref().is_some_and(|ours| ours.revision >= list.revision) {
+            return Ok(false);
+        }
+        list.verify(&room.owner, topic)?;
+        room.list = Some(list);
+        Ok(true)
+    }
+
+    // Take room info someone passed on, under the same rules as allowlists
+    pub fn update_info(&self, topic: &TopicId, info: RoomInfo) -> Result<bool> {
+        let mut rooms = self.0.lock().expect("membership lock poisoned");
+        let Some(room) = rooms.get_mut(topic) else {
+            return Ok(false);
+        };
+        if room.info.as_ref().is_some_and(|ours| ours.revision >= info.revision) {
+            return Ok(false);
+        }
+        info.verify(&room.owner, topic)?;
+        room.info = Some(info);
+        Ok(true)
+    }
+
+    // Make a room read-only, if its owner signed the publishers and it isn't already. A new owner
+    // signs the publishers again, which replace the ones its predecessor signed. Returns whether it
+    // changed anything.
+    pub fn update_publishers(&self, topic: &TopicId, publishers: Publishers) -> Result<bool> {
+        let mut rooms = self.0.lock().expect("membership lock poisoned");
+        let Some(room) = rooms.get_mut(topic) else {
+            return Ok(false);
+        };
+        if room.publishers.as_ref().is_some_and(|ours| ours.verify(&room.owner, topic).is_ok()) {
+            return Ok(false);
+        }
+        publishers.verify(&room.owner, topic)?;
+        room.publishers = Some(publishers);
+        Ok(true)
+    }
+
+    pub fn transfers(&self, topic: &TopicId) -> Vec<Transfer> {
+        let rooms = self.0.lock().expect("membership lock poisoned");
+        rooms.get(topic).map(|room| room.transfers.clone()).unwrap_or_default()
+    }
+
+    // The generation the next transfer of a room has to carry
+    pub fn next_generation(&self, topic: &TopicId) -> u64 {
+        let rooms = self.0.lock().expect("membership lock poisoned");
+        rooms.get(topic).and_then(|room| room.transfers.last()).map_or(1, |last| last.generation + 1)
+    }
+
+    // Hand a room to a new owner, if its current owner signed the transfer and it is newer than the
+    // last one we saw. Returns the previous owner if it changed hands.
+    pub fn transfer(&self, topic: &TopicId, transfer: Transfer) -> Result<Option<NodeId>> {
+        let mut rooms = self.0.lock().expect("membership lock poisoned");
+        let Some(room) = rooms.get_mut(topic) else {
+            return Ok(None);
+        };
+        if room.transfers.last().is_some_and(|last| last.generation >= transfer.generation) {
+            return Ok(None);
+        }
+        transfer.verify(&room.owner, topic)?;
+        let previous = std::mem::replace(&mut room.owner, transfer.new_owner);
+        room.banned.remove(&transfer.new_owner);
+        room.kicked.remove(&transfer.new_owner);
+        room.transfers.push(transfer);
+        Ok(Some(previous))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPIC: TopicId = TopicId::from_bytes([1; 32]);
+
+    fn key(seed: u8) -> SecretKey {
+        SecretKey::from_bytes(&[seed; 32])
+    }
+
+    // A room owned by `owner`, with no list or moderation yet
+    fn room(owner: &SecretKey) -> Membership {
+        let membership = Membership::default();
+        membership.add_room(TOPIC, owner.public(), None, None, Moderation::default());
+        membership
+    }
+
+    #[test]
+    fn drops_replayed_moderation() {
+        let owner = key(1);
+        let (peer, moderator) = (key(2).public(), key(3).public());
+        let membership = room(&owner);
+        let us = owner.public();
+        assert!(membership.moderate(&TOPIC, us, &ModAction::Ban, peer, None, 1).unwrap());
+        assert!(membership.moderate(&TOPIC, us, &ModAction::Unban, peer, None, 2).unwrap());
+        assert!(membership.moderate(&TOPIC, us, &ModAction::Ban, peer, None, 3).unwrap());
+        // The banned peer passing the owner's unban on again doesn't get it back in
+        assert!(!membership.moderate(&TOPIC, us, &ModAction::Unban, peer, None, 2).unwrap());
+        assert!(!membership.allows(&TOPIC, &peer));
+
+        // Nor does a dismissed moderator replaying its appointment
+        let appoint = ModAction::Appoint(Grant::sign(&owner, TOPIC, moderator));
+        assert!(membership.moderate(&TOPIC, us, &appoint, moderator, None, 4).unwrap());
+        assert!(membership.moderate(&TOPIC, us, &ModAction::Dismiss, moderator, None, 5).unwrap());
+        assert!(!membership.moderate(&TOPIC, us, &appoint, moderator, None, 4).unwrap());
+        assert!(membership.grant(&TOPIC, &moderator).is_none());
+
+        // The revision comes back after a restart, and the next one is past it
+        let saved = membership.moderation(&TOPIC).unwrap();
+        let restarted = Membership::default();
+        restarted.add_room(TOPIC, us, None, None, saved);
+        assert!(!restarted.moderate(&TOPIC, us, &appoint, moderator, None, 4).unwrap());
+        assert_eq!(restarted.next_revision(&TOPIC), 6);
+    }
+
+    #[test]
+    fn forged_moderation_does_not_hold_back_the_owner() {
+        let owner = key(1);
+        let (peer, mallory) = (key(2).public(), key(4).public());
+        let membership = room(&owner);
+        // Not a moderator, so its revision doesn't count either
+        assert!(membership.moderate(&TOPIC, mallory, &ModAction::Ban, peer, None, u64::MAX).is_err());
+        assert_eq!(membership.next_revision(&TOPIC), 1);
+        assert!(membership.moderate(&TOPIC, owner.public(), &ModAction::Ban, peer, None, 1).unwrap());
+    }
+
+    #[test]
+    fn drops_stale_and_forged_revisions() {
+        let (owner, moderator, mallory) = (key(1), key(3), key(4));
+        let peer = key(2).public();
+        let membership = room(&owner);
+        let us = owner.public();
+        let grant = Grant::sign(&owner, TOPIC, moderator.public());
+        let appoint = ModAction::Appoint(grant.clone());
+        assert!(membership.moderate(&TOPIC, us, &appoint, moderator.public(), None, 1).unwrap());
+        assert!(membership.moderate(&TOPIC, moderator.public(), &ModAction::Ban, peer, Some(&grant), 2).unwrap());
+        // Behind what was applied already, or the same revision again
+        let stale = membership.moderate(&TOPIC, moderator.public(), &ModAction::Unban, peer, Some(&grant), 1);
+        assert!(!stale.unwrap());
+        assert!(!membership.moderate(&TOPIC, us, &ModAction::Unban, peer, None, 2).unwrap());
+        assert!(!membership.allows(&TOPIC, &peer));
+        // A grant mallory signed itself, or the moderator's grant claimed for mallory
+        let forged = Grant::sign(&mallory, TOPIC, mallory.public());
+        let unban = membership.moderate(&TOPIC, mallory.public(), &ModAction::Unban, peer, Some(&forged), 10);
+        assert!(unban.is_err());
+        let unban = membership.moderate(&TOPIC, mallory.public(), &ModAction::Unban, peer, Some(&grant), 10);
+        assert!(unban.is_err());
+        assert_eq!(membership.next_revision(&TOPIC), 3);
+        assert!(!membership.allows(&TOPIC, &peer));
+    }
+
+    #[test]
+    fn only_the_owner_appoints_and_dismisses() {
+        let (owner, moderator, mallory) = (key(1), key(3), key(4));
+        let membership = room(&owner);
+        let grant = Grant::sign(&owner, TOPIC, moderator.public());
+        let appoint = ModAction::Appoint(grant.clone());
+        assert!(membership.moderate(&TOPIC, owner.public(), &appoint, moderator.public(), None, 1).unwrap());
+        // A moderator can't make others moderators, even with a grant the owner really signed for them
+        let theirs = ModAction::Appoint(Grant::sign(&owner, TOPIC, mallory.public()));
+        let appointed = membership.moderate(&TOPIC, moderator.public(), &theirs, mallory.public(), Some(&grant), 2);
+        assert!(appointed.is_err());
+        assert!(membership.grant(&TOPIC, &mallory.public()).is_none());
+        // Nor can a peer with a grant it signed itself, or take back the owner's appointments
+        let (own, them) = (Grant::sign(&mallory, TOPIC, mallory.public()), mallory.public());
+        let appointed = membership.moderate(&TOPIC, them, &ModAction::Appoint(own.clone()), them, None, 3);
+        assert!(appointed.is_err());
+        let dismissed = membership.moderate(&TOPIC, them, &ModAction::Dismiss, moderator.public(), Some(&own), 3);
+        assert!(dismissed.is_err());
+        assert!(membership.grant(&TOPIC, &moderator.public()).is_some());
+    }
+
+    #[test]
+    fn moderators_cannot_kick_each_other() {
+        let (owner, alice, bob) = (key(1), key(3), key(4));
+        let membership = room(&owner);
+        let us = owner.public();
+        let (alices, bobs) = (Grant::sign(&owner, TOPIC, alice.public()), Grant::sign(&owner, TOPIC, bob.public()));
+        let appoint = ModAction::Appoint(alices.clone());
+        assert!(membership.moderate(&TOPIC, us, &appoint, alice.public(), None, 1).unwrap());
+        assert!(membership.moderate(&TOPIC, us, &ModAction::Appoint(bobs), bob.public(), None, 2).unwrap());
+        for action in [ModAction::Kick, ModAction::Ban] {
+            let moderated = membership.moderate(&TOPIC, alice.public(), &action, bob.public(), Some(&alices), 3);
+            assert!(moderated.is_err());
+        }
+        // Or the owner
+        assert!(membership.moderate(&TOPIC, alice.public(), &ModAction::Kick, us, Some(&alices), 3).is_err());
+        assert!(membership.allows(&TOPIC, &bob.public()) && membership.allows(&TOPIC, &us));
+        // The owner can
+        assert!(membership.moderate(&TOPIC, us, &ModAction::Kick, bob.public(), None, 3).unwrap());
+        assert!(!membership.allows(&TOPIC, &bob.public()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn kicks_last_until_the_peer_left_and_came_back() {
+        let owner = key(1);
+        let (peer, other) = (key(2).public(), key(3).public());
+        let membership = room(&owner);
+        let us = owner.public();
+        membership.moderate(&TOPIC, us, &ModAction::Kick, peer, None, 1).unwrap();
+        membership.moderate(&TOPIC, us, &ModAction::Kick, other, None, 2).unwrap();
+        // Announcing itself again right away doesn't undo the kick
+        membership.rejoined(&TOPIC, &peer);
+        assert!(!membership.allows(&TOPIC, &peer));
+        // Leaving first does
+        membership.left(&TOPIC, &peer);
+        membership.rejoined(&TOPIC, &peer);
+        assert!(membership.allows(&TOPIC, &peer));
+        // And so does waiting long enough
+        tokio::time::advance(KICK_MIN - Duration::from_secs(1)).await;
+        membership.rejoined(&TOPIC, &other);
+        assert!(!membership.allows(&TOPIC, &other));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        membership.rejoined(&TOPIC, &other);
+        assert!(membership.allows(&TOPIC, &other));
+    }
+}
@@ -0,0 +1,33 @@
+use iroh::NodeId;
+use std::ops::Range;
+
+// Where a text @mentions someone: its `@name` tokens, the @ included. Names go on until a space or
+// punctuation other than `_ - . #`, and a `.` at their end closes the sentence instead.
+pub fn mentions(text: &str) -> Vec<Range<usize>> {
+    let name_char = |c: char| c.is_alphanumeric() || "_-.#".contains(c);
+    let mut found = Vec::new();
+    for (at, _) in text.match_indices('@') {
+        // Like in e-mail addresses, an @ right after a word doesn't start a mention
+        if text[..at].chars().next_back().is_some_and(char::is_alphanumeric) {
+            continue;
+        }
+        let rest = &text[at + 1..];
+        let len = rest.find(|c: char| !name_char(c)).unwrap_or(rest.len());
+        let len = rest[..len].trim_end_matches('.').len();
+        if len > 0 {
+            found.push(at..at + 1 + len);
+        }
+    }
+    found
+}
+
+// Whether a mention, without its @, names `name`: in any case, and maybe with a suffix `node_id`
+// starts with, like alice#3f2a
+pub fn is_of(mention: &str, name: &str, node_id: &NodeId) -> bool {
+    let same = |mention: &str| mention.to_lowercase() == name.to_lowercase();
+    match mention.rsplit_once('#') {
+        _ if same(mention) => true,
+        Some((mention, suffix)) => !suffix.is_empty() && same(mention) && node_id.to_string().starts_with(suffix),
+        None => false,
+    }
+}
@@ -0,0 +1,399 @@
+use crate::code;
+use crate::crypto::RoomKey;
+use crate::devices::DeviceCert;
+use crate::location;
+use crate::membership::{Allowlist, Grant, ModAction, Publishers, RoomInfo, Transfer};
+use crate::presence::Status;
+use anyhow::{bail, Result};
+use data_encoding::HEXLOWER;
+use iroh::{NodeAddr, NodeId, PublicKey, SecretKey};
+use iroh_base::Signature;
+use iroh_blobs::Hash;
+use iroh_gossip::proto::TopicId;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Version of the wire format, the first byte of every decrypted message
+const WIRE_VERSION: u8 = 2;
+
+// Serialized messages larger than this are sent zstd-compressed
+const COMPRESS_THRESHOLD: usize = 1024;
+
+// Refuse to decompress messages that would grow beyond this
+const MAX_DECOMPRESSED_SIZE: usize = 1024 * 1024;
+
+// How far, in milliseconds, the time a message says it was sent may be from when it arrived
+// before we stop believing the sender's clock
+const MAX_CLOCK_SKEW: i64 = 5 * 60 * 1000;
+
+// Random per-message id. It doubles as the nonce that keeps gossip from deduplicating equal
+// messages, and lets later messages (acks, ...) refer back to this one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct MessageId([u8; 16]);
+
+impl MessageId {
+    pub fn random() -> Self {
+        Self(random())
+    }
+
+    // The first 6 hex characters, enough to tell messages in a room apart
+    pub fn fmt_short(&self) -> String {
+        HEXLOWER.encode(&self.0[..3])
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", HEXLOWER.encode(&self.0))
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = HEXLOWER.decode(s.as_bytes())?;
+        match bytes.try_into() {
+            Ok(bytes) => Ok(Self(bytes)),
+            Err(_) => bail!("message ids are 32 hex characters"),
+        }
+    }
+}
+
+// What an Ack confirms about a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AckKind {
+    Delivered,
+    Read,
+}
+
+// postcard encodes variants by position, so new ones go at the end
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MessageBody {
+    AboutMe { from: NodeId, name: String },
+    Message { from: NodeId, text: String },
+    FileOffer { from: NodeId, hash: Hash, name: String, size: u64 },
+    // Periodic heartbeat so peers know we are still online
+    Presence { from: NodeId, name: String },
+    // Sent now and then while composing. Like Presence it is ephemeral: never stored or replayed.
+    Typing { from: NodeId },
+    // Tells the author of message `id` it reached us, or that we read it. Ephemeral as well.
+    Ack { from: NodeId, id: MessageId, kind: AckKind },
+    Reaction { from: NodeId, target_id: MessageId, emoji: String },
+    // Replaces the text of one of the sender's own messages
+    Edit { from: NodeId, target_id: MessageId, new_text: String },
+    // Retracts one of the sender's own messages
+    Delete { from: NodeId, target_id: MessageId },
+    // Sent when joining a room, and in answer to the first Hello from each peer, so peers on
+    // incompatible versions notice each other
+    Hello { from: NodeId, proto_version: u32, features: Vec<String> },
+    // Part `index` of `total` of the encrypted message `message_id`, which was too large to gossip
+    Chunk { from: NodeId, message_id: MessageId, index: u32, total: u32, data: Vec<u8> },
+    // Sent when leaving a room or shutting down, so peers don't wait for us to time out
+    Leave { from: NodeId },
+    // The room owner's allowlist, passed on by any member. `list` carries the owner's signature.
+    Allowlist { from: NodeId, list: Allowlist },
+    // A kick, ban or appointment by the room owner, or by a moderator whose grant is `signed_by`.
+    // `revision` is the room's next moderation revision, so the message can't be replayed later.
+    Moderation { from: NodeId, action: ModAction, target: NodeId, signed_by: Option<Grant>, revision: u64 },
+    // The room's name, description and topic, signed by its owner and passed on like Allowlist
+    RoomInfo { from: NodeId, info: RoomInfo },
+    // A public room announced on the directory topic, with a ticket to join it
+    Listing { from: NodeId, name: String, description: String, ticket: String },
+    // The owner moved the room to `topic`. The new key isn't in here: members fetch it from the owner.
+    Migrate { from: NodeId, topic: TopicId },
+    // Who may post in a read-only room, signed by its owner and passed on like Allowlist
+    Publishers { from: NodeId, publishers: Publishers },
+    // The owner handing the room to someone else, passed on like Allowlist
+    OwnershipTransfer { from: NodeId, transfer: Transfer },
+    // The heads of the sender's log, sent on meeting new neighbors so whoever is missing any of
+    // them can fetch them from the sender
+    Heads { from: NodeId, heads: Vec<MessageId> },
+    // A linked device showing which account it speaks for, sent along with its Hello
+    Device { from: NodeId, cert: DeviceCert },
+    // A directory shared as an iroh-blobs collection of its `files` files, `size` bytes in all
+    DirectoryOffer { from: NodeId, hash: Hash, name: String, size: u64, files: u64 },
+    // A chat message too long to gossip, stored as a blob of `size` bytes. Only its start comes
+    // along, the rest is fetched from the sender on demand.
+    LongText { from: NodeId, hash: Hash, size: u64, preview: String },
+    // A chat message that is a code snippet in language `lang`, shown with its lines as they are
+    Code { from: NodeId, lang: String, text: String },
+    // Someone the sender knows, under the name it goes by, and where the sender last reached it
+    ContactCard { from: NodeId, node_id: NodeId, name: String, addr: Option<NodeAddr> },
+    // A place on the map, in degrees, with what the sender calls it
+    Location { from: NodeId, lat: f64, lon: f64, label: String },
+    // Whether the sender is away, sent with its heartbeats while it is and once when it is back.
+    // Ephemeral like Presence.
+    Status { from: NodeId, status: Status },
+    // Where some of the room's members, the sender included, can be reached, sent now and then so
+    // the room holds together without the peers in its ticket. Ephemeral like Presence.
+    Peers { from: NodeId, peers: Vec<NodeAddr> },
+}
+
+impl MessageBody {
+    pub fn from(&self) -> NodeId {
+        match self {
+            MessageBody::AboutMe { from, .. } => *from,
+            MessageBody::Message { from, .. } => *from,
+            MessageBody::FileOffer { from, .. } => *from,
+            MessageBody::Presence { from, .. } => *from,
+            MessageBody::Typing { from } => *from,
+            MessageBody::Ack { from, .. } => *from,
+            MessageBody::Reaction { from, .. } => *from,
+            MessageBody::Edit { from, .. } => *from,
+            MessageBody::Delete { from, .. } => *from,
+            MessageBody::Hello { from, .. } => *from,
+            MessageBody::Leave { from } => *from,
+            MessageBody::Chunk { from, .. } => *from,
+            MessageBody::Allowlist { from, .. } => *from,
+            MessageBody::Moderation { from, .. } => *from,
+            MessageBody::RoomInfo { from, .. } => *from,
+            MessageBody::Listing { from, .. } => *from,
+            MessageBody::Migrate { from, .. } => *from,
+            MessageBody::Publishers { from, .. } => *from,
+            MessageBody::OwnershipTransfer { from, .. } => *from,
+            MessageBody::Heads { from, .. } => *from,
+            MessageBody::Device { from, .. } => *from,
+            MessageBody::DirectoryOffer { from, .. } => *from,
+            MessageBody::LongText { from, .. } => *from,
+            MessageBody::Code { from, .. } => *from,
+            MessageBody::ContactCard { from, .. } => *from,
+            MessageBody::Location { from, .. } => *from,
+            MessageBody::Status { from, .. } => *from,
+            MessageBody::Peers { from, .. } => *from,
+        }
+    }
+
+    // The text chat messages are shown and kept in history with, for the kinds that are one
+    pub fn text(&self) -> Option<String> {
+        match self {
+            MessageBody::Message { text, .. } => Some(text.clone()),
+            MessageBody::Code { lang, text, .. } => Some(code::fence(lang, text)),
+            MessageBody::Location { lat, lon, label, .. } => Some(location::describe(*lat, *lon, label)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub body: MessageBody,
+    pub id: MessageId,
+    // The message this one answers, if it is a reply
+    pub reply_to: Option<MessageId>,
+    // Lamport clock of the sender's room, set on chat messages to order them the same everywhere
+    pub clock: u64,
+    // The heads of the room's log the sender had, set on chat messages. Receivers missing any of
+    // them fetch them from the sender.
+    pub parents: Vec<MessageId>,
+    // When the sender sent it by its own clock, in milliseconds since the Unix epoch. Set on chat
+    // messages, and 0 on the rest.
+    pub timestamp: i64,
+    // Sent by a plugin rather than typed, so plugins receiving it leave it alone
+    pub automated: bool,
+}
+
+// When a message with `timestamp` says it was sent, or when it was `received` if it doesn't say,
+// and whether that is too far from `received` to be true
+pub fn sent(timestamp: i64, received: i64) -> (i64, bool) {
+    match timestamp {
+        0 => (received, false),
+        sent => (sent, (sent - received).abs() > MAX_CLOCK_SKEW),
+    }
+}
+
+impl Message {
+    pub fn new(body: MessageBody) -> Self {
+        Self {
+            body,
+            id: MessageId::random(),
+            reply_to: None,
+            clock: 0,
+            parents: Vec::new(),
+            timestamp: 0,
+            automated: false,
+        }
+    }
+
+    pub fn reply(body: MessageBody, to: MessageId) -> Self {
+        Self { reply_to: Some(to), ..Self::new(body) }
+    }
+
+    pub fn automated(body: MessageBody) -> Self {
+        Self { automated: true, ..Self::new(body) }
+    }
+
+    // Encrypt the signed envelope with the room key
+    pub fn to_bytes(&self, secret_key: &SecretKey, key: &RoomKey) -> Vec<u8> {
+        key.encrypt(&self.sign(secret_key))
+    }
+
+    // Sign the serialized (and, if large, compressed) message so receivers can check it really
+    // came from `from`: the version byte and signed envelope. History keeps it as it is, so peers
+    // fetching history can check it too.
+    pub fn sign(&self, secret_key: &SecretKey) -> Vec<u8> {
+        let mut data = postcard::to_stdvec(self).expect("Serialization failed");
+        let mut compressed = false;
+        if data.len() > COMPRESS_THRESHOLD {
+            let packed = zstd::bulk::compress(&data, 0).expect("Compression failed");
+            if packed.len() < data.len() {
+                data = packed;
+                compressed = true;
+            }
+        }
+        let signature = secret_key.sign(&data);
+        let signed = SignedMessage { from: secret_key.public(), data, signature, compressed };
+        let mut framed = vec![WIRE_VERSION];
+        framed.extend(postcard::to_stdvec(&signed).expect("Serialization failed"));
+        framed
+    }
+
+    pub fn from_bytes(bytes: &[u8], key: &RoomKey) -> Result<Self> {
+        Ok(Self::open(bytes, key)?.0)
+    }
+
+    // Decrypt and check a message, keeping the envelope it was signed in
+    pub fn open(bytes: &[u8], key: &RoomKey) -> Result<(Self, Vec<u8>)> {
+        let framed = key.decrypt(bytes)?;
+        let message = Self::verify(&framed)?;
+        Ok((message, framed))
+    }
+
+    // Check the signature on an envelope from `sign` and read the message in it
+    pub fn verify(framed: &[u8]) -> Result<Self> {
+        let signed: SignedMessage = match framed.split_first() {
+            Some((&WIRE_VERSION, rest)) => postcard::from_bytes(rest)?,
+            Some((version, _)) => bail!("unsupported wire format version {}", version),
+            None => bail!("empty message"),
+        };
+        // Check the signature before decompressing anything
+        signed.from.verify(&signed.data, &signed.signature)?;
+        let data = match signed.compressed {
+            true => zstd::bulk::decompress(&signed.data, MAX_DECOMPRESSED_SIZE)?,
+            false => signed.data,
+        };
+        let message: Self = postcard::from_bytes(&data)?;
+        if message.body.from() != signed.from {
+            bail!(
+                "claims to be from {} but was signed by {}",
+                message.body.from().fmt_short(),
+                signed.from.fmt_short()
+            );
+        }
+        Ok(message)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedMessage {
+    from: PublicKey,
+    data: Vec<u8>,
+    signature: Signature,
+    // `data` is zstd-compressed
+    compressed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(secret_key: &SecretKey, text: &str) -> Message {
+        Message::new(MessageBody::Message { from: secret_key.public(), text: text.to_string() })
+    }
+
+    fn text_of(message: &Message) -> &str {
+        match &message.body {
+            MessageBody::Message { text, .. } => text,
+            body => panic!("not a chat message: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let secret_key = SecretKey::from_bytes(&[1; 32]);
+        let key = RoomKey::generate();
+        let mut message = text(&secret_key, "hello");
+        message.clock = 7;
+        message.timestamp = 1_700_000_000_000;
+        let bytes = message.to_bytes(&secret_key, &key);
+        let decoded = Message::from_bytes(&bytes, &key).unwrap();
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.clock, 7);
+        assert_eq!(decoded.timestamp, 1_700_000_000_000);
+        assert_eq!(text_of(&decoded), "hello");
+        assert_eq!(key.decrypt(&bytes).unwrap()[0], WIRE_VERSION);
+    }
+
+    #[test]
+    fn compresses_large_messages() {
+        let secret_key = SecretKey::from_bytes(&[1; 32]);
+        let key = RoomKey::generate();
+        let long = "all work and no play ".repeat(200);
+        let bytes = text(&secret_key, &long).to_bytes(&secret_key, &key);
+        assert_eq!(key.decrypt(&bytes).unwrap()[0], WIRE_VERSION);
+        assert!(bytes.len() < long.len());
+        assert_eq!(text_of(&Message::from_bytes(&bytes, &key).unwrap()), long);
+    }
+
+    #[test]
+    fn marks_automated_messages() {
+        let secret_key = SecretKey::from_bytes(&[1; 32]);
+        let key = RoomKey::generate();
+        let automated = Message::automated(MessageBody::Typing { from: secret_key.public() });
+        let bytes = automated.to_bytes(&secret_key, &key);
+        assert!(Message::from_bytes(&bytes, &key).unwrap().automated);
+        assert!(!Message::from_bytes(&text(&secret_key, "hi").to_bytes(&secret_key, &key), &key).unwrap().automated);
+    }
+
+    // Sign `message` as `signer` but put `from` on the envelope
+    fn forged(message: &Message, signer: &SecretKey, from: PublicKey, key: &RoomKey) -> Vec<u8> {
+        let data = postcard::to_stdvec(message).unwrap();
+        let signature = signer.sign(&data);
+        let mut framed = vec![WIRE_VERSION];
+        let signed = SignedMessage { from, data, signature, compressed: false };
+        framed.extend(postcard::to_stdvec(&signed).unwrap());
+        key.encrypt(&framed)
+    }
+
+    #[test]
+    fn verifies_signature() {
+        let alice = SecretKey::from_bytes(&[1; 32]);
+        let mallory = SecretKey::from_bytes(&[2; 32]);
+        let key = RoomKey::generate();
+        let message = text(&alice, "hi");
+        assert!(Message::from_bytes(&forged(&message, &alice, alice.public(), &key), &key).is_ok());
+        // Signed by someone other than the envelope says
+        assert!(Message::from_bytes(&forged(&message, &mallory, alice.public(), &key), &key).is_err());
+        // Signed properly, but the body claims someone else sent it
+        assert!(Message::from_bytes(&forged(&message, &mallory, mallory.public(), &key), &key).is_err());
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let secret_key = SecretKey::from_bytes(&[1; 32]);
+        let key = RoomKey::generate();
+        let message = text(&secret_key, "hi");
+        let mut bytes = message.to_bytes(&secret_key, &key);
+        assert!(Message::from_bytes(&bytes, &RoomKey::generate()).is_err());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(Message::from_bytes(&bytes, &key).is_err());
+
+        // Data changed after signing, under a valid room key
+        let mut data = postcard::to_stdvec(&message).unwrap();
+        let signature = secret_key.sign(&data);
+        *data.last_mut().unwrap() ^= 1;
+        let mut framed = vec![WIRE_VERSION];
+        let signed = SignedMessage { from: secret_key.public(), data, signature, compressed: false };
+        framed.extend(postcard::to_stdvec(&signed).unwrap());
+        assert!(Message::from_bytes(&key.encrypt(&framed), &key).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_wire_version() {
+        let key = RoomKey::generate();
+        assert!(Message::from_bytes(&key.encrypt(&[9, 0, 0]), &key).is_err());
+        assert!(Message::from_bytes(&key.encrypt(&[]), &key).is_err());
+    }
+}
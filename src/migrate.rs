@@ -0,0 +1,101 @@
+use crate::crypto::RoomKey;
+use crate::membership::Membership;
+use anyhow::{bail, Result};
+use iroh::endpoint::Connection;
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const ALPN: &[u8] = b"iroh-chat/migrate/0";
+
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+// A room its owner moved to a fresh topic, as announced in the old one
+#[derive(Debug, Clone, Copy)]
+pub struct Move {
+    pub from: TopicId,
+    pub to: TopicId,
+    pub owner: NodeId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Fetch {
+    topic: TopicId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Answer {
+    Key(RoomKey),
+    Refused(String),
+}
+
+// The new keys of the rooms we rotated, by their new topic. The move is announced under the old
+// key, which whoever we want out still has, so the new key is never gossiped: members fetch it
+// from us, and we only hand it to peers the room's allowlist and bans still let in.
+#[derive(Debug, Clone)]
+pub struct MigrateProtocol {
+    keys: Arc<Mutex<HashMap<TopicId, RoomKey>>>,
+    membership: Membership,
+    us: NodeId,
+}
+
+impl MigrateProtocol {
+    pub fn new(membership: Membership, us: NodeId) -> Self {
+        Self { keys: Default::default(), membership, us }
+    }
+
+    pub fn add_room(&self, topic: TopicId, key: RoomKey) {
+        self.keys.lock().expect("migrations lock poisoned").insert(topic, key);
+    }
+
+    fn key_for(&self, topic: &TopicId, node_id: &NodeId) -> Result<RoomKey> {
+        let key = self.keys.lock().expect("migrations lock poisoned").get(topic).copied();
+        // Only while we are still in the room as its owner, or an unknown room would let anyone in
+        let Some(key) = key.filter(|_| self.membership.owner(topic) == Some(self.us)) else {
+            bail!("no room moved to {}", topic.fmt_short());
+        };
+        if !self.membership.allows(topic, node_id) {
+            bail!("you are not a member of the room anymore");
+        }
+        Ok(key)
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        let from = conn.remote_node_id()?;
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let request: Fetch = serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?;
+        let answer = match self.key_for(&request.topic, &from) {
+            Ok(key) => Answer::Key(key),
+            Err(err) => Answer::Refused(err.to_string()),
+        };
+        send.write_all(&serde_json::to_vec(&answer)?).await?;
+        send.finish()?;
+        conn.closed().await;
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for MigrateProtocol {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
+
+// Ask the owner of a room that moved for the key of its new topic
+pub async fn fetch(endpoint: &Endpoint, owner: NodeAddr, topic: TopicId) -> Result<RoomKey> {
+    let conn = endpoint.connect(owner, ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&serde_json::to_vec(&Fetch { topic })?).await?;
+    send.finish()?;
+    let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+    conn.close(0u32.into(), b"done");
+    match serde_json::from_slice(&bytes)? {
+        Answer::Key(key) => Ok(key),
+        Answer::Refused(reason) => bail!("the owner refused: {}", reason),
+    }
+}
@@ -0,0 +1,180 @@
+use crate::contacts::Contacts;
+use crate::devices::Devices;
+use crate::mention;
+use iroh::NodeId;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+// How many hex characters of the node id tell apart peers using the same name
+const SUFFIX_LEN: usize = 4;
+
+// What a peer's announcement meant for the names we know
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim {
+    // We hadn't heard of the peer before
+    New,
+    // Nothing changed, or the peer renamed itself to a name nobody else uses
+    Known,
+    // Someone else already goes by this name, so both are shown with a suffix from now on
+    Clash,
+}
+
+// The names peers announced, bound to the node ids that signed the announcements. Names aren't
+// unique, so anyone can claim to be "alice"; when two nodes do, both are shown with the start of
+// their node id (alice#3f2a) and can be addressed that way. Linked devices go by the name of
+// their account.
+#[derive(Debug, Clone)]
+pub struct Names {
+    // Ours, which /nick changes for the rest of the session
+    our_name: Arc<Mutex<String>>,
+    names: Arc<Mutex<HashMap<NodeId, String>>>,
+    // Peers using a name pinned to someone else, shown with a suffix even when alone
+    disputed: Arc<Mutex<HashSet<NodeId>>>,
+    // Our aliases for peers, which beat the names they announce
+    contacts: Contacts,
+    devices: Devices,
+}
+
+impl Names {
+    pub fn new(us: NodeId, our_name: String) -> Self {
+        Self {
+            our_name: Arc::new(Mutex::new(our_name)),
+            names: Default::default(),
+            disputed: Default::default(),
+            contacts: Default::default(),
+            devices: Devices::new(us),
+        }
+    }
+
+    pub fn ours(&self) -> String {
+        self.our_name.lock().expect("names lock poisoned").clone()
+    }
+
+    // Go by another name, returning the old one
+    pub fn rename_us(&self, name: String) -> String {
+        std::mem::replace(&mut *self.our_name.lock().expect("names lock poisoned"), name)
+    }
+
+    // Whether an @mention, without the @, is of us
+    pub fn is_us(&self, mention: &str) -> bool {
+        mention::is_of(mention, &self.ours(), &self.devices.account())
+    }
+
+    pub fn claim(&self, node_id: NodeId, name: &str) -> Claim {
+        let node_id = self.devices.account_of(&node_id);
+        // Our other devices go by our name
+        if node_id == self.devices.account() {
+            return Claim::Known;
+        }
+        let mut names = self.names.lock().expect("names lock poisoned");
+        let previous = names.insert(node_id, name.to_string());
+        if previous.as_deref() == Some(name) {
+            return Claim::Known;
+        }
+        let taken = name == self.ours()
+            || names.iter().any(|(id, other)| *id != node_id && other == name)
+            || self.contacts.find(name).is_some_and(|contact| contact != node_id);
+        match (taken, previous) {
+            (true, _) => Claim::Clash,
+            (false, None) => Claim::New,
+            (false, Some(_)) => Claim::Known,
+        }
+    }
+
+    // The name a peer announced, as it announced it
+    pub fn get(&self, node_id: &NodeId) -> Option<String> {
+        let node_id = &self.devices.account_of(node_id);
+        self.names.lock().expect("names lock poisoned").get(node_id).cloned()
+    }
+
+    pub fn contacts(&self) -> Contacts {
+        self.contacts.clone()
+    }
+
+    pub fn devices(&self) -> Devices {
+        self.devices.clone()
+    }
+
+    // A device turned out to speak for an account: the name it claimed is the account's now
+    pub fn linked(&self, device: &NodeId) {
+        let account = self.devices.account_of(device);
+        let mut names = self.names.lock().expect("names lock poisoned");
+        if let Some(name) = names.remove(device)
+            && account != self.devices.account()
+        {
+            names.entry(account).or_insert(name);
+        }
+    }
+
+    // How to show a peer: our alias for it, its name, with a suffix if someone else uses it too, or
+    // its short node id if it never told us its name
+    pub fn display(&self, node_id: &NodeId) -> String {
+        let node_id = &self.devices.account_of(node_id);
+        if *node_id == self.devices.account() {
+            return self.ours();
+        }
+        if let Some(alias) = self.contacts.alias(node_id) {
+            return alias;
+        }
+        let names = self.names.lock().expect("names lock poisoned");
+        match names.get(node_id) {
+            Some(name) if self.ambiguous(&names, name) || self.is_disputed(node_id) => suffixed(name, node_id),
+            Some(name) => name.clone(),
+            None => node_id.fmt_short(),
+        }
+    }
+
+    // Show a peer with a suffix until `undispute`
+    pub fn dispute(&self, node_id: NodeId) {
+        let node_id = self.devices.account_of(&node_id);
+        self.disputed.lock().expect("names lock poisoned").insert(node_id);
+    }
+
+    pub fn undispute(&self, node_id: &NodeId) {
+        let node_id = &self.devices.account_of(node_id);
+        self.disputed.lock().expect("names lock poisoned").remove(node_id);
+    }
+
+    fn is_disputed(&self, node_id: &NodeId) -> bool {
+        self.disputed.lock().expect("names lock poisoned").contains(node_id)
+    }
+
+    fn ambiguous(&self, names: &HashMap<NodeId, String>, name: &str) -> bool {
+        name == self.ours()
+            || names.values().filter(|other| *other == name).count() > 1
+            || self.contacts.find(name).is_some()
+    }
+
+    // Everyone we know, by how they are shown
+    pub fn list(&self) -> Vec<(NodeId, String)> {
+        let ids: Vec<_> = self.names.lock().expect("names lock poisoned").keys().copied().collect();
+        ids.into_iter().map(|id| (id, self.display(&id))).collect()
+    }
+
+    // The peers going by `query`: an alias, a name, or a suffixed one like alice#3f2a
+    pub fn find(&self, query: &str) -> Vec<NodeId> {
+        if let Some(contact) = self.contacts.find(query) {
+            return vec![contact];
+        }
+        let names = self.names.lock().expect("names lock poisoned");
+        // Names may contain # themselves
+        let (name, suffix) = match query.rsplit_once('#') {
+            Some((name, suffix)) => (name, Some(suffix)),
+            None => (query, None),
+        };
+        names
+            .iter()
+            .filter(|(id, other)| {
+                let suffixed =
+                    suffix.is_some_and(|suffix| other.as_str() == name && id.to_string().starts_with(suffix));
+                other.as_str() == query || suffixed
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+// "alice#3f2a"
+pub fn suffixed(name: &str, node_id: &NodeId) -> String {
+    format!("{}#{}", name, &node_id.to_string()[..SUFFIX_LEN])
+}
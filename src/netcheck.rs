@@ -0,0 +1,121 @@
+use crate::ping::{self, Latency};
+use anyhow::{bail, Result};
+use iroh::dns::N0_DNS_NODE_ORIGIN_PROD;
+use iroh::endpoint::ConnectionType;
+use iroh::{Endpoint, NodeId, RelayUrl, Watcher};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+use tokio::time::{sleep, timeout, Instant};
+
+// How long the endpoint gets to find a relay and finish its first report on the network
+const REPORT_TIMEOUT: Duration = Duration::from_secs(15);
+
+// How long n0's DNS gets to serve what we published, asking every PUBLISH_POLL
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+const PUBLISH_POLL: Duration = Duration::from_secs(3);
+
+// How long a connection to the helper peer gets to switch from the relay to a direct path
+const HOLEPUNCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+// What `doctor` found out about how this machine reaches and is reached by peers
+#[derive(Debug, Clone, Default)]
+pub struct Diagnosis {
+    // The relay peers reach us through, if one answered
+    pub home_relay: Option<RelayUrl>,
+    // How long each relay took to answer, fastest first
+    pub relays: Vec<(RelayUrl, Duration)>,
+    // Whether UDP got through to the relays' address probes, over IPv4 and IPv6
+    pub udp_v4: bool,
+    pub udp_v6: bool,
+    // Where the relays saw us come from
+    pub public_v4: Option<SocketAddrV4>,
+    pub public_v6: Option<SocketAddrV6>,
+    // Whether our public port differs for each server we talk to, as with symmetric NATs, which
+    // defeats holepunching. None if it couldn't be told.
+    pub mapping_varies: Option<bool>,
+    pub captive_portal: Option<bool>,
+    // The addresses we tell peers to try, local and public
+    pub direct_addresses: Vec<SocketAddr>,
+    // Whether n0's DNS serves the relay we published, None when we don't publish
+    pub published: Option<Result<(), String>>,
+    // The test against a helper peer, if one was given
+    pub holepunch: Option<Result<Holepunch, String>>,
+}
+
+// How a connection to the helper peer went
+#[derive(Debug, Clone)]
+pub struct Holepunch {
+    pub latency: Latency,
+    // How long after dialing the path went direct, None if it stayed on the relay
+    pub direct_after: Option<Duration>,
+}
+
+pub async fn diagnose(endpoint: &Endpoint, publishing: bool, helper: Option<NodeId>) -> Diagnosis {
+    let mut diagnosis = Diagnosis::default();
+    // Both stay unset when there's no network, or relays are off
+    let _ = timeout(REPORT_TIMEOUT, endpoint.home_relay().initialized()).await;
+    let report = timeout(REPORT_TIMEOUT, endpoint.net_report().initialized()).await.ok();
+    diagnosis.home_relay = endpoint.home_relay().get().into_iter().next();
+    if let Some(report) = report {
+        let mut relays: Vec<(RelayUrl, Duration)> =
+            report.relay_latency.iter().map(|(url, latency)| (url.clone(), latency)).collect();
+        relays.sort_by_key(|(_, latency)| *latency);
+        diagnosis.relays = relays;
+        diagnosis.udp_v4 = report.udp_v4;
+        diagnosis.udp_v6 = report.udp_v6;
+        diagnosis.public_v4 = report.global_v4;
+        diagnosis.public_v6 = report.global_v6;
+        diagnosis.mapping_varies = report.mapping_varies_by_dest();
+        diagnosis.captive_portal = report.captive_portal;
+    }
+    let addrs = endpoint.direct_addresses().get().unwrap_or_default();
+    diagnosis.direct_addresses = addrs.into_iter().map(|addr| addr.addr).collect();
+    if publishing {
+        let found = published(endpoint, diagnosis.home_relay.as_ref()).await;
+        diagnosis.published = Some(found.map_err(|err| err.to_string()));
+    }
+    if let Some(helper) = helper {
+        diagnosis.holepunch = Some(holepunch(endpoint, helper).await.map_err(|err| err.to_string()));
+    }
+    diagnosis
+}
+
+// Look ourselves up the way peers do, until n0's DNS answers with our relay
+async fn published(endpoint: &Endpoint, home_relay: Option<&RelayUrl>) -> Result<()> {
+    let Some(home_relay) = home_relay else {
+        bail!("there is no relay to publish");
+    };
+    let deadline = Instant::now() + PUBLISH_TIMEOUT;
+    loop {
+        let resolver = endpoint.dns_resolver();
+        let last = match resolver.lookup_node_by_id(&endpoint.node_id(), N0_DNS_NODE_ORIGIN_PROD).await {
+            Ok(info) if info.relay_url() == Some(home_relay) => return Ok(()),
+            Ok(info) => match info.relay_url() {
+                Some(relay) => format!("it still has an older relay, {}", relay),
+                None => "it has no relay for us".to_string(),
+            },
+            Err(err) => err.to_string(),
+        };
+        if Instant::now() + PUBLISH_POLL > deadline {
+            bail!("{}", last);
+        }
+        sleep(PUBLISH_POLL).await;
+    }
+}
+
+// Ping the helper, which starts out through the relays, and wait for the path to go direct
+async fn holepunch(endpoint: &Endpoint, helper: NodeId) -> Result<Holepunch> {
+    let start = Instant::now();
+    let latency = ping::ping(endpoint, helper).await?;
+    let direct = |endpoint: &Endpoint| {
+        let conn_type = endpoint.conn_type(helper).map(|mut conn_type| conn_type.get());
+        matches!(conn_type, Some(ConnectionType::Direct(_)))
+    };
+    while !direct(endpoint) && start.elapsed() < HOLEPUNCH_TIMEOUT {
+        // The endpoint only keeps trying while there is traffic
+        let _ = ping::ping(endpoint, helper).await;
+        sleep(Duration::from_millis(500)).await;
+    }
+    let direct_after = direct(endpoint).then(|| start.elapsed());
+    Ok(Holepunch { latency, direct_after })
+}
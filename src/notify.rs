@@ -0,0 +1,52 @@
+use notify_rust::Notification;
+use p2p_chat::{ChatClient, ChatEvent, Notify};
+
+// How much of a message its notification shows, in characters
+const PREVIEW: usize = 200;
+
+// The title and text of the desktop notification an event deserves, if any: direct messages, and
+// messages in a room as its /notify rule says. Our own messages never do.
+pub fn worth(client: &ChatClient, event: &ChatEvent) -> Option<(String, String)> {
+    let (title, text) = match event {
+        ChatEvent::DirectMessage { name, text, .. } => (format!("{} (direct message)", name), text),
+        ChatEvent::Message { room, from, name, text, .. } if *from != client.node_id() => {
+            let wanted = match client.notify_rule(room).unwrap_or_default() {
+                Notify::All => true,
+                Notify::Mentions => mentions(text, &client.name()),
+                Notify::Off => false,
+            };
+            if !wanted {
+                return None;
+            }
+            let room = match client.room_info(room) {
+                Some(info) if !info.name.is_empty() => info.name,
+                _ => room.fmt_short(),
+            };
+            (format!("{} in {}", name, room), text)
+        }
+        _ => return None,
+    };
+    let preview = match text.chars().nth(PREVIEW) {
+        Some(_) => format!("{}…", text.chars().take(PREVIEW).collect::<String>()),
+        None => text.clone(),
+    };
+    Some((title, preview))
+}
+
+// Whether a message says our name, as a word of its own and in any case
+pub fn mentions(text: &str, name: &str) -> bool {
+    let (text, name) = (text.to_lowercase(), name.to_lowercase());
+    let word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    text.match_indices(&name).any(|(at, _)| {
+        !word(text[..at].chars().next_back()) && !word(text[at + name.len()..].chars().next())
+    })
+}
+
+// Show a notification with notify-rust, over D-Bus on Linux and the BSDs and through the system's
+// notification center on macOS and Windows, in the background
+pub fn show(title: String, text: String) {
+    tokio::task::spawn_blocking(move || {
+        // Without a notifier there is nowhere to show it, which isn't worth interrupting the chat for
+        let _ = Notification::new().appname("iroh-chat").summary(&title).body(&text).show();
+    });
+}
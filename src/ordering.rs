@@ -0,0 +1,141 @@
+use crate::client::ChatEvent;
+use crate::message::MessageId;
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+// How long an incoming chat message is held back so messages that were sent at about the same
+// time, but arrive in a different order, can still be shown in clock order
+pub const REORDER_DELAY: Duration = Duration::from_millis(500);
+
+// One Lamport clock per room: bumped for every chat message we send, and moved past every clock
+// we receive, so a message always sorts after everything its sender had seen
+#[derive(Debug, Clone, Default)]
+pub struct Clocks(Arc<Mutex<HashMap<TopicId, u64>>>);
+
+impl Clocks {
+    // The clock for a message we are about to send
+    pub fn tick(&self, room: TopicId) -> u64 {
+        let mut clocks = self.0.lock().expect("clocks lock poisoned");
+        let clock = clocks.entry(room).or_default();
+        *clock += 1;
+        *clock
+    }
+
+    // Record a clock seen on a message in `room`
+    pub fn observe(&self, room: TopicId, seen: u64) {
+        let mut clocks = self.0.lock().expect("clocks lock poisoned");
+        let clock = clocks.entry(room).or_default();
+        *clock = (*clock).max(seen);
+    }
+
+    pub fn remove_room(&self, room: &TopicId) {
+        self.0.lock().expect("clocks lock poisoned").remove(room);
+    }
+}
+
+// Chat messages of one room waiting to be shown. Messages are sorted by clock, with ties broken
+// by sender and id, so every peer ends up with the same order.
+#[derive(Debug, Default)]
+pub struct Reorder(BTreeMap<(u64, NodeId, MessageId), (Instant, ChatEvent)>);
+
+impl Reorder {
+    pub fn push(&mut self, clock: u64, from: NodeId, id: MessageId, event: ChatEvent) {
+        self.0.insert((clock, from, id), (Instant::now(), event));
+    }
+
+    // When the oldest held back message is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.0.values().map(|(at, _)| *at + REORDER_DELAY).min()
+    }
+
+    // The messages that have waited long enough, in order, along with anything sorting before them
+    pub fn ready(&mut self) -> Vec<ChatEvent> {
+        let due = self.0.iter().rev().find(|(_, (at, _))| at.elapsed() >= REORDER_DELAY);
+        let Some((&last, _)) = due else {
+            return Vec::new();
+        };
+        let rest = self.0.split_off(&last);
+        let mut ready = std::mem::replace(&mut self.0, rest);
+        // split_off leaves `last` itself in the upper half
+        if let Some((key, entry)) = self.0.pop_first() {
+            ready.insert(key, entry);
+        }
+        ready.into_values().map(|(_, event)| event).collect()
+    }
+
+    // Everything still held back, in order
+    pub fn drain(&mut self) -> Vec<ChatEvent> {
+        std::mem::take(&mut self.0).into_values().map(|(_, event)| event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> NodeId {
+        iroh::SecretKey::from_bytes(&[seed; 32]).public()
+    }
+
+    fn event(name: &str) -> ChatEvent {
+        ChatEvent::Joined { room: TopicId::from_bytes([0; 32]), from: node(0), name: name.to_string() }
+    }
+
+    fn names(events: Vec<ChatEvent>) -> Vec<String> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                ChatEvent::Joined { name, .. } => name,
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clocks_move_past_what_they_see() {
+        let clocks = Clocks::default();
+        let (room, other) = (TopicId::from_bytes([1; 32]), TopicId::from_bytes([2; 32]));
+        assert_eq!(clocks.tick(room), 1);
+        clocks.observe(room, 10);
+        clocks.observe(room, 3);
+        assert_eq!(clocks.tick(room), 11);
+        assert_eq!(clocks.tick(other), 1);
+        clocks.remove_room(&room);
+        assert_eq!(clocks.tick(room), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_messages_back_and_sorts_them() {
+        let mut reorder = Reorder::default();
+        assert_eq!(reorder.next_due(), None);
+        let start = Instant::now();
+        reorder.push(2, node(1), MessageId::random(), event("second"));
+        tokio::time::advance(Duration::from_millis(100)).await;
+        reorder.push(1, node(1), MessageId::random(), event("first"));
+        assert_eq!(reorder.next_due(), Some(start + REORDER_DELAY));
+        assert!(reorder.ready().is_empty());
+
+        // The late arrival sorts before the due one, so both come out, in clock order
+        tokio::time::advance(REORDER_DELAY).await;
+        reorder.push(3, node(1), MessageId::random(), event("third"));
+        assert_eq!(names(reorder.ready()), ["first", "second"]);
+        assert_eq!(names(reorder.drain()), ["third"]);
+        assert!(reorder.drain().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn breaks_ties_on_sender_not_arrival() {
+        let (low, high) = match node(1) < node(2) {
+            true => (node(1), node(2)),
+            false => (node(2), node(1)),
+        };
+        let mut reorder = Reorder::default();
+        reorder.push(5, high, MessageId::random(), event("high"));
+        reorder.push(5, low, MessageId::random(), event("low"));
+        tokio::time::advance(REORDER_DELAY).await;
+        assert_eq!(names(reorder.ready()), ["low", "high"]);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::crypto::RoomKey;
+use anyhow::{anyhow, bail, Result};
+use iroh::endpoint::Connection;
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+pub const ALPN: &[u8] = b"iroh-chat/pake/1";
+
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+// How long a peer has to wait after its first wrong password for a room, doubling with each one
+// after that up to MAX_BACKOFF. Failures are forgotten after FORGET_FAILURES without any.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+const FORGET_FAILURES: Duration = Duration::from_secs(60 * 60);
+// Node ids cost nothing, so a room also backs off for everyone once this many wrong passwords for
+// it came in, whoever gave them
+const ROOM_GRACE: u32 = 5;
+
+// What the joiner encrypts with the session key to show the member it got the same one
+const CONFIRMATION: &[u8] = b"iroh-chat pake confirmation";
+
+// The joiner's half of the exchange, for the room it wants in
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    topic: TopicId,
+    message: Vec<u8>,
+}
+
+// CONFIRMATION encrypted with the key the joiner derived, sent on a second stream once it has the
+// member's half
+#[derive(Debug, Serialize, Deserialize)]
+struct Confirm {
+    proof: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Answer {
+    // The member's half, in answer to Hello
+    Message(Vec<u8>),
+    // The room key encrypted with the key both sides derived, in answer to a Confirm that checks out
+    Key(Vec<u8>),
+    Refused(String),
+}
+
+// A member turned us away, e.g. for a wrong password. The other members would say the same.
+#[derive(Debug)]
+pub struct Refused(pub String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+// Wrong passwords a peer gave for a room, and when it gave the last one
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    // When the peer may try again, after `grace` failures that don't count
+    fn retry_at(&self, grace: u32) -> Instant {
+        let Some(counted) = self.count.checked_sub(grace + 1) else {
+            return self.last;
+        };
+        let backoff = FIRST_BACKOFF.saturating_mul(1 << counted.min(16)).min(MAX_BACKOFF);
+        self.last + backoff
+    }
+
+    fn add(&mut self) {
+        self.count += 1;
+        self.last = Instant::now();
+    }
+}
+
+// Passwords of the protected rooms we are in. Members hand the room key to peers that complete a
+// SPAKE2 exchange with the same password, so a ticket without the password is useless and neither
+// side ever sends the password itself.
+#[derive(Debug, Clone, Default)]
+pub struct PakeProtocol {
+    rooms: Arc<Mutex<HashMap<TopicId, (String, RoomKey)>>>,
+    // Peers only get to guess again after backing off, so a password can't be found by trying them
+    // all in turn. The rooms' own failures catch guessers that use a new node id for each guess.
+    failures: Arc<Mutex<HashMap<(NodeId, TopicId), Failures>>>,
+    room_failures: Arc<Mutex<HashMap<TopicId, Failures>>>,
+}
+
+impl PakeProtocol {
+    pub fn add_room(&self, topic: TopicId, password: String, key: RoomKey) {
+        self.rooms.lock().expect("passwords lock poisoned").insert(topic, (password, key));
+    }
+
+    pub fn remove_room(&self, topic: &TopicId) {
+        self.rooms.lock().expect("passwords lock poisoned").remove(topic);
+        self.failures.lock().expect("failures lock poisoned").retain(|(_, room), _| room != topic);
+        self.room_failures.lock().expect("failures lock poisoned").remove(topic);
+    }
+
+    // Keep protecting a room that moved to a new topic and key. Returns its password, if it has one.
+    pub fn move_room(&self, from: &TopicId, to: TopicId, key: RoomKey) -> Option<String> {
+        let mut rooms = self.rooms.lock().expect("passwords lock poisoned");
+        let (password, _) = rooms.remove(from)?;
+        rooms.insert(to, (password.clone(), key));
+        Some(password)
+    }
+
+    pub fn is_protected(&self, topic: &TopicId) -> bool {
+        self.rooms.lock().expect("passwords lock poisoned").contains_key(topic)
+    }
+
+    // How long `from` still has to wait before guessing again, if at all: until both it and the
+    // room are done backing off
+    fn backing_off(&self, from: NodeId, topic: TopicId) -> Option<Duration> {
+        let mut failures = self.failures.lock().expect("failures lock poisoned");
+        failures.retain(|_, failed| failed.last.elapsed() < FORGET_FAILURES);
+        let mut room_failures = self.room_failures.lock().expect("failures lock poisoned");
+        room_failures.retain(|_, failed| failed.last.elapsed() < FORGET_FAILURES);
+        let ours = failures.get(&(from, topic)).map(|failed| failed.retry_at(0));
+        let room = room_failures.get(&topic).map(|failed| failed.retry_at(ROOM_GRACE));
+        ours.max(room)?.checked_duration_since(Instant::now()).filter(|wait| !wait.is_zero())
+    }
+
+    fn failed(&self, from: NodeId, topic: TopicId) {
+        let new = || Failures { count: 0, last: Instant::now() };
+        self.failures.lock().expect("failures lock poisoned").entry((from, topic)).or_insert_with(new).add();
+        self.room_failures.lock().expect("failures lock poisoned").entry(topic).or_insert_with(new).add();
+    }
+
+    fn succeeded(&self, from: NodeId, topic: TopicId) {
+        self.failures.lock().expect("failures lock poisoned").remove(&(from, topic));
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        let from = conn.remote_node_id()?;
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let hello: Hello = serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?;
+        let room = self.rooms.lock().expect("passwords lock poisoned").get(&hello.topic).cloned();
+        let Some((password, room_key)) = room else {
+            bail!("no password for topic {}", hello.topic.fmt_short());
+        };
+        if let Some(wait) = self.backing_off(from, hello.topic) {
+            let reason = format!("too many wrong passwords, try again in {}s", wait.as_secs() + 1);
+            send.write_all(&serde_json::to_vec(&Answer::Refused(reason))?).await?;
+            send.finish()?;
+            conn.closed().await;
+            return Ok(());
+        }
+        let (state, message) = start(&password, &hello.topic);
+        let session = session_key(state, &hello.message)?;
+        send.write_all(&serde_json::to_vec(&Answer::Message(message))?).await?;
+        send.finish()?;
+
+        // Only a joiner that derived the same key, and so knows the password, gets the room key.
+        // Anyone else learns nothing they could test more passwords against offline.
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let confirm: Confirm = serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?;
+        let answer = match session.decrypt(&confirm.proof) {
+            Ok(proof) if proof == CONFIRMATION => {
+                self.succeeded(from, hello.topic);
+                Answer::Key(session.encrypt(&serde_json::to_vec(&room_key)?))
+            }
+            _ => {
+                self.failed(from, hello.topic);
+                Answer::Refused("wrong password".to_string())
+            }
+        };
+        send.write_all(&serde_json::to_vec(&answer)?).await?;
+        send.finish()?;
+        conn.closed().await;
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for PakeProtocol {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
+
+// Both sides use the symmetric variant, bound to the topic so an exchange can't be replayed for
+// another room
+fn start(password: &str, topic: &TopicId) -> (Spake2<Ed25519Group>, Vec<u8>) {
+    Spake2::<Ed25519Group>::start_symmetric(&Password::new(password), &Identity::new(topic.as_bytes()))
+}
+
+fn session_key(state: Spake2<Ed25519Group>, their_message: &[u8]) -> Result<RoomKey> {
+    let shared = state.finish(their_message).map_err(|err| anyhow!("password exchange failed: {:?}", err))?;
+    let bytes: [u8; 32] = shared.try_into().map_err(|_| anyhow!("unexpected session key length"))?;
+    Ok(RoomKey::from_bytes(bytes))
+}
+
+// Prove to `member` that we know the room's password and get the room key in return
+pub async fn exchange(endpoint: &Endpoint, member: NodeAddr, topic: TopicId, password: &str) -> Result<RoomKey> {
+    let (state, message) = start(password, &topic);
+    let conn = endpoint.connect(member, ALPN).await?;
+    let result = async {
+        let their_message = match ask(&conn, &Hello { topic, message }).await? {
+            Answer::Message(message) => message,
+            Answer::Refused(reason) => return Err(Refused(reason).into()),
+            Answer::Key(_) => bail!("the member skipped the password exchange"),
+        };
+        let session = session_key(state, &their_message)?;
+        let sealed_key = match ask(&conn, &Confirm { proof: session.encrypt(CONFIRMATION) }).await? {
+            Answer::Key(sealed_key) => sealed_key,
+            Answer::Refused(reason) => return Err(Refused(reason).into()),
+            Answer::Message(_) => bail!("the member answered out of turn"),
+        };
+        let room_key = session.decrypt(&sealed_key)?;
+        Ok(serde_json::from_slice(&room_key)?)
+    }
+    .await;
+    conn.close(0u32.into(), b"done");
+    result
+}
+
+// Send one step of the exchange on a stream of its own and read the member's answer
+async fn ask(conn: &Connection, request: &impl Serialize) -> Result<Answer> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&serde_json::to_vec(request)?).await?;
+    send.finish()?;
+    Ok(serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::protocol::Router;
+    use iroh::{RelayMode, Watcher};
+
+    async fn endpoint() -> Endpoint {
+        Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_wrong_passwords_and_backs_off() {
+        let topic = TopicId::from_bytes([5; 32]);
+        let key = RoomKey::generate();
+        let passwords = PakeProtocol::default();
+        passwords.add_room(topic, "hunter2".to_string(), key);
+        let member = endpoint().await;
+        let router = Router::builder(member.clone()).accept(ALPN, passwords.clone()).spawn();
+        let addr = member.node_addr().initialized().await;
+
+        let guesser = endpoint().await;
+        let err = exchange(&guesser, addr.clone(), topic, "hunter3").await.unwrap_err();
+        assert!(err.downcast_ref::<Refused>().is_some_and(|refused| refused.0 == "wrong password"));
+        // The right password right after a wrong one has to wait too
+        let err = exchange(&guesser, addr.clone(), topic, "hunter2").await.unwrap_err();
+        assert!(err.downcast_ref::<Refused>().is_some_and(|refused| refused.0.starts_with("too many")));
+
+        let joiner = endpoint().await;
+        let got = exchange(&joiner, addr, topic, "hunter2").await.unwrap();
+        assert!(got.decrypt(&key.encrypt(b"probe")).is_ok());
+        router.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let last = Instant::now();
+        let wait = |count| Failures { count, last }.retry_at(0) - last;
+        assert_eq!(wait(1), FIRST_BACKOFF);
+        assert_eq!(wait(2), FIRST_BACKOFF * 2);
+        assert_eq!(wait(4), FIRST_BACKOFF * 8);
+        assert_eq!(wait(40), MAX_BACKOFF);
+        let grace = Failures { count: ROOM_GRACE, last };
+        assert_eq!(grace.retry_at(ROOM_GRACE), last);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_guessers_with_a_new_node_id_each_time() {
+        let (topic, other) = (TopicId::from_bytes([6; 32]), TopicId::from_bytes([7; 32]));
+        let passwords = PakeProtocol::default();
+        let node = |seed: u8| iroh::SecretKey::from_bytes(&[seed; 32]).public();
+        for seed in 0..ROOM_GRACE as u8 {
+            passwords.failed(node(seed), topic);
+            assert!(passwords.backing_off(node(100), topic).is_none());
+        }
+        // One more wrong password from yet another node id, and the room backs off for everyone
+        passwords.failed(node(50), topic);
+        assert_eq!(passwords.backing_off(node(100), topic), Some(FIRST_BACKOFF));
+        assert!(passwords.backing_off(node(100), other).is_none());
+        passwords.failed(node(51), topic);
+        assert_eq!(passwords.backing_off(node(100), topic), Some(FIRST_BACKOFF * 2));
+        tokio::time::advance(FIRST_BACKOFF * 2).await;
+        assert!(passwords.backing_off(node(100), topic).is_none());
+    }
+}
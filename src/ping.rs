@@ -0,0 +1,113 @@
+use crate::blocks::Blocks;
+use anyhow::{bail, Context, Result};
+use iroh::endpoint::{Connection, ConnectionType};
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{Endpoint, NodeId, Watcher};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+pub const ALPN: &[u8] = b"iroh-chat/ping/0";
+
+// How many pings one /ping sends, one after the other over the same connection
+const PINGS: usize = 3;
+
+// How long the peer gets to accept the connection, and then to answer each ping
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_PING_SIZE: usize = 64;
+
+// One round trip, each on its own stream
+#[derive(Debug, Serialize, Deserialize)]
+enum PingMessage {
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
+// How a peer answered our pings
+#[derive(Debug, Clone)]
+pub struct Latency {
+    // From dialing to the connection being up, holepunching or finding a relay included
+    pub connect: Duration,
+    // Each ping's round trip, in the order they were sent
+    pub round_trips: Vec<Duration>,
+    // Whether the pings went through a relay rather than straight to the peer
+    pub relayed: bool,
+}
+
+// Answers pings from anyone but blocked peers, for as long as the pinger keeps the connection
+#[derive(Debug, Clone)]
+pub struct PingProtocol {
+    blocks: Blocks,
+}
+
+impl PingProtocol {
+    pub fn new(blocks: Blocks) -> Self {
+        Self { blocks }
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        // Blocked peers can't tell this from us being offline
+        if self.blocks.contains(&conn.remote_node_id()?) {
+            conn.close(0u32.into(), b"closed");
+            return Ok(());
+        }
+        // The pinger closing the connection ends it
+        while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+            let PingMessage::Ping { nonce } = serde_json::from_slice(&recv.read_to_end(MAX_PING_SIZE).await?)? else {
+                bail!("expected a ping");
+            };
+            send.write_all(&serde_json::to_vec(&PingMessage::Pong { nonce })?).await?;
+            send.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for PingProtocol {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
+
+// Open a direct connection to `peer` and time PINGS round trips over it
+pub async fn ping(endpoint: &Endpoint, peer: NodeId) -> Result<Latency> {
+    let start = Instant::now();
+    let conn = timeout(PING_TIMEOUT, endpoint.connect(peer, ALPN)).await.context("no answer")??;
+    let connect = start.elapsed();
+    let mut round_trips = Vec::with_capacity(PINGS);
+    for _ in 0..PINGS {
+        let result = timeout(PING_TIMEOUT, round_trip(&conn)).await.context("no answer to a ping");
+        let round_trip = match result {
+            Ok(Ok(round_trip)) => round_trip,
+            Ok(Err(err)) | Err(err) => {
+                conn.close(0u32.into(), b"done");
+                return Err(err);
+            }
+        };
+        round_trips.push(round_trip);
+    }
+    let relayed = match endpoint.conn_type(peer).map(|mut conn_type| conn_type.get()) {
+        Some(ConnectionType::Relay(_) | ConnectionType::Mixed(..)) => true,
+        Some(ConnectionType::Direct(_) | ConnectionType::None) | None => false,
+    };
+    conn.close(0u32.into(), b"done");
+    Ok(Latency { connect, round_trips, relayed })
+}
+
+async fn round_trip(conn: &Connection) -> Result<Duration> {
+    let nonce = random();
+    let start = Instant::now();
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&serde_json::to_vec(&PingMessage::Ping { nonce })?).await?;
+    send.finish()?;
+    let pong = serde_json::from_slice(&recv.read_to_end(MAX_PING_SIZE).await?)?;
+    let elapsed = start.elapsed();
+    match pong {
+        PingMessage::Pong { nonce: answered } if answered == nonce => Ok(elapsed),
+        _ => bail!("answered with something other than our ping"),
+    }
+}
@@ -0,0 +1,65 @@
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+// What a name announcement says about the node id we pinned for that name
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pin {
+    // Nobody had used the name in the room before, so it is pinned to this node now
+    First,
+    // The pinned node, or a mismatch we warned about already
+    Same,
+    // Someone other than the pinned node uses the name
+    Changed { pinned: NodeId },
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    pins: HashMap<(TopicId, String), NodeId>,
+    // Mismatches warned about this session, so heartbeats don't repeat the warning
+    warned: HashSet<(TopicId, String, NodeId)>,
+}
+
+// The node id that first used each name in each room, trusted on first use like SSH host keys. A
+// different node showing up under a pinned name is either the same person on a new device or
+// someone pretending to be them, and only the user can tell which.
+#[derive(Debug, Clone, Default)]
+pub struct Pins(Arc<Mutex<Inner>>);
+
+impl Pins {
+    // Restore a pin saved in an earlier session
+    pub fn load(&self, topic: TopicId, name: String, node_id: NodeId) {
+        self.0.lock().expect("pins lock poisoned").pins.insert((topic, name), node_id);
+    }
+
+    pub fn check(&self, topic: TopicId, name: &str, node_id: NodeId) -> Pin {
+        let mut inner = self.0.lock().expect("pins lock poisoned");
+        let key = (topic, name.to_string());
+        match inner.pins.get(&key).copied() {
+            None => {
+                inner.pins.insert(key, node_id);
+                Pin::First
+            }
+            Some(pinned) if pinned == node_id => Pin::Same,
+            Some(pinned) => match inner.warned.insert((topic, key.1, node_id)) {
+                true => Pin::Changed { pinned },
+                false => Pin::Same,
+            },
+        }
+    }
+
+    // Pin `name` to `node_id`, replacing whoever had it. Returns the node it was pinned to before.
+    pub fn trust(&self, topic: TopicId, name: &str, node_id: NodeId) -> Option<NodeId> {
+        let mut inner = self.0.lock().expect("pins lock poisoned");
+        inner.warned.retain(|(t, n, id)| !(*t == topic && n == name && *id == node_id));
+        inner.pins.insert((topic, name.to_string()), node_id).filter(|old| *old != node_id)
+    }
+
+    // Forget the pin for `name`, so whoever uses it next is trusted. Returns the node it was pinned to.
+    pub fn distrust(&self, topic: TopicId, name: &str) -> Option<NodeId> {
+        let mut inner = self.0.lock().expect("pins lock poisoned");
+        inner.warned.retain(|(t, n, _)| !(*t == topic && n == name));
+        inner.pins.remove(&(topic, name.to_string()))
+    }
+}
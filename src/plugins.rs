@@ -0,0 +1,257 @@
+use crate::client::{ChatEvent, Events};
+use crate::ratelimit::{Admit, Limiter, RateLimit};
+use anyhow::{anyhow, bail, Context, Result};
+use iroh_gossip::proto::TopicId;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use wasmi::{Caller, Engine, Extern, Linker, Memory, Module, Store, TypedFunc};
+
+// How much work a plugin may do on one message before it is stopped
+const FUEL: u64 = 10_000_000;
+
+// Longest string a plugin may hand back to us
+const MAX_TEXT: usize = 64 * 1024;
+
+// How many messages the plugins together may send to a room of their own accord
+const SEND_LIMIT: RateLimit = RateLimit { per_second: 0.2, burst: 5.0 };
+
+// Which way a message is going when a plugin sees it
+#[derive(Debug, Clone, Copy)]
+pub enum Hook {
+    Inbound,
+    Outbound,
+}
+
+impl Hook {
+    fn export(self) -> &'static str {
+        match self {
+            Hook::Inbound => "on_inbound",
+            Hook::Outbound => "on_outbound",
+        }
+    }
+}
+
+// What every plugin together made of a message
+#[derive(Debug)]
+pub enum Verdict {
+    // Show, send or save this text, possibly rewritten
+    Pass(String),
+    // The named plugin filtered the message out
+    Dropped(String),
+}
+
+#[derive(Debug)]
+pub struct Outcome {
+    pub verdict: Verdict,
+    // Messages the plugins asked to send to the room in response
+    pub replies: Vec<String>,
+}
+
+// What a hook can see and do while it runs
+#[derive(Debug, Default)]
+struct Host {
+    // Name of whoever wrote the message
+    from: String,
+    // Names of the peers online in the room
+    roster: Vec<String>,
+    outbox: Vec<String>,
+    logs: Vec<String>,
+}
+
+struct Plugin {
+    name: String,
+    store: Mutex<Store<Host>>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    hooks: [Option<TypedFunc<(i32, i32), i64>>; 2],
+}
+
+// The WASM modules in the plugins directory, run in file name order on every chat message
+#[derive(Clone)]
+pub struct Plugins {
+    plugins: Arc<Vec<Plugin>>,
+    // What they sent to each room lately
+    sends: Arc<Mutex<Limiter<TopicId>>>,
+}
+
+impl Default for Plugins {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Plugins {
+    // Load every .wasm file in `dir`. A missing directory means no plugins.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("could not read {}", dir.display())),
+        };
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+            .collect();
+        paths.sort();
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let linker = linker(&engine)?;
+        let plugins = paths
+            .iter()
+            .map(|path| {
+                Plugin::load(&engine, &linker, path)
+                    .with_context(|| format!("could not load plugin {}", path.display()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self::new(plugins))
+    }
+
+    fn new(plugins: Vec<Plugin>) -> Self {
+        Self { plugins: Arc::new(plugins), sends: Arc::new(Mutex::new(Limiter::new(SEND_LIMIT))) }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.plugins.iter().map(|plugin| plugin.name.clone()).collect()
+    }
+
+    // Whether the plugins may send another message to `room` now. Going over SEND_LIMIT is
+    // reported once, and the messages are dropped until they slow down.
+    pub fn may_send(&self, room: TopicId, events: &Events) -> bool {
+        match self.sends.lock().expect("plugin sends lock poisoned").admit(room) {
+            Admit::Yes | Admit::Resumed { .. } => true,
+            Admit::Limited => {
+                events.warn("plugins are sending too many messages, dropping them for now".to_string());
+                false
+            }
+            Admit::No => false,
+        }
+    }
+
+    // Pass a message through each plugin in turn, stopping at the first that drops it. A plugin
+    // that fails leaves the message as it was.
+    pub fn run(&self, hook: Hook, from: &str, roster: &[String], text: String, events: &Events) -> Outcome {
+        let mut text = text;
+        let mut replies = Vec::new();
+        for plugin in self.plugins.iter() {
+            let (result, host) = plugin.call(hook, from, roster, &text);
+            for log in host.logs {
+                events.emit(ChatEvent::PluginLog { plugin: plugin.name.clone(), text: log });
+            }
+            replies.extend(host.outbox);
+            match result {
+                Ok(Some(Some(rewritten))) => text = rewritten,
+                Ok(Some(None)) => return Outcome { verdict: Verdict::Dropped(plugin.name.clone()), replies },
+                Ok(None) => {}
+                Err(err) => events.warn(format!("plugin {} failed: {}", plugin.name, err)),
+            }
+        }
+        Outcome { verdict: Verdict::Pass(text), replies }
+    }
+}
+
+impl Plugin {
+    fn load(engine: &Engine, linker: &Linker<Host>, path: &Path) -> Result<Self> {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let module = Module::new(engine, std::fs::read(path)?)?;
+        let mut store = Store::new(engine, Host::default());
+        store.set_fuel(FUEL)?;
+        let instance = linker.instantiate_and_start(&mut store, &module)?;
+        let memory = instance.get_memory(&store, "memory").ok_or_else(|| anyhow!("no memory export"))?;
+        let alloc = instance.get_typed_func(&store, "alloc").context("alloc")?;
+        let hook = |hook: Hook| match instance.get_export(&store, hook.export()) {
+            Some(_) => instance.get_typed_func(&store, hook.export()).context(hook.export()).map(Some),
+            None => Ok(None),
+        };
+        let hooks = [hook(Hook::Inbound)?, hook(Hook::Outbound)?];
+        if hooks.iter().all(Option::is_none) {
+            bail!("exports neither on_inbound nor on_outbound");
+        }
+        Ok(Self { name, store: Mutex::new(store), memory, alloc, hooks })
+    }
+
+    // Run one hook. Ok(None) leaves the message alone, Ok(Some(None)) drops it and
+    // Ok(Some(Some(text))) replaces it.
+    fn call(&self, hook: Hook, from: &str, roster: &[String], text: &str) -> (Result<Option<Option<String>>>, Host) {
+        let mut store = self.store.lock().expect("plugin lock poisoned");
+        let Some(func) = self.hooks[hook as usize] else {
+            return (Ok(None), Host::default());
+        };
+        *store.data_mut() = Host { from: from.to_string(), roster: roster.to_vec(), ..Default::default() };
+        let result = (|| {
+            store.set_fuel(FUEL)?;
+            let len = text.len() as i32;
+            let ptr = self.alloc.call(&mut *store, len)?;
+            self.memory.write(&mut *store, ptr as usize, text.as_bytes())?;
+            match func.call(&mut *store, (ptr, len))? {
+                0 => Ok(None),
+                -1 => Ok(Some(None)),
+                packed => {
+                    let (ptr, len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+                    let bytes = read(self.memory, &*store, ptr, len)?;
+                    Ok(Some(Some(String::from_utf8(bytes)?)))
+                }
+            }
+        })();
+        (result, std::mem::take(store.data_mut()))
+    }
+}
+
+// The functions plugins can import from the "chat" module
+fn linker(engine: &Engine) -> Result<Linker<Host>> {
+    let mut linker = Linker::new(engine);
+    // log(ptr, len): show a line of text in the chat
+    linker.func_wrap("chat", "log", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+        let text = read_string(&caller, ptr, len)?;
+        caller.data_mut().logs.push(text);
+        Ok(())
+    })?;
+    // send(ptr, len): send a message to the room once the hook returns
+    linker.func_wrap("chat", "send", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+        let text = read_string(&caller, ptr, len)?;
+        caller.data_mut().outbox.push(text);
+        Ok(())
+    })?;
+    // sender(ptr, cap) -> len: the name of whoever wrote the message
+    linker.func_wrap("chat", "sender", |caller: Caller<'_, Host>, ptr: i32, cap: i32| {
+        let from = caller.data().from.clone();
+        write_string(caller, ptr, cap, &from)
+    })?;
+    // roster(ptr, cap) -> len: the names of the peers online in the room, one per line
+    linker.func_wrap("chat", "roster", |caller: Caller<'_, Host>, ptr: i32, cap: i32| {
+        let roster = caller.data().roster.join("\n");
+        write_string(caller, ptr, cap, &roster)
+    })?;
+    Ok(linker)
+}
+
+fn read(memory: Memory, store: impl wasmi::AsContext, ptr: usize, len: usize) -> Result<Vec<u8>> {
+    if len > MAX_TEXT {
+        bail!("plugin returned {} bytes, at most {}", len, MAX_TEXT);
+    }
+    let mut bytes = vec![0; len];
+    memory.read(store, ptr, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_string(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    let memory = exported_memory(caller)?;
+    let bytes = read(memory, caller, ptr as usize, len as usize).map_err(|err| wasmi::Error::new(err.to_string()))?;
+    String::from_utf8(bytes).map_err(|err| wasmi::Error::new(err.to_string()))
+}
+
+// Copy as much of `text` as fits into the plugin's buffer and return its full length, so the
+// plugin can retry with a bigger buffer
+fn write_string(mut caller: Caller<'_, Host>, ptr: i32, cap: i32, text: &str) -> Result<i32, wasmi::Error> {
+    let memory = exported_memory(&caller)?;
+    let bytes = &text.as_bytes()[..text.len().min(cap.max(0) as usize)];
+    memory.write(&mut caller, ptr as usize, bytes).map_err(|err| wasmi::Error::new(err.to_string()))?;
+    Ok(text.len() as i32)
+}
+
+fn exported_memory(caller: &Caller<'_, Host>) -> Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("plugin has no memory export"))
+}
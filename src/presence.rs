@@ -0,0 +1,140 @@
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How often we announce that we are still around
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Send at most one typing notice per room this often while composing
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+// Peers we haven't heard from for this long are considered offline
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// When we last heard from each peer, per room
+#[derive(Debug, Clone, Default)]
+pub struct Roster(Arc<Mutex<HashMap<TopicId, HashMap<NodeId, Instant>>>>);
+
+impl Roster {
+    // Record that `node_id` is alive in `topic`
+    pub fn seen(&self, topic: TopicId, node_id: NodeId) {
+        let mut rooms = self.0.lock().expect("roster lock poisoned");
+        rooms.entry(topic).or_default().insert(node_id, Instant::now());
+    }
+
+    // Forget a peer that said it is leaving
+    pub fn remove(&self, topic: &TopicId, node_id: &NodeId) {
+        if let Some(peers) = self.0.lock().expect("roster lock poisoned").get_mut(topic) {
+            peers.remove(node_id);
+        }
+    }
+
+    pub fn remove_room(&self, topic: &TopicId) {
+        self.0.lock().expect("roster lock poisoned").remove(topic);
+    }
+
+    // Peers heard from recently in `topic`, with how long ago that was
+    pub fn online(&self, topic: &TopicId) -> Vec<(NodeId, Duration)> {
+        self.heard_within(topic, ONLINE_TIMEOUT)
+    }
+
+    // The same for any span of time, among the peers that didn't say they are leaving
+    pub fn heard_within(&self, topic: &TopicId, span: Duration) -> Vec<(NodeId, Duration)> {
+        let rooms = self.0.lock().expect("roster lock poisoned");
+        let Some(peers) = rooms.get(topic) else {
+            return Vec::new();
+        };
+        peers
+            .iter()
+            .map(|(node_id, last)| (*node_id, last.elapsed()))
+            .filter(|(_, elapsed)| *elapsed < span)
+            .collect()
+    }
+}
+
+// The gossip neighbors we are connected to, per room, as NeighborUp and NeighborDown tell, with
+// how many bytes of the room's messages each passed us since it came up
+#[derive(Debug, Clone, Default)]
+pub struct Neighbors(Arc<Mutex<HashMap<TopicId, HashMap<NodeId, u64>>>>);
+
+impl Neighbors {
+    pub fn up(&self, topic: TopicId, node_id: NodeId) {
+        self.0.lock().expect("neighbors lock poisoned").entry(topic).or_default().entry(node_id).or_default();
+    }
+
+    pub fn received(&self, topic: &TopicId, node_id: &NodeId, bytes: usize) {
+        let mut rooms = self.0.lock().expect("neighbors lock poisoned");
+        if let Some(received) = rooms.get_mut(topic).and_then(|neighbors| neighbors.get_mut(node_id)) {
+            *received += bytes as u64;
+        }
+    }
+
+    pub fn down(&self, topic: &TopicId, node_id: &NodeId) {
+        if let Some(neighbors) = self.0.lock().expect("neighbors lock poisoned").get_mut(topic) {
+            neighbors.remove(node_id);
+        }
+    }
+
+    pub fn remove_room(&self, topic: &TopicId) {
+        self.0.lock().expect("neighbors lock poisoned").remove(topic);
+    }
+
+    pub fn of(&self, topic: &TopicId) -> Vec<NodeId> {
+        self.traffic(topic).into_iter().map(|(node_id, _)| node_id).collect()
+    }
+
+    pub fn traffic(&self, topic: &TopicId) -> Vec<(NodeId, u64)> {
+        let rooms = self.0.lock().expect("neighbors lock poisoned");
+        let neighbors = rooms.get(topic).into_iter().flatten();
+        neighbors.map(|(node_id, received)| (*node_id, *received)).collect()
+    }
+}
+
+// What we tell our rooms about ourselves besides our name, sent along with our heartbeats while
+// there is anything to tell
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    // Set while away, to the note left with /away, which may be empty
+    pub away: Option<String>,
+    // In do-not-disturb, for those that want their rooms to know
+    pub dnd: bool,
+    // What the peer says it is up to, set with /status
+    pub text: String,
+}
+
+// Our own status and the latest each peer sent. A peer's holds for as long as it keeps sending it,
+// so one that left or missed telling us it is back doesn't stay away forever.
+#[derive(Debug, Clone, Default)]
+pub struct Statuses {
+    ours: Arc<Mutex<Status>>,
+    peers: Arc<Mutex<HashMap<NodeId, (Status, Instant)>>>,
+}
+
+impl Statuses {
+    pub fn ours(&self) -> Status {
+        self.ours.lock().expect("status lock poisoned").clone()
+    }
+
+    // Returns the status we had before
+    pub fn set_ours(&self, status: Status) -> Status {
+        std::mem::replace(&mut *self.ours.lock().expect("status lock poisoned"), status)
+    }
+
+    // Record what `node_id` sent, returning what it had before
+    pub fn heard(&self, node_id: NodeId, status: Status) -> Status {
+        let old = self.of(&node_id);
+        self.peers.lock().expect("status lock poisoned").insert(node_id, (status, Instant::now()));
+        old
+    }
+
+    pub fn of(&self, node_id: &NodeId) -> Status {
+        let peers = self.peers.lock().expect("status lock poisoned");
+        match peers.get(node_id) {
+            Some((status, heard)) if heard.elapsed() < ONLINE_TIMEOUT => status.clone(),
+            _ => Status::default(),
+        }
+    }
+}
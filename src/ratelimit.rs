@@ -0,0 +1,133 @@
+use iroh::NodeId;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::hash::Hash;
+use tokio::time::Instant;
+
+// How much each peer may send us in a room: `per_second` messages on average, and up to `burst`
+// at once
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self { per_second: 5.0, burst: 20.0 }
+    }
+}
+
+// What to do with a peer's message
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admit {
+    Yes,
+    // Drop it; the peer just went over the limit
+    Limited,
+    // Drop it, the peer was over the limit already
+    No,
+    // Take it; the peer had been over the limit and `dropped` of its messages went unseen
+    Resumed { dropped: usize },
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    // Messages dropped since the peer went over the limit, if it is over it
+    dropped: Option<usize>,
+}
+
+// One token bucket per peer, or whatever else is being limited. A peer that runs its bucket dry
+// stays limited until the bucket is full again, so a steady flood gets one notice instead of one
+// every few messages.
+#[derive(Debug)]
+pub struct Limiter<K = NodeId> {
+    limit: RateLimit,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Eq + Hash> Limiter<K> {
+    pub fn new(limit: RateLimit) -> Self {
+        Self { limit, buckets: HashMap::new() }
+    }
+
+    pub fn admit(&mut self, from: K) -> Admit {
+        let now = Instant::now();
+        let limit = self.limit;
+        let bucket = self.buckets.entry(from).or_insert(Bucket { tokens: limit.burst, last: now, dropped: None });
+        let refill = now.duration_since(bucket.last).as_secs_f64() * limit.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(limit.burst);
+        bucket.last = now;
+        let resumed = match bucket.dropped {
+            Some(dropped) if bucket.tokens >= limit.burst => {
+                bucket.dropped = None;
+                Some(dropped)
+            }
+            Some(dropped) => {
+                bucket.dropped = Some(dropped + 1);
+                return Admit::No;
+            }
+            None => None,
+        };
+        if bucket.tokens < 1.0 {
+            bucket.dropped = Some(1);
+            return Admit::Limited;
+        }
+        bucket.tokens -= 1.0;
+        match resumed {
+            Some(dropped) => Admit::Resumed { dropped },
+            None => Admit::Yes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LIMIT: RateLimit = RateLimit { per_second: 2.0, burst: 4.0 };
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_until_the_bucket_is_full_again() {
+        let mut limiter = Limiter::new(LIMIT);
+        for _ in 0..4 {
+            assert_eq!(limiter.admit("alice"), Admit::Yes);
+        }
+        assert_eq!(limiter.admit("alice"), Admit::Limited);
+        assert_eq!(limiter.admit("alice"), Admit::No);
+        // Others have buckets of their own
+        assert_eq!(limiter.admit("bob"), Admit::Yes);
+        // Half full is enough to send again, but not to stop being limited
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.admit("alice"), Admit::No);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.admit("alice"), Admit::Resumed { dropped: 3 });
+        for _ in 0..3 {
+            assert_eq!(limiter.admit("alice"), Admit::Yes);
+        }
+        assert_eq!(limiter.admit("alice"), Admit::Limited);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_the_rate_up_to_the_burst() {
+        let mut limiter = Limiter::new(LIMIT);
+        for _ in 0..3 {
+            assert_eq!(limiter.admit("alice"), Admit::Yes);
+        }
+        // One left, and one more after half a second
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.admit("alice"), Admit::Yes);
+        assert_eq!(limiter.admit("alice"), Admit::Yes);
+        assert_eq!(limiter.admit("alice"), Admit::Limited);
+        // Quiet for a long time still only buys a burst
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(limiter.admit("alice"), Admit::Resumed { dropped: 1 });
+        for _ in 0..3 {
+            assert_eq!(limiter.admit("alice"), Admit::Yes);
+        }
+        assert_eq!(limiter.admit("alice"), Admit::Limited);
+    }
+}
@@ -0,0 +1,41 @@
+use crate::message::MessageId;
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+// The room a message is in, and who reacted to it with each emoji
+#[derive(Debug)]
+struct MessageReactions {
+    room: TopicId,
+    emoji: BTreeMap<String, BTreeSet<NodeId>>,
+}
+
+// Who reacted with what to each message
+#[derive(Debug, Clone, Default)]
+pub struct Reactions(Arc<Mutex<HashMap<MessageId, MessageReactions>>>);
+
+impl Reactions {
+    // Record a reaction. Returns the message's reaction counts, or None if `from` already reacted
+    // with this emoji.
+    pub fn add(
+        &self,
+        room: TopicId,
+        id: MessageId,
+        emoji: String,
+        from: NodeId,
+    ) -> Option<Vec<(String, usize)>> {
+        let mut messages = self.0.lock().expect("reactions lock poisoned");
+        let reactions = messages
+            .entry(id)
+            .or_insert_with(|| MessageReactions { room, emoji: BTreeMap::new() });
+        if !reactions.emoji.entry(emoji).or_default().insert(from) {
+            return None;
+        }
+        Some(reactions.emoji.iter().map(|(emoji, from)| (emoji.clone(), from.len())).collect())
+    }
+
+    pub fn remove_room(&self, room: &TopicId) {
+        self.0.lock().expect("reactions lock poisoned").retain(|_, reactions| reactions.room != *room);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::message::{AckKind, MessageId};
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+// Who has acknowledged one of our own messages
+#[derive(Debug)]
+struct Receipt {
+    room: TopicId,
+    delivered: BTreeSet<NodeId>,
+    read: BTreeSet<NodeId>,
+}
+
+// Delivery state of the messages we sent, and the messages we received but haven't read yet
+#[derive(Debug, Clone, Default)]
+pub struct Receipts {
+    sent: Arc<Mutex<HashMap<MessageId, Receipt>>>,
+    unread: Arc<Mutex<HashMap<TopicId, Vec<MessageId>>>>,
+}
+
+impl Receipts {
+    // Start collecting acks for a message we sent
+    pub fn track(&self, room: TopicId, id: MessageId) {
+        let receipt = Receipt { room, delivered: BTreeSet::new(), read: BTreeSet::new() };
+        self.sent.lock().expect("receipts lock poisoned").insert(id, receipt);
+    }
+
+    // Record an ack. Returns the message's room and who it was delivered to and read by so far,
+    // or None if it isn't one of ours or the ack changes nothing.
+    pub fn ack(
+        &self,
+        id: MessageId,
+        from: NodeId,
+        kind: AckKind,
+    ) -> Option<(TopicId, Vec<NodeId>, Vec<NodeId>)> {
+        let mut sent = self.sent.lock().expect("receipts lock poisoned");
+        let receipt = sent.get_mut(&id)?;
+        // Reading a message implies it was delivered
+        let mut changed = receipt.delivered.insert(from);
+        if kind == AckKind::Read {
+            changed |= receipt.read.insert(from);
+        }
+        if !changed {
+            return None;
+        }
+        let delivered = receipt.delivered.iter().copied().collect();
+        let read = receipt.read.iter().copied().collect();
+        Some((receipt.room, delivered, read))
+    }
+
+    // Remember a message that arrived while its room wasn't active
+    pub fn unread(&self, room: TopicId, id: MessageId) {
+        self.unread.lock().expect("receipts lock poisoned").entry(room).or_default().push(id);
+    }
+
+    // The messages to send read acks for once `room` becomes active
+    pub fn take_unread(&self, room: &TopicId) -> Vec<MessageId> {
+        self.unread.lock().expect("receipts lock poisoned").remove(room).unwrap_or_default()
+    }
+
+    pub fn remove_room(&self, room: &TopicId) {
+        self.sent.lock().expect("receipts lock poisoned").retain(|_, receipt| receipt.room != *room);
+        self.unread.lock().expect("receipts lock poisoned").remove(room);
+    }
+}
@@ -0,0 +1,2417 @@
+use crate::blocks::Blocks;
+use crate::bot::{Bot, Reply};
+use crate::chunks::{self, Reassembly};
+use crate::client::{ChatEvent, Events};
+use crate::location;
+use crate::contacts::{Cards, Contact};
+use crate::crypto::RoomKey;
+use crate::devices::{self, Setting, SyncMessage};
+use crate::directory;
+use crate::dm;
+use crate::files::{self, Files, Offer};
+use crate::history::{self, HistoryProtocol};
+use crate::membership::{Allowlist, Grant, ModAction, Membership, Moderation, Publishers, RoomInfo, Transfer};
+use crate::message::{self, AckKind, Message, MessageBody, MessageId};
+use crate::migrate::{MigrateProtocol, Move};
+use crate::names::{Claim, Names};
+use crate::ordering::{Clocks, Reorder};
+use crate::pins::{Pin, Pins};
+use crate::plugins::{Hook, Outcome, Plugins, Verdict};
+use crate::presence::{self, Neighbors, Roster, Status, Statuses};
+use crate::ratelimit::{Admit, Limiter, RateLimit};
+use crate::reactions::Reactions;
+use crate::receipts::Receipts;
+use crate::storage::{self, Signed, Storage, StoredMessage};
+use crate::ticket::Ticket;
+use crate::version::{self, Versions};
+use crate::HISTORY_LIMIT;
+use anyhow::{anyhow, bail, Context, Result};
+use futures_lite::StreamExt;
+use rand::random;
+use rand::seq::SliceRandom;
+use iroh::{Endpoint, NodeAddr, NodeId, Watcher};
+use iroh_gossip::api::{Event, GossipReceiver, GossipSender};
+use iroh_gossip::{net::Gossip, proto::TopicId, ALPN as GOSSIP_ALPN};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+// A topic we are subscribed to: the sending half plus the tasks draining the receiving half,
+// sending our heartbeats and, for public rooms, announcing the room in the directory
+struct Room {
+    key: RoomKey,
+    sender: GossipSender,
+    receiver: JoinHandle<Result<()>>,
+    heartbeat: JoinHandle<()>,
+    listing: Option<JoinHandle<()>>,
+}
+
+impl Room {
+    fn stop(&self) {
+        self.receiver.abort();
+        self.heartbeat.abort();
+        if let Some(listing) = &self.listing {
+            listing.abort();
+        }
+    }
+}
+
+// State shared between the manager and every room's receive loop
+#[derive(Clone)]
+struct Shared {
+    endpoint: Endpoint,
+    storage: Arc<Storage>,
+    files: Files,
+    names: Names,
+    active: Arc<Mutex<Option<TopicId>>>,
+    roster: Roster,
+    neighbors: Neighbors,
+    statuses: Statuses,
+    receipts: Receipts,
+    reactions: Reactions,
+    clocks: Clocks,
+    versions: Versions,
+    membership: Membership,
+    blocks: Blocks,
+    pins: Pins,
+    cards: Cards,
+    rate_limit: RateLimit,
+    plugins: Plugins,
+    bot: Option<Bot>,
+    // Where to report rooms their owner moved, for the client to follow
+    moves: Option<mpsc::UnboundedSender<Move>>,
+    // Whether we tell rooms we keep their history for members that were away
+    mailbox: bool,
+    events: Events,
+}
+
+// How many peers besides us a regenerated ticket lists, to keep it pasteable
+const TICKET_PEERS: usize = 8;
+
+// How far before a gap to ask a mailbox or neighbor from, for senders whose clocks run behind
+const CATCH_UP_SLACK: i64 = 2 * 60 * 1000;
+
+// The most messages one catch-up fetches from a mailbox, in pages of history::MAX_LIMIT
+const CATCH_UP_LIMIT: usize = 5000;
+
+// How many heads of the room's log a message of ours names as its parents
+const MAX_PARENTS: usize = 8;
+
+// The most messages one repair of the log fetches, up to history::MAX_LIMIT a round
+const REPAIR_LIMIT: usize = 5000;
+
+// Messages longer than this many bytes go into the blob store, and the room only gets the first
+// LONG_TEXT_PREVIEW characters and the hash to fetch the rest with
+const LONG_TEXT: usize = 4 * 1024;
+const LONG_TEXT_PREVIEW: usize = 280;
+
+// After losing every neighbor in a room while peers were still in it, how long until trying to
+// reach them again, doubling each time up to RECONNECT_MAX
+const RECONNECT_FIRST: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+// How long each attempt waits for a peer to answer
+const RECONNECT_DIAL: Duration = Duration::from_secs(10);
+// Peers count as still in the room for that if we heard from them this recently
+const RECONNECT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// Every this many heartbeats, tell the room where up to PEERS_SAMPLE of its members are. While
+// we have fewer than PEERS_WANTED neighbors, we also join the ones we hear of this way.
+const PEERS_EVERY: u32 = 6;
+const PEERS_SAMPLE: usize = 8;
+const PEERS_WANTED: usize = 3;
+
+// Acks grow with everyone else's traffic, so each peer gets this many times its message limit
+// for them, in a bucket of their own
+const ACK_ALLOWANCE: f64 = 10.0;
+
+// Holds every room we are in, keyed by topic, and which one typed messages go to
+pub struct RoomManager {
+    endpoint: Endpoint,
+    gossip: Gossip,
+    history: HistoryProtocol,
+    migrations: MigrateProtocol,
+    shared: Shared,
+    rooms: HashMap<TopicId, Room>,
+    order: Vec<TopicId>,
+    // Our subscription to the public directory, once we publish a room there
+    directory: Option<(GossipSender, JoinHandle<()>)>,
+    // When we last told a room we are typing, for throttling
+    typing_sent: Option<(TopicId, Instant)>,
+}
+
+impl RoomManager {
+    pub fn new(
+        endpoint: Endpoint,
+        gossip: Gossip,
+        history: HistoryProtocol,
+        storage: Arc<Storage>,
+        files: Files,
+        name: String,
+        events: Events,
+    ) -> Self {
+        let names = Names::new(endpoint.node_id(), name);
+        let membership = Membership::default();
+        Self {
+            endpoint: endpoint.clone(),
+            gossip,
+            history,
+            migrations: MigrateProtocol::new(membership.clone(), endpoint.node_id()),
+            shared: Shared {
+                endpoint,
+                storage,
+                files,
+                names,
+                active: Default::default(),
+                roster: Default::default(),
+                neighbors: Default::default(),
+                statuses: Default::default(),
+                receipts: Default::default(),
+                reactions: Default::default(),
+                clocks: Default::default(),
+                versions: Default::default(),
+                membership,
+                blocks: Default::default(),
+                pins: Default::default(),
+                cards: Default::default(),
+                rate_limit: Default::default(),
+                plugins: Default::default(),
+                bot: None,
+                moves: None,
+                mailbox: false,
+                events,
+            },
+            rooms: HashMap::new(),
+            order: Vec::new(),
+            directory: None,
+            typing_sent: None,
+        }
+    }
+
+    // Run chat messages through these plugins on their way in and out
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.shared.plugins = plugins;
+        self
+    }
+
+    // Drop what peers send beyond this rate, per room
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.shared.rate_limit = rate_limit;
+        self
+    }
+
+    // Answer the bot's commands in every room
+    pub fn with_bot(mut self, bot: Bot) -> Self {
+        self.shared.bot = Some(bot);
+        self
+    }
+
+    // Report the rooms whose owner moved them to a new topic here
+    pub fn with_moves(mut self, moves: mpsc::UnboundedSender<Move>) -> Self {
+        self.shared.moves = Some(moves);
+        self
+    }
+
+    // Announce ourselves as a mailbox, for members to catch up from after being away
+    pub fn with_mailbox(mut self) -> Self {
+        self.shared.mailbox = true;
+        self
+    }
+
+    // Subscribe to a topic, announce ourselves and make it the active room. Only the room's owner,
+    // if the ticket names one, can restrict who we listen to, and make the room read-only for all
+    // but the ticket's `publishers`.
+    pub async fn join(
+        &mut self,
+        topic_id: TopicId,
+        key: RoomKey,
+        peers: Vec<NodeAddr>,
+        owner: Option<NodeId>,
+        publishers: Option<Publishers>,
+    ) -> Result<()> {
+        if self.rooms.contains_key(&topic_id) {
+            bail!("already in room {}", topic_id.fmt_short());
+        }
+        self.history.add_room(topic_id, key);
+        // The allowlist, info and moderation we saved last time, unless the ticket says someone else
+        // owns the room now
+        let saved = self.shared.storage.allowlist(&topic_id)?;
+        let info = self.shared.storage.room_info(&topic_id)?;
+        let moderation = self.shared.storage.moderation(&topic_id)?;
+        let saved_publishers = self.shared.storage.publishers(&topic_id)?;
+        let saved_owner = saved.as_ref().map(|(owner, _)| *owner).or(moderation.as_ref().map(|(owner, _)| *owner));
+        if let Some(owner) = owner.or(saved_owner) {
+            let list = saved.filter(|(saved_owner, _)| *saved_owner == owner).map(|(_, list)| list);
+            let info = info.filter(|(saved_owner, _)| *saved_owner == owner).map(|(_, info)| info);
+            let moderation = moderation.filter(|(saved_owner, _)| *saved_owner == owner).map(|(_, state)| state);
+            self.shared.membership.add_room(topic_id, owner, list, info, moderation.unwrap_or_default());
+            let saved_publishers = saved_publishers.filter(|(saved_owner, _)| *saved_owner == owner);
+            if let Some(publishers) = publishers.or(saved_publishers.map(|(_, publishers)| publishers)) {
+                self.shared
+                    .membership
+                    .update_publishers(&topic_id, publishers.clone())
+                    .context("the room's publishers aren't signed by its owner")?;
+                self.shared.storage.save_publishers(&topic_id, &owner, &publishers)?;
+                self.shared.events.emit(read_only_event(&self.shared.names, topic_id, owner, &publishers));
+            }
+        }
+        for (name, node_id) in self.shared.storage.pins(&topic_id)? {
+            self.shared.pins.load(topic_id, name, node_id);
+        }
+        // Pick up the room's clock where our history left it, and count what comes next as unread
+        // if we never read here
+        let max_clock = self.shared.storage.max_clock(&topic_id)?;
+        self.shared.clocks.observe(topic_id, max_clock);
+        self.shared.storage.start_reading(&topic_id, max_clock)?;
+
+        // Add known peers to the endpoint
+        for addr in &peers {
+            self.endpoint.add_node_addr(addr.clone())?;
+        }
+
+        // Subscribe to topic and wait for peers to connect
+        let topic = self
+            .gossip
+            .subscribe(topic_id, peers.iter().map(|a| a.node_id).collect())
+            .await?;
+        let (sender, receiver) = topic.split();
+
+        // Announce ourselves, showing whose device we are first so the name counts for the account.
+        // Nobody may hear this yet: Hello and Device go out again as neighbors come up, and peers that
+        // miss our AboutMe take our next heartbeat for it.
+        if let Some(device) = device(&self.endpoint, &self.shared.names) {
+            broadcast(&sender, &self.endpoint, &key, device).await?;
+        }
+        let about_me = MessageBody::AboutMe { from: self.endpoint.node_id(), name: self.shared.names.ours() };
+        broadcast(&sender, &self.endpoint, &key, about_me).await?;
+        broadcast(&sender, &self.endpoint, &key, hello(&self.endpoint, self.shared.mailbox)).await?;
+
+        // Spawn receiver loop
+        let receiver = tokio::spawn(subscribe_loop(
+            receiver,
+            sender.clone(),
+            topic_id,
+            key,
+            self.gossip.clone(),
+            peers.clone(),
+            self.shared.clone(),
+        ));
+        let heartbeat = tokio::spawn(heartbeat(
+            sender.clone(),
+            self.endpoint.clone(),
+            self.shared.names.clone(),
+            self.shared.statuses.clone(),
+            self.shared.roster.clone(),
+            topic_id,
+            key,
+        ));
+        self.rooms.insert(topic_id, Room { key, sender, receiver, heartbeat, listing: None });
+        let bookmarked = self.shared.storage.find_bookmark(&topic_id)?.is_some();
+        self.shared.storage.bookmark(&topic_id, &key, owner.as_ref(), &peers)?;
+        // Rejoining changes nothing our other devices need to know
+        if !bookmarked {
+            let bookmark = self.shared.storage.find_bookmark(&topic_id)?;
+            self.changed(Setting::Bookmark(topic_id, bookmark))?;
+        }
+        self.order.push(topic_id);
+        *self.shared.active.lock().await = Some(topic_id);
+
+        // Replay what we remember of this room from earlier sessions
+        let recent = self.shared.storage.recent(&topic_id, HISTORY_LIMIT)?;
+        for stored in recent.into_iter().filter(|stored| !self.shared.blocks.contains(&stored.sender)) {
+            let quoted = quoted(&self.shared.storage, &topic_id, stored.reply_to);
+            self.shared.events.emit(ChatEvent::history(topic_id, stored, false, false, quoted));
+        }
+        tokio::spawn(backfill(self.endpoint.clone(), peers, topic_id, key, self.shared.clone()));
+        Ok(())
+    }
+
+    // Rooms are addressed by their position in `list()`, the name their owner gave them or a prefix
+    // of the topic id
+    pub fn find(&self, query: &str) -> Result<TopicId> {
+        if let Ok(index) = query.parse::<usize>()
+            && let Some(topic_id) = index.checked_sub(1).and_then(|i| self.order.get(i))
+        {
+            return Ok(*topic_id);
+        }
+        let membership = &self.shared.membership;
+        let named = |topic_id: &TopicId| membership.info(topic_id).is_some_and(|info| info.name == query);
+        let matches: Vec<_> = self
+            .order
+            .iter()
+            .filter(|topic_id| named(topic_id) || topic_id.to_string().starts_with(query))
+            .collect();
+        match matches.as_slice() {
+            [topic_id] => Ok(**topic_id),
+            [] => bail!("no room matching {}", query),
+            _ => bail!("{} matches more than one room", query),
+        }
+    }
+
+    // Tell every room we are going and stop their tasks, before the endpoint shuts down
+    pub async fn shutdown(&mut self) -> Vec<TopicId> {
+        let left: Vec<_> = self.order.drain(..).collect();
+        for topic_id in &left {
+            // Where to look for the room's peers next time, unless we are alone and don't know
+            let peers = self.known_peers(topic_id);
+            if !peers.is_empty()
+                && let Err(err) = self.shared.storage.bookmark_peers(topic_id, &peers)
+            {
+                self.shared.events.warn(format!("failed to save the peers of {}: {}", topic_id.fmt_short(), err));
+            }
+            if let Some(room) = self.rooms.remove(topic_id) {
+                self.say_goodbye(*topic_id, &room).await;
+            }
+        }
+        *self.shared.active.lock().await = None;
+        if let Some((_, drain)) = self.directory.take() {
+            drain.abort();
+        }
+        left
+    }
+
+    // Show every room which account we speak for, once we were linked to one
+    pub async fn announce_device(&self) {
+        for (topic_id, room) in &self.rooms {
+            let Some(device) = device(&self.endpoint, &self.shared.names) else {
+                return;
+            };
+            if let Err(err) = broadcast(&room.sender, &self.endpoint, &room.key, device).await {
+                let room = topic_id.fmt_short();
+                self.shared.events.warn(format!("could not announce our account in {}: {}", room, err));
+            }
+        }
+    }
+
+    // Take another name and announce it in every room, whose members show it from then on
+    pub async fn nick(&self, name: &str) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() {
+            bail!("the name can't be empty");
+        }
+        if name == self.shared.names.ours() {
+            bail!("you already go by {}", name);
+        }
+        let old = self.shared.names.rename_us(name.to_string());
+        for (topic_id, room) in &self.rooms {
+            let about_me = MessageBody::AboutMe { from: self.endpoint.node_id(), name: name.to_string() };
+            if let Err(err) = broadcast(&room.sender, &self.endpoint, &room.key, about_me).await {
+                let room = topic_id.fmt_short();
+                self.shared.events.warn(format!("could not announce our new name in {}: {}", room, err));
+            }
+        }
+        Ok(old)
+    }
+
+    // Tell every room we are away, or back, and return what we were before. Rooms keep hearing it
+    // with our heartbeats.
+    pub async fn set_status(&self, status: Status) -> Status {
+        let old = self.shared.statuses.set_ours(status.clone());
+        for (topic_id, room) in &self.rooms {
+            let body = MessageBody::Status { from: self.endpoint.node_id(), status: status.clone() };
+            if let Err(err) = broadcast(&room.sender, &self.endpoint, &room.key, body).await {
+                let room = topic_id.fmt_short();
+                self.shared.events.warn(format!("could not tell {} whether we are away: {}", room, err));
+            }
+        }
+        old
+    }
+
+    async fn say_goodbye(&self, topic_id: TopicId, room: &Room) {
+        let leave = MessageBody::Leave { from: self.endpoint.node_id() };
+        if let Err(err) = broadcast(&room.sender, &self.endpoint, &room.key, leave).await {
+            self.shared.events.warn(format!("could not say goodbye to {}: {}", topic_id.fmt_short(), err));
+        }
+        room.stop();
+    }
+
+    pub async fn switch(&mut self, query: &str) -> Result<TopicId> {
+        let topic_id = self.find(query)?;
+        *self.shared.active.lock().await = Some(topic_id);
+        // Everything that arrived while we were elsewhere is on screen now
+        read_up_to(&self.shared, topic_id, self.shared.storage.max_clock(&topic_id)?);
+        let room = &self.rooms[&topic_id];
+        for id in self.shared.receipts.take_unread(&topic_id) {
+            let ack = MessageBody::Ack { from: self.endpoint.node_id(), id, kind: AckKind::Read };
+            broadcast(&room.sender, &self.endpoint, &room.key, ack).await?;
+        }
+        Ok(topic_id)
+    }
+
+    // Leave the given room, or the active one. Dropping the sender and receiver unsubscribes.
+    pub async fn leave(&mut self, query: Option<&str>) -> Result<TopicId> {
+        let topic_id = match query {
+            Some(query) => self.find(query)?,
+            None => self.active().await?,
+        };
+        if let Some(room) = self.rooms.remove(&topic_id) {
+            self.say_goodbye(topic_id, &room).await;
+        }
+        self.forget(topic_id).await?;
+        Ok(topic_id)
+    }
+
+    // Drop everything we know about a room we are no longer in
+    async fn forget(&mut self, topic_id: TopicId) -> Result<()> {
+        // Leaving is for good, shutting down isn't
+        self.shared.storage.remove_bookmark(&topic_id)?;
+        self.changed(Setting::Bookmark(topic_id, None))?;
+        self.history.remove_room(&topic_id);
+        self.shared.roster.remove_room(&topic_id);
+        self.shared.neighbors.remove_room(&topic_id);
+        self.shared.receipts.remove_room(&topic_id);
+        self.shared.reactions.remove_room(&topic_id);
+        self.shared.clocks.remove_room(&topic_id);
+        self.shared.membership.remove_room(&topic_id);
+        self.order.retain(|t| *t != topic_id);
+        let mut active = self.shared.active.lock().await;
+        if *active == Some(topic_id) {
+            *active = self.order.last().copied();
+        }
+        Ok(())
+    }
+
+    // Move the active room to a fresh topic and key, as its owner, and leave the old topic behind.
+    // The move is announced under the old key, but the new one is only handed to peers that ask us
+    // and that the room still lets in, so revoke or ban whoever should be left out first. Returns
+    // the old topic, the new one and its key.
+    pub async fn rotate(&mut self) -> Result<(TopicId, TopicId, RoomKey)> {
+        let from = self.active().await?;
+        let us = self.endpoint.node_id();
+        if self.shared.membership.owner(&from) != Some(us) {
+            bail!("only the room's owner can rotate it");
+        }
+        let to = TopicId::from_bytes(random());
+        let key = RoomKey::generate();
+        self.carry_owner_state(from, to)?;
+        self.migrations.add_room(to, key);
+        self.join(to, key, vec![], Some(us), None).await?;
+        self.adopt(from, to)?;
+        let room = &self.rooms[&from];
+        broadcast(&room.sender, &self.endpoint, &room.key, MessageBody::Migrate { from: us, topic: to }).await?;
+        self.retire(from, to).await?;
+        Ok((from, to, key))
+    }
+
+    // Follow a room its owner moved, once we have the new key, without changing which room is active
+    pub async fn migrate(&mut self, moved: Move, key: RoomKey, owner: NodeAddr) -> Result<()> {
+        if !self.rooms.contains_key(&moved.from) || self.rooms.contains_key(&moved.to) {
+            return Ok(());
+        }
+        let active = *self.shared.active.lock().await;
+        // The owner passes the publishers of read-only rooms on like the rest of its state
+        self.join(moved.to, key, vec![owner], Some(moved.owner), None).await?;
+        self.adopt(moved.from, moved.to)?;
+        self.retire(moved.from, moved.to).await?;
+        if active != Some(moved.from) {
+            *self.shared.active.lock().await = active;
+        }
+        Ok(())
+    }
+
+    // Take over a moved room's history and pins once its new topic is joined, which leaves nothing
+    // to replay: it is all on screen already
+    fn adopt(&self, from: TopicId, to: TopicId) -> Result<()> {
+        let storage = &self.shared.storage;
+        storage.move_room(&from, &to)?;
+        self.shared.clocks.observe(to, storage.max_clock(&to)?);
+        for (name, node_id) in storage.pins(&to)? {
+            self.shared.pins.load(to, name, node_id);
+        }
+        Ok(())
+    }
+
+    // Sign the allowlist, info and moderation of a room again for the topic it moves to, and save
+    // them for joining it to pick up. Dismissed moderators don't get a new grant.
+    fn carry_owner_state(&self, from: TopicId, to: TopicId) -> Result<()> {
+        let (secret_key, us) = (self.endpoint.secret_key(), self.endpoint.node_id());
+        let membership = &self.shared.membership;
+        let storage = &self.shared.storage;
+        if let Some(list) = membership.list(&from) {
+            storage.save_allowlist(&to, &us, &Allowlist::sign(secret_key, to, list.revision, list.members))?;
+        }
+        if let Some(info) = membership.info(&from) {
+            let info = RoomInfo::sign(secret_key, to, info.revision, info.name, info.description, info.topic);
+            storage.save_room_info(&to, &us, &info)?;
+        }
+        if let Some(moderation) = membership.moderation(&from) {
+            let moderators = moderation
+                .moderators
+                .iter()
+                .filter(|grant| !moderation.dismissed.contains(&grant.moderator))
+                .map(|grant| Grant::sign(secret_key, to, grant.moderator))
+                .collect();
+            let revision = moderation.revision;
+            let moderation = Moderation { moderators, dismissed: Vec::new(), banned: moderation.banned, revision };
+            storage.save_moderation(&to, &us, &moderation)?;
+        }
+        if let Some(publishers) = membership.publishers(&from) {
+            storage.save_publishers(&to, &us, &Publishers::sign(secret_key, to, publishers.members))?;
+        }
+        Ok(())
+    }
+
+    // Drop a room that moved to `to`, which takes its place in the room list. Nobody is told we
+    // left: everyone who should be is on their way to the new topic as well.
+    async fn retire(&mut self, from: TopicId, to: TopicId) -> Result<()> {
+        self.order.retain(|topic_id| *topic_id != to);
+        if let Some(slot) = self.order.iter_mut().find(|topic_id| **topic_id == from) {
+            *slot = to;
+        }
+        if let Some(room) = self.rooms.remove(&from) {
+            room.stop();
+        }
+        self.forget(from).await?;
+        self.shared.events.emit(ChatEvent::Moved { room: to, old_room: from });
+        Ok(())
+    }
+
+    // Hands out the new keys of the rooms we rotated
+    pub fn migrations(&self) -> MigrateProtocol {
+        self.migrations.clone()
+    }
+
+    pub async fn active(&self) -> Result<TopicId> {
+        match *self.shared.active.lock().await {
+            Some(topic_id) => Ok(topic_id),
+            None => bail!("not in any room, join one first"),
+        }
+    }
+
+    // Topics in join order, with a flag for the active one
+    pub async fn list(&self) -> Vec<(TopicId, bool)> {
+        let active = *self.shared.active.lock().await;
+        self.order.iter().map(|t| (*t, Some(*t) == active)).collect()
+    }
+
+    // A fresh ticket for the active room, listing us and the peers most recently heard from there,
+    // so anyone in the room can invite others even after whoever opened it has left
+    pub async fn ticket(&self) -> Result<Ticket> {
+        let topic_id = self.active().await?;
+        let mut nodes = vec![self.endpoint.node_addr().initialized().await];
+        nodes.extend(self.known_peers(&topic_id));
+        let owner = self.shared.membership.owner(&topic_id);
+        let publishers = self.shared.membership.publishers(&topic_id);
+        Ok(Ticket { owner, ..Ticket::new(topic_id, nodes, self.rooms[&topic_id].key) }.with_publishers(publishers))
+    }
+
+    // Announce the active room in the public directory until we leave it. Protected rooms are listed
+    // with a ticket that leaves out the key.
+    pub async fn publish(&mut self, protected: bool) -> Result<()> {
+        let topic_id = self.active().await?;
+        if self.directory.is_none() {
+            // The directory needs someone to bootstrap from like any topic: try the room's peers and
+            // our contacts
+            let online = self.shared.roster.online(&topic_id).into_iter().map(|(node_id, _)| node_id);
+            let contacts = self.shared.names.contacts().list().into_iter().map(|contact| contact.node_id);
+            self.directory = Some(directory::join(&self.gossip, online.chain(contacts).collect()).await?);
+        }
+        let mut ticket = self.ticket().await?;
+        if protected {
+            let read_only = Ticket { owner: ticket.owner, ..Ticket::protected(ticket.topic, ticket.nodes) };
+            ticket = read_only.with_publishers(ticket.publishers);
+        }
+        let (sender, _) = self.directory.as_ref().expect("joined above");
+        let membership = self.shared.membership.clone();
+        let listing = directory::announce(sender.clone(), self.endpoint.clone(), membership, topic_id, ticket);
+        let room = self.rooms.get_mut(&topic_id).expect("the active room is joined");
+        if let Some(old) = room.listing.replace(listing) {
+            old.abort();
+        }
+        Ok(())
+    }
+
+    // Addresses of the peers online in a room, most recently seen first
+    fn known_peers(&self, topic_id: &TopicId) -> Vec<NodeAddr> {
+        let mut online = self.shared.roster.online(topic_id);
+        online.sort_by_key(|(_, last_seen)| *last_seen);
+        let known = online.iter().filter_map(|(node_id, _)| self.endpoint.remote_info(*node_id));
+        known.take(TICKET_PEERS).map(NodeAddr::from).collect()
+    }
+
+    pub fn is_joined(&self, topic_id: &TopicId) -> bool {
+        self.rooms.contains_key(topic_id)
+    }
+
+    // Let `node_id` speak in the active room. The first change makes the room invite-only, starting
+    // from us and everyone online there so nobody already in the room is cut off.
+    pub async fn allow(&self, node_id: NodeId) -> Result<()> {
+        let (topic_id, mut members) = self.own_members().await?;
+        if !members.contains(&node_id) {
+            members.push(node_id);
+        }
+        self.restrict(topic_id, members).await
+    }
+
+    // Stop listening to `node_id` in the active room, and have every other member do the same
+    pub async fn revoke(&self, node_id: NodeId) -> Result<()> {
+        if node_id == self.endpoint.node_id() {
+            bail!("you can't revoke yourself");
+        }
+        let (topic_id, mut members) = self.own_members().await?;
+        members.retain(|member| *member != node_id);
+        self.restrict(topic_id, members).await
+    }
+
+    // Kick, ban, unban or dismiss a peer in the active room, as its owner or one of its moderators
+    pub async fn moderate(&self, target: NodeId, action: ModAction) -> Result<()> {
+        let topic_id = self.active().await?;
+        let us = self.endpoint.node_id();
+        if target == us {
+            bail!("you can't moderate yourself");
+        }
+        let membership = &self.shared.membership;
+        let (signed_by, revision) = (membership.grant(&topic_id, &us), membership.next_revision(&topic_id));
+        if !membership.moderate(&topic_id, us, &action, target, signed_by.as_ref(), revision)? {
+            let name = self.shared.names.display(&target);
+            match action {
+                ModAction::Kick => bail!("{} was already kicked", name),
+                ModAction::Ban => bail!("{} is already banned", name),
+                ModAction::Unban => bail!("{} isn't banned", name),
+                ModAction::Appoint(_) => bail!("{} is already a moderator", name),
+                ModAction::Dismiss => bail!("{} isn't a moderator", name),
+            }
+        }
+        moderated(&self.shared, topic_id, &action, &target);
+        let room = &self.rooms[&topic_id];
+        let name = action.name().to_string();
+        let body = MessageBody::Moderation { from: us, action, target, signed_by, revision };
+        broadcast(&room.sender, &self.endpoint, &room.key, body).await?;
+        self.shared.events.emit(ChatEvent::Moderated {
+            room: topic_id,
+            action: name,
+            from: us,
+            by: self.shared.names.ours(),
+            target,
+            name: self.shared.names.display(&target),
+        });
+        Ok(())
+    }
+
+    // Make a peer a moderator of the active room, which has to be one we opened
+    pub async fn appoint(&self, target: NodeId) -> Result<()> {
+        let topic_id = self.active().await?;
+        if self.shared.membership.owner(&topic_id) != Some(self.endpoint.node_id()) {
+            bail!("only the room's owner can appoint moderators");
+        }
+        let grant = Grant::sign(self.endpoint.secret_key(), topic_id, target);
+        self.moderate(target, ModAction::Appoint(grant)).await
+    }
+
+    // Hand the active room to another peer for good, leaving us an ordinary member. The new owner
+    // signs the room's allowlist, info, publishers and moderators again once it hears of it.
+    pub async fn transfer(&self, new_owner: NodeId) -> Result<()> {
+        let topic_id = self.active().await?;
+        let us = self.endpoint.node_id();
+        if self.shared.membership.owner(&topic_id) != Some(us) {
+            bail!("only the room's owner can hand it over");
+        }
+        if new_owner == us {
+            bail!("you own the room already");
+        }
+        let generation = self.shared.membership.next_generation(&topic_id);
+        let transfer = Transfer::sign(self.endpoint.secret_key(), topic_id, generation, new_owner);
+        self.shared.membership.transfer(&topic_id, transfer.clone())?;
+        changed_hands(&self.shared, topic_id, us, new_owner);
+        let room = &self.rooms[&topic_id];
+        let body = MessageBody::OwnershipTransfer { from: us, transfer };
+        broadcast(&room.sender, &self.endpoint, &room.key, body).await?;
+        Ok(())
+    }
+
+    // The active room's members for its owner to change: the current allowlist, or everyone online
+    // if there is none yet
+    async fn own_members(&self) -> Result<(TopicId, Vec<NodeId>)> {
+        let topic_id = self.active().await?;
+        let us = self.endpoint.node_id();
+        if self.shared.membership.owner(&topic_id) != Some(us) {
+            bail!("only the room's owner can change who may speak in it");
+        }
+        let members = match self.shared.membership.list(&topic_id) {
+            Some(list) => list.members,
+            None => {
+                let online = self.shared.roster.online(&topic_id);
+                std::iter::once(us).chain(online.into_iter().map(|(node_id, _)| node_id)).collect()
+            }
+        };
+        Ok((topic_id, members))
+    }
+
+    // Sign the next revision of a room's allowlist, keep it and hand it to the room
+    async fn restrict(&self, topic_id: TopicId, members: Vec<NodeId>) -> Result<()> {
+        let revision = self.shared.membership.list(&topic_id).map_or(1, |list| list.revision + 1);
+        let list = Allowlist::sign(self.endpoint.secret_key(), topic_id, revision, members);
+        self.shared.membership.update(&topic_id, list.clone())?;
+        self.shared.storage.save_allowlist(&topic_id, &self.endpoint.node_id(), &list)?;
+        let room = &self.rooms[&topic_id];
+        let body = MessageBody::Allowlist { from: self.endpoint.node_id(), list: list.clone() };
+        broadcast(&room.sender, &self.endpoint, &room.key, body).await?;
+        let members = list.members.iter().map(|id| self.shared.names.display(id)).collect();
+        self.shared.events.emit(ChatEvent::Members { room: topic_id, revision, members });
+        Ok(())
+    }
+
+    // Change what the active room is called and about, as its owner. Fields left out keep their value.
+    pub async fn set_info(
+        &self,
+        name: Option<String>,
+        description: Option<String>,
+        topic: Option<String>,
+    ) -> Result<()> {
+        let topic_id = self.active().await?;
+        let us = self.endpoint.node_id();
+        if self.shared.membership.owner(&topic_id) != Some(us) {
+            bail!("only the room's owner can change its name, description and topic");
+        }
+        let old = self.shared.membership.info(&topic_id);
+        let revision = old.as_ref().map_or(1, |info| info.revision + 1);
+        let (old_name, old_description, old_topic) =
+            old.map(|info| (info.name, info.description, info.topic)).unwrap_or_default();
+        let info = RoomInfo::sign(
+            self.endpoint.secret_key(),
+            topic_id,
+            revision,
+            name.unwrap_or(old_name),
+            description.unwrap_or(old_description),
+            topic.unwrap_or(old_topic),
+        );
+        self.shared.membership.update_info(&topic_id, info.clone())?;
+        self.shared.storage.save_room_info(&topic_id, &us, &info)?;
+        let room = &self.rooms[&topic_id];
+        let body = MessageBody::RoomInfo { from: us, info: info.clone() };
+        broadcast(&room.sender, &self.endpoint, &room.key, body).await?;
+        self.shared.events.emit(info_event(topic_id, info));
+        Ok(())
+    }
+
+    pub fn membership(&self) -> Membership {
+        self.shared.membership.clone()
+    }
+
+    pub fn active_handle(&self) -> Arc<Mutex<Option<TopicId>>> {
+        self.shared.active.clone()
+    }
+
+    pub fn roster(&self) -> Roster {
+        self.shared.roster.clone()
+    }
+
+    pub fn neighbors(&self) -> Neighbors {
+        self.shared.neighbors.clone()
+    }
+
+    pub fn statuses(&self) -> Statuses {
+        self.shared.statuses.clone()
+    }
+
+    pub fn versions(&self) -> Versions {
+        self.shared.versions.clone()
+    }
+
+    pub fn blocks(&self) -> Blocks {
+        self.shared.blocks.clone()
+    }
+
+    // A contact card shared in one of the rooms, by the id it was shown with
+    pub fn card(&self, id: usize) -> Result<Contact> {
+        self.shared.cards.get(id)
+    }
+
+    // Stop listening to a peer everywhere, now and after restarts. Returns whether it is new.
+    pub fn block(&self, node_id: NodeId, name: String) -> Result<bool> {
+        if node_id == self.endpoint.node_id() {
+            bail!("you can't block yourself");
+        }
+        if !self.shared.blocks.insert(node_id, name.clone()) {
+            return Ok(false);
+        }
+        self.shared.storage.block(&node_id, &name)?;
+        self.changed(Setting::Blocked(node_id, Some(name)))?;
+        Ok(true)
+    }
+
+    // Listen to a blocked peer again. Returns the name it was blocked under.
+    pub fn unblock(&self, node_id: &NodeId) -> Result<String> {
+        let Some(name) = self.shared.blocks.remove(node_id) else {
+            bail!("{} isn't blocked", node_id.fmt_short());
+        };
+        self.shared.storage.unblock(node_id)?;
+        self.changed(Setting::Blocked(*node_id, None))?;
+        Ok(name)
+    }
+
+    // Record a setting we changed, for our other devices to take up too
+    fn changed(&self, setting: Setting) -> Result<()> {
+        devices::changed(&self.endpoint, &self.shared.names.devices(), &self.shared.storage, setting)
+    }
+
+    // Accept the node id a peer uses in the active room as the rightful owner of its name, like
+    // after a pinned peer moved to a new device. Returns the name.
+    pub async fn trust(&self, node_id: NodeId) -> Result<String> {
+        let topic_id = self.active().await?;
+        let Some(name) = self.shared.names.get(&node_id) else {
+            bail!("{} hasn't told us its name yet", node_id.fmt_short());
+        };
+        self.shared.pins.trust(topic_id, &name, node_id);
+        self.shared.storage.pin(&topic_id, &name, &node_id)?;
+        self.shared.names.undispute(&node_id);
+        Ok(name)
+    }
+
+    // Forget who a name in the active room is pinned to, so the next node using it is trusted
+    pub async fn distrust(&self, name: &str) -> Result<NodeId> {
+        let topic_id = self.active().await?;
+        let Some(node_id) = self.shared.pins.distrust(topic_id, name) else {
+            bail!("{} isn't pinned in this room", name);
+        };
+        self.shared.storage.unpin(&topic_id, name)?;
+        Ok(node_id)
+    }
+
+    pub fn names_handle(&self) -> Names {
+        self.shared.names.clone()
+    }
+
+    // Look up a message of the active room by a prefix of its id (optionally starting with #),
+    // or the latest message if there is no query
+    pub async fn find_message(&self, query: Option<&str>) -> Result<MessageId> {
+        let topic_id = self.active().await?;
+        let prefix = query.unwrap_or_default().trim_start_matches('#').to_lowercase();
+        if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("{} is not a message id", prefix);
+        }
+        let matches = self.shared.storage.find(&topic_id, &prefix, 2)?;
+        match (query, matches.as_slice()) {
+            (None, []) => bail!("no messages in this room yet"),
+            (Some(query), []) => bail!("no message matching {}", query),
+            (None, [id, ..]) | (Some(_), [id]) => Ok(*id),
+            (Some(query), _) => bail!("{} matches more than one message", query),
+        }
+    }
+
+    // React to a message in the active room
+    pub async fn react(&self, target_id: MessageId, emoji: String) -> Result<()> {
+        let topic_id = self.active().await?;
+        let room = &self.rooms[&topic_id];
+        let from = self.endpoint.node_id();
+        if !self.shared.membership.may_post(&topic_id, &from) {
+            bail!("the room is read-only, only its publishers can post");
+        }
+        let reaction = MessageBody::Reaction { from, target_id, emoji: emoji.clone() };
+        broadcast(&room.sender, &self.endpoint, &room.key, reaction).await?;
+        if let Some(counts) = self.shared.reactions.add(topic_id, target_id, emoji.clone(), from) {
+            self.shared.events.emit(ChatEvent::Reaction {
+                room: topic_id,
+                id: target_id,
+                from,
+                name: self.shared.names.ours(),
+                emoji,
+                counts,
+            });
+        }
+        Ok(())
+    }
+
+    // Change the text of one of our own messages in the active room
+    pub async fn edit(&self, target_id: MessageId, new_text: String) -> Result<()> {
+        let topic_id = self.active().await?;
+        let from = self.endpoint.node_id();
+        if !self.shared.membership.may_post(&topic_id, &from) {
+            bail!("the room is read-only, only its publishers can post");
+        }
+        match self.shared.storage.get(&topic_id, &target_id)? {
+            Some(stored) if stored.deleted => bail!("message #{} was deleted", target_id.fmt_short()),
+            Some(stored) if stored.sender == from => {}
+            Some(_) => bail!("you can only edit your own messages"),
+            None => bail!("no message #{} in this room", target_id.fmt_short()),
+        }
+        let room = &self.rooms[&topic_id];
+        let edit = MessageBody::Edit { from, target_id, new_text: new_text.clone() };
+        let change = broadcast(&room.sender, &self.endpoint, &room.key, edit).await?;
+        self.shared.storage.edit(&topic_id, &target_id, &from, &new_text, &change)?;
+        self.shared.events.emit(ChatEvent::Edited { room: topic_id, id: target_id, text: new_text });
+        Ok(())
+    }
+
+    // Our newest message in the active room that is still there
+    pub async fn last_sent(&self) -> Result<MessageId> {
+        let topic_id = self.active().await?;
+        match self.shared.storage.last_from(&topic_id, &self.endpoint.node_id())? {
+            Some(id) => Ok(id),
+            None => bail!("you have no messages in this room"),
+        }
+    }
+
+    // Retract one of our own messages in the active room
+    pub async fn delete(&self, target_id: MessageId) -> Result<()> {
+        let topic_id = self.active().await?;
+        let from = self.endpoint.node_id();
+        if !self.shared.membership.may_post(&topic_id, &from) {
+            bail!("the room is read-only, only its publishers can post");
+        }
+        match self.shared.storage.get(&topic_id, &target_id)? {
+            Some(stored) if stored.deleted => bail!("message #{} was already deleted", target_id.fmt_short()),
+            Some(stored) if stored.sender == from => {}
+            Some(_) => bail!("you can only delete your own messages"),
+            None => bail!("no message #{} in this room", target_id.fmt_short()),
+        }
+        let room = &self.rooms[&topic_id];
+        let delete = MessageBody::Delete { from, target_id };
+        let change = broadcast(&room.sender, &self.endpoint, &room.key, delete).await?;
+        self.shared.storage.delete(&topic_id, &target_id, &from, &change)?;
+        self.shared.events.emit(ChatEvent::Deleted { room: topic_id, id: target_id });
+        Ok(())
+    }
+
+    // Import a file into the blob store and offer it to the active room
+    pub async fn send_file(&self, path: &Path) -> Result<(String, u64)> {
+        let topic_id = self.active().await?;
+        if !self.shared.membership.may_post(&topic_id, &self.endpoint.node_id()) {
+            bail!("the room is read-only, only its publishers can post");
+        }
+        let (hash, name, size) = self.shared.files.import(path).await?;
+        let offer = MessageBody::FileOffer { from: self.endpoint.node_id(), hash, name: name.clone(), size };
+        let room = &self.rooms[&topic_id];
+        broadcast(&room.sender, &self.endpoint, &room.key, offer).await?;
+        Ok((name, size))
+    }
+
+    // Offer a file we hold in memory to the active room, under `name`
+    pub async fn send_bytes(&self, name: String, bytes: Vec<u8>) -> Result<u64> {
+        let topic_id = self.active().await?;
+        if !self.shared.membership.may_post(&topic_id, &self.endpoint.node_id()) {
+            bail!("the room is read-only, only its publishers can post");
+        }
+        let size = bytes.len() as u64;
+        let hash = self.shared.files.import_bytes(&name, bytes).await?;
+        let room = &self.rooms[&topic_id];
+        let offer = MessageBody::FileOffer { from: self.endpoint.node_id(), hash, name, size };
+        broadcast(&room.sender, &self.endpoint, &room.key, offer).await?;
+        Ok(size)
+    }
+
+    // Offer a directory to the active room. Returns its name, size and how many files it holds.
+    pub async fn send_directory(&self, path: &Path) -> Result<(String, u64, u64)> {
+        let topic_id = self.active().await?;
+        if !self.shared.membership.may_post(&topic_id, &self.endpoint.node_id()) {
+            bail!("the room is read-only, only its publishers can post");
+        }
+        let (hash, name, size, files) = self.shared.files.import_dir(path).await?;
+        let from = self.endpoint.node_id();
+        let offer = MessageBody::DirectoryOffer { from, hash, name: name.clone(), size, files };
+        let room = &self.rooms[&topic_id];
+        broadcast(&room.sender, &self.endpoint, &room.key, offer).await?;
+        Ok((name, size, files))
+    }
+
+    // Send a code snippet to the active room
+    pub async fn send_code(&mut self, lang: String, text: String) -> Result<MessageId> {
+        self.send_body(MessageBody::Code { from: self.endpoint.node_id(), lang, text }).await
+    }
+
+    // Send a place on the map to the active room
+    pub async fn send_location(&mut self, lat: f64, lon: f64, label: String) -> Result<MessageId> {
+        self.send_body(MessageBody::Location { from: self.endpoint.node_id(), lat, lon, label }).await
+    }
+
+    // Code and places skip the plugins, which work on plain text
+    async fn send_body(&mut self, body: MessageBody) -> Result<MessageId> {
+        let topic_id = self.active().await?;
+        self.typing_sent = None;
+        let (room, name) = (&self.rooms[&topic_id], self.shared.names.ours());
+        let (msg, event) = post(&self.shared, &room.sender, &room.key, topic_id, &name, Message::new(body)).await?;
+        self.shared.events.emit(event);
+        Ok(msg.id)
+    }
+
+    // Hand the active room a card for one of our contacts
+    pub async fn share_contact(&self, card: &Contact) -> Result<()> {
+        let topic_id = self.active().await?;
+        let from = self.endpoint.node_id();
+        if !self.shared.membership.may_post(&topic_id, &from) {
+            bail!("the room is read-only, only its publishers can post");
+        }
+        let (node_id, name, addr) = (card.node_id, card.alias.clone(), card.addr.clone());
+        let room = &self.rooms[&topic_id];
+        let card = MessageBody::ContactCard { from, node_id, name, addr };
+        broadcast(&room.sender, &self.endpoint, &room.key, card).await?;
+        Ok(())
+    }
+
+    // Let the active room know we are composing, at most once per TYPING_INTERVAL
+    pub async fn typing(&mut self) -> Result<()> {
+        let topic_id = self.active().await?;
+        if let Some((sent_to, at)) = self.typing_sent
+            && sent_to == topic_id
+            && at.elapsed() < presence::TYPING_INTERVAL
+        {
+            return Ok(());
+        }
+        // Nobody would hear it in a room we can't post in
+        if !self.shared.membership.may_post(&topic_id, &self.endpoint.node_id()) {
+            return Ok(());
+        }
+        self.typing_sent = Some((topic_id, Instant::now()));
+        let room = &self.rooms[&topic_id];
+        let typing = MessageBody::Typing { from: self.endpoint.node_id() };
+        broadcast(&room.sender, &self.endpoint, &room.key, typing).await?;
+        Ok(())
+    }
+
+    // Broadcast a chat message to the active room
+    pub async fn send(&mut self, text: String) -> Result<MessageId> {
+        self.send_message(text, None).await
+    }
+
+    // Answer a message of the active room
+    pub async fn reply(&mut self, to: MessageId, text: String) -> Result<MessageId> {
+        let topic_id = self.active().await?;
+        if self.shared.storage.get(&topic_id, &to)?.is_none() {
+            bail!("no message #{} in this room", to.fmt_short());
+        }
+        self.send_message(text, Some(to)).await
+    }
+
+    async fn send_message(&mut self, text: String, reply_to: Option<MessageId>) -> Result<MessageId> {
+        let topic_id = self.active().await?;
+        // Whatever we type next is a new message
+        self.typing_sent = None;
+        let (room, name) = (&self.rooms[&topic_id], self.shared.names.ours());
+        let roster = online_names(&self.shared.roster, &self.shared.names, &topic_id);
+        let outcome = self.shared.plugins.run(Hook::Outbound, &name, &roster, text, &self.shared.events);
+        let text = match outcome.verdict {
+            Verdict::Pass(text) => text,
+            Verdict::Dropped(plugin) => bail!("plugin {} dropped the message", plugin),
+        };
+        let from = self.endpoint.node_id();
+        let body = MessageBody::Message { from, text };
+        let msg = Message { reply_to, ..Message::new(body) };
+        let (msg, event) = post(&self.shared, &room.sender, &room.key, topic_id, &name, msg).await?;
+        self.shared.events.emit(event);
+        // Plugin replies skip the plugins, ours and everyone else's, or two of them could keep
+        // answering each other
+        for reply in outcome.replies {
+            if !self.shared.plugins.may_send(topic_id, &self.shared.events) {
+                continue;
+            }
+            let reply = Message::automated(MessageBody::Message { from, text: reply });
+            match post(&self.shared, &room.sender, &room.key, topic_id, &name, reply).await {
+                Ok((_, event)) => self.shared.events.emit(event),
+                Err(err) => self.shared.events.warn(format!("failed to send plugin reply: {}", err)),
+            }
+        }
+        Ok(msg.id)
+    }
+
+    // The replies leading up to a message of the active room, the message itself and the replies
+    // below it, each with its depth
+    pub async fn thread(&self, id: MessageId) -> Result<Vec<(usize, StoredMessage)>> {
+        let topic_id = self.active().await?;
+        let storage = &self.shared.storage;
+        let Some(message) = storage.get(&topic_id, &id)? else {
+            bail!("no message #{} in this room", id.fmt_short());
+        };
+        // Walk up to the first message; a parent we never saw ends the chain
+        let mut chain = vec![message];
+        while let Some(parent) = chain[0].reply_to {
+            if chain.iter().any(|m| m.id == parent) {
+                break;
+            }
+            match storage.get(&topic_id, &parent)? {
+                Some(parent) => chain.insert(0, parent),
+                None => break,
+            }
+        }
+        let mut thread: Vec<_> = chain.into_iter().enumerate().collect();
+        // Then everything below the message, depth first
+        let depth = thread.len();
+        let mut below: Vec<_> = storage.replies(&topic_id, &id)?.into_iter().rev().map(|m| (depth, m)).collect();
+        while let Some((depth, reply)) = below.pop() {
+            if thread.iter().any(|(_, m)| m.id == reply.id) {
+                continue;
+            }
+            for child in storage.replies(&topic_id, &reply.id)?.into_iter().rev() {
+                below.push((depth + 1, child));
+            }
+            thread.push((depth, reply));
+        }
+        Ok(thread)
+    }
+}
+
+// Sign, gossip and save a chat message of ours. Returns the event showing it, for the caller to
+// emit when it fits.
+async fn post(
+    shared: &Shared,
+    sender: &GossipSender,
+    key: &RoomKey,
+    topic_id: TopicId,
+    name: &str,
+    mut msg: Message,
+) -> Result<(Message, ChatEvent)> {
+    let from = shared.endpoint.node_id();
+    if !shared.membership.may_post(&topic_id, &from) {
+        bail!("the room is read-only, only its publishers can post");
+    }
+    let text = msg.body.text().context("only chat messages can be posted")?;
+    let reply_to = msg.reply_to;
+    // We keep the whole text either way, only the room gets the short version
+    if text.len() > LONG_TEXT && matches!(msg.body, MessageBody::Message { .. }) {
+        let hash = shared.files.import_text(msg.id, &text).await?;
+        let preview = text.chars().take(LONG_TEXT_PREVIEW).collect();
+        msg.body = MessageBody::LongText { from, hash, size: text.len() as u64, preview };
+    }
+    msg.clock = shared.clocks.tick(topic_id);
+    msg.parents = shared.storage.heads(&topic_id, MAX_PARENTS)?;
+    msg.timestamp = storage::now();
+    let envelope = publish(sender, &shared.endpoint, key, &msg).await?;
+    shared.receipts.track(topic_id, msg.id);
+    let event = ChatEvent::Message {
+        room: topic_id,
+        id: msg.id,
+        from,
+        name: name.to_string(),
+        text: text.clone(),
+        reply_to,
+        quoted: quoted(&shared.storage, &topic_id, reply_to),
+        timestamp: msg.timestamp,
+        skewed: false,
+    };
+    let stored = StoredMessage {
+        id: msg.id,
+        sender: from,
+        name: Some(name.to_string()),
+        text,
+        timestamp: msg.timestamp,
+        edited: false,
+        deleted: false,
+        reply_to,
+        clock: msg.clock,
+        parents: msg.parents.clone(),
+    };
+    if let Err(err) = shared.storage.insert(&topic_id, &stored, &Signed::new(envelope)) {
+        shared.events.warn(format!("failed to save message: {}", err));
+    }
+    // Whoever writes has read what came before
+    read_up_to(shared, topic_id, msg.clock);
+    Ok((msg, event))
+}
+
+// Keep how far we read in a room, and tell our other devices
+fn read_up_to(shared: &Shared, topic_id: TopicId, clock: u64) {
+    match shared.storage.set_read(&topic_id, clock) {
+        Ok(true) => {
+            let read = SyncMessage::Read(vec![(topic_id, clock)]);
+            devices::share(&shared.endpoint, &shared.names.devices(), read);
+        }
+        Ok(false) => {}
+        Err(err) => shared.events.warn(format!("failed to save how far you read: {}", err)),
+    }
+}
+
+// Names of the peers online in a room, for plugins
+fn online_names(roster: &Roster, names: &Names, topic_id: &TopicId) -> Vec<String> {
+    roster.online(topic_id).iter().map(|(id, _)| names.display(id)).collect()
+}
+
+// Keep a change to a room's moderation, and drop the target from who is online if it is out
+fn moderated(shared: &Shared, topic_id: TopicId, action: &ModAction, target: &NodeId) {
+    if matches!(action, ModAction::Kick | ModAction::Ban) {
+        shared.roster.remove(&topic_id, target);
+    }
+    let (Some(owner), Some(moderation)) =
+        (shared.membership.owner(&topic_id), shared.membership.moderation(&topic_id))
+    else {
+        return;
+    };
+    if let Err(err) = shared.storage.save_moderation(&topic_id, &owner, &moderation) {
+        shared.events.warn(format!("failed to save moderation: {}", err));
+    }
+}
+
+// Keep who owns a room now, along with its moderation, and tell the user
+fn changed_hands(shared: &Shared, topic_id: TopicId, previous: NodeId, owner: NodeId) {
+    if let Err(err) = shared.storage.bookmark_owner(&topic_id, &owner) {
+        shared.events.warn(format!("failed to save the room's new owner: {}", err));
+    }
+    if let Some(moderation) = shared.membership.moderation(&topic_id)
+        && let Err(err) = shared.storage.save_moderation(&topic_id, &owner, &moderation)
+    {
+        shared.events.warn(format!("failed to save moderation: {}", err));
+    }
+    let names = &shared.names;
+    shared.events.emit(ChatEvent::OwnerChanged {
+        room: topic_id,
+        from: previous,
+        by: names.display(&previous),
+        owner,
+        name: names.display(&owner),
+    });
+}
+
+// We were handed a room: sign its allowlist, info, publishers and moderator grants again, since
+// they only count as long as the previous owner is the owner, and give them to the room
+async fn take_over(shared: &Shared, sender: &GossipSender, key: &RoomKey, topic_id: TopicId) -> Result<()> {
+    let (endpoint, membership, storage) = (&shared.endpoint, &shared.membership, &shared.storage);
+    let (secret_key, us) = (endpoint.secret_key(), endpoint.node_id());
+    if let Some(list) = membership.list(&topic_id) {
+        let list = Allowlist::sign(secret_key, topic_id, list.revision + 1, list.members);
+        membership.update(&topic_id, list.clone())?;
+        storage.save_allowlist(&topic_id, &us, &list)?;
+    }
+    if let Some(info) = membership.info(&topic_id) {
+        let info = RoomInfo::sign(secret_key, topic_id, info.revision + 1, info.name, info.description, info.topic);
+        membership.update_info(&topic_id, info.clone())?;
+        storage.save_room_info(&topic_id, &us, &info)?;
+    }
+    if let Some(publishers) = membership.publishers(&topic_id) {
+        let publishers = Publishers::sign(secret_key, topic_id, publishers.members);
+        membership.update_publishers(&topic_id, publishers.clone())?;
+        storage.save_publishers(&topic_id, &us, &publishers)?;
+    }
+    share_owner_state(sender, endpoint, key, membership, topic_id).await;
+    let Some(moderation) = membership.moderation(&topic_id) else {
+        return Ok(());
+    };
+    for grant in moderation.moderators.into_iter().filter(|grant| !moderation.dismissed.contains(&grant.moderator)) {
+        let target = grant.moderator;
+        let action = ModAction::Appoint(Grant::sign(secret_key, topic_id, target));
+        let revision = membership.next_revision(&topic_id);
+        membership.moderate(&topic_id, us, &action, target, None, revision)?;
+        let body = MessageBody::Moderation { from: us, action, target, signed_by: None, revision };
+        broadcast(sender, endpoint, key, body).await?;
+    }
+    if let Some(moderation) = membership.moderation(&topic_id) {
+        storage.save_moderation(&topic_id, &us, &moderation)?;
+    }
+    Ok(())
+}
+
+// As the owner, repeat the bans and dismissals for peers that weren't there when they happened.
+// Each goes out as a new revision, which peers that already had it take as a no-op.
+async fn announce_moderation(sender: &GossipSender, key: &RoomKey, shared: &Shared, topic_id: TopicId) {
+    let (endpoint, membership) = (&shared.endpoint, &shared.membership);
+    let us = endpoint.node_id();
+    if membership.owner(&topic_id) != Some(us) {
+        return;
+    }
+    let Some(moderation) = membership.moderation(&topic_id) else {
+        return;
+    };
+    let bans = moderation.banned.into_iter().map(|target| (ModAction::Ban, target));
+    let dismissals = moderation.dismissed.into_iter().map(|target| (ModAction::Dismiss, target));
+    for (action, target) in bans.chain(dismissals) {
+        let revision = membership.next_revision(&topic_id);
+        if membership.moderate(&topic_id, us, &action, target, None, revision).is_err() {
+            continue;
+        }
+        let body = MessageBody::Moderation { from: us, action, target, signed_by: None, revision };
+        // Retried the next time a neighbor comes up
+        let _ = broadcast(sender, endpoint, key, body).await;
+    }
+    if let Some(moderation) = membership.moderation(&topic_id)
+        && let Err(err) = shared.storage.save_moderation(&topic_id, &us, &moderation)
+    {
+        shared.events.warn(format!("failed to save moderation: {}", err));
+    }
+}
+
+// Tell the room where our log ends, so neighbors missing any of it can ask us for the rest
+async fn share_heads(
+    sender: &GossipSender,
+    endpoint: &Endpoint,
+    key: &RoomKey,
+    storage: &Storage,
+    topic_id: TopicId,
+) {
+    let heads = match storage.heads(&topic_id, MAX_PARENTS) {
+        Ok(heads) if !heads.is_empty() => heads,
+        _ => return,
+    };
+    // Retried the next time a neighbor comes up
+    let _ = broadcast(sender, endpoint, key, MessageBody::Heads { from: endpoint.node_id(), heads }).await;
+}
+
+// Pass on how the room changed hands and its allowlist, info and publishers, if it has them, for
+// peers that weren't there when they changed
+async fn share_owner_state(
+    sender: &GossipSender,
+    endpoint: &Endpoint,
+    key: &RoomKey,
+    membership: &Membership,
+    topic_id: TopicId,
+) {
+    let from = endpoint.node_id();
+    // Retried the next time a neighbor comes up
+    for transfer in membership.transfers(&topic_id) {
+        let _ = broadcast(sender, endpoint, key, MessageBody::OwnershipTransfer { from, transfer }).await;
+    }
+    if let Some(list) = membership.list(&topic_id) {
+        let _ = broadcast(sender, endpoint, key, MessageBody::Allowlist { from, list }).await;
+    }
+    if let Some(info) = membership.info(&topic_id) {
+        let _ = broadcast(sender, endpoint, key, MessageBody::RoomInfo { from, info }).await;
+    }
+    if let Some(publishers) = membership.publishers(&topic_id) {
+        let _ = broadcast(sender, endpoint, key, MessageBody::Publishers { from, publishers }).await;
+    }
+}
+
+// Everyone who may post in a read-only room, owner first
+fn read_only_event(names: &Names, topic_id: TopicId, owner: NodeId, publishers: &Publishers) -> ChatEvent {
+    let others = publishers.members.iter().filter(|id| **id != owner);
+    let publishers = std::iter::once(&owner).chain(others).map(|id| names.display(id)).collect();
+    ChatEvent::ReadOnly { room: topic_id, publishers }
+}
+
+fn info_event(topic_id: TopicId, info: RoomInfo) -> ChatEvent {
+    ChatEvent::RoomInfo { room: topic_id, name: info.name, description: info.description, topic: info.topic }
+}
+
+// Trust the first node id to use a name in a room, and warn loudly when another one turns up with it
+fn check_pin(
+    storage: &Storage,
+    pins: &Pins,
+    names: &Names,
+    events: &Events,
+    topic_id: TopicId,
+    from: NodeId,
+    name: &str,
+) {
+    // Names are pinned to accounts, whichever device of theirs we hear from
+    let from = names.devices().account_of(&from);
+    match pins.check(topic_id, name, from) {
+        Pin::First => {
+            if let Err(err) = storage.pin(&topic_id, name, &from) {
+                events.warn(format!("failed to save the key of {}: {}", name, err));
+            }
+        }
+        Pin::Same => {}
+        Pin::Changed { pinned } => {
+            names.dispute(from);
+            let shown_as = names.display(&from);
+            events.emit(ChatEvent::KeyChanged { room: topic_id, from, name: name.to_string(), pinned, shown_as });
+        }
+    }
+}
+
+// The message a reply answers, if it is in our history
+fn quoted(storage: &Storage, topic_id: &TopicId, reply_to: Option<MessageId>) -> Option<StoredMessage> {
+    storage.get(topic_id, &reply_to?).ok().flatten()
+}
+
+// Sign, encrypt and gossip a message to one room
+async fn broadcast(
+    sender: &GossipSender,
+    endpoint: &Endpoint,
+    key: &RoomKey,
+    body: MessageBody,
+) -> Result<Vec<u8>> {
+    publish(sender, endpoint, key, &Message::new(body)).await
+}
+
+// Messages too large for a single gossip message go out in chunks. Returns the envelope the
+// message was signed in.
+async fn publish(sender: &GossipSender, endpoint: &Endpoint, key: &RoomKey, msg: &Message) -> Result<Vec<u8>> {
+    let envelope = msg.sign(endpoint.secret_key());
+    let payload = key.encrypt(&envelope);
+    if payload.len() <= chunks::MAX_PAYLOAD {
+        sender.broadcast(payload.into()).await?;
+        return Ok(envelope);
+    }
+    let parts = chunks::split(&payload)?;
+    let total = parts.len() as u32;
+    for (index, data) in parts.into_iter().enumerate() {
+        let chunk =
+            MessageBody::Chunk { from: endpoint.node_id(), message_id: msg.id, index: index as u32, total, data };
+        sender.broadcast(Message::new(chunk).to_bytes(endpoint.secret_key(), key).into()).await?;
+    }
+    Ok(envelope)
+}
+
+// Our device certificate, if we are linked to an account
+fn device(endpoint: &Endpoint, names: &Names) -> Option<MessageBody> {
+    let cert = names.devices().cert()?;
+    Some(MessageBody::Device { from: endpoint.node_id(), cert })
+}
+
+fn hello(endpoint: &Endpoint, mailbox: bool) -> MessageBody {
+    let mut features: Vec<_> = version::FEATURES.iter().map(|f| f.to_string()).collect();
+    if mailbox {
+        features.push(version::MAILBOX.to_string());
+    }
+    MessageBody::Hello { from: endpoint.node_id(), proto_version: version::PROTOCOL_VERSION, features }
+}
+
+// Keep telling the room we are here until the room is left
+async fn heartbeat(
+    sender: GossipSender,
+    endpoint: Endpoint,
+    names: Names,
+    statuses: Statuses,
+    roster: Roster,
+    topic_id: TopicId,
+    key: RoomKey,
+) {
+    for tick in 1u32.. {
+        sleep(presence::HEARTBEAT_INTERVAL).await;
+        let presence = MessageBody::Presence { from: endpoint.node_id(), name: names.ours() };
+        // A failed heartbeat is retried on the next tick
+        let _ = broadcast(&sender, &endpoint, &key, presence).await;
+        let status = statuses.ours();
+        if status != Status::default() {
+            let status = MessageBody::Status { from: endpoint.node_id(), status };
+            let _ = broadcast(&sender, &endpoint, &key, status).await;
+        }
+        if tick % PEERS_EVERY == 0 {
+            let peers = sample_peers(&endpoint, &roster, &topic_id);
+            let peers = MessageBody::Peers { from: endpoint.node_id(), peers };
+            let _ = broadcast(&sender, &endpoint, &key, peers).await;
+        }
+    }
+}
+
+// Our address and those of a few members online in the room, picked at random so that over time
+// everyone hears of everyone
+fn sample_peers(endpoint: &Endpoint, roster: &Roster, topic_id: &TopicId) -> Vec<NodeAddr> {
+    let mut known: Vec<NodeAddr> = roster
+        .online(topic_id)
+        .into_iter()
+        .filter_map(|(node_id, _)| endpoint.remote_info(node_id))
+        .map(NodeAddr::from)
+        .filter(|addr| !addr.is_empty())
+        .collect();
+    known.shuffle(&mut rand::thread_rng());
+    known.truncate(PEERS_SAMPLE - 1);
+    known.extend(endpoint.node_addr().get());
+    known
+}
+
+// Ask the ticket's peers for messages sent before we arrived
+async fn backfill(endpoint: Endpoint, peers: Vec<NodeAddr>, topic_id: TopicId, key: RoomKey, shared: Shared) {
+    for peer in peers {
+        let peer_id = peer.node_id;
+        let page = match history::fetch(&endpoint, peer, topic_id, &key, HISTORY_LIMIT).await {
+            Ok(page) => page,
+            Err(err) => {
+                shared.events.warn(format!("could not fetch history from {}: {}", peer_id.fmt_short(), err));
+                continue;
+            }
+        };
+        let parents = page.messages.iter().flat_map(|fetched| fetched.stored.parents.iter().copied());
+        let parents: Vec<_> = parents.collect();
+        keep_history(&shared, topic_id, peer_id, page, false);
+        // What was said before we came isn't news
+        match shared.storage.max_clock(&topic_id) {
+            Ok(clock) => read_up_to(&shared, topic_id, clock),
+            Err(err) => shared.events.warn(format!("failed to read the room's clock: {}", err)),
+        }
+        // The rest of the log, back to the room's first message, so we end up with what the others have
+        match shared.storage.missing(&topic_id, &parents) {
+            Ok(missing) if !missing.is_empty() => repair(endpoint, peer_id, topic_id, key, missing, shared).await,
+            Ok(_) => {}
+            Err(err) => shared.events.warn(format!("failed to read the room's log: {}", err)),
+        }
+        return;
+    }
+}
+
+// Ask a mailbox for what was said in the room after `since`, a page at a time
+async fn catch_up(endpoint: Endpoint, mailbox: NodeId, topic_id: TopicId, key: RoomKey, since: i64, shared: Shared) {
+    let mut since = since;
+    let mut fetched = 0;
+    while fetched < CATCH_UP_LIMIT {
+        let page = match history::fetch_since(&endpoint, mailbox.into(), topic_id, &key, since).await {
+            Ok(page) => page,
+            Err(err) => {
+                shared.events.warn(format!("could not catch up from mailbox {}: {}", mailbox.fmt_short(), err));
+                return;
+            }
+        };
+        let Some(last) = page.last else {
+            return;
+        };
+        since = last;
+        fetched += page.messages.len() + page.rejected;
+        let full = page.full;
+        keep_history(&shared, topic_id, mailbox, page, false);
+        if !full {
+            return;
+        }
+    }
+}
+
+// Ask a neighbor for what the room said since `since`, the newest message we had when the
+// receiver lagged, going on to the next if one can't serve it
+async fn recover(
+    endpoint: Endpoint,
+    peers: Vec<NodeId>,
+    topic_id: TopicId,
+    key: RoomKey,
+    since: i64,
+    shared: Shared,
+) {
+    for peer in peers {
+        let mut since = since;
+        let mut fetched = 0;
+        let served = loop {
+            let page = match history::fetch_since(&endpoint, peer.into(), topic_id, &key, since).await {
+                Ok(page) => page,
+                Err(err) => break Err(err),
+            };
+            let full = page.full;
+            if let Some(last) = page.last {
+                since = last;
+            }
+            fetched += page.messages.len() + page.rejected;
+            keep_history(&shared, topic_id, peer, page, true);
+            if !full || fetched >= CATCH_UP_LIMIT {
+                break Ok(());
+            }
+        };
+        match served {
+            Ok(()) => return,
+            Err(err) => {
+                shared.events.warn(format!("could not recover messages from {}: {}", peer.fmt_short(), err));
+            }
+        }
+    }
+}
+
+// Start repairing the log from `peer` if we lack any of `ids`, unless we asked for them already
+fn ask_for_missing(
+    shared: &Shared,
+    requested: &mut HashSet<MessageId>,
+    peer: NodeId,
+    topic_id: TopicId,
+    key: RoomKey,
+    ids: &[MessageId],
+) {
+    let missing = match shared.storage.missing(&topic_id, ids) {
+        Ok(missing) => missing.into_iter().filter(|id| requested.insert(*id)).collect::<Vec<_>>(),
+        Err(err) => {
+            shared.events.warn(format!("failed to read the room's log: {}", err));
+            return;
+        }
+    };
+    if !missing.is_empty() {
+        tokio::spawn(repair(shared.endpoint.clone(), peer, topic_id, key, missing, shared.clone()));
+    }
+}
+
+// Fetch messages of the room's log we are missing from `peer`, and then whatever they were sent
+// after that we are missing too, until we have it all or `peer` has nothing more
+async fn repair(
+    endpoint: Endpoint,
+    peer: NodeId,
+    topic_id: TopicId,
+    key: RoomKey,
+    missing: Vec<MessageId>,
+    shared: Shared,
+) {
+    let mut wanted = missing;
+    let mut asked = HashSet::new();
+    let mut fetched = 0;
+    while !wanted.is_empty() && fetched < REPAIR_LIMIT {
+        let ids: Vec<_> = wanted.drain(..wanted.len().min(history::MAX_IDS)).collect();
+        asked.extend(ids.iter().copied());
+        let mut page = match history::fetch_ids(&endpoint, peer.into(), topic_id, &key, ids).await {
+            Ok(page) => page,
+            Err(err) => {
+                shared.events.warn(format!("could not repair history from {}: {}", peer.fmt_short(), err));
+                return;
+            }
+        };
+        if page.messages.is_empty() {
+            return;
+        }
+        fetched += page.messages.len();
+        wanted.extend(page.messages.iter().flat_map(|fetched| fetched.stored.parents.iter().copied()));
+        // They come newest first, and are shown in the order they were sent
+        page.messages.sort_by_key(|fetched| (fetched.stored.clock, fetched.stored.timestamp));
+        keep_history(&shared, topic_id, peer, page, false);
+        wanted = match shared.storage.missing(&topic_id, &wanted) {
+            Ok(missing) => missing.into_iter().filter(|id| !asked.contains(id)).collect(),
+            Err(err) => {
+                shared.events.warn(format!("failed to read the room's log: {}", err));
+                return;
+            }
+        };
+    }
+}
+
+// Try to get back in touch with a room's peers after losing the network: the addresses we know
+// for them again, a fresh connection to each and into the swarm through those
+async fn reconnect(
+    endpoint: Endpoint,
+    gossip: Gossip,
+    sender: GossipSender,
+    peers: Vec<NodeAddr>,
+    gone: Vec<NodeId>,
+) {
+    let known = gone.iter().filter_map(|node_id| endpoint.remote_info(*node_id)).map(NodeAddr::from);
+    for addr in peers.iter().cloned().chain(known.collect::<Vec<_>>()) {
+        // Peers the endpoint can't dial from what we know may still be found through discovery
+        let _ = endpoint.add_node_addr(addr);
+    }
+    let mut ids: Vec<NodeId> = peers.iter().map(|addr| addr.node_id).chain(gone).collect();
+    ids.sort();
+    ids.dedup();
+    ids.retain(|node_id| *node_id != endpoint.node_id());
+    // Gossip goes on sending over the connection that died with the network, so it gets a new one
+    for node_id in &ids {
+        let dial = endpoint.connect(*node_id, GOSSIP_ALPN);
+        if let Ok(Ok(conn)) = tokio::time::timeout(RECONNECT_DIAL, dial).await {
+            let _ = gossip.handle_connection(conn).await;
+        }
+    }
+    // A closed subscription ends the receive loop by itself
+    let _ = sender.join_peers(ids).await;
+}
+
+// Store and show the messages a peer served us that we didn't have yet, as `recovered` if we asked
+// for them because the receiver lagged. Only what its senders signed is taken.
+fn keep_history(shared: &Shared, topic_id: TopicId, peer: NodeId, page: history::Page, recovered: bool) {
+    let Shared { storage, files, clocks, membership, blocks, events, .. } = shared;
+    if page.rejected > 0 {
+        let (peer, rejected) = (peer.fmt_short(), page.rejected);
+        events.warn(format!("dropped {} messages from {} that weren't signed by their senders", rejected, peer));
+    }
+    // Peers that didn't get the allowlist yet may still have messages from outsiders
+    let wanted = |stored: &StoredMessage| {
+        membership.allows(&topic_id, &stored.sender)
+            && membership.may_post(&topic_id, &stored.sender)
+            && !blocks.contains(&stored.sender)
+    };
+    for history::Fetched { stored, signed, long_text } in page.messages {
+        if !wanted(&stored) {
+            // Still part of the log, so repairs don't keep asking for it
+            if let Err(err) = storage.remember(&topic_id, &stored) {
+                events.warn(format!("failed to save message: {}", err));
+            }
+            continue;
+        }
+        clocks.observe(topic_id, stored.clock);
+        if let Some((from, hash, size)) = long_text {
+            files.add_text(stored.id, from, hash, size);
+        }
+        match storage.insert(&topic_id, &stored, &signed) {
+            Ok(true) => {
+                let quoted = quoted(storage, &topic_id, stored.reply_to);
+                events.emit(ChatEvent::history(topic_id, stored, true, recovered, quoted));
+            }
+            Ok(false) => {}
+            Err(err) => events.warn(format!("failed to save message: {}", err)),
+        }
+    }
+}
+
+// What one room's receive loop keeps between events
+struct Subscription {
+    shared: Shared,
+    sender: GossipSender,
+    gossip: Gossip,
+    topic_id: TopicId,
+    key: RoomKey,
+    // The ticket's peers, to get back in touch through
+    peers: Vec<NodeAddr>,
+    // Where a mailbox would have to start to cover our time away: the newest message we kept
+    away_since: i64,
+    // The mailboxes in the room, and when we lost the connection to those we lost
+    mailboxes: HashMap<NodeId, Option<i64>>,
+    // Messages of the log we already asked someone for, until a new neighbor may have them
+    requested: HashSet<MessageId>,
+    // Chat messages wait here for a moment so they come out in clock order
+    reorder: Reorder,
+    // Chunks of large messages, until every chunk of the message is in
+    chunks: Reassembly,
+    // Peers flooding the room, with messages and with acks
+    limiter: Limiter,
+    acks: Limiter,
+    // Bot answers to post in the room: the command's message and the text
+    answers: mpsc::UnboundedSender<(MessageId, String)>,
+    // Once the network is gone: who was in the room then, when to try reaching them next and how
+    // long to wait after that
+    lost: Option<(Vec<NodeId>, Instant, Duration)>,
+}
+
+async fn subscribe_loop(
+    mut receiver: GossipReceiver,
+    sender: GossipSender,
+    topic_id: TopicId,
+    key: RoomKey,
+    gossip: Gossip,
+    peers: Vec<NodeAddr>,
+    shared: Shared,
+) -> Result<()> {
+    let away_since = shared.storage.latest(&topic_id)?.unwrap_or_default();
+    let (answers, mut answered) = mpsc::unbounded_channel::<(MessageId, String)>();
+    let mut room = Subscription {
+        limiter: Limiter::new(shared.rate_limit),
+        acks: Limiter::new(RateLimit {
+            per_second: shared.rate_limit.per_second * ACK_ALLOWANCE,
+            burst: shared.rate_limit.burst * ACK_ALLOWANCE,
+        }),
+        shared,
+        sender,
+        gossip,
+        topic_id,
+        key,
+        peers,
+        away_since,
+        mailboxes: HashMap::new(),
+        requested: HashSet::new(),
+        reorder: Reorder::default(),
+        chunks: Reassembly::default(),
+        answers,
+        lost: None,
+    };
+    loop {
+        let due = room.reorder.next_due();
+        let expiry = room.chunks.next_expiry();
+        let retry = room.lost.as_ref().map(|(_, at, _)| *at);
+        tokio::select! {
+            event = receiver.try_next() => match event? {
+                Some(Event::Received(msg)) => room.received(msg.delivered_from, &msg.content).await,
+                Some(Event::NeighborUp(node_id)) => room.neighbor_up(node_id).await,
+                Some(Event::NeighborDown(node_id)) => room.neighbor_down(node_id),
+                Some(Event::Lagged) => room.lagged().await,
+                None => break,
+            },
+            _ = sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                room.reorder.ready().into_iter().for_each(|event| room.shared.events.emit(event));
+            }
+            _ = sleep_until(expiry.unwrap_or_else(Instant::now)), if expiry.is_some() => {
+                for from in room.chunks.expire() {
+                    room.shared.events.warn(format!("gave up on an incomplete message from {}", from.fmt_short()));
+                }
+            }
+            _ = sleep_until(retry.unwrap_or_else(Instant::now)), if retry.is_some() => room.reconnect(),
+            Some((id, text)) = answered.recv() => room.answer(id, text).await,
+        }
+    }
+    room.reorder.drain().into_iter().for_each(|event| room.shared.events.emit(event));
+    Ok(())
+}
+
+impl Subscription {
+    fn invalid(&self, via: NodeId, error: String) {
+        self.shared.events.emit(ChatEvent::InvalidMessage { room: self.topic_id, via, error });
+    }
+
+    // Try the room's peers again, waiting longer each time
+    fn reconnect(&mut self) {
+        if let Some((gone, at, wait)) = &mut self.lost {
+            let (gossip, sender, peers) = (self.gossip.clone(), self.sender.clone(), self.peers.clone());
+            tokio::spawn(reconnect(self.shared.endpoint.clone(), gossip, sender, peers, gone.clone()));
+            *at = Instant::now() + *wait;
+            *wait = (*wait * 2).min(RECONNECT_MAX);
+        }
+    }
+
+    // Post what the bot answered to a command
+    async fn answer(&mut self, id: MessageId, text: String) {
+        let Shared { endpoint, names, events, .. } = &self.shared;
+        let answer = Message::reply(MessageBody::Message { from: endpoint.node_id(), text }, id);
+        match post(&self.shared, &self.sender, &self.key, self.topic_id, &names.ours(), answer).await {
+            Ok((answer, event)) => self.reorder.push(answer.clock, endpoint.node_id(), answer.id, event),
+            Err(err) => events.warn(format!("failed to send bot reply: {}", err)),
+        }
+    }
+
+    async fn received(&mut self, via: NodeId, content: &[u8]) {
+        self.shared.neighbors.received(&self.topic_id, &via, content.len());
+        let Some((message, envelope)) = self.accept(via, content) else {
+            return;
+        };
+        let topic_id = self.topic_id;
+        let Shared { endpoint, names, roster, statuses, receipts, reactions, storage, events, .. } = &self.shared;
+        match message.body {
+            MessageBody::AboutMe { from, name } => self.named(from, name, false),
+            MessageBody::Presence { from, name } => self.named(from, name, true),
+            MessageBody::Hello { from, proto_version, features } => self.hello(from, proto_version, features).await,
+            MessageBody::Device { from, cert } => self.device(via, from, cert),
+            MessageBody::Heads { from, heads } => {
+                if from != endpoint.node_id() {
+                    ask_for_missing(&self.shared, &mut self.requested, from, topic_id, self.key, &heads);
+                }
+            }
+            // Chunks never contain chunks
+            MessageBody::Chunk { .. } => {}
+            // Turned into a Message by `accept`
+            MessageBody::LongText { .. } | MessageBody::Code { .. } | MessageBody::Location { .. } => {}
+            // Listings belong on the directory topic, not in rooms
+            MessageBody::Listing { .. } => {}
+            MessageBody::Leave { from } => {
+                roster.remove(&topic_id, &from);
+                let name = names.display(&from);
+                events.emit(ChatEvent::Left { room: topic_id, from, name });
+            }
+            MessageBody::Typing { from } => {
+                let name = names.display(&from);
+                events.emit(ChatEvent::Typing { room: topic_id, from, name });
+            }
+            MessageBody::Peers { peers, .. } => self.peers_shared(peers).await,
+            // Only changes are news, not every heartbeat repeating them
+            MessageBody::Status { from, status } => {
+                let old = statuses.heard(from, status.clone());
+                if old.away != status.away {
+                    let name = names.display(&from);
+                    events.emit(ChatEvent::Away { room: topic_id, from, name, away: status.away });
+                }
+            }
+            MessageBody::Ack { from, id, kind } => {
+                if let Some((room, delivered, read)) = receipts.ack(id, from, kind) {
+                    let name_of = |id: &NodeId| names.display(id);
+                    events.emit(ChatEvent::Receipt {
+                        room,
+                        id,
+                        delivered: delivered.iter().map(name_of).collect(),
+                        read: read.iter().map(name_of).collect(),
+                    });
+                }
+            }
+            MessageBody::Reaction { from, target_id, emoji } => {
+                if let Some(counts) = reactions.add(topic_id, target_id, emoji.clone(), from) {
+                    events.emit(ChatEvent::Reaction {
+                        room: topic_id,
+                        id: target_id,
+                        from,
+                        name: names.display(&from),
+                        emoji,
+                        counts,
+                    });
+                }
+            }
+            MessageBody::Edit { from, target_id, new_text } => {
+                // Only the author can edit: `from` is checked against the signature, and the
+                // stored message must be theirs too
+                match storage.edit(&topic_id, &target_id, &from, &new_text, &envelope) {
+                    Ok(true) => events.emit(ChatEvent::Edited { room: topic_id, id: target_id, text: new_text }),
+                    Ok(false) => {}
+                    Err(err) => events.warn(format!("failed to save edit: {}", err)),
+                }
+            }
+            MessageBody::Delete { from, target_id } => {
+                // Same rule as edits: only the author's own messages are affected
+                match storage.delete(&topic_id, &target_id, &from, &envelope) {
+                    Ok(true) => events.emit(ChatEvent::Deleted { room: topic_id, id: target_id }),
+                    Ok(false) => {}
+                    Err(err) => events.warn(format!("failed to save deletion: {}", err)),
+                }
+            }
+            MessageBody::Message { .. } => self.chat(message, envelope).await,
+            MessageBody::Allowlist { list, .. } => self.allowlist(via, list),
+            MessageBody::RoomInfo { info, .. } => self.room_info(via, info),
+            MessageBody::Publishers { publishers, .. } => self.publishers(via, publishers),
+            MessageBody::OwnershipTransfer { transfer, .. } => self.transfer(transfer).await,
+            MessageBody::Moderation { from, action, target, signed_by, revision } => {
+                self.moderation(via, from, action, target, signed_by, revision)
+            }
+            // Only the owner can move a room, and only members it still lets in get the new key
+            MessageBody::Migrate { from, topic } => {
+                if self.shared.membership.owner(&topic_id) == Some(from)
+                    && let Some(moves) = &self.shared.moves
+                {
+                    let _ = moves.send(Move { from: topic_id, to: topic, owner: from });
+                }
+            }
+            MessageBody::FileOffer { from, hash, name: file, size } => {
+                let name = names.display(&from);
+                let offer = Offer { from, hash, name: file.clone(), directory: false, size };
+                let id = self.shared.files.add_offer(offer);
+                events.emit(ChatEvent::FileOffered { room: topic_id, from, name, file, size, id });
+            }
+            MessageBody::DirectoryOffer { from, hash, name: dir, size, files: count } => {
+                let name = names.display(&from);
+                let id = self.shared.files.add_offer(Offer { from, hash, name: dir.clone(), directory: true, size });
+                let room = topic_id;
+                events.emit(ChatEvent::DirectoryOffered { room, from, name, dir, size, files: count, id });
+            }
+            MessageBody::ContactCard { from, node_id, name: card, addr } => {
+                let name = names.display(&from);
+                let id = self.shared.cards.add(Contact { node_id, alias: card.clone(), notes: String::new(), addr });
+                events.emit(ChatEvent::ContactShared { room: topic_id, from, name, node_id, card, id });
+            }
+        }
+    }
+
+    // Decrypt and check a gossiped message, putting chunks together, and drop it if it isn't for
+    // us. Returns it with the envelope it was signed in, long messages, code and places turned into
+    // chat messages with the text history keeps them as.
+    fn accept(&mut self, via: NodeId, content: &[u8]) -> Option<(Message, Vec<u8>)> {
+        let topic_id = self.topic_id;
+        let Shared { membership, blocks, names, roster, files, events, .. } = &self.shared;
+        let (mut message, mut envelope) = match Message::open(content, &self.key) {
+            Ok(opened) => opened,
+            Err(err) => {
+                self.invalid(via, err.to_string());
+                return None;
+            }
+        };
+        // A chunk stands in for the message it completes, if it completes one
+        if let MessageBody::Chunk { from, message_id, index, total, data } = message.body {
+            let whole = match self.chunks.push(from, message_id, index, total, data) {
+                Ok(Some(whole)) => Message::open(&whole, &self.key),
+                Ok(None) => return None,
+                Err(err) => Err(err),
+            };
+            (message, envelope) = match whole {
+                Ok(opened) => opened,
+                Err(err) => {
+                    self.invalid(via, err.to_string());
+                    return None;
+                }
+            };
+        }
+        // Outsiders and peers we blocked are ignored entirely. The allowlist, room info and
+        // publishers may come from anyone, it is the owner's signature on them that counts.
+        let from = message.body.from();
+        match message.body {
+            MessageBody::Leave { .. } => membership.left(&topic_id, &from),
+            MessageBody::AboutMe { .. } => membership.rejoined(&topic_id, &from),
+            _ => {}
+        }
+        let outsider = !membership.allows(&topic_id, &from) || blocks.contains(&from);
+        let signed_by_owner = matches!(
+            message.body,
+            MessageBody::Allowlist { .. }
+                | MessageBody::RoomInfo { .. }
+                | MessageBody::Publishers { .. }
+                | MessageBody::OwnershipTransfer { .. }
+        );
+        if outsider && !signed_by_owner {
+            return None;
+        }
+        // Read-only rooms only hear chat messages, files and what goes with them from their publishers.
+        // Edits and deletions of others' messages are left alone by storage, which checks the author.
+        let posts = matches!(
+            message.body,
+            MessageBody::Message { .. }
+                | MessageBody::LongText { .. }
+                | MessageBody::Code { .. }
+                | MessageBody::ContactCard { .. }
+                | MessageBody::Location { .. }
+                | MessageBody::FileOffer { .. }
+                | MessageBody::DirectoryOffer { .. }
+                | MessageBody::Reaction { .. }
+                | MessageBody::Edit { .. }
+                | MessageBody::Delete { .. }
+                | MessageBody::Typing { .. }
+        );
+        if posts && !membership.may_post(&topic_id, &from) {
+            return None;
+        }
+        let limiter = match message.body {
+            MessageBody::Ack { .. } => &mut self.acks,
+            _ => &mut self.limiter,
+        };
+        let name = || names.display(&from);
+        match limiter.admit(from) {
+            Admit::Yes => {}
+            Admit::No => return None,
+            Admit::Limited => {
+                events.emit(ChatEvent::RateLimited { room: topic_id, from, name: name() });
+                return None;
+            }
+            Admit::Resumed { dropped } => {
+                events.emit(ChatEvent::RateLimitLifted { room: topic_id, from, name: name(), dropped })
+            }
+        }
+        roster.seen(topic_id, message.body.from());
+        // A long message goes on as one with its start, pointing at how to get the rest
+        if let MessageBody::LongText { from, hash, size, preview } = message.body {
+            files.add_text(message.id, from, hash, size);
+            let text = files::long_text(message.id, &preview, size);
+            message.body = MessageBody::Message { from, text };
+        }
+        // Code and places as one with the text history keeps them as
+        if let MessageBody::Location { lat, lon, .. } = message.body
+            && location::check(lat, lon).is_err()
+        {
+            return None;
+        }
+        if matches!(message.body, MessageBody::Code { .. } | MessageBody::Location { .. })
+            && let Some(text) = message.body.text()
+        {
+            message.body = MessageBody::Message { from, text };
+        }
+        Some((message, envelope))
+    }
+
+    // The name can't be forged: `from` was checked against the signature. It can only be copied, and
+    // then both are shown with a suffix. Announcing another name than before is a /nick, and a
+    // heartbeat from someone we missed the AboutMe of also counts as joining, or as renaming.
+    fn named(&self, from: NodeId, name: String, heartbeat: bool) {
+        let topic_id = self.topic_id;
+        let Shared { storage, names, pins, events, .. } = &self.shared;
+        let renamed = names.get(&from).is_some_and(|old| old != name);
+        let old = names.display(&from);
+        let claim = names.claim(from, &name);
+        check_pin(storage, pins, names, events, topic_id, from, &name);
+        let shown = names.display(&from);
+        let joined = match heartbeat {
+            true => claim == Claim::New,
+            false => !renamed,
+        };
+        if joined {
+            events.emit(ChatEvent::Joined { room: topic_id, from, name: shown });
+        } else if renamed && old != shown {
+            events.emit(ChatEvent::Renamed { room: topic_id, from, old, name: shown });
+        }
+        if claim == Claim::Clash {
+            let shown_as = names.display(&from);
+            events.emit(ChatEvent::NameClash { room: topic_id, from, name, shown_as });
+        }
+    }
+
+    async fn hello(&mut self, from: NodeId, proto_version: u32, features: Vec<String>) {
+        let (topic_id, key) = (self.topic_id, self.key);
+        let Shared { endpoint, names, versions, mailbox, events, .. } = &self.shared;
+        let is_mailbox = features.iter().any(|feature| feature == version::MAILBOX);
+        if is_mailbox && from != endpoint.node_id() && !self.mailboxes.contains_key(&from) {
+            self.mailboxes.insert(from, None);
+            let since = self.away_since - CATCH_UP_SLACK;
+            tokio::spawn(catch_up(endpoint.clone(), from, topic_id, key, since, self.shared.clone()));
+        }
+        if !versions.hello(topic_id, from, proto_version, features) {
+            return;
+        }
+        // Let newcomers know what we speak in turn
+        if let Err(err) = broadcast(&self.sender, endpoint, &key, hello(endpoint, *mailbox)).await {
+            events.warn(format!("failed to send hello: {}", err));
+        }
+        if proto_version != version::PROTOCOL_VERSION {
+            let name = names.display(&from);
+            events.emit(ChatEvent::IncompatiblePeer { room: topic_id, from, name, version: proto_version });
+        }
+    }
+
+    // The gossip signature shows the device itself vouches for the certificate, so no account can
+    // claim someone else's node as its device
+    fn device(&self, via: NodeId, from: NodeId, cert: devices::DeviceCert) {
+        let Shared { storage, names, events, .. } = &self.shared;
+        let added = match cert.device == from {
+            true => names.devices().add(cert.clone()),
+            false => Err(anyhow!("it is for {}", cert.device.fmt_short())),
+        };
+        match added {
+            Ok(true) => {
+                names.linked(&from);
+                if let Err(err) = storage.save_device(&cert) {
+                    events.warn(format!("failed to save device: {}", err));
+                }
+            }
+            Ok(false) => {}
+            Err(err) => self.invalid(via, format!("bad device certificate: {}", err)),
+        }
+    }
+
+    // Remember where members are, and reach out to them while we hang by a thread
+    async fn peers_shared(&self, peers: Vec<NodeAddr>) {
+        let topic_id = self.topic_id;
+        let Shared { endpoint, neighbors, membership, blocks, .. } = &self.shared;
+        let mut joining = Vec::new();
+        let connected = neighbors.of(&topic_id);
+        for addr in peers {
+            let node_id = addr.node_id;
+            let stranger = !membership.allows(&topic_id, &node_id) || blocks.contains(&node_id);
+            if node_id == endpoint.node_id() || stranger || endpoint.add_node_addr(addr).is_err() {
+                continue;
+            }
+            if !connected.contains(&node_id) {
+                joining.push(node_id);
+            }
+        }
+        if connected.len() < PEERS_WANTED && !joining.is_empty() {
+            joining.truncate(PEERS_WANTED - connected.len());
+            let _ = self.sender.join_peers(joining).await;
+        }
+    }
+
+    // A chat message: acknowledged, run through the plugins, shown, handed to the bot and kept
+    async fn chat(&mut self, message: Message, envelope: Vec<u8>) {
+        let MessageBody::Message { from, text } = message.body else {
+            return;
+        };
+        let (topic_id, key) = (self.topic_id, self.key);
+        let Shared { endpoint, storage, names, active, roster, receipts, clocks, plugins, bot, events, .. } =
+            &self.shared;
+        // Messages in the active room count as read, the others once we switch there
+        let kind = match *active.lock().await == Some(topic_id) {
+            true => {
+                read_up_to(&self.shared, topic_id, message.clock);
+                AckKind::Read
+            }
+            false => {
+                receipts.unread(topic_id, message.id);
+                AckKind::Delivered
+            }
+        };
+        let ack = MessageBody::Ack { from: endpoint.node_id(), id: message.id, kind };
+        if let Err(err) = broadcast(&self.sender, endpoint, &key, ack).await {
+            events.warn(format!("failed to acknowledge message: {}", err));
+        }
+        clocks.observe(topic_id, message.clock);
+        // Parents we never got are messages we missed: the sender had them all
+        ask_for_missing(&self.shared, &mut self.requested, from, topic_id, key, &message.parents);
+        let known = names.get(&from);
+        let author = names.display(&from);
+        let online = online_names(roster, names, &topic_id);
+        // Another plugin's messages skip ours, so no two of them keep answering each other
+        let outcome = match message.automated {
+            true => Outcome { verdict: Verdict::Pass(text), replies: Vec::new() },
+            false => plugins.run(Hook::Inbound, &author, &online, text, events),
+        };
+        // Auto-replies go through the reorder buffer too, so they show after what they answer
+        for reply in outcome.replies {
+            if !plugins.may_send(topic_id, events) {
+                continue;
+            }
+            let body = MessageBody::Message { from: endpoint.node_id(), text: reply };
+            let reply = Message::automated(body);
+            match post(&self.shared, &self.sender, &key, topic_id, &names.ours(), reply).await {
+                Ok((reply, event)) => self.reorder.push(reply.clock, endpoint.node_id(), reply.id, event),
+                Err(err) => events.warn(format!("failed to send plugin reply: {}", err)),
+            }
+        }
+        // Stored as when the sender says they sent it, like it's shown
+        let (timestamp, skewed) = message::sent(message.timestamp, storage::now());
+        let Verdict::Pass(text) = outcome.verdict else {
+            let dropped = StoredMessage {
+                id: message.id,
+                sender: from,
+                name: None,
+                text: String::new(),
+                timestamp,
+                edited: false,
+                deleted: false,
+                reply_to: None,
+                clock: message.clock,
+                parents: message.parents,
+            };
+            // Dropped here, but still part of the log
+            if let Err(err) = storage.remember(&topic_id, &dropped) {
+                events.warn(format!("failed to save message: {}", err));
+            }
+            return;
+        };
+        let event = ChatEvent::Message {
+            room: topic_id,
+            id: message.id,
+            from,
+            name: author.clone(),
+            text: text.clone(),
+            reply_to: message.reply_to,
+            quoted: quoted(storage, &topic_id, message.reply_to),
+            timestamp,
+            skewed,
+        };
+        self.reorder.push(message.clock, from, message.id, event);
+        if let Some(bot) = bot
+            && let Some(response) = bot.dispatch(topic_id, message.id, from, author, &text)
+        {
+            let (answers, endpoint, events) = (self.answers.clone(), endpoint.clone(), events.clone());
+            let our_name = names.ours();
+            tokio::spawn(async move {
+                match response.await {
+                    Ok(Reply::Room(text)) => {
+                        let _ = answers.send((message.id, text));
+                    }
+                    Ok(Reply::Direct(text)) => {
+                        if let Err(err) = dm::send(&endpoint, from, &our_name, &text).await {
+                            events.warn(format!("failed to send bot reply: {}", err));
+                        }
+                    }
+                    Ok(Reply::Nothing) => {}
+                    Err(err) => events.warn(format!("bot command failed: {}", err)),
+                }
+            });
+        }
+        let stored = StoredMessage {
+            id: message.id,
+            sender: from,
+            name: known,
+            text,
+            timestamp,
+            edited: false,
+            deleted: false,
+            reply_to: message.reply_to,
+            clock: message.clock,
+            parents: message.parents,
+        };
+        if let Err(err) = storage.insert(&topic_id, &stored, &Signed::new(envelope)) {
+            events.warn(format!("failed to save message: {}", err));
+        }
+    }
+
+    // Whether an update signed by the owner is news, reporting it if it doesn't check out
+    fn updated(&self, via: NodeId, what: &str, update: Result<bool>) -> bool {
+        match update {
+            Ok(updated) => updated,
+            Err(err) => {
+                self.invalid(via, format!("bad {}: {}", what, err));
+                false
+            }
+        }
+    }
+
+    fn allowlist(&self, via: NodeId, list: Allowlist) {
+        let topic_id = self.topic_id;
+        let Shared { endpoint, storage, names, membership, events, .. } = &self.shared;
+        let revision = list.revision;
+        if !self.updated(via, "allowlist", membership.update(&topic_id, list.clone())) {
+            return;
+        }
+        let owner = membership.owner(&topic_id).expect("updated rooms have an owner");
+        if let Err(err) = storage.save_allowlist(&topic_id, &owner, &list) {
+            events.warn(format!("failed to save allowlist: {}", err));
+        }
+        let members = list.members.iter().map(|id| names.display(id)).collect();
+        events.emit(ChatEvent::Members { room: topic_id, revision, members });
+        if !list.members.contains(&endpoint.node_id()) && owner != endpoint.node_id() {
+            events.warn(format!(
+                "you are no longer a member of room {}, the others will ignore your messages",
+                topic_id.fmt_short()
+            ));
+        }
+    }
+
+    fn room_info(&self, via: NodeId, info: RoomInfo) {
+        let topic_id = self.topic_id;
+        let Shared { storage, membership, events, .. } = &self.shared;
+        if !self.updated(via, "room info", membership.update_info(&topic_id, info.clone())) {
+            return;
+        }
+        let owner = membership.owner(&topic_id).expect("updated rooms have an owner");
+        if let Err(err) = storage.save_room_info(&topic_id, &owner, &info) {
+            events.warn(format!("failed to save room info: {}", err));
+        }
+        events.emit(info_event(topic_id, info));
+    }
+
+    fn publishers(&self, via: NodeId, publishers: Publishers) {
+        let topic_id = self.topic_id;
+        let Shared { storage, names, membership, events, .. } = &self.shared;
+        if !self.updated(via, "publishers", membership.update_publishers(&topic_id, publishers.clone())) {
+            return;
+        }
+        let owner = membership.owner(&topic_id).expect("updated rooms have an owner");
+        if let Err(err) = storage.save_publishers(&topic_id, &owner, &publishers) {
+            events.warn(format!("failed to save publishers: {}", err));
+        }
+        events.emit(read_only_event(names, topic_id, owner, &publishers));
+    }
+
+    // The transfers from before the owner our ticket named don't check out against it, so ones that
+    // don't are ignored rather than reported
+    async fn transfer(&self, transfer: Transfer) {
+        let topic_id = self.topic_id;
+        let owner = transfer.new_owner;
+        let Ok(Some(previous)) = self.shared.membership.transfer(&topic_id, transfer) else {
+            return;
+        };
+        changed_hands(&self.shared, topic_id, previous, owner);
+        if owner == self.shared.endpoint.node_id()
+            && let Err(err) = take_over(&self.shared, &self.sender, &self.key, topic_id).await
+        {
+            self.shared.events.warn(format!("failed to take over room {}: {}", topic_id.fmt_short(), err));
+        }
+    }
+
+    fn moderation(
+        &self,
+        via: NodeId,
+        from: NodeId,
+        action: ModAction,
+        target: NodeId,
+        signed_by: Option<Grant>,
+        revision: u64,
+    ) {
+        let topic_id = self.topic_id;
+        let Shared { names, membership, events, .. } = &self.shared;
+        let update = membership.moderate(&topic_id, from, &action, target, signed_by.as_ref(), revision);
+        if !self.updated(via, "moderation", update) {
+            return;
+        }
+        moderated(&self.shared, topic_id, &action, &target);
+        events.emit(ChatEvent::Moderated {
+            room: topic_id,
+            action: action.name().to_string(),
+            from,
+            by: names.display(&from),
+            target,
+            name: names.display(&target),
+        });
+    }
+
+    async fn neighbor_up(&mut self, node_id: NodeId) {
+        let (topic_id, key) = (self.topic_id, self.key);
+        let Shared { endpoint, storage, names, neighbors, membership, mailbox, events, .. } = &self.shared;
+        let sender = &self.sender;
+        neighbors.up(topic_id, node_id);
+        // Our Hello on joining may have gone out before anyone could hear it
+        if let Err(err) = broadcast(sender, endpoint, &key, hello(endpoint, *mailbox)).await {
+            events.warn(format!("failed to send hello: {}", err));
+        }
+        if let Some(device) = device(endpoint, names) {
+            // Retried the next time a neighbor comes up
+            let _ = broadcast(sender, endpoint, &key, device).await;
+        }
+        share_owner_state(sender, endpoint, &key, membership, topic_id).await;
+        announce_moderation(sender, &key, &self.shared, topic_id).await;
+        // Whatever either side missed while apart shows up as heads the other lacks
+        self.requested.clear();
+        share_heads(sender, endpoint, &key, storage, topic_id).await;
+        // Back in touch with a mailbox, which heard what we didn't in between
+        if let Some(lost) = self.mailboxes.get_mut(&node_id)
+            && let Some(since) = lost.take()
+        {
+            let since = since - CATCH_UP_SLACK;
+            tokio::spawn(catch_up(endpoint.clone(), node_id, topic_id, key, since, self.shared.clone()));
+        }
+        events.emit(ChatEvent::NeighborUp { room: topic_id, node_id });
+        if self.lost.take().is_some() {
+            events.emit(ChatEvent::Reconnected { room: topic_id });
+        }
+    }
+
+    fn neighbor_down(&mut self, node_id: NodeId) {
+        let topic_id = self.topic_id;
+        let Shared { roster, neighbors, membership, events, .. } = &self.shared;
+        neighbors.down(&topic_id, &node_id);
+        membership.left(&topic_id, &node_id);
+        if let Some(lost) = self.mailboxes.get_mut(&node_id) {
+            *lost = Some(storage::now());
+        }
+        events.emit(ChatEvent::NeighborDown { room: topic_id, node_id });
+        // The last neighbor going while others we heard from didn't say goodbye is the network
+        // going, as on suspend or a Wi-Fi change, not the room emptying
+        let staying = roster.heard_within(&topic_id, RECONNECT_WINDOW);
+        let staying: Vec<NodeId> = staying.into_iter().map(|(peer, _)| peer).collect();
+        if self.lost.is_none() && neighbors.of(&topic_id).is_empty() && !staying.is_empty() {
+            self.lost = Some((staying, Instant::now() + RECONNECT_FIRST, RECONNECT_FIRST * 2));
+            events.emit(ChatEvent::Disconnected { room: topic_id });
+        }
+    }
+
+    // What we dropped also shows up as missing parents of the next messages, or as heads the
+    // others announce, but only once someone speaks again
+    async fn lagged(&mut self) {
+        let (topic_id, key) = (self.topic_id, self.key);
+        let Shared { endpoint, storage, neighbors, events, .. } = &self.shared;
+        self.requested.clear();
+        share_heads(&self.sender, endpoint, &key, storage, topic_id).await;
+        events.emit(ChatEvent::Lagged { room: topic_id });
+        match storage.latest(&topic_id) {
+            Ok(latest) => {
+                let since = latest.unwrap_or_default() - CATCH_UP_SLACK;
+                let peers = neighbors.of(&topic_id);
+                tokio::spawn(recover(endpoint.clone(), peers, topic_id, key, since, self.shared.clone()));
+            }
+            Err(err) => events.warn(format!("failed to read the room's log: {}", err)),
+        }
+    }
+}
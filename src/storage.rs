@@ -0,0 +1,1461 @@
+use crate::contacts::Contact;
+use crate::crypto::RoomKey;
+use crate::devices::{DeviceCert, Setting};
+use crate::membership::{Allowlist, Moderation, Publishers, RoomInfo};
+use crate::message::MessageId;
+use anyhow::{anyhow, bail, Result};
+use iroh::{NodeAddr, NodeId, SecretKey};
+use iroh_blobs::Hash;
+use iroh_gossip::proto::TopicId;
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+// A chat message as kept in the local history database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: MessageId,
+    pub sender: NodeId,
+    pub name: Option<String>,
+    pub text: String,
+    pub timestamp: i64,
+    // Missing in history served by older peers
+    #[serde(default)]
+    pub edited: bool,
+    // Retracted by its author; the text is gone and only this tombstone remains
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub reply_to: Option<MessageId>,
+    // Lamport clock the message was sent with; history is shown in clock order
+    #[serde(default)]
+    pub clock: u64,
+    // The heads of the room's log when it was sent, see `heads`
+    #[serde(default)]
+    pub parents: Vec<MessageId>,
+}
+
+// What a stored message was signed in, so peers fetching it from us can check it: the envelope
+// it was gossiped in and the edit or deletion since. Deleted messages only keep the deletion,
+// which takes their text with it.
+#[derive(Debug, Clone, Default)]
+pub struct Signed {
+    pub envelope: Option<Vec<u8>>,
+    pub change: Option<Vec<u8>>,
+}
+
+impl Signed {
+    pub fn new(envelope: Vec<u8>) -> Self {
+        Self { envelope: Some(envelope), change: None }
+    }
+}
+
+// A room we are in, kept so we can rejoin it on startup without its ticket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub topic: TopicId,
+    pub key: RoomKey,
+    pub owner: Option<NodeId>,
+    // For protected rooms, so we keep handing the key to peers that know it
+    pub password: Option<String>,
+    // Where the room's peers were when we last saw them
+    pub peers: Vec<NodeAddr>,
+}
+
+// A file or directory in the blob store, which we offered or fetched
+#[derive(Debug, Clone)]
+pub struct CachedBlob {
+    pub hash: Hash,
+    pub name: String,
+    // For directories, of all their files
+    pub size: u64,
+    pub directory: bool,
+    // Whether we offered it rather than fetched it
+    pub sent: bool,
+    // When we last offered, fetched or showed it, in milliseconds since the epoch
+    pub used: i64,
+}
+
+// How much of a room's history to keep. Both limits apply when both are set, neither keeps
+// everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_messages: Option<u64>,
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.max_age, self.max_messages) {
+            (None, None) => write!(f, "everything"),
+            (Some(age), None) => write!(f, "{}", format_age(age)),
+            (None, Some(count)) => write!(f, "the last {} messages", count),
+            (Some(age), Some(count)) => write!(f, "{}, at most {} messages", format_age(age), count),
+        }
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let (secs, days) = (age.as_secs(), age.as_secs() / (24 * 60 * 60));
+    match days {
+        0 => format!("the last {} hours", secs.div_ceil(60 * 60)),
+        1 => "the last day".to_string(),
+        _ => format!("the last {} days", days),
+    }
+}
+
+// Which messages of a room are worth a desktop notification. DMs always are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Notify {
+    All,
+    // Only the ones that mention us
+    #[default]
+    Mentions,
+    Off,
+}
+
+impl FromStr for Notify {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(Notify::All),
+            "mentions" => Ok(Notify::Mentions),
+            "off" => Ok(Notify::Off),
+            _ => bail!("notifications are \"all\", \"mentions\" or \"off\", not {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notify::All => write!(f, "every message"),
+            Notify::Mentions => write!(f, "messages mentioning you"),
+            Notify::Off => write!(f, "nothing"),
+        }
+    }
+}
+
+// How often every room's retention is applied
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// The columns `read_row` expects, in order
+const COLUMNS: &str = "nonce, sender, name, text, timestamp, edited, deleted, reply_to, clock, parents";
+
+fn read_row(row: &Row) -> Result<StoredMessage> {
+    Ok(StoredMessage {
+        id: row.get::<_, String>(0)?.parse()?,
+        sender: row.get::<_, String>(1)?.parse()?,
+        name: row.get(2)?,
+        text: row.get(3)?,
+        timestamp: row.get(4)?,
+        edited: row.get(5)?,
+        deleted: row.get(6)?,
+        reply_to: row.get::<_, Option<String>>(7)?.map(|id| id.parse()).transpose()?,
+        clock: row.get::<_, i64>(8)? as u64,
+        parents: row.get::<_, String>(9)?.split_whitespace().map(|id| id.parse()).collect::<Result<_>>()?,
+    })
+}
+
+// What the history database is encrypted with at rest (by SQLCipher, so every page is, contacts
+// and bookmarks included)
+#[derive(Clone)]
+pub enum HistoryKey {
+    // Stretched by SQLCipher with a salt kept in the database
+    Passphrase(String),
+    // Used as it is
+    Key([u8; 32]),
+}
+
+impl HistoryKey {
+    // Nothing to type, but whoever has the identity key file can read the history too, so this
+    // only helps with the key kept elsewhere
+    pub fn from_identity(secret_key: &SecretKey) -> Self {
+        Self::Key(blake3::derive_key("iroh-chat 2025 history key", &secret_key.to_bytes()))
+    }
+
+    // What PRAGMA key expects
+    fn pragma(&self) -> String {
+        match self {
+            HistoryKey::Passphrase(passphrase) => passphrase.clone(),
+            HistoryKey::Key(key) => format!("x'{}'", data_encoding::HEXLOWER.encode(key)),
+        }
+    }
+}
+
+// Keep the key itself out of debug output
+impl fmt::Debug for HistoryKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HistoryKey(..)")
+    }
+}
+
+// Whether the database at `path` is encrypted, as far as its first bytes tell: a plain SQLite
+// file starts with a fixed header, an encrypted one with its salt
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(!bytes.is_empty() && !bytes.starts_with(b"SQLite format 3\0"))
+}
+
+// Rewrite a plain database encrypted, keeping its schema version
+fn encrypt_in_place(path: &Path, key: &HistoryKey) -> Result<()> {
+    let encrypted = path.with_extension("db.encrypting");
+    let _ = std::fs::remove_file(&encrypted);
+    let conn = Connection::open(path)?;
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    conn.execute(
+        "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+        params![encrypted.to_string_lossy(), key.pragma()],
+    )?;
+    conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+    conn.execute_batch(&format!("PRAGMA encrypted.user_version = {}; DETACH DATABASE encrypted;", version))?;
+    drop(conn);
+    std::fs::rename(&encrypted, path)?;
+    Ok(())
+}
+
+// SQLite-backed message history shared by the receive and send paths
+#[derive(Debug)]
+pub struct Storage {
+    conn: Mutex<Connection>,
+}
+
+impl Storage {
+    pub fn open(path: &Path, key: Option<&HistoryKey>) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let encrypted = is_encrypted(path)?;
+        if let Some(key) = key
+            && !encrypted
+            && path.exists()
+        {
+            encrypt_in_place(path, key)?;
+        }
+        let conn = Connection::open(path)?;
+        if let Some(key) = key {
+            conn.pragma_update(None, "key", key.pragma())?;
+            // SQLCipher would log a wrong key to stderr, over the UI; we report it ourselves
+            conn.pragma_update(None, "cipher_log_level", "NONE")?;
+        }
+        // Nothing is decrypted until the first read, which is where a wrong key shows
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(())).map_err(|_| match key {
+            Some(_) => anyhow!("could not unlock the history database, wrong passphrase?"),
+            None if encrypted => anyhow!("the history database is encrypted, but no key was given for it"),
+            None => anyhow!("could not read the history database {}", path.display()),
+        })?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id        INTEGER PRIMARY KEY,
+                topic     TEXT NOT NULL,
+                nonce     TEXT NOT NULL,
+                sender    TEXT NOT NULL,
+                name      TEXT,
+                text      TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                UNIQUE (topic, nonce)
+            );
+            CREATE INDEX IF NOT EXISTS messages_topic_time ON messages (topic, timestamp);",
+        )?;
+        // Columns added after the first release, tracked with SQLite's user_version
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN edited INTEGER NOT NULL DEFAULT 0;
+                 PRAGMA user_version = 1;",
+            )?;
+        }
+        if version < 2 {
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+                 PRAGMA user_version = 2;",
+            )?;
+        }
+        if version < 3 {
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN reply_to TEXT;
+                 PRAGMA user_version = 3;",
+            )?;
+        }
+        if version < 4 {
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN clock INTEGER NOT NULL DEFAULT 0;
+                 PRAGMA user_version = 4;",
+            )?;
+        }
+        if version < 5 {
+            conn.execute_batch(
+                "CREATE TABLE allowlists (
+                     topic TEXT PRIMARY KEY,
+                     owner TEXT NOT NULL,
+                     list  TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 5;",
+            )?;
+        }
+        if version < 6 {
+            conn.execute_batch(
+                "CREATE TABLE moderation (
+                     topic TEXT PRIMARY KEY,
+                     owner TEXT NOT NULL,
+                     state TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 6;",
+            )?;
+        }
+        if version < 7 {
+            conn.execute_batch(
+                "CREATE TABLE blocked (
+                     node TEXT PRIMARY KEY,
+                     name TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 7;",
+            )?;
+        }
+        if version < 8 {
+            conn.execute_batch(
+                "CREATE TABLE pins (
+                     topic TEXT NOT NULL,
+                     name  TEXT NOT NULL,
+                     node  TEXT NOT NULL,
+                     PRIMARY KEY (topic, name)
+                 );
+                 PRAGMA user_version = 8;",
+            )?;
+        }
+        if version < 9 {
+            conn.execute_batch(
+                "CREATE TABLE contacts (
+                     node  TEXT PRIMARY KEY,
+                     alias TEXT NOT NULL,
+                     notes TEXT NOT NULL,
+                     addr  TEXT
+                 );
+                 PRAGMA user_version = 9;",
+            )?;
+        }
+        if version < 10 {
+            conn.execute_batch(
+                "CREATE TABLE room_info (
+                     topic TEXT PRIMARY KEY,
+                     owner TEXT NOT NULL,
+                     info  TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 10;",
+            )?;
+        }
+        if version < 11 {
+            conn.execute_batch(
+                "CREATE TABLE bookmarks (
+                     topic    TEXT PRIMARY KEY,
+                     key      TEXT NOT NULL,
+                     owner    TEXT,
+                     password TEXT,
+                     peers    TEXT NOT NULL,
+                     joined   INTEGER NOT NULL
+                 );
+                 PRAGMA user_version = 11;",
+            )?;
+        }
+        if version < 12 {
+            conn.execute_batch(
+                "CREATE TABLE publishers (
+                     topic TEXT PRIMARY KEY,
+                     owner TEXT NOT NULL,
+                     list  TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 12;",
+            )?;
+        }
+        if version < 13 {
+            conn.execute_batch(
+                "CREATE TABLE retention (
+                     topic        TEXT PRIMARY KEY,
+                     max_age      INTEGER,
+                     max_messages INTEGER
+                 );
+                 PRAGMA user_version = 13;",
+            )?;
+        }
+        if version < 14 {
+            // The room's log as a DAG: every message we ever had, and the heads it was sent after.
+            // Messages from before this version join it without parents.
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN parents TEXT NOT NULL DEFAULT '';
+                 CREATE TABLE dag_nodes (
+                     topic TEXT NOT NULL,
+                     id    TEXT NOT NULL,
+                     clock INTEGER NOT NULL,
+                     PRIMARY KEY (topic, id)
+                 );
+                 CREATE TABLE dag_edges (
+                     topic  TEXT NOT NULL,
+                     child  TEXT NOT NULL,
+                     parent TEXT NOT NULL,
+                     PRIMARY KEY (topic, child, parent)
+                 );
+                 CREATE INDEX dag_edges_parent ON dag_edges (topic, parent);
+                 INSERT OR IGNORE INTO dag_nodes (topic, id, clock) SELECT topic, nonce, clock FROM messages;
+                 PRAGMA user_version = 14;",
+            )?;
+        }
+        if version < 15 {
+            // Device certificates: our own once we are linked, our other devices' and peers'
+            conn.execute_batch(
+                "CREATE TABLE devices (
+                     device  TEXT PRIMARY KEY,
+                     account TEXT NOT NULL,
+                     cert    TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 15;",
+            )?;
+        }
+        if version < 16 {
+            // How far we read in each room, as the clock of the last message read, which is the same
+            // on every device
+            conn.execute_batch(
+                "CREATE TABLE read_positions (
+                     topic TEXT PRIMARY KEY,
+                     clock INTEGER NOT NULL
+                 );
+                 PRAGMA user_version = 16;",
+            )?;
+        }
+        if version < 17 {
+            // Settings synced between our devices, with when they last changed, as the latest
+            // `Setting` under its key. Deletions are kept too, so they win over older changes.
+            conn.execute_batch(
+                "CREATE TABLE settings (
+                     key     TEXT PRIMARY KEY,
+                     setting TEXT NOT NULL,
+                     updated INTEGER NOT NULL
+                 );
+                 PRAGMA user_version = 17;",
+            )?;
+        }
+        if version < 18 {
+            // What the blob store holds for us, to evict the least recently used once it is full
+            conn.execute_batch(
+                "CREATE TABLE blobs (
+                     hash      TEXT PRIMARY KEY,
+                     name      TEXT NOT NULL,
+                     size      INTEGER NOT NULL,
+                     directory INTEGER NOT NULL,
+                     sent      INTEGER NOT NULL,
+                     used      INTEGER NOT NULL
+                 );
+                 PRAGMA user_version = 18;",
+            )?;
+        }
+        if version < 19 {
+            // Rooms whose notifications differ from the default
+            conn.execute_batch(
+                "CREATE TABLE notify (
+                     topic TEXT PRIMARY KEY,
+                     rule  TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 19;",
+            )?;
+        }
+        if version < 20 {
+            // What messages were signed in, see `Signed`. Messages kept before stay unsigned and
+            // aren't served to peers that check.
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN envelope BLOB;
+                 ALTER TABLE messages ADD COLUMN change BLOB;
+                 PRAGMA user_version = 20;",
+            )?;
+        }
+        let storage = Self { conn: Mutex::new(conn) };
+        // What we had before settings were synced counts as older than any change since
+        if version < 17 {
+            let mut settings = Vec::new();
+            for contact in storage.contacts()? {
+                settings.push(Setting::Contact(contact.node_id, Some(contact)));
+            }
+            for (node_id, name) in storage.blocked()? {
+                settings.push(Setting::Blocked(node_id, Some(name)));
+            }
+            for bookmark in storage.bookmarks()? {
+                settings.push(Setting::Bookmark(bookmark.topic, Some(bookmark)));
+            }
+            for setting in settings {
+                storage.merge_setting(&setting, 0)?;
+            }
+        }
+        Ok(storage)
+    }
+
+    // Messages are keyed by their id (kept in the nonce column), so seeing the same message
+    // twice is a no-op. Returns whether the message was new.
+    pub fn insert(&self, topic: &TopicId, message: &StoredMessage, signed: &Signed) -> Result<bool> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        remember(&conn, topic, message)?;
+        // Backfilled history older than we keep would only be pruned again
+        if let Some(age) = read_retention(&conn, topic)?.max_age
+            && message.timestamp < now() - age.as_millis() as i64
+        {
+            return Ok(false);
+        }
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO messages
+                 (topic, nonce, sender, name, text, timestamp, edited, deleted, reply_to, clock, parents,
+                  envelope, change)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                topic.to_string(),
+                message.id.to_string(),
+                message.sender.to_string(),
+                message.name,
+                message.text,
+                message.timestamp,
+                message.edited,
+                message.deleted,
+                message.reply_to.map(|id| id.to_string()),
+                message.clock as i64,
+                message.parents.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(" "),
+                signed.envelope,
+                signed.change,
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    // Put a message in the room's log without keeping it, so it isn't asked for again
+    pub fn remember(&self, topic: &TopicId, message: &StoredMessage) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        remember(&conn, topic, message)
+    }
+
+    // The newest messages of the room's log nothing was sent after yet, at most `limit`. A
+    // message names them as its parents, so everyone holding it knows what it was sent after.
+    pub fn heads(&self, topic: &TopicId, limit: usize) -> Result<Vec<MessageId>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(
+            "SELECT id FROM dag_nodes n WHERE topic = ?1
+             AND NOT EXISTS (SELECT 1 FROM dag_edges e WHERE e.topic = n.topic AND e.parent = n.id)
+             ORDER BY clock DESC, id DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![topic.to_string(), limit as i64], |row| row.get::<_, String>(0))?;
+        let mut heads = Vec::new();
+        for row in rows {
+            heads.push(row?.parse()?);
+        }
+        Ok(heads)
+    }
+
+    // Which of `ids` the room's log doesn't have
+    pub fn missing(&self, topic: &TopicId, ids: &[MessageId]) -> Result<Vec<MessageId>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT 1 FROM dag_nodes WHERE topic = ?1 AND id = ?2")?;
+        let mut missing = Vec::new();
+        for id in ids {
+            if !stmt.exists(params![topic.to_string(), id.to_string()])? && !missing.contains(id) {
+                missing.push(*id);
+            }
+        }
+        Ok(missing)
+    }
+
+    // `ids` and the messages they were sent after, and so on back, at most `limit` of them. A
+    // branch ends at messages we don't keep.
+    pub fn ancestors(&self, topic: &TopicId, ids: &[MessageId], limit: usize) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt =
+            conn.prepare(&format!("SELECT {} FROM messages WHERE topic = ?1 AND nonce = ?2", COLUMNS))?;
+        let mut queue: VecDeque<MessageId> = ids.iter().copied().collect();
+        let mut seen: HashSet<MessageId> = queue.iter().copied().collect();
+        let mut found = Vec::new();
+        while found.len() < limit
+            && let Some(id) = queue.pop_front()
+        {
+            let mut rows = stmt.query(params![topic.to_string(), id.to_string()])?;
+            let Some(row) = rows.next()? else {
+                continue;
+            };
+            let message = read_row(row)?;
+            queue.extend(message.parents.iter().filter(|parent| seen.insert(**parent)));
+            found.push(message);
+        }
+        Ok(found)
+    }
+
+    // Ids of the newest messages of a topic whose id starts with `prefix`, newest first
+    pub fn find(&self, topic: &TopicId, prefix: &str, limit: usize) -> Result<Vec<MessageId>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(
+            "SELECT nonce FROM messages WHERE topic = ?1 AND nonce LIKE ?2
+             ORDER BY timestamp DESC, id DESC LIMIT ?3",
+        )?;
+        let pattern = format!("{}%", prefix);
+        let rows =
+            stmt.query_map(params![topic.to_string(), pattern, limit as i64], |row| row.get::<_, String>(0))?;
+        let mut ids = Vec::new();
+        for row in rows {
+            ids.push(row?.parse()?);
+        }
+        Ok(ids)
+    }
+
+    // The last `limit` messages of a topic in clock order, oldest first. Ties are broken by sender
+    // and message id, like the reorder buffer does, never by arrival, so every peer shows one order.
+    pub fn recent(&self, topic: &TopicId, limit: usize) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages WHERE topic = ?1
+             ORDER BY clock DESC, sender DESC, nonce DESC LIMIT ?2",
+            COLUMNS
+        ))?;
+        let mut rows = stmt.query(params![topic.to_string(), limit as i64])?;
+        let mut messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            messages.push(read_row(row)?);
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+
+    // The first `limit` messages of a room sent after `since`, oldest first
+    pub fn since(&self, topic: &TopicId, since: i64, limit: usize) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages WHERE topic = ?1 AND timestamp > ?2 ORDER BY timestamp, id LIMIT ?3",
+            COLUMNS
+        ))?;
+        let mut rows = stmt.query(params![topic.to_string(), since, limit as i64])?;
+        let mut messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            messages.push(read_row(row)?);
+        }
+        Ok(messages)
+    }
+
+    // When the newest stored message of a room was sent, if there is one
+    pub fn latest(&self, topic: &TopicId) -> Result<Option<i64>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let sql = "SELECT MAX(timestamp) FROM messages WHERE topic = ?1";
+        Ok(conn.query_row(sql, params![topic.to_string()], |row| row.get(0))?)
+    }
+
+    // Every stored message of a room, oldest first
+    pub fn messages(&self, topic: &TopicId) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages WHERE topic = ?1 ORDER BY clock, sender, nonce",
+            COLUMNS
+        ))?;
+        let mut rows = stmt.query(params![topic.to_string()])?;
+        let mut messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            messages.push(read_row(row)?);
+        }
+        Ok(messages)
+    }
+
+    // Messages containing `query`, ignoring ASCII case, in one room or all of them, newest first
+    pub fn search(
+        &self,
+        query: &str,
+        topic: Option<&TopicId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<(TopicId, StoredMessage)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, topic FROM messages
+             WHERE deleted = 0 AND text LIKE ?1 ESCAPE '\\' AND (?2 IS NULL OR topic = ?2)
+             ORDER BY timestamp DESC, id DESC LIMIT ?3 OFFSET ?4",
+            COLUMNS
+        ))?;
+        let escaped = query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        let topic = topic.map(|topic| topic.to_string());
+        let pattern = format!("%{}%", escaped);
+        let mut rows = stmt.query(params![pattern, topic, limit as i64, offset as i64])?;
+        let mut found = Vec::new();
+        while let Some(row) = rows.next()? {
+            let topic: String = row.get(10)?;
+            found.push((topic.parse()?, read_row(row)?));
+        }
+        Ok(found)
+    }
+
+    // Every room we have messages of
+    pub fn topics(&self) -> Result<Vec<TopicId>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT DISTINCT topic FROM messages")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut topics = Vec::new();
+        for row in rows {
+            topics.push(row?.parse()?);
+        }
+        Ok(topics)
+    }
+
+    pub fn get(&self, topic: &TopicId, id: &MessageId) -> Result<Option<StoredMessage>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt =
+            conn.prepare(&format!("SELECT {} FROM messages WHERE topic = ?1 AND nonce = ?2", COLUMNS))?;
+        let mut rows = stmt.query(params![topic.to_string(), id.to_string()])?;
+        rows.next()?.map(read_row).transpose()
+    }
+
+    // The direct replies to a message, oldest first
+    pub fn replies(&self, topic: &TopicId, id: &MessageId) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages WHERE topic = ?1 AND reply_to = ?2 ORDER BY timestamp, id",
+            COLUMNS
+        ))?;
+        let mut rows = stmt.query(params![topic.to_string(), id.to_string()])?;
+        let mut messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            messages.push(read_row(row)?);
+        }
+        Ok(messages)
+    }
+
+    // Replace the text of a message, but only if `sender` wrote it, keeping the edit's envelope.
+    // Returns whether it did.
+    pub fn edit(&self, topic: &TopicId, id: &MessageId, sender: &NodeId, text: &str, change: &[u8]) -> Result<bool> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let updated = conn.execute(
+            "UPDATE messages SET text = ?4, edited = 1, change = ?5
+             WHERE topic = ?1 AND nonce = ?2 AND sender = ?3 AND deleted = 0",
+            params![topic.to_string(), id.to_string(), sender.to_string(), text, change],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // Put the whole text of a long message in place of its start. Edits and deletions since win.
+    pub fn expand(&self, topic: &TopicId, id: &MessageId, text: &str) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "UPDATE messages SET text = ?3 WHERE topic = ?1 AND nonce = ?2 AND edited = 0 AND deleted = 0",
+            params![topic.to_string(), id.to_string(), text],
+        )?;
+        Ok(())
+    }
+
+    // Replace a message with a tombstone, but only if `sender` wrote it, keeping the deletion's
+    // envelope in place of the message's. Returns whether it did.
+    pub fn delete(&self, topic: &TopicId, id: &MessageId, sender: &NodeId, change: &[u8]) -> Result<bool> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let updated = conn.execute(
+            "UPDATE messages SET text = '', deleted = 1, envelope = NULL, change = ?4
+             WHERE topic = ?1 AND nonce = ?2 AND sender = ?3 AND deleted = 0",
+            params![topic.to_string(), id.to_string(), sender.to_string(), change],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // What those of `ids` we keep were signed in
+    pub fn signed(&self, topic: &TopicId, ids: &[MessageId]) -> Result<HashMap<MessageId, Signed>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT envelope, change FROM messages WHERE topic = ?1 AND nonce = ?2")?;
+        let mut found = HashMap::new();
+        for id in ids {
+            let mut rows = stmt.query(params![topic.to_string(), id.to_string()])?;
+            if let Some(row) = rows.next()? {
+                found.insert(*id, Signed { envelope: row.get(0)?, change: row.get(1)? });
+            }
+        }
+        Ok(found)
+    }
+
+    // The highest clock seen in a topic, to carry the room's clock over from earlier sessions
+    pub fn max_clock(&self, topic: &TopicId) -> Result<u64> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let clock: Option<i64> =
+            conn.query_row("SELECT MAX(clock) FROM messages WHERE topic = ?1", params![topic.to_string()], |row| {
+                row.get(0)
+            })?;
+        Ok(clock.unwrap_or_default() as u64)
+    }
+
+    // The newest message `sender` sent to a topic that is still there
+    pub fn last_from(&self, topic: &TopicId, sender: &NodeId) -> Result<Option<MessageId>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(
+            "SELECT nonce FROM messages WHERE topic = ?1 AND sender = ?2 AND deleted = 0
+             ORDER BY timestamp DESC, id DESC LIMIT 1",
+        )?;
+        let mut rows = stmt.query(params![topic.to_string(), sender.to_string()])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get::<_, String>(0)?.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    // Keep the newest allowlist of a room, to enforce it again after a restart
+    pub fn save_allowlist(&self, topic: &TopicId, owner: &NodeId, list: &Allowlist) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR REPLACE INTO allowlists (topic, owner, list) VALUES (?1, ?2, ?3)",
+            params![topic.to_string(), owner.to_string(), serde_json::to_string(list)?],
+        )?;
+        Ok(())
+    }
+
+    // The owner of a room and its allowlist, if we ever got one
+    pub fn allowlist(&self, topic: &TopicId) -> Result<Option<(NodeId, Allowlist)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT owner, list FROM allowlists WHERE topic = ?1")?;
+        let mut rows = stmt.query(params![topic.to_string()])?;
+        match rows.next()? {
+            Some(row) => {
+                let owner = row.get::<_, String>(0)?.parse()?;
+                Ok(Some((owner, serde_json::from_str(&row.get::<_, String>(1)?)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Keep a room's moderators and bans, to enforce them again after a restart
+    pub fn save_moderation(&self, topic: &TopicId, owner: &NodeId, moderation: &Moderation) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR REPLACE INTO moderation (topic, owner, state) VALUES (?1, ?2, ?3)",
+            params![topic.to_string(), owner.to_string(), serde_json::to_string(moderation)?],
+        )?;
+        Ok(())
+    }
+
+    // The owner of a room and its moderation, if anyone ever moderated it
+    pub fn moderation(&self, topic: &TopicId) -> Result<Option<(NodeId, Moderation)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT owner, state FROM moderation WHERE topic = ?1")?;
+        let mut rows = stmt.query(params![topic.to_string()])?;
+        match rows.next()? {
+            Some(row) => {
+                let owner = row.get::<_, String>(0)?.parse()?;
+                Ok(Some((owner, serde_json::from_str(&row.get::<_, String>(1)?)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_room_info(&self, topic: &TopicId, owner: &NodeId, info: &RoomInfo) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR REPLACE INTO room_info (topic, owner, info) VALUES (?1, ?2, ?3)",
+            params![topic.to_string(), owner.to_string(), serde_json::to_string(info)?],
+        )?;
+        Ok(())
+    }
+
+    // The owner of a room and the info it last set, if it did
+    pub fn room_info(&self, topic: &TopicId) -> Result<Option<(NodeId, RoomInfo)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT owner, info FROM room_info WHERE topic = ?1")?;
+        let mut rows = stmt.query(params![topic.to_string()])?;
+        match rows.next()? {
+            Some(row) => {
+                let owner = row.get::<_, String>(0)?.parse()?;
+                Ok(Some((owner, serde_json::from_str(&row.get::<_, String>(1)?)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_publishers(&self, topic: &TopicId, owner: &NodeId, publishers: &Publishers) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR REPLACE INTO publishers (topic, owner, list) VALUES (?1, ?2, ?3)",
+            params![topic.to_string(), owner.to_string(), serde_json::to_string(publishers)?],
+        )?;
+        Ok(())
+    }
+
+    // The owner of a read-only room and who may post in it
+    pub fn publishers(&self, topic: &TopicId) -> Result<Option<(NodeId, Publishers)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT owner, list FROM publishers WHERE topic = ?1")?;
+        let mut rows = stmt.query(params![topic.to_string()])?;
+        match rows.next()? {
+            Some(row) => {
+                let owner = row.get::<_, String>(0)?.parse()?;
+                Ok(Some((owner, serde_json::from_str(&row.get::<_, String>(1)?)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Remember a room we joined, keeping the password of a bookmark we had already
+    pub fn bookmark(
+        &self,
+        topic: &TopicId,
+        key: &RoomKey,
+        owner: Option<&NodeId>,
+        peers: &[NodeAddr],
+    ) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT INTO bookmarks (topic, key, owner, peers, joined) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (topic) DO UPDATE SET key = ?2, owner = ?3, peers = ?4",
+            params![
+                topic.to_string(),
+                serde_json::to_string(key)?,
+                owner.map(|owner| owner.to_string()),
+                serde_json::to_string(peers)?,
+                now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    // A bookmarked room changed hands
+    pub fn bookmark_owner(&self, topic: &TopicId, owner: &NodeId) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "UPDATE bookmarks SET owner = ?2 WHERE topic = ?1",
+            params![topic.to_string(), owner.to_string()],
+        )?;
+        Ok(())
+    }
+
+    pub fn bookmark_password(&self, topic: &TopicId, password: &str) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute("UPDATE bookmarks SET password = ?2 WHERE topic = ?1", params![topic.to_string(), password])?;
+        Ok(())
+    }
+
+    pub fn bookmark_peers(&self, topic: &TopicId, peers: &[NodeAddr]) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "UPDATE bookmarks SET peers = ?2 WHERE topic = ?1",
+            params![topic.to_string(), serde_json::to_string(peers)?],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_bookmark(&self, topic: &TopicId) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute("DELETE FROM bookmarks WHERE topic = ?1", params![topic.to_string()])?;
+        Ok(())
+    }
+
+    // Every bookmarked room, in the order we first joined them
+    pub fn bookmarks(&self) -> Result<Vec<Bookmark>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT topic, key, owner, password, peers FROM bookmarks ORDER BY joined")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+        let mut bookmarks = Vec::new();
+        for row in rows {
+            let (topic, key, owner, password, peers) = row?;
+            bookmarks.push(Bookmark {
+                topic: topic.parse()?,
+                key: serde_json::from_str(&key)?,
+                owner: owner.map(|owner| owner.parse()).transpose()?,
+                password,
+                peers: serde_json::from_str(&peers)?,
+            });
+        }
+        Ok(bookmarks)
+    }
+
+    pub fn find_bookmark(&self, topic: &TopicId) -> Result<Option<Bookmark>> {
+        Ok(self.bookmarks()?.into_iter().find(|bookmark| bookmark.topic == *topic))
+    }
+
+    // Carry a room's history and pins over to the topic it moved to
+    pub fn move_room(&self, from: &TopicId, to: &TopicId) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let (from, to) = (from.to_string(), to.to_string());
+        conn.execute("UPDATE OR IGNORE messages SET topic = ?2 WHERE topic = ?1", params![from, to])?;
+        conn.execute("UPDATE OR IGNORE pins SET topic = ?2 WHERE topic = ?1", params![from, to])?;
+        conn.execute("UPDATE OR IGNORE retention SET topic = ?2 WHERE topic = ?1", params![from, to])?;
+        conn.execute("UPDATE OR IGNORE notify SET topic = ?2 WHERE topic = ?1", params![from, to])?;
+        conn.execute("UPDATE OR IGNORE dag_nodes SET topic = ?2 WHERE topic = ?1", params![from, to])?;
+        conn.execute("UPDATE OR IGNORE dag_edges SET topic = ?2 WHERE topic = ?1", params![from, to])?;
+        conn.execute("UPDATE OR IGNORE read_positions SET topic = ?2 WHERE topic = ?1", params![from, to])?;
+        Ok(())
+    }
+
+    pub fn set_retention(&self, topic: &TopicId, retention: Retention) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        if retention == Retention::default() {
+            conn.execute("DELETE FROM retention WHERE topic = ?1", params![topic.to_string()])?;
+            return Ok(());
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO retention (topic, max_age, max_messages) VALUES (?1, ?2, ?3)",
+            params![
+                topic.to_string(),
+                retention.max_age.map(|age| age.as_secs() as i64),
+                retention.max_messages.map(|count| count as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn retention(&self, topic: &TopicId) -> Result<Retention> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        read_retention(&conn, topic)
+    }
+
+    pub fn set_notify(&self, topic: &TopicId, notify: Notify) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        if notify == Notify::default() {
+            conn.execute("DELETE FROM notify WHERE topic = ?1", params![topic.to_string()])?;
+            return Ok(());
+        }
+        let rule = serde_json::to_string(&notify)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO notify (topic, rule) VALUES (?1, ?2)",
+            params![topic.to_string(), rule],
+        )?;
+        Ok(())
+    }
+
+    pub fn notify(&self, topic: &TopicId) -> Result<Notify> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT rule FROM notify WHERE topic = ?1")?;
+        let mut rows = stmt.query(params![topic.to_string()])?;
+        match rows.next()? {
+            Some(row) => Ok(serde_json::from_str(&row.get::<_, String>(0)?)?),
+            None => Ok(Notify::default()),
+        }
+    }
+
+    // Drop the messages every room's retention no longer keeps, then compact the file if that
+    // freed anything. Returns how many messages went.
+    pub fn prune(&self) -> Result<usize> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT topic, max_age, max_messages FROM retention")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?, row.get::<_, Option<i64>>(2)?))
+        })?;
+        let policies = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(stmt);
+        let mut pruned = 0;
+        for (topic, max_age, max_messages) in policies {
+            if let Some(max_age) = max_age {
+                let cutoff = now() - max_age * 1000;
+                pruned += conn.execute(
+                    "DELETE FROM messages WHERE topic = ?1 AND timestamp < ?2",
+                    params![topic, cutoff],
+                )?;
+            }
+            if let Some(max_messages) = max_messages {
+                pruned += conn.execute(
+                    "DELETE FROM messages WHERE topic = ?1 AND id NOT IN (
+                         SELECT id FROM messages WHERE topic = ?1
+                         ORDER BY clock DESC, sender DESC, nonce DESC LIMIT ?2
+                     )",
+                    params![topic, max_messages],
+                )?;
+            }
+        }
+        if pruned > 0 {
+            conn.execute_batch("VACUUM")?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn block(&self, node_id: &NodeId, name: &str) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR REPLACE INTO blocked (node, name) VALUES (?1, ?2)",
+            params![node_id.to_string(), name],
+        )?;
+        Ok(())
+    }
+
+    pub fn unblock(&self, node_id: &NodeId) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute("DELETE FROM blocked WHERE node = ?1", params![node_id.to_string()])?;
+        Ok(())
+    }
+
+    // Every blocked peer and the name it was blocked under
+    pub fn blocked(&self) -> Result<Vec<(NodeId, String)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT node, name FROM blocked")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut blocked = Vec::new();
+        for row in rows {
+            let (node_id, name) = row?;
+            blocked.push((node_id.parse()?, name));
+        }
+        Ok(blocked)
+    }
+
+    // Record a setting that changed `updated` milliseconds since the epoch, unless what we have
+    // under its key is newer. Equal times are settled by the setting, so every device keeps the
+    // same one. Returns whether it was recorded.
+    pub fn merge_setting(&self, setting: &Setting, updated: i64) -> Result<bool> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let changed = conn.execute(
+            "INSERT INTO settings (key, setting, updated) VALUES (?1, ?2, ?3)
+             ON CONFLICT (key) DO UPDATE SET setting = excluded.setting, updated = excluded.updated
+             WHERE excluded.updated > settings.updated
+             OR (excluded.updated = settings.updated AND excluded.setting > settings.setting)",
+            params![setting.key(), serde_json::to_string(setting)?, updated],
+        )?;
+        Ok(changed > 0)
+    }
+
+    // Record a setting we changed just now, or just after its last change if our clock is behind
+    // the device that made it. Returns when it changed.
+    pub fn stamp_setting(&self, setting: &Setting) -> Result<i64> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let sql = "SELECT MAX(updated) FROM settings WHERE key = ?1";
+        let last: Option<i64> = conn.query_row(sql, params![setting.key()], |row| row.get(0))?;
+        let updated = last.map_or(now(), |last| now().max(last + 1));
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, setting, updated) VALUES (?1, ?2, ?3)",
+            params![setting.key(), serde_json::to_string(setting)?, updated],
+        )?;
+        Ok(updated)
+    }
+
+    // Every synced setting and when it last changed
+    pub fn settings(&self) -> Result<Vec<(Setting, i64)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT setting, updated FROM settings")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+        let mut settings = Vec::new();
+        for row in rows {
+            let (setting, updated) = row?;
+            settings.push((serde_json::from_str(&setting)?, updated));
+        }
+        Ok(settings)
+    }
+
+    // Note a blob we offered or fetched, or that we used it again
+    pub fn cache_blob(&self, hash: &Hash, name: &str, size: u64, directory: bool, sent: bool) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT INTO blobs (hash, name, size, directory, sent, used) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (hash) DO UPDATE SET name = ?2, used = ?6, sent = sent OR ?5",
+            params![hash.to_string(), name, size as i64, directory, sent, now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn uncache_blob(&self, hash: &Hash) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute("DELETE FROM blobs WHERE hash = ?1", params![hash.to_string()])?;
+        Ok(())
+    }
+
+    pub fn clear_blobs(&self) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute("DELETE FROM blobs", [])?;
+        Ok(())
+    }
+
+    // Everything in the blob store, least recently used first
+    pub fn cached_blobs(&self) -> Result<Vec<CachedBlob>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT hash, name, size, directory, sent, used FROM blobs ORDER BY used")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, bool>(4)?,
+                row.get::<_, i64>(5)?,
+            ))
+        })?;
+        let mut blobs = Vec::new();
+        for row in rows {
+            let (hash, name, size, directory, sent, used) = row?;
+            blobs.push(CachedBlob { hash: hash.parse()?, name, size: size as u64, directory, sent, used });
+        }
+        Ok(blobs)
+    }
+
+    // Move a room's read position up to `clock`. Returns false if it was there or further already.
+    pub fn set_read(&self, topic: &TopicId, clock: u64) -> Result<bool> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let changed = conn.execute(
+            "INSERT INTO read_positions (topic, clock) VALUES (?1, ?2)
+             ON CONFLICT (topic) DO UPDATE SET clock = excluded.clock WHERE excluded.clock > read_positions.clock",
+            params![topic.to_string(), clock as i64],
+        )?;
+        Ok(changed > 0)
+    }
+
+    // Start counting unread messages in a room from `clock`, unless we read there before
+    pub fn start_reading(&self, topic: &TopicId, clock: u64) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR IGNORE INTO read_positions (topic, clock) VALUES (?1, ?2)",
+            params![topic.to_string(), clock as i64],
+        )?;
+        Ok(())
+    }
+
+    // Every room's read position
+    pub fn read_positions(&self) -> Result<Vec<(TopicId, u64)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT topic, clock FROM read_positions")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+        let mut positions = Vec::new();
+        for row in rows {
+            let (topic, clock) = row?;
+            positions.push((topic.parse()?, clock as u64));
+        }
+        Ok(positions)
+    }
+
+    // How many stored messages of a room came after its read position, leaving out those sent by
+    // any of `ours`
+    pub fn unread(&self, topic: &TopicId, ours: &[NodeId]) -> Result<usize> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare(
+            "SELECT sender FROM messages WHERE topic = ?1 AND NOT deleted
+             AND clock > (SELECT clock FROM read_positions WHERE topic = ?1)",
+        )?;
+        let rows = stmt.query_map(params![topic.to_string()], |row| row.get::<_, String>(0))?;
+        let mut unread = 0;
+        for row in rows {
+            if !ours.contains(&row?.parse()?) {
+                unread += 1;
+            }
+        }
+        Ok(unread)
+    }
+
+    pub fn save_device(&self, cert: &DeviceCert) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR IGNORE INTO devices (device, account, cert) VALUES (?1, ?2, ?3)",
+            params![cert.device.to_string(), cert.account.to_string(), serde_json::to_string(cert)?],
+        )?;
+        Ok(())
+    }
+
+    pub fn devices(&self) -> Result<Vec<DeviceCert>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT cert FROM devices")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut certs = Vec::new();
+        for row in rows {
+            certs.push(serde_json::from_str(&row?)?);
+        }
+        Ok(certs)
+    }
+
+    pub fn pin(&self, topic: &TopicId, name: &str, node_id: &NodeId) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR REPLACE INTO pins (topic, name, node) VALUES (?1, ?2, ?3)",
+            params![topic.to_string(), name, node_id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    pub fn unpin(&self, topic: &TopicId, name: &str) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute("DELETE FROM pins WHERE topic = ?1 AND name = ?2", params![topic.to_string(), name])?;
+        Ok(())
+    }
+
+    // The node id each name in a room is pinned to
+    pub fn pins(&self, topic: &TopicId) -> Result<Vec<(String, NodeId)>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT name, node FROM pins WHERE topic = ?1")?;
+        let rows = stmt.query_map(params![topic.to_string()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut pins = Vec::new();
+        for row in rows {
+            let (name, node_id) = row?;
+            pins.push((name, node_id.parse()?));
+        }
+        Ok(pins)
+    }
+
+    pub fn save_contact(&self, contact: &Contact) -> Result<()> {
+        let addr = contact.addr.as_ref().map(serde_json::to_string).transpose()?;
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute(
+            "INSERT OR REPLACE INTO contacts (node, alias, notes, addr) VALUES (?1, ?2, ?3, ?4)",
+            params![contact.node_id.to_string(), contact.alias, contact.notes, addr],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_contact(&self, node_id: &NodeId) -> Result<()> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        conn.execute("DELETE FROM contacts WHERE node = ?1", params![node_id.to_string()])?;
+        Ok(())
+    }
+
+    pub fn contacts(&self) -> Result<Vec<Contact>> {
+        let conn = self.conn.lock().expect("storage lock poisoned");
+        let mut stmt = conn.prepare("SELECT node, alias, notes, addr FROM contacts")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?, row.get::<_, Option<String>>(3)?))
+        })?;
+        let mut contacts = Vec::new();
+        for row in rows {
+            let (node_id, alias, notes, addr) = row?;
+            let addr = addr.map(|addr| serde_json::from_str(&addr)).transpose()?;
+            contacts.push(Contact { node_id: node_id.parse()?, alias, notes, addr });
+        }
+        Ok(contacts)
+    }
+}
+
+// Every message we ever had stays in the log, even once retention drops its text
+fn remember(conn: &Connection, topic: &TopicId, message: &StoredMessage) -> Result<()> {
+    let (topic, id) = (topic.to_string(), message.id.to_string());
+    conn.execute(
+        "INSERT OR IGNORE INTO dag_nodes (topic, id, clock) VALUES (?1, ?2, ?3)",
+        params![topic, id, message.clock as i64],
+    )?;
+    for parent in &message.parents {
+        conn.execute(
+            "INSERT OR IGNORE INTO dag_edges (topic, child, parent) VALUES (?1, ?2, ?3)",
+            params![topic, id, parent.to_string()],
+        )?;
+    }
+    Ok(())
+}
+
+// A room's retention policy, which keeps everything if it has none
+fn read_retention(conn: &Connection, topic: &TopicId) -> Result<Retention> {
+    let mut stmt = conn.prepare("SELECT max_age, max_messages FROM retention WHERE topic = ?1")?;
+    let mut rows = stmt.query(params![topic.to_string()])?;
+    match rows.next()? {
+        Some(row) => Ok(Retention {
+            max_age: row.get::<_, Option<i64>>(0)?.map(|secs| Duration::from_secs(secs as u64)),
+            max_messages: row.get::<_, Option<i64>>(1)?.map(|count| count as u64),
+        }),
+        None => Ok(Retention::default()),
+    }
+}
+
+// Apply every room's retention now and then, for as long as the storage is around
+pub fn prune_periodically(storage: Weak<Storage>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let Some(storage) = storage.upgrade() else {
+                return;
+            };
+            // Pruning blocks on SQLite, VACUUM most of all. A failed run is retried on the next tick.
+            let _ = tokio::task::spawn_blocking(move || storage.prune()).await;
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+        }
+    })
+}
+
+// Milliseconds since the unix epoch
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> Storage {
+        Storage::open(Path::new(":memory:"), None).unwrap()
+    }
+
+    fn message(sender: NodeId, text: &str, timestamp: i64) -> StoredMessage {
+        StoredMessage {
+            id: MessageId::random(),
+            sender,
+            name: None,
+            text: text.to_string(),
+            timestamp,
+            edited: false,
+            deleted: false,
+            reply_to: None,
+            clock: 0,
+            parents: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn only_authors_edit_and_delete() {
+        let storage = storage();
+        let topic = TopicId::from_bytes([1; 32]);
+        let (alice, mallory) = (SecretKey::from_bytes(&[1; 32]).public(), SecretKey::from_bytes(&[2; 32]).public());
+        let kept = message(alice, "hello", now());
+        storage.insert(&topic, &kept, &Signed::default()).unwrap();
+        assert!(!storage.edit(&topic, &kept.id, &mallory, "forged", b"").unwrap());
+        assert!(!storage.delete(&topic, &kept.id, &mallory, b"").unwrap());
+        assert_eq!(storage.get(&topic, &kept.id).unwrap().unwrap().text, "hello");
+        assert!(storage.edit(&topic, &kept.id, &alice, "hello, world", b"").unwrap());
+        assert!(storage.delete(&topic, &kept.id, &alice, b"").unwrap());
+        assert!(storage.get(&topic, &kept.id).unwrap().unwrap().deleted);
+    }
+
+    #[test]
+    fn prunes_what_retention_no_longer_keeps() {
+        let storage = storage();
+        let (topic, other) = (TopicId::from_bytes([1; 32]), TopicId::from_bytes([2; 32]));
+        let alice = SecretKey::from_bytes(&[1; 32]).public();
+        let day = 24 * 60 * 60 * 1000;
+        let mut kept = Vec::new();
+        for (clock, age) in [(1, 3 * day), (2, 2 * day), (3, 0), (4, 0), (5, 0)] {
+            let message = StoredMessage { clock, ..message(alice, "hi", now() - age) };
+            storage.insert(&topic, &message, &Signed::default()).unwrap();
+            storage.insert(&other, &message, &Signed::default()).unwrap();
+            kept.push(message.id);
+        }
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        storage.set_retention(&topic, Retention { max_age: Some(week), max_messages: None }).unwrap();
+        assert_eq!(storage.prune().unwrap(), 0);
+        // A day goes for the old two, the count for the oldest of the ones left
+        let day = Duration::from_secs(24 * 60 * 60);
+        storage.set_retention(&topic, Retention { max_age: Some(day), max_messages: Some(2) }).unwrap();
+        assert_eq!(storage.prune().unwrap(), 3);
+        let left: Vec<_> = storage.messages(&topic).unwrap().into_iter().map(|message| message.id).collect();
+        assert_eq!(left, kept[3..]);
+        // Other rooms keep everything, and older history isn't backfilled past the retention
+        assert_eq!(storage.messages(&other).unwrap().len(), 5);
+        let old = message(alice, "late", now() - 2 * 24 * 60 * 60 * 1000);
+        assert!(!storage.insert(&topic, &old, &Signed::default()).unwrap());
+        storage.set_retention(&topic, Retention::default()).unwrap();
+        assert_eq!(storage.retention(&topic).unwrap(), Retention::default());
+    }
+
+    #[test]
+    fn keeps_encrypted_history_from_the_wrong_key() {
+        let dir = std::env::temp_dir().join(format!("iroh-chat-test-{}", rand::random::<u64>()));
+        let path = dir.join("history.db");
+        let (topic, alice) = (TopicId::from_bytes([1; 32]), SecretKey::from_bytes(&[1; 32]).public());
+        let right = HistoryKey::Passphrase("correct horse".to_string());
+        let kept = message(alice, "secret", now());
+        Storage::open(&path, Some(&right)).unwrap().insert(&topic, &kept, &Signed::default()).unwrap();
+        assert!(is_encrypted(&path).unwrap());
+        let wrong = HistoryKey::Passphrase("battery staple".to_string());
+        let err = Storage::open(&path, Some(&wrong)).unwrap_err();
+        assert!(err.to_string().contains("wrong passphrase"));
+        let err = Storage::open(&path, None).unwrap_err();
+        assert!(err.to_string().contains("no key was given"));
+        let reopened = Storage::open(&path, Some(&right)).unwrap();
+        assert_eq!(reopened.get(&topic, &kept.id).unwrap().unwrap().text, "secret");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{bail, Context, Result};
+use p2p_chat::config::ThemeConfig;
+use ratatui::style::{Color, Modifier, Style};
+
+// What the frontends color lines and pieces of them with. None leaves the terminal's own color.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Theme {
+    pub text: Option<Color>,
+    pub own: Option<Color>,
+    pub notice: Option<Color>,
+    pub mention: Option<Color>,
+    pub timestamp: Option<Color>,
+}
+
+const DARK: Theme = Theme {
+    text: None,
+    own: Some(Color::Gray),
+    notice: Some(Color::DarkGray),
+    mention: Some(Color::LightYellow),
+    timestamp: Some(Color::DarkGray),
+};
+
+const LIGHT: Theme = Theme {
+    text: None,
+    own: Some(Color::Blue),
+    notice: Some(Color::DarkGray),
+    mention: Some(Color::Red),
+    timestamp: Some(Color::DarkGray),
+};
+
+// Ethan Schoonover's palette: base0, base1, base01, orange and base01
+const SOLARIZED: Theme = Theme {
+    text: Some(Color::Rgb(131, 148, 150)),
+    own: Some(Color::Rgb(147, 161, 161)),
+    notice: Some(Color::Rgb(88, 110, 117)),
+    mention: Some(Color::Rgb(203, 75, 22)),
+    timestamp: Some(Color::Rgb(88, 110, 117)),
+};
+
+impl Theme {
+    pub fn from_config(config: &ThemeConfig) -> Result<Self> {
+        let mut theme = match config.name.as_deref().unwrap_or("dark") {
+            "dark" => DARK,
+            "light" => LIGHT,
+            "solarized" => SOLARIZED,
+            other => bail!("there is no theme {}, only dark, light and solarized", other),
+        };
+        let colors = [
+            (&mut theme.text, &config.text, "text"),
+            (&mut theme.own, &config.own, "own"),
+            (&mut theme.notice, &config.notice, "notice"),
+            (&mut theme.mention, &config.mention, "mention"),
+            (&mut theme.timestamp, &config.timestamp, "timestamp"),
+        ];
+        for (color, configured, key) in colors {
+            if let Some(configured) = configured {
+                let parsed = configured.parse().ok().with_context(|| format!("invalid color for theme.{}", key));
+                *color = Some(parsed?);
+            }
+        }
+        Ok(theme)
+    }
+
+    // The color of a whole line: a message by us or someone else, a notice, or neither
+    pub fn line(&self, line: &str, message: bool, own: bool) -> Option<Color> {
+        match (message, own) {
+            (true, true) => self.own,
+            (true, false) => self.text,
+            _ if line.starts_with('>') => self.notice,
+            _ => None,
+        }
+    }
+
+    // Mentions of us stand out in bold and reversed, in the mention color if there is one
+    pub fn mention_style(&self) -> Style {
+        fg(self.mention).add_modifier(Modifier::BOLD | Modifier::REVERSED)
+    }
+
+    pub fn mention_sgr(&self) -> String {
+        match self.mention {
+            Some(color) => format!("1;7;{}", sgr(color)),
+            None => "1;7".to_string(),
+        }
+    }
+}
+
+pub fn fg(color: Option<Color>) -> Style {
+    color.map_or_else(Style::default, |color| Style::default().fg(color))
+}
+
+// The SGR parameters of a foreground color, for the plain interface
+pub fn sgr(color: Color) -> String {
+    let code = match color {
+        Color::Reset => 39,
+        Color::Black => 30,
+        Color::Red => 31,
+        Color::Green => 32,
+        Color::Yellow => 33,
+        Color::Blue => 34,
+        Color::Magenta => 35,
+        Color::Cyan => 36,
+        Color::Gray => 37,
+        Color::DarkGray => 90,
+        Color::LightRed => 91,
+        Color::LightGreen => 92,
+        Color::LightYellow => 93,
+        Color::LightBlue => 94,
+        Color::LightMagenta => 95,
+        Color::LightCyan => 96,
+        Color::White => 97,
+        Color::Indexed(index) => return format!("38;5;{}", index),
+        Color::Rgb(r, g, b) => return format!("38;2;{};{};{}", r, g, b),
+    };
+    code.to_string()
+}
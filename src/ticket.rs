@@ -0,0 +1,182 @@
+use crate::crypto::RoomKey;
+use crate::membership::Publishers;
+use anyhow::bail;
+use data_encoding::BASE32_NOPAD;
+use iroh::{NodeAddr, NodeId};
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// The newest ticket version we understand. Each ticket carries the lowest version that can make
+// sense of it, so older clients only refuse the tickets they really can't use. Tickets from before
+// versioning count as version 0.
+pub const TICKET_VERSION: u32 = 3;
+
+// Plain tickets and invites
+const INVITE_VERSION: u32 = 1;
+// Tickets for password-protected rooms
+const PASSWORD_VERSION: u32 = 2;
+// Tickets for read-only rooms, which older clients would let everyone post in
+const READ_ONLY_VERSION: u32 = 3;
+
+// Everything needed to join a room: the topic, peers to bootstrap from and either the room key,
+// an invite to trade for it, or a note that the members want a password
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ticket {
+    #[serde(default)]
+    pub version: u32,
+    pub topic: TopicId,
+    pub nodes: Vec<NodeAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<RoomKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite: Option<Invite>,
+    // Members hand out the key to whoever proves they know the room's password
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub password: bool,
+    // Whoever opened the room, the only one who can restrict who may speak in it. Older clients
+    // ignore it, so it needs no new version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<NodeId>,
+    // Who besides the owner may post, if the room is read-only. Signed by the owner.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publishers: Option<Publishers>,
+}
+
+// A restricted ticket leaves out the key. Whoever issued it hands the key out in exchange for the
+// token, as long as the invite hasn't expired or, if it is single-use, been used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub token: [u8; 16],
+    // Unix time in milliseconds
+    pub expires: Option<i64>,
+    pub single_use: bool,
+}
+
+impl Ticket {
+    // A ticket carrying the room key itself, usable by anyone forever
+    pub fn new(topic: TopicId, nodes: Vec<NodeAddr>, key: RoomKey) -> Self {
+        Self {
+            version: INVITE_VERSION,
+            topic,
+            nodes,
+            key: Some(key),
+            invite: None,
+            password: false,
+            owner: None,
+            publishers: None,
+        }
+    }
+
+    // A ticket without the key, for rooms joined with a password
+    pub fn protected(topic: TopicId, nodes: Vec<NodeAddr>) -> Self {
+        Self {
+            version: PASSWORD_VERSION,
+            topic,
+            nodes,
+            key: None,
+            invite: None,
+            password: true,
+            owner: None,
+            publishers: None,
+        }
+    }
+
+    // The same ticket for a read-only room, if `publishers` makes it one
+    pub fn with_publishers(self, publishers: Option<Publishers>) -> Self {
+        match publishers {
+            Some(publishers) => {
+                Self { version: self.version.max(READ_ONLY_VERSION), publishers: Some(publishers), ..self }
+            }
+            None => self,
+        }
+    }
+}
+
+impl fmt::Display for Ticket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = serde_json::to_vec(self).expect("Serialization failed");
+        let text = BASE32_NOPAD.encode(&bytes).to_lowercase();
+        write!(f, "{}", text)
+    }
+}
+
+impl FromStr for Ticket {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = BASE32_NOPAD.decode(s.to_uppercase().as_bytes())?;
+        let ticket: Ticket = serde_json::from_slice(&bytes)?;
+        if ticket.version > TICKET_VERSION {
+            bail!("ticket is from a newer version of iroh-chat (ticket version {})", ticket.version);
+        }
+        if ticket.key.is_none() && ticket.invite.is_none() && !ticket.password {
+            bail!("ticket has neither a room key nor an invite");
+        }
+        Ok(ticket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic() -> TopicId {
+        TopicId::from_bytes([7; 32])
+    }
+
+    // Whether both keys open each other's ciphertexts
+    fn same_key(a: &RoomKey, b: &RoomKey) -> bool {
+        b.decrypt(&a.encrypt(b"probe")).is_ok()
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = RoomKey::generate();
+        let ticket = Ticket::new(topic(), Vec::new(), key);
+        let parsed: Ticket = ticket.to_string().parse().unwrap();
+        assert_eq!(parsed.topic, topic());
+        assert_eq!(parsed.version, INVITE_VERSION);
+        assert!(same_key(&parsed.key.unwrap(), &key));
+        assert!(parsed.invite.is_none() && !parsed.password);
+        // Uppercase, as some chat clients turn it, still parses
+        assert!(ticket.to_string().to_uppercase().parse::<Ticket>().is_ok());
+    }
+
+    #[test]
+    fn parses_invites_and_password_tickets() {
+        let invite = Invite { token: [3; 16], expires: Some(1_700_000_000_000), single_use: true };
+        let plain = Ticket::new(topic(), Vec::new(), RoomKey::generate());
+        let ticket = Ticket { key: None, invite: Some(invite), ..plain };
+        let parsed: Ticket = ticket.to_string().parse().unwrap();
+        let invite = parsed.invite.unwrap();
+        assert!(parsed.key.is_none());
+        assert_eq!(invite.token, [3; 16]);
+        assert_eq!(invite.expires, Some(1_700_000_000_000));
+        assert!(invite.single_use);
+
+        let parsed: Ticket = Ticket::protected(topic(), Vec::new()).to_string().parse().unwrap();
+        assert!(parsed.password && parsed.key.is_none());
+        assert_eq!(parsed.version, PASSWORD_VERSION);
+    }
+
+    #[test]
+    fn parses_unversioned_tickets() {
+        let key = RoomKey::generate();
+        let json = serde_json::json!({ "topic": topic(), "nodes": [], "key": key });
+        let text = BASE32_NOPAD.encode(&serde_json::to_vec(&json).unwrap()).to_lowercase();
+        let parsed: Ticket = text.parse().unwrap();
+        assert_eq!(parsed.version, 0);
+        assert!(same_key(&parsed.key.unwrap(), &key));
+    }
+
+    #[test]
+    fn refuses_bad_tickets() {
+        let newer = Ticket { version: TICKET_VERSION + 1, ..Ticket::new(topic(), Vec::new(), RoomKey::generate()) };
+        let err = newer.to_string().parse::<Ticket>().unwrap_err();
+        assert!(err.to_string().contains("newer version"));
+        let keyless = Ticket { key: None, ..Ticket::new(topic(), Vec::new(), RoomKey::generate()) };
+        assert!(keyless.to_string().parse::<Ticket>().is_err());
+        assert!("not a ticket!".parse::<Ticket>().is_err());
+    }
+}
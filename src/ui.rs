@@ -0,0 +1,1748 @@
+use crate::commands::ACTION;
+use crate::complete;
+use crate::editor::{Input, Screen};
+use crate::graphics::{Graphics, PREVIEW_LIMIT};
+use crate::highlight::{Highlighter, Kind};
+use crate::keys::{Action, Keymap, Lookup};
+use crate::markdown::{self, Emphasis};
+use crate::notify;
+use crate::voice;
+use crate::theme::{self, Theme};
+use anyhow::{bail, Result};
+use crossterm::event::{
+    DisableFocusChange, EnableFocusChange, Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use futures_lite::{Stream, StreamExt};
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use chrono::{DateTime, Datelike, Local, NaiveDate, Utc};
+use p2p_chat::{
+    format_size, is_image, mentions, to_shortcodes, unfence, ChatClient, ChatEvent, Latency, Links, MessageId,
+    RoomInfo, SearchHit, Status, StoredMessage, PROTOCOL_VERSION,
+};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, BorderType, List, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{HashMap, VecDeque};
+use std::io::IsTerminal;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use unicode_width::UnicodeWidthStr;
+
+// How the frontends show things, from the command line and config.toml
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Colored names and code
+    pub color: bool,
+    // Desktop notifications for DMs and the messages rooms' /notify rules pick
+    pub notify: bool,
+    // When messages were sent, in front of them
+    pub clock: Option<Clock>,
+    // What notices, messages and their parts are colored with, along with `color`
+    pub theme: Theme,
+    // Emoji as they are, or as their :shortcodes: when false
+    pub emoji: bool,
+    // Bold, italics, code and links in message text, along with `color`
+    pub markdown: bool,
+    // Vim-like normal and insert modes in the full-screen UI, with these normal mode keys
+    pub keymap: Option<Keymap>,
+    // Plain lines that each say who or what they are from, read as typed in, for screen readers
+    pub accessible: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    // A strftime format like %H:%M
+    pub format: String,
+    pub utc: bool,
+}
+
+impl Clock {
+    // "[14:32] " for messages
+    fn stamp(&self, event: &ChatEvent) -> Option<String> {
+        Some(format!("[{}] ", self.time(event)?))
+    }
+
+    // The time a message was sent, with a ? after it when the sender's clock is off
+    fn time(&self, event: &ChatEvent) -> Option<String> {
+        let (timestamp, skewed) = match event {
+            ChatEvent::Message { timestamp, skewed, .. } => (*timestamp, *skewed),
+            ChatEvent::History { timestamp, .. } => (*timestamp, false),
+            // Direct messages come as they are sent, with no time of their own
+            ChatEvent::DirectMessage { .. } => (Utc::now().timestamp_millis(), false),
+            _ => return None,
+        };
+        let time = DateTime::from_timestamp_millis(timestamp).unwrap_or_default();
+        let time = match self.utc {
+            true => time.format(&self.format).to_string(),
+            false => time.with_timezone(&Local).format(&self.format).to_string(),
+        };
+        Some(format!("{}{}", time, if skewed { "?" } else { "" }))
+    }
+}
+
+// The messages shown one after another, so a sender's run of them shows their name once and a
+// line marks where a new day starts
+#[derive(Default)]
+struct Run {
+    // Who sent the last message shown and when, unless something else was shown since
+    last: Option<(NodeId, i64)>,
+    day: Option<NaiveDate>,
+}
+
+impl Run {
+    // "── March 3 ──" when the message an event is was sent on another day than the one before.
+    // The first one only gets it if it wasn't sent today.
+    fn new_day(&mut self, event: &ChatEvent, utc: bool) -> Option<String> {
+        let (_, timestamp) = sent(event)?;
+        let day = |time: DateTime<Utc>| match utc {
+            true => time.date_naive(),
+            false => time.with_timezone(&Local).date_naive(),
+        };
+        let today = day(Utc::now());
+        let date = day(DateTime::from_timestamp_millis(timestamp).unwrap_or_default());
+        let last = self.day.replace(date);
+        if last == Some(date) || (last.is_none() && date == today) {
+            return None;
+        }
+        let format = if date.year() == today.year() { "%B %-d" } else { "%B %-d, %Y" };
+        Some(format!("── {} ──", date.format(format)))
+    }
+
+    // Whether the message an event is comes from whoever sent the one shown last, soon after it.
+    // Anything else shown in between ends the run.
+    fn follows(&mut self, event: &ChatEvent) -> bool {
+        let next = sent(event);
+        match (std::mem::replace(&mut self.last, next), next) {
+            (Some((before, then)), Some((from, now))) => before == from && (now - then).abs() < GROUP_WINDOW,
+            _ => false,
+        }
+    }
+}
+
+// Who sent the chat message an event is, and when
+fn sent(event: &ChatEvent) -> Option<(NodeId, i64)> {
+    match event {
+        ChatEvent::Message { from, timestamp, .. } | ChatEvent::History { from, timestamp, .. } => {
+            Some((*from, *timestamp))
+        }
+        _ => None,
+    }
+}
+
+// A message line without the sender's name at `name`, for following one of theirs: the text lines
+// up with the text above it, like "#3fa2c2        hi". Actions keep the name.
+fn grouped(line: &str, name: Range<usize>) -> Option<String> {
+    let prefix = line.get(name.start..name.end + 2).filter(|prefix| prefix.ends_with(": "))?;
+    Some(format!("{}{}{}", &line[..name.start], " ".repeat(prefix.width()), &line[name.end + 2..]))
+}
+
+// A line with the time in front, if the event has one to show, and how long that time is
+fn stamped(clock: Option<&Clock>, event: &ChatEvent, mut line: String) -> (String, usize) {
+    let stamp = clock.and_then(|clock| clock.stamp(event)).unwrap_or_default();
+    line.insert_str(0, &stamp);
+    (line, stamp.len())
+}
+
+// Where the CLI's own notices end up, next to the client's events, whichever frontend is running
+#[derive(Debug, Clone)]
+pub struct Output(mpsc::UnboundedSender<String>);
+
+impl Output {
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(tx), rx)
+    }
+
+    pub fn line(&self, text: impl Into<String>) {
+        // The frontend only goes away on shutdown, so a failed send can be ignored
+        let _ = self.0.send(text.into());
+    }
+}
+
+// What is left of a deleted message
+const TOMBSTONE: &str = "[message deleted]";
+
+// How long a typing notice is shown without another one arriving
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Quoted parents of replies are cut off after this many characters
+const QUOTE_LENGTH: usize = 60;
+
+// The lines of a code snippet are shown below the message line, each behind this
+const GUTTER: &str = "  │ ";
+
+// How many lines the full-screen UI keeps for each room, dropping the oldest past that
+const SCROLLBACK: usize = 1000;
+// How many panes /split fits side by side
+const MAX_TILES: usize = 4;
+
+// How long after one message the next from the same sender still goes without their name, in
+// milliseconds
+const GROUP_WINDOW: i64 = 2 * 60 * 1000;
+
+// An event with the emoji in what people wrote, and in reactions, turned into :shortcodes:
+fn shortcoded(mut event: ChatEvent) -> ChatEvent {
+    match &mut event {
+        ChatEvent::Message { text, quoted, .. } | ChatEvent::History { text, quoted, .. } => {
+            *text = to_shortcodes(text);
+            if let Some(quoted) = quoted {
+                quoted.text = to_shortcodes(&quoted.text);
+            }
+        }
+        ChatEvent::Edited { text, .. } | ChatEvent::DirectMessage { text, .. } => *text = to_shortcodes(text),
+        ChatEvent::Reaction { emoji, counts, .. } => {
+            *emoji = to_shortcodes(emoji);
+            counts.iter_mut().for_each(|(emoji, _)| *emoji = to_shortcodes(emoji));
+        }
+        _ => {}
+    }
+    event
+}
+
+// What is shown after someone's name of what they are up to and whether they are away or in
+// do-not-disturb, like " (in a meeting, away: lunch)"
+pub fn presence(status: &Status) -> String {
+    let away = match status.away.as_deref() {
+        Some("") => Some("away".to_string()),
+        Some(note) => Some(format!("away: {}", note)),
+        None => None,
+    };
+    let dnd = status.dnd.then(|| "do not disturb".to_string());
+    let text = Some(status.text.clone()).filter(|text| !text.is_empty());
+    let said: Vec<_> = text.into_iter().chain(away).chain(dnd).collect();
+    match said.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", said.join(", ")),
+    }
+}
+
+// The notice of someone going away, or coming back when `note` is None
+pub fn went_away(name: &str, note: Option<&str>) -> String {
+    match note {
+        Some("") => format!("> {} is away", name),
+        Some(note) => format!("> {} is away: {}", name, note),
+        None => format!("> {} is back", name),
+    }
+}
+
+// What /ping found: the middle round trip, the spread, and whether a relay was in the way. A slow
+// connect with quick pings is the path being set up, not the peer being slow.
+pub fn latency(name: &str, latency: &Latency) -> String {
+    let mut round_trips: Vec<u128> = latency.round_trips.iter().map(Duration::as_millis).collect();
+    round_trips.sort();
+    let (Some(fastest), Some(slowest)) = (round_trips.first(), round_trips.last()) else {
+        return format!("> {} answered", name);
+    };
+    let path = if latency.relayed { "through a relay" } else { "directly" };
+    format!(
+        "> {} answered in {} ms ({}-{} ms over {} pings), {}; connecting took {} ms",
+        name,
+        round_trips[round_trips.len() / 2],
+        fastest,
+        slowest,
+        round_trips.len(),
+        path,
+        latency.connect.as_millis()
+    )
+}
+
+// Rows of cells lined up in columns, the last one left ragged
+pub fn table(rows: &[Vec<String>]) -> Vec<String> {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| rows.iter().filter_map(|row| row.get(column)).map(|cell| cell.width()).max().unwrap_or(0))
+        .collect();
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (column, cell) in row.iter().enumerate() {
+                line.push_str(cell);
+                if column + 1 < row.len() {
+                    line.push_str(&" ".repeat(widths[column] - cell.width() + 2));
+                }
+            }
+            line
+        })
+        .collect()
+}
+
+// Comings and goings, which do-not-disturb doesn't show
+fn noise(event: &ChatEvent) -> bool {
+    matches!(
+        event,
+        ChatEvent::Joined { .. }
+            | ChatEvent::Left { .. }
+            | ChatEvent::NeighborUp { .. }
+            | ChatEvent::NeighborDown { .. }
+    )
+}
+
+// What do-not-disturb held back when it is over: the latest messages that mentioned us meanwhile
+fn missed(client: &ChatClient, event: &ChatEvent) -> Vec<String> {
+    let ChatEvent::DoNotDisturbOver { since } = event else {
+        return Vec::new();
+    };
+    let hits = client.recent_mentions().unwrap_or_default();
+    let hits: Vec<_> = hits.iter().filter(|hit| hit.message.timestamp >= *since).collect();
+    if hits.is_empty() {
+        return vec!["> Nothing mentioned you meanwhile".to_string()];
+    }
+    let lines = hits.into_iter().rev().map(|hit| format!("> {}", render_search(hit)));
+    ["> Meanwhile you were mentioned in:".to_string()].into_iter().chain(lines).collect()
+}
+
+// How an event is shown, if it is shown as a line at all.
+// Lines from rooms other than the active one are tagged with the room.
+pub fn render(event: &ChatEvent, active: Option<TopicId>) -> Option<String> {
+    let prefix = match event.room() {
+        Some(room) if Some(room) != active => format!("[{}] ", room.fmt_short()),
+        _ => String::new(),
+    };
+    let line = match event {
+        ChatEvent::Joined { from, name, .. } => format!("> {} joined as {}", from.fmt_short(), name),
+        ChatEvent::Message { id, name, text, .. } => format!("#{} {}", id.fmt_short(), said(name, text)),
+        ChatEvent::History { id, name, deleted: true, remote, recovered, .. } => {
+            let marker = history_marker(*remote, *recovered);
+            format!("{}#{} {}: {}", marker, id.fmt_short(), name, TOMBSTONE)
+        }
+        ChatEvent::History { id, name, text, edited, remote, recovered, .. } => {
+            let marker = history_marker(*remote, *recovered);
+            format!("{}#{} {}{}", marker, id.fmt_short(), said(name, text), edited_marker(*edited))
+        }
+        ChatEvent::Edited { id, text, .. } => format!("> #{} was edited: {}", id.fmt_short(), text),
+        ChatEvent::Deleted { id, .. } => format!("> #{} was deleted", id.fmt_short()),
+        ChatEvent::Reaction { id, name, emoji, .. } => {
+            format!("> {} reacted {} to #{}", name, emoji, id.fmt_short())
+        }
+        ChatEvent::FileOffered { name, file, size, id, .. } if is_image(file) => {
+            format!("> {}: [image: {}, {}, /get {}]", name, file, format_size(*size), id)
+        }
+        ChatEvent::FileOffered { name, file, size, id, .. } if voice::is_clip(file) => {
+            format!("> {}: [voice clip, {}, /voice play {}]", name, format_size(*size), id)
+        }
+        ChatEvent::FileOffered { name, file, size, id, .. } => {
+            format!("> {} offers {} ({}), /get {} to download", name, file, format_size(*size), id)
+        }
+        ChatEvent::DirectoryOffered { name, dir, size, files, id, .. } => format!(
+            "> {} offers directory {}/ ({} files, {}), /ls {} to list it, /get {} [path] to download",
+            name,
+            dir,
+            files,
+            format_size(*size),
+            id,
+            id
+        ),
+        ChatEvent::ContactShared { name, node_id, card, id, .. } => format!(
+            "> {} shares contact {} ({}), /contact save {} to add it",
+            name,
+            card,
+            node_id.fmt_short(),
+            id
+        ),
+        ChatEvent::FileProgress { id, done, size } => format!("> Fetching #{} {}", id, progress(*done, *size)),
+        ChatEvent::DirectMessage { from, name, text } => {
+            format!("[dm from {} ({})] {}", name, from.fmt_short(), text)
+        }
+        ChatEvent::DeviceLinked { device } => format!("> Device {} is linked to your account", device.fmt_short()),
+        ChatEvent::Left { from, name, .. } => format!("> {} ({}) left", name, from.fmt_short()),
+        ChatEvent::IncompatiblePeer { from, name, version, .. } => format!(
+            "> Warning: {} ({}) speaks protocol {}, we speak {}",
+            name,
+            from.fmt_short(),
+            version,
+            PROTOCOL_VERSION
+        ),
+        ChatEvent::NeighborUp { node_id, .. } => format!("> Neighbor connected: {}", node_id.fmt_short()),
+        ChatEvent::NeighborDown { node_id, .. } => format!("> Neighbor disconnected: {}", node_id.fmt_short()),
+        ChatEvent::Disconnected { .. } => {
+            "> Lost the connection to everyone in the room, reconnecting…".to_string()
+        }
+        ChatEvent::Reconnected { .. } => "> Reconnected".to_string(),
+        ChatEvent::Lagged { .. } => {
+            "> Warning: Message queue lagged, asking a neighbor for what was missed".to_string()
+        }
+        ChatEvent::InvalidMessage { via, error, .. } => {
+            format!("> Dropped invalid message via {}: {}", via.fmt_short(), error)
+        }
+        ChatEvent::Members { members, .. } => format!("> Members: {}", members.join(", ")),
+        ChatEvent::OwnerChanged { by, name, .. } => format!("> {} handed the room to {}, who owns it now", by, name),
+        ChatEvent::ReadOnly { publishers, .. } => {
+            format!("> The room is read-only, only {} can post in it", publishers.join(", "))
+        }
+        ChatEvent::Moved { room, old_room } => {
+            let (old_room, room) = (old_room.fmt_short(), room.fmt_short());
+            format!("> Room {} moved to {}, old tickets for it no longer work", old_room, room)
+        }
+        ChatEvent::RoomInfo { room, name, description, topic } => {
+            let mut line = format!("> Room {}", room.fmt_short());
+            if !name.is_empty() {
+                line = format!("{} is called {}", line, name);
+            }
+            if !topic.is_empty() {
+                line = format!("{}, topic: {}", line, topic);
+            }
+            if !description.is_empty() {
+                line = format!("{} ({})", line, description);
+            }
+            line
+        }
+        ChatEvent::Moderated { action, by, name, .. } => match action.as_str() {
+            "kick" => format!("> {} kicked {}", by, name),
+            "ban" => format!("> {} banned {}", by, name),
+            "unban" => format!("> {} unbanned {}", by, name),
+            "appoint" => format!("> {} made {} a moderator", by, name),
+            _ => format!("> {} dismissed {} as moderator", by, name),
+        },
+        ChatEvent::RateLimited { from, name, .. } => {
+            format!("> Peer {} ({}) is being rate-limited", name, from.fmt_short())
+        }
+        ChatEvent::RateLimitLifted { name, dropped, .. } => {
+            format!("> {} slowed down, {} of their messages were dropped", name, dropped)
+        }
+        ChatEvent::Renamed { old, name, .. } => format!("> {} is now known as {}", old, name),
+        ChatEvent::Away { name, away, .. } => went_away(name, away.as_deref()),
+        ChatEvent::NameClash { from, name, shown_as, .. } => format!(
+            "> Warning: {} also calls itself {}, showing it as {} (check the node id before trusting it)",
+            from.fmt_short(),
+            name,
+            shown_as
+        ),
+        ChatEvent::KeyChanged { from, name, pinned, shown_as, .. } => format!(
+            "> WARNING: THE KEY OF {} HAS CHANGED! It was {}, now {} claims the name. This may be someone \
+             impersonating them. Showing it as {}; /trust {} if you know they switched devices.",
+            name,
+            pinned.fmt_short(),
+            from.fmt_short(),
+            shown_as,
+            shown_as
+        ),
+        ChatEvent::PluginLog { plugin, text } => format!("> [plugin {}] {}", plugin, text),
+        ChatEvent::DoNotDisturbOver { .. } => "> Do not disturb is over".to_string(),
+        ChatEvent::Warning(text) => format!("> Warning: {}", text),
+        ChatEvent::Typing { .. } | ChatEvent::Receipt { .. } => return None,
+    };
+    Some(format!("{}{}", prefix, line))
+}
+
+// How --accessible reads an event out: what it is and who it is from first, like "Message from
+// alice at 14:32: hi", then the message id last, and the lines of code snippets each saying so
+fn spoken(event: &ChatEvent, active: Option<TopicId>, us: NodeId, clock: Option<&Clock>) -> Option<String> {
+    let room = match event.room() {
+        Some(room) if Some(room) != active => format!(" in room {}", room.fmt_short()),
+        _ => String::new(),
+    };
+    let at = clock.and_then(|clock| clock.time(event)).map(|time| format!(" at {}", time)).unwrap_or_default();
+    let line = match event {
+        ChatEvent::Message { id, from, name, text, reply_to, quoted, .. }
+        | ChatEvent::History { id, from, name, text, reply_to, quoted, deleted: false, .. } => {
+            let who = if *from == us { "you" } else { name.as_str() };
+            let (kind, text) = match text.strip_prefix(ACTION) {
+                Some(action) => ("Action", action.to_string()),
+                None => ("Message", shown(text)),
+            };
+            let reply = match (reply_to, quoted) {
+                (_, Some(parent)) => {
+                    format!(", replying to {} in message {}", name_of(parent), parent.id.fmt_short())
+                }
+                (Some(reply_to), None) => format!(", replying to message {}", reply_to.fmt_short()),
+                (None, None) => String::new(),
+            };
+            let edited = match event {
+                ChatEvent::History { edited: true, recovered: true, .. } => ", edited, recovered",
+                ChatEvent::History { edited: true, .. } => ", edited",
+                ChatEvent::History { recovered: true, .. } => ", recovered",
+                _ => "",
+            };
+            let id = id.fmt_short();
+            // The lines of a snippet come after the id
+            let (first, below) = text.split_at(text.find('\n').unwrap_or(text.len()));
+            format!("{} from {}{}{}{}: {} (message {}{}){}", kind, who, room, at, reply, first, id, edited, below)
+        }
+        ChatEvent::History { id, name, .. } => {
+            format!("Deleted message from {}{} (message {})", name, room, id.fmt_short())
+        }
+        ChatEvent::DirectMessage { from, name, text } => {
+            format!("Direct message from {} ({}){}: {}", name, from.fmt_short(), at, text)
+        }
+        ChatEvent::Edited { id, text, .. } => format!("Edit{} of message {}: {}", room, id.fmt_short(), shown(text)),
+        // Without the bar
+        ChatEvent::FileProgress { id, done, size } => match size {
+            Some(size) if *size > 0 => format!("Notice: fetching {}, {}% done", id, done.min(size) * 100 / size),
+            _ => format!("Notice: fetching {}, {} so far", id, format_size(*done)),
+        },
+        _ => return render(event, event.room()).map(|line| labeled(&line, &room)),
+    };
+    let mut lines = line.split('\n');
+    let first = lines.next().unwrap_or_default().to_string();
+    Some(lines.fold(first, |spoken, line| {
+        format!("{}\nCode line: {}", spoken, line.strip_prefix(GUTTER).unwrap_or(line))
+    }))
+}
+
+// A notice or command output as --accessible reads it, like "Notice: Bob joined" or "Error: ..."
+fn labeled(line: &str, room: &str) -> String {
+    if let Some((name, text)) = line.strip_prefix("[dm to ").and_then(|rest| rest.split_once("] ")) {
+        return format!("Direct message to {}: {}", name, text);
+    }
+    let text = line.strip_prefix("> ").unwrap_or(line);
+    let label = [("Error: ", "Error"), ("Warning: ", "Warning"), ("WARNING: ", "Warning")]
+        .into_iter()
+        .find_map(|(prefix, label)| Some((label, text.strip_prefix(prefix)?)));
+    match label {
+        Some((label, text)) => format!("{}{}: {}", label, room, text),
+        None => format!("Notice{}: {}", room, text),
+    }
+}
+
+// The line shown above a reply: the start of the message it answers
+pub fn render_quote(event: &ChatEvent) -> Option<String> {
+    let (reply_to, quoted) = match event {
+        ChatEvent::Message { reply_to, quoted, .. } | ChatEvent::History { reply_to, quoted, .. } => {
+            (reply_to.as_ref()?, quoted)
+        }
+        _ => return None,
+    };
+    let Some(parent) = quoted else {
+        return Some(format!("  ┌ #{} (not in history)", reply_to.fmt_short()));
+    };
+    let mut text = match unfence(text_of(parent)) {
+        Some((lang, code)) => format!("[{} code] {}", lang, code.lines().next().unwrap_or_default().trim()),
+        None => text_of(parent).to_string(),
+    };
+    if text.chars().count() > QUOTE_LENGTH {
+        text = text.chars().take(QUOTE_LENGTH).chain(['…']).collect();
+    }
+    Some(format!("  ┌ {}: {}", name_of(parent), text))
+}
+
+// One message of a /thread listing, indented by its depth
+pub fn render_thread(depth: usize, message: &StoredMessage) -> String {
+    format!(
+        "> {}#{} {}{}",
+        "  ".repeat(depth),
+        message.id.fmt_short(),
+        said(&name_of(message), text_of(message)),
+        edited_marker(message.edited)
+    )
+}
+
+// One match of a search: where and when it was said, by whom, and the text around the match
+pub fn render_search(hit: &SearchHit) -> String {
+    let room = hit.room.clone().unwrap_or_else(|| hit.topic.fmt_short());
+    let time = DateTime::from_timestamp_millis(hit.message.timestamp).unwrap_or_default().with_timezone(&Local);
+    let sender = hit.message.sender.fmt_short();
+    let from = match &hit.message.name {
+        Some(name) => format!("{} ({})", name, sender),
+        None => sender,
+    };
+    let time = time.format("%Y-%m-%d %H:%M");
+    format!("[{}] {} #{} {}: {}", room, time, hit.message.id.fmt_short(), from, hit.snippet)
+}
+
+// "[#####-----] 50%" for fetches of known size, "20.0 MB so far" otherwise
+fn progress(done: u64, size: Option<u64>) -> String {
+    const WIDTH: u64 = 20;
+    match size {
+        Some(size) if size > 0 => {
+            let done = done.min(size);
+            let filled = (done * WIDTH / size) as usize;
+            let bar = format!("{}{}", "#".repeat(filled), "-".repeat(WIDTH as usize - filled));
+            format!("[{}] {}%", bar, done * 100 / size)
+        }
+        _ => format!("{} so far", format_size(done)),
+    }
+}
+
+// The files of a directory as an indented tree, a line per file and per directory above them
+pub fn render_tree(paths: &[String]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut above: Vec<&str> = Vec::new();
+    for path in paths {
+        let parts: Vec<&str> = path.split('/').collect();
+        let (file, dirs) = parts.split_last().expect("split always yields a part");
+        let shared = above.iter().zip(dirs).take_while(|(a, b)| a == b).count();
+        for (depth, dir) in dirs.iter().enumerate().skip(shared) {
+            lines.push(format!("{}{}/", "  ".repeat(depth), dir));
+        }
+        lines.push(format!("{}{}", "  ".repeat(dirs.len()), file));
+        above = dirs.to_vec();
+    }
+    lines
+}
+
+// A message's text as shown. Code snippets get a line naming the language, with their own lines
+// below it as they are but for tabs, which terminals show at whatever width they like.
+// "alice: hi", or "* alice waves" for an action sent with /me
+fn said(name: &str, text: &str) -> String {
+    match text.starts_with(ACTION) {
+        true => format!("* {} {}", name, body(text)),
+        false => format!("{}: {}", name, body(text)),
+    }
+}
+
+// The text of a message as it follows the sender
+fn body(text: &str) -> String {
+    match text.strip_prefix(ACTION) {
+        Some(action) => action.to_string(),
+        None => shown(text),
+    }
+}
+
+fn shown(text: &str) -> String {
+    let Some((lang, code)) = unfence(text) else {
+        return text.to_string();
+    };
+    let lines: Vec<_> = code.split('\n').map(|line| format!("{}{}", GUTTER, line.replace('\t', "    "))).collect();
+    format!("[{} code]\n{}", lang, lines.join("\n"))
+}
+
+// The language of the code snippet an event shows, if it shows one
+fn code_of(event: &ChatEvent) -> Option<&str> {
+    match event {
+        ChatEvent::Message { text, .. } | ChatEvent::History { text, deleted: false, .. } => {
+            unfence(text).map(|(lang, _)| lang)
+        }
+        _ => None,
+    }
+}
+
+// The code lines of a shown snippet, highlighted for a terminal
+fn paint(lang: &str, shown: &str) -> String {
+    let mut highlighter = Highlighter::new(lang);
+    let lines: Vec<_> = shown
+        .split('\n')
+        .map(|line| match line.strip_prefix(GUTTER) {
+            Some(code) => format!("{}{}", GUTTER, highlighter.ansi(code)),
+            None => line.to_string(),
+        })
+        .collect();
+    lines.join("\n")
+}
+
+fn name_of(message: &StoredMessage) -> String {
+    message.name.clone().unwrap_or(message.sender.fmt_short())
+}
+
+fn text_of(message: &StoredMessage) -> &str {
+    if message.deleted { TOMBSTONE } else { &message.text }
+}
+
+// Senders' names get one of these, as an SGR code for plain lines and a color for the full-screen UI
+const NAME_COLORS: &[(u8, Color)] = &[
+    (31, Color::Red),
+    (32, Color::Green),
+    (33, Color::Yellow),
+    (34, Color::Blue),
+    (35, Color::Magenta),
+    (36, Color::Cyan),
+    (91, Color::LightRed),
+    (92, Color::LightGreen),
+    (93, Color::LightYellow),
+    (94, Color::LightBlue),
+    (95, Color::LightMagenta),
+    (96, Color::LightCyan),
+];
+
+// The color of a sender's name, picked by FNV-1a over its node id so it stays the same in every
+// session and on every machine
+fn name_color(node_id: &NodeId) -> (u8, Color) {
+    let hash = node_id
+        .as_bytes()
+        .iter()
+        .fold(0x811c9dc5u32, |hash, byte| (hash ^ u32::from(*byte)).wrapping_mul(0x01000193));
+    NAME_COLORS[hash as usize % NAME_COLORS.len()]
+}
+
+// Who sent what an event shows, and where their name is in its line
+fn sender_of(event: &ChatEvent, line: &str) -> Option<(NodeId, Range<usize>)> {
+    let (from, name) = match event {
+        ChatEvent::Message { from, name, .. }
+        | ChatEvent::History { from, name, .. }
+        | ChatEvent::DirectMessage { from, name, .. } => (from, name),
+        _ => return None,
+    };
+    let start = line.find(&format!(" {}", name))? + 1;
+    Some((*from, start..start + name.len()))
+}
+
+// Where the message text starts in the line an event shows, for the events whose text is
+// Markdown: chat messages and DMs, but not code snippets
+fn markdown_at(event: &ChatEvent, line: &str) -> Option<usize> {
+    let text = match event {
+        ChatEvent::Message { text, .. } | ChatEvent::History { text, deleted: false, .. } => text,
+        ChatEvent::DirectMessage { text, .. } => text,
+        _ => return None,
+    };
+    if unfence(text).is_some() {
+        return None;
+    }
+    let (shown, line) = match event {
+        ChatEvent::DirectMessage { .. } => (text.clone(), line),
+        ChatEvent::History { edited, .. } => (body(text), line.strip_suffix(edited_marker(*edited)).unwrap_or(line)),
+        _ => (body(text), line),
+    };
+    line.ends_with(&shown).then(|| line.len() - shown.len())
+}
+
+// A line with the Markdown of the message text from `at` on rendered, as far as its first line
+// goes, and where that left emphasis
+fn marked_down(line: &str, at: usize) -> (String, Vec<(Range<usize>, Emphasis)>) {
+    let (head, rest) = line.split_at(at);
+    let (text, tail) = rest.split_at(rest.find('\n').unwrap_or(rest.len()));
+    let (text, emphasis) = markdown::render(text);
+    let emphasis = emphasis.into_iter().map(|(range, emphasis)| (range.start + at..range.end + at, emphasis));
+    (format!("{}{}{}", head, text, tail), emphasis.collect())
+}
+
+// Where a line @mentions us
+fn our_mentions(line: &str, is_us: &dyn Fn(&str) -> bool) -> Vec<Range<usize>> {
+    mentions(line).into_iter().filter(|range| is_us(&line[range.start + 1..range.end])).collect()
+}
+
+// A line cut into pieces at the ranges to style, the rest left plain. Ranges overlapping an
+// earlier one are left out.
+fn pieces<S>(line: &str, mut ranges: Vec<(Range<usize>, S)>) -> Vec<(&str, Option<S>)> {
+    ranges.sort_by_key(|(range, _)| range.start);
+    let (mut pieces, mut at) = (Vec::new(), 0);
+    for (range, style) in ranges {
+        if range.start < at || range.end > line.len() {
+            continue;
+        }
+        if range.start > at {
+            pieces.push((&line[at..range.start], None));
+        }
+        pieces.push((&line[range.clone()], Some(style)));
+        at = range.end;
+    }
+    if at < line.len() {
+        pieces.push((&line[at..], None));
+    }
+    pieces
+}
+
+// How the full-screen UI colors each piece of a code line
+fn style(kind: Kind) -> Style {
+    match kind {
+        Kind::Plain => Style::default(),
+        Kind::Keyword => Style::default().fg(Color::Magenta),
+        Kind::Str => Style::default().fg(Color::Green),
+        Kind::Number => Style::default().fg(Color::Yellow),
+        Kind::Comment => Style::default().fg(Color::DarkGray),
+    }
+}
+
+// Messages fetched from a peer rather than remembered, and which of them we asked for after lagging
+fn history_marker(remote: bool, recovered: bool) -> &'static str {
+    match (remote, recovered) {
+        (_, true) => "[recovered] ",
+        (true, false) => "[history] ",
+        (false, false) => "",
+    }
+}
+
+fn edited_marker(edited: bool) -> &'static str {
+    if edited { " (edited)" } else { "" }
+}
+
+// What the plain frontend keeps between the lines it prints
+struct Printer {
+    client: ChatClient,
+    screen: Screen,
+    graphics: Option<Graphics>,
+    // Colors only go to a terminal
+    color: bool,
+    options: Options,
+    run: Run,
+}
+
+impl Printer {
+    fn line(&mut self, line: String) {
+        self.run.last = None;
+        self.screen.println(&if self.options.accessible { labeled(&line, "") } else { line });
+    }
+
+    async fn event(&mut self, event: ChatEvent) {
+        let (client, screen, options) = (&self.client, &self.screen, &self.options);
+        // Plain lines can't tell whether the terminal is in front, so they always notify
+        let dnd = client.dnd();
+        if options.notify && !dnd && let Some((title, text)) = notify::worth(client, &event) {
+            notify::show(title, text);
+        }
+        if dnd && noise(&event) {
+            return;
+        }
+        let event = if options.emoji { event } else { shortcoded(event) };
+        // No grouping, day lines, colors or previews, just one sentence after another
+        if options.accessible {
+            let active = client.active_room().await;
+            if let Some(line) = spoken(&event, active, client.node_id(), options.clock.as_ref()) {
+                screen.println(&line);
+            }
+            for line in missed(client, &event) {
+                screen.println(&labeled(&line, ""));
+            }
+            return;
+        }
+        if let Some(line) = render(&event, client.active_room().await) {
+            let (mut line, stamp) = stamped(options.clock.as_ref(), &event, line);
+            let utc = options.clock.as_ref().is_some_and(|clock| clock.utc);
+            if let Some(day) = self.run.new_day(&event, utc) {
+                screen.println(&day);
+            }
+            let quote = render_quote(&event);
+            let follows = self.run.follows(&event) && quote.is_none();
+            if let Some(quote) = quote {
+                screen.println(&quote);
+            }
+            let mut sender = sender_of(&event, &line);
+            let message = sender.is_some();
+            let own = sender.as_ref().is_some_and(|(from, _)| *from == client.node_id());
+            let shorter = sender.as_ref().filter(|_| follows).and_then(|(_, name)| grouped(&line, name.clone()));
+            if let Some(shorter) = shorter {
+                (line, sender) = (shorter, None);
+            }
+            if self.color {
+                line = self.colored(&event, line, sender, stamp, message, own);
+            }
+            if let Some(lang) = code_of(&event).filter(|_| self.color) {
+                line = paint(lang, &line);
+            }
+            screen.println(&line);
+        }
+        for line in missed(client, &event) {
+            screen.println(&line);
+        }
+        if let ChatEvent::FileOffered { file, size, id, .. } = &event
+            && let Some(graphics) = self.graphics.filter(|graphics| graphics.shows(file))
+            && *size <= PREVIEW_LIMIT
+        {
+            preview(client, screen, graphics, *id);
+        }
+    }
+
+    // A rendered line in the theme's colors, with the sender's name, our mentions and emphasis
+    // picked out. `message` and `own` say whether it is a chat message, and one of ours.
+    fn colored(
+        &self,
+        event: &ChatEvent,
+        mut line: String,
+        sender: Option<(NodeId, Range<usize>)>,
+        stamp: usize,
+        message: bool,
+        own: bool,
+    ) -> String {
+        let theme = &self.options.theme;
+        let mut emphasis = Vec::new();
+        if self.options.markdown && let Some(at) = markdown_at(event, &line) {
+            (line, emphasis) = marked_down(&line, at);
+        }
+        let base = theme.line(&line, message, own).map(theme::sgr);
+        let name = sender.map(|(from, name)| (name, name_color(&from).0.to_string()));
+        let mut ranges: Vec<_> = name.into_iter().collect();
+        if let Some(color) = theme.timestamp.filter(|_| stamp > 0) {
+            ranges.push((0..stamp, theme::sgr(color)));
+        }
+        // Code snippets have lines below, which are highlighted instead
+        let (first, below) = line.split_at(line.find('\n').unwrap_or(line.len()));
+        let is_us = |mention: &str| self.client.is_us(mention);
+        let mentions = our_mentions(first, &is_us).into_iter();
+        ranges.extend(mentions.map(|at| (at, theme.mention_sgr())));
+        // Emphasis goes on top of the line's own color
+        let on_base = |emphasis: Emphasis| match &base {
+            Some(base) => format!("{};{}", base, emphasis.sgr()),
+            None => emphasis.sgr().to_string(),
+        };
+        ranges.extend(emphasis.into_iter().map(|(range, emphasis)| (range, on_base(emphasis))));
+        let mut painted: String = pieces(first, ranges)
+            .into_iter()
+            .map(|(piece, sgr)| match sgr.or_else(|| base.clone()) {
+                Some(sgr) => format!("\x1b[{}m{}\x1b[0m", sgr, piece),
+                None => piece.to_string(),
+            })
+            .collect();
+        painted.push_str(below);
+        painted
+    }
+}
+
+// Line-based frontend: print output as it arrives and read messages from stdin
+pub async fn run_plain(
+    client: &ChatClient,
+    output: Output,
+    mut lines: mpsc::UnboundedReceiver<String>,
+    mut events: impl Stream<Item = ChatEvent> + Send + Unpin + 'static,
+    options: Options,
+) -> Result<()> {
+    let screen = Screen::default();
+    let (color, accessible) = (options.color && std::io::stdout().is_terminal(), options.accessible);
+    let mut printer = Printer {
+        client: client.clone(),
+        screen: screen.clone(),
+        graphics: Graphics::detect(),
+        color,
+        options,
+        run: Run::default(),
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(line) = lines.recv() => printer.line(line),
+                Some(event) = events.next() => printer.event(event).await,
+                else => break,
+            }
+        }
+    });
+    output.line("> Type messages and press enter to send...");
+    // On a terminal lines are edited here, in raw mode; piped in they are read as they come, and so
+    // are lines typed for a screen reader, which follows the terminal's own line editing best
+    if !accessible && std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+        crossterm::terminal::enable_raw_mode()?;
+        screen.start();
+        let edited = read_edited(client, &output, &screen).await;
+        screen.stop();
+        crossterm::terminal::disable_raw_mode()?;
+        return edited;
+    }
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = stdin.next_line() => match line? {
+                Some(line) => {
+                    if !crate::handle_line(client, &output, line).await {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            // Ctrl-C ends the session like end of input does, so we still leave properly
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    Ok(())
+}
+
+async fn read_edited(client: &ChatClient, output: &Output, screen: &Screen) -> Result<()> {
+    let mut keys = EventStream::new();
+    loop {
+        // Raw mode turns Ctrl-C into a key, but a SIGINT from elsewhere still ends the session
+        let event = tokio::select! {
+            event = keys.next() => event,
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        let Some(event) = event else {
+            break;
+        };
+        let Event::Key(key @ KeyEvent { kind: KeyEventKind::Press, .. }) = event? else {
+            continue;
+        };
+        // Only Tab needs who is online, to complete @names
+        let names = match key.code {
+            KeyCode::Tab => client.who().await.unwrap_or_default().into_iter().map(|peer| peer.name).collect(),
+            _ => Vec::new(),
+        };
+        let quit = match screen.key(key, &names) {
+            Some(Input::Line(line)) => !crate::handle_line(client, output, line).await,
+            Some(Input::Choices(choices)) => {
+                screen.println(&format!("> {}", choices.join("  ")));
+                false
+            }
+            Some(Input::Quit) => true,
+            None => false,
+        };
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Fetch an offered image and draw it below the lines printed so far, in the background
+fn preview(client: &ChatClient, screen: &Screen, graphics: Graphics, id: usize) {
+    let (client, screen) = (client.clone(), screen.clone());
+    tokio::spawn(async move {
+        match client.preview_file(id).await {
+            Ok(image) => screen.println(&graphics.encode(&image)),
+            Err(err) => screen.println(&format!("> Error: could not show image #{}: {}", id, err)),
+        }
+    });
+}
+
+// "general │ 3 online │ you 3f2a1b9c0d │ 2 direct, 1 relayed │ 4 unread elsewhere"
+async fn status(room: Option<String>, online: usize, client: &ChatClient, unread: usize) -> String {
+    let you = format!("you {}", client.node_id().fmt_short());
+    let mut parts = match room {
+        Some(room) => {
+            let links = match client.links().await {
+                Links { direct: 0, relayed: 0 } => "no neighbors".to_string(),
+                Links { direct, relayed } => format!("{} direct, {} relayed", direct, relayed),
+            };
+            vec![room, format!("{} online", online), you, links]
+        }
+        None => vec!["No room".to_string(), you],
+    };
+    if unread > 0 {
+        parts.push(format!("{} unread elsewhere", unread));
+    }
+    format!(" {} ", parts.join(" │ "))
+}
+
+// The room's name and topic if its owner set them, its short topic id if not
+fn room_title(topic_id: &TopicId, info: Option<RoomInfo>, can_post: bool) -> String {
+    let (name, topic) = info.map(|info| (info.name, info.topic)).unwrap_or_default();
+    let mut name = match name.is_empty() {
+        true => format!("Room {}", topic_id.fmt_short()),
+        false => name,
+    };
+    if !can_post {
+        name = format!("{} (read-only)", name);
+    }
+    match topic.is_empty() {
+        true => format!(" {} ", name),
+        false => format!(" {} - {} ", name, topic),
+    }
+}
+
+// Full-screen frontend: message pane, input line and a peer sidebar
+pub async fn run_tui(
+    client: &ChatClient,
+    output: Output,
+    lines: mpsc::UnboundedReceiver<String>,
+    events: impl Stream<Item = ChatEvent> + Unpin,
+    options: Options,
+) -> Result<()> {
+    let mut terminal = ratatui::init();
+    // Terminals that report focus tell us when notifications are needed
+    let _ = crossterm::execute!(std::io::stdout(), EnableFocusChange);
+    let result = tui_loop(&mut terminal, client, output, lines, events, options).await;
+    let _ = crossterm::execute!(std::io::stdout(), DisableFocusChange);
+    ratatui::restore();
+    result
+}
+
+async fn tui_loop(
+    terminal: &mut DefaultTerminal,
+    client: &ChatClient,
+    output: Output,
+    mut lines: mpsc::UnboundedReceiver<String>,
+    mut chat: impl Stream<Item = ChatEvent> + Unpin,
+    options: Options,
+) -> Result<()> {
+    let us = client.clone();
+    let is_us: IsUs = Box::new(move |mention| us.is_us(mention));
+    let (color, theme, clock, us) = (options.color, options.theme, options.clock.clone(), Some(client.node_id()));
+    let (markdown, keymap) = (options.markdown, options.keymap.clone());
+    let is_us = Some(is_us);
+    let mut app = App { color, theme, clock, us, markdown, keymap, focused: true, is_us, ..Default::default() };
+    let mut events = EventStream::new();
+    // Redraw now and then so quiet peers drop out of the sidebar and typing notices expire
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    output.line(match app.keymap {
+        Some(_) => "> Type messages and press enter to send, Tab to complete, Esc for normal mode",
+        None => "> Type messages and press enter to send, Tab to complete, Esc to quit, PageUp/PageDown to scroll",
+    });
+    while !app.quit {
+        if let Some(topic_id) = app.focusing.take() {
+            let _ = client.switch(&topic_id.to_string()).await;
+        }
+        let active = client.active_room().await;
+        app.switch(active);
+        // The sidebar lists who is online in the active room
+        let online = client.who().await.unwrap_or_default();
+        let peers: Vec<String> = online
+            .iter()
+            .map(|peer| format!("{}{} ({})", peer.name, presence(&peer.status), peer.node_id.fmt_short()))
+            .collect();
+        // Above it, the rooms we are in and how much we missed in the others
+        let joined = client.rooms().await;
+        let place = joined.iter().position(|(_, active)| *active);
+        app.room = (place.unwrap_or_default(), joined.len());
+        app.prune(&joined);
+        let titles = match app.tiles.is_empty() {
+            true => vec![tile_title(client, None, active)],
+            false => app.tiles.iter().map(|(tile, _)| tile_title(client, Some(*tile), active)).collect(),
+        };
+        let (rooms, here, elsewhere) = room_list(client, joined);
+        app.status = status(here, online.len(), client, elsewhere).await;
+        app.names = online.into_iter().map(|peer| peer.name).collect();
+        let typing = app.typing_line(active);
+        // Lines typed while writing a snippet go into it instead of out
+        let draft = client.code_draft().await;
+        app.writing_code = draft.is_some();
+        let prompt = match draft {
+            Some((lang, lines)) => format!(" {} code, {} lines - /end sends it, /cancel drops it ", lang, lines),
+            None => " Message ".to_string(),
+        };
+        let prompt = match app.normal {
+            true => " -- NORMAL -- ".to_string(),
+            false => prompt,
+        };
+        terminal.draw(|frame| app.draw(frame, &titles, &prompt, &rooms, &peers, &typing))?;
+
+        tokio::select! {
+            _ = tick.tick() => {}
+            Some(line) = lines.recv() => app.on_line(line),
+            Some(event) = chat.next() => {
+                let dnd = client.dnd();
+                if options.notify
+                    && !app.focused
+                    && !dnd
+                    && let Some((title, text)) = notify::worth(client, &event)
+                {
+                    notify::show(title, text);
+                }
+                let missed = missed(client, &event);
+                if !(dnd && noise(&event)) {
+                    app.on_chat(if options.emoji { event } else { shortcoded(event) });
+                }
+                for line in missed {
+                    app.push(None, Entry::new(None, line));
+                }
+            }
+            Some(event) = events.next() => {
+                if let Some(text) = app.on_event(event?) {
+                    let split = text.strip_prefix("/split").filter(|rest| rest.is_empty() || rest.starts_with(' '));
+                    match split.filter(|_| !app.writing_code) {
+                        Some(arg) => {
+                            let line = split_command(&mut app, client, arg.trim()).await;
+                            app.push(None, Entry::new(None, line.unwrap_or_else(|err| format!("> Error: {}", err))));
+                        }
+                        None => app.quit = !crate::handle_line(client, &output, text).await,
+                    }
+                }
+                if std::mem::take(&mut app.composing) {
+                    // Not being in a room is the only error, and then nobody needs to know
+                    let _ = client.typing().await;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The sidebar's lines for the rooms we are in, the active room's name and how many messages we
+// missed in the others
+fn room_list(client: &ChatClient, joined: Vec<(TopicId, bool)>) -> (Vec<String>, Option<String>, usize) {
+    let (mut rooms, mut here, mut elsewhere) = (Vec::new(), None, 0);
+    for (topic_id, active) in joined {
+        let name = match client.room_info(&topic_id) {
+            Some(info) if !info.name.is_empty() => info.name,
+            _ => topic_id.fmt_short(),
+        };
+        rooms.push(match client.unread(&topic_id).unwrap_or_default() {
+            _ if active => format!("▸ {}", name),
+            0 => format!("  {}", name),
+            unread => {
+                elsewhere += unread;
+                format!("  {} ({} unread)", name, unread)
+            }
+        });
+        if active {
+            here = Some(name);
+        }
+    }
+    (rooms, here, elsewhere)
+}
+
+// The title of a room's tile, with what we missed there unless it is the active room, or of the
+// DMs' tile. None is the active room shown alone.
+fn tile_title(client: &ChatClient, tile: Option<Tile>, active: Option<TopicId>) -> String {
+    let topic_id = match tile {
+        Some(Tile::Direct) => return " Direct messages ".to_string(),
+        Some(Tile::Room(topic_id)) => topic_id,
+        None => match active {
+            Some(topic_id) => topic_id,
+            None => return " No room ".to_string(),
+        },
+    };
+    let title = room_title(&topic_id, client.room_info(&topic_id), client.can_post(&topic_id));
+    match client.unread(&topic_id).unwrap_or_default() {
+        unread if unread > 0 && Some(topic_id) != active => format!("{}({} unread) ", title, unread),
+        _ => title,
+    }
+}
+
+// /split, which only the full-screen UI knows: another room, or the DMs, beside the active room
+async fn split_command(app: &mut App, client: &ChatClient, arg: &str) -> Result<String> {
+    let Some(active) = app.active else {
+        bail!("not in a room");
+    };
+    let tile = match arg {
+        "" => bail!("usage: /split <room> | dm | off"),
+        "off" => {
+            app.unsplit();
+            return Ok("> Showing the active room alone".to_string());
+        }
+        "dm" => Tile::Direct,
+        room => Tile::Room(client.find_room(room).await?),
+    };
+    if app.tiles.is_empty() {
+        app.tiles.push((Tile::Room(active), 0));
+    }
+    if app.tiles.iter().any(|(shown, _)| *shown == tile) {
+        bail!("that is already on screen");
+    }
+    if app.tiles.len() >= MAX_TILES {
+        bail!("only {} fit side by side", MAX_TILES);
+    }
+    app.tiles.push((tile, 0));
+    Ok(match tile {
+        Tile::Direct => "> Showing direct messages beside the room, Ctrl-W moves between them".to_string(),
+        Tile::Room(topic_id) => format!("> Showing room {} beside, Ctrl-W moves between them", topic_id.fmt_short()),
+    })
+}
+
+// What a tile of the split view shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tile {
+    Room(TopicId),
+    Direct,
+}
+
+// A line in the message pane, with the id of the message it shows so later events can update it
+struct Entry {
+    id: Option<MessageId>,
+    text: String,
+    // Where the message text starts in `text`, so an edit can replace it
+    body: usize,
+    edited: bool,
+    // ✓ once one of our messages reached someone, ✓✓ and who once it was read
+    receipt: String,
+    // "👍 2" and so on, shown on a line of their own below the message
+    reactions: Vec<String>,
+    // The start of the message this one replies to, shown above it
+    quote: Option<String>,
+    // The file offer the line shows and how far fetching it got, shown after it
+    offer: Option<usize>,
+    progress: String,
+    // The language of the code snippet the message is, to highlight the lines below it
+    code: Option<String>,
+    // Where the sender's name is in `text`, and the color it is shown in
+    sender: Option<(Range<usize>, Color)>,
+    // Whether we sent the message
+    own: bool,
+    // How long the time in front of `text` is
+    stamp: usize,
+    // Whether the message text from `body` on is rendered as Markdown
+    markdown: bool,
+    // Whether the sender's name was left out, for following their message above
+    grouped: bool,
+}
+
+impl Entry {
+    fn new(id: Option<MessageId>, text: String) -> Self {
+        Self {
+            id,
+            body: text.len(),
+            text,
+            edited: false,
+            receipt: String::new(),
+            reactions: Vec::new(),
+            quote: None,
+            offer: None,
+            progress: String::new(),
+            code: None,
+            sender: None,
+            own: false,
+            stamp: 0,
+            markdown: false,
+            grouped: false,
+        }
+    }
+
+    // A chat message, whose rendered line ends with `body`
+    fn message(id: MessageId, text: String, body: &str, edited: bool) -> Self {
+        let mut entry = Self::new(Some(id), text);
+        entry.body = entry.text.len() - body.len();
+        entry.edited = edited;
+        entry
+    }
+
+    fn edit(&mut self, text: &str) {
+        self.text.truncate(self.body);
+        self.text.push_str(&body(text));
+        self.edited = true;
+        self.code = unfence(text).map(|(lang, _)| lang.to_string());
+    }
+
+    fn delete(&mut self) {
+        self.text.truncate(self.body);
+        self.text.push_str(TOMBSTONE);
+        self.edited = false;
+        self.reactions.clear();
+        self.code = None;
+        self.markdown = false;
+    }
+
+    fn lines(&self, color: bool, theme: &Theme, is_us: &dyn Fn(&str) -> bool) -> Vec<Line<'_>> {
+        let edited = edited_marker(self.edited);
+        let mut lines: Vec<_> = self.quote.iter().map(|quote| Line::raw(quote.as_str())).collect();
+        // Markers go on the first line, code snippets have more below it
+        let (first, below) = match self.text.split_once('\n') {
+            Some((first, below)) => (first, Some(below)),
+            None => (self.text.as_str(), None),
+        };
+        let (first, emphasis) = match self.markdown && color && self.body <= first.len() {
+            true => marked_down(first, self.body),
+            false => (first.to_string(), Vec::new()),
+        };
+        let first = first.as_str();
+        let sender = self.sender.iter().map(|(name, fg)| (name.clone(), Style::default().fg(*fg)));
+        let mut ranges: Vec<_> = sender.collect();
+        if let Some(color) = theme.timestamp.filter(|_| self.stamp > 0) {
+            ranges.push((0..self.stamp, Style::default().fg(color)));
+        }
+        ranges.extend(our_mentions(first, is_us).into_iter().map(|at| (at, theme.mention_style())));
+        ranges.extend(emphasis.into_iter().map(|(range, emphasis)| (range, emphasis.style())));
+        let mut spans: Vec<_> = match color {
+            true => pieces(first, ranges)
+                .into_iter()
+                .map(|(piece, style)| Span::styled(piece.to_string(), style.unwrap_or_default()))
+                .collect(),
+            false => vec![Span::raw(first.to_string())],
+        };
+        spans.push(Span::raw(format!("{}{}{}", edited, self.receipt, self.progress)));
+        let base = theme.line(first, self.sender.is_some() || self.grouped, self.own).filter(|_| color);
+        lines.push(Line::from(spans).style(theme::fg(base)));
+        let mut highlighter = self.code.as_deref().filter(|_| color).map(Highlighter::new);
+        for line in below.into_iter().flat_map(|below| below.split('\n')) {
+            lines.push(match (&mut highlighter, line.strip_prefix(GUTTER)) {
+                (Some(highlighter), Some(code)) => {
+                    let pieces = highlighter.tokens(code).into_iter();
+                    let spans = pieces.map(|(kind, piece)| Span::styled(piece, style(kind)));
+                    Line::from([Span::raw(GUTTER)].into_iter().chain(spans).collect::<Vec<_>>())
+                }
+                _ => Line::raw(line),
+            });
+        }
+        if !self.reactions.is_empty() {
+            lines.push(Line::raw(format!("    {}", self.reactions.join("  "))));
+        }
+        lines
+    }
+}
+
+// Tells whether an @mention, without the @, is of us
+type IsUs = Box<dyn Fn(&str) -> bool>;
+
+#[derive(Default)]
+struct App {
+    // The lines of each room; notices and command output go to the room being looked at, or to
+    // None outside of every room
+    panes: HashMap<Option<TopicId>, VecDeque<Entry>>,
+    // Each pane's run of messages, keyed the same way
+    runs: HashMap<Option<TopicId>, Run>,
+    active: Option<TopicId>,
+    input: String,
+    // How many lines we are scrolled up from the bottom of the message pane
+    scroll: usize,
+    // Who is typing and since when, by node
+    typing: HashMap<NodeId, (TopicId, String, Instant)>,
+    // Set when the user edited a message since the last check
+    composing: bool,
+    // Whether lines go into a code snippet, where blank ones count too
+    writing_code: bool,
+    // Whether names and code are shown in color, and in which others
+    color: bool,
+    theme: Theme,
+    markdown: bool,
+    clock: Option<Clock>,
+    // Our node id, to tell our own messages
+    us: Option<NodeId>,
+    // Whether the terminal is in front, as far as it tells us
+    focused: bool,
+    // Whether an @mention is of us, to highlight it
+    is_us: Option<IsUs>,
+    // The names of who is online in the active room, for completing @mentions
+    names: Vec<String>,
+    // The line along the bottom of the screen
+    status: String,
+    // With modal keys, whether we are in normal mode and the start of a binding typed there
+    keymap: Option<Keymap>,
+    normal: bool,
+    pending: Vec<KeyEvent>,
+    // Where the active room is among the joined ones and how many there are, for moving between them
+    room: (usize, usize),
+    // How many lines of messages fit in the pane
+    height: usize,
+    // What /split shows side by side, each with how far it is scrolled up while another has focus.
+    // Empty shows the active room alone, and otherwise one of them is the active room.
+    tiles: Vec<(Tile, usize)>,
+    // The tile the scroll keys move, which is the active room's unless it is the DMs'
+    focus: usize,
+    // Direct messages sent and received while they have a tile
+    direct: VecDeque<Entry>,
+    // A room to make active, for giving its tile focus
+    focusing: Option<TopicId>,
+    quit: bool,
+}
+
+impl App {
+    // The line showing message `id`, if it is still in some room's scrollback
+    fn entry(&mut self, id: &MessageId) -> Option<&mut Entry> {
+        self.panes.values_mut().find_map(|pane| pane.iter_mut().rev().find(|e| e.id == Some(*id)))
+    }
+
+    fn push(&mut self, room: Option<TopicId>, entry: Entry) {
+        // Only messages keep a run of them going
+        if entry.id.is_none() {
+            self.runs.entry(room.or(self.active)).or_default().last = None;
+        }
+        let pane = self.panes.entry(room.or(self.active)).or_default();
+        pane.push_back(entry);
+        if pane.len() > SCROLLBACK {
+            pane.pop_front();
+        }
+    }
+
+    fn push_direct(&mut self, entry: Entry) {
+        self.direct.push_back(entry);
+        if self.direct.len() > SCROLLBACK {
+            self.direct.pop_front();
+        }
+    }
+
+    fn direct_tiled(&self) -> bool {
+        self.tiles.iter().any(|(tile, _)| *tile == Tile::Direct)
+    }
+
+    // A line of output. Those about DMs we sent go with the DMs when those have a tile.
+    fn on_line(&mut self, line: String) {
+        match line.starts_with("[dm to ") && self.direct_tiled() {
+            true => self.push_direct(Entry::new(None, line)),
+            false => self.push(None, Entry::new(None, line)),
+        }
+    }
+
+    // Look at another room, from its latest lines, or from where its tile was scrolled to. A room
+    // without a tile takes over the one of the room that was active.
+    fn switch(&mut self, active: Option<TopicId>) {
+        if active == self.active {
+            return;
+        }
+        let before = std::mem::replace(&mut self.active, active);
+        let Some(topic_id) = active.filter(|_| !self.tiles.is_empty()) else {
+            self.scroll = 0;
+            return;
+        };
+        self.tiles[self.focus].1 = self.scroll;
+        let at = match self.tile_of(topic_id) {
+            Some(at) => at,
+            None => {
+                let at = before.and_then(|before| self.tile_of(before)).unwrap_or(self.focus);
+                self.tiles[at] = (Tile::Room(topic_id), 0);
+                at
+            }
+        };
+        self.focus = at;
+        self.scroll = self.tiles[at].1;
+    }
+
+    fn tile_of(&self, topic_id: TopicId) -> Option<usize> {
+        self.tiles.iter().position(|(tile, _)| *tile == Tile::Room(topic_id))
+    }
+
+    // Focus on the next tile; a room's takes the active room along
+    fn cycle(&mut self) {
+        if self.tiles.len() < 2 {
+            return;
+        }
+        let next = (self.focus + 1) % self.tiles.len();
+        match self.tiles[next].0 {
+            Tile::Room(topic_id) if Some(topic_id) != self.active => self.focusing = Some(topic_id),
+            _ => {
+                self.tiles[self.focus].1 = self.scroll;
+                self.focus = next;
+                self.scroll = self.tiles[next].1;
+            }
+        }
+    }
+
+    // The tiles of rooms we left go, and the split with them once one is left
+    fn prune(&mut self, joined: &[(TopicId, bool)]) {
+        let gone = |tile: &Tile| matches!(tile, Tile::Room(topic_id) if !joined.iter().any(|(t, _)| t == topic_id));
+        if !self.tiles.iter().any(|(tile, _)| gone(tile)) {
+            return;
+        }
+        self.tiles.retain(|(tile, _)| !gone(tile));
+        self.focus = self.active.and_then(|topic_id| self.tile_of(topic_id)).unwrap_or_default();
+        self.scroll = 0;
+        if self.tiles.len() < 2 {
+            self.unsplit();
+        }
+    }
+
+    fn unsplit(&mut self) {
+        self.tiles.clear();
+        self.focus = 0;
+        self.scroll = 0;
+    }
+
+    fn on_chat(&mut self, event: ChatEvent) {
+        match &event {
+            ChatEvent::Typing { room, from, name } => {
+                self.typing.insert(*from, (*room, name.clone(), Instant::now()));
+            }
+            ChatEvent::Receipt { id, delivered, read, .. } => {
+                if let Some(entry) = self.entry(id) {
+                    entry.receipt = match read.is_empty() {
+                        true if delivered.is_empty() => String::new(),
+                        true => " ✓".to_string(),
+                        false => format!(" ✓✓ {}", read.join(", ")),
+                    };
+                }
+            }
+            // Shown under the message instead of as a line of its own
+            ChatEvent::Reaction { id, counts, .. } => {
+                if let Some(entry) = self.entry(id) {
+                    entry.reactions = counts.iter().map(|(emoji, n)| format!("{} {}", emoji, n)).collect();
+                }
+                return;
+            }
+            // Shown next to the offer, or as a line of its own if the offer is not shown
+            ChatEvent::FileProgress { id, done, size } => {
+                let mut entries = self.panes.values_mut().flat_map(|pane| pane.iter_mut().rev());
+                let offer = entries.find(|e| e.offer == Some(*id));
+                if let Some(entry) = offer {
+                    entry.progress = format!("  {}", progress(*done, *size));
+                    return;
+                }
+            }
+            ChatEvent::Edited { id, text, .. } => {
+                let markdown = self.markdown;
+                if let Some(entry) = self.entry(id) {
+                    entry.edit(text);
+                    entry.markdown = markdown && unfence(text).is_none();
+                }
+                return;
+            }
+            ChatEvent::Deleted { id, .. } => {
+                if let Some(entry) = self.entry(id) {
+                    entry.delete();
+                }
+                return;
+            }
+            ChatEvent::Message { from, .. } | ChatEvent::Left { from, .. } => {
+                self.typing.remove(from);
+            }
+            _ => {}
+        }
+        // Each room has a pane of its own, so its lines need no room in front
+        let room = event.room();
+        let Some(line) = render(&event, room) else {
+            return;
+        };
+        let (mut line, stamp) = stamped(self.clock.as_ref(), &event, line);
+        let utc = self.clock.as_ref().is_some_and(|clock| clock.utc);
+        if let Some(day) = self.runs.entry(room.or(self.active)).or_default().new_day(&event, utc) {
+            self.push(room, Entry::new(None, day));
+        }
+        let quote = render_quote(&event);
+        let follows = self.runs.entry(room.or(self.active)).or_default().follows(&event) && quote.is_none();
+        let mut sender = sender_of(&event, &line);
+        let own = sender.as_ref().is_some_and(|(from, _)| Some(*from) == self.us);
+        let shorter = sender.as_ref().filter(|_| follows).and_then(|(_, name)| grouped(&line, name.clone()));
+        let grouped = shorter.is_some();
+        if let Some(shorter) = shorter {
+            (line, sender) = (shorter, None);
+        }
+        let mut entry = match &event {
+            ChatEvent::Message { id, text, .. } => Entry::message(*id, line, &body(text), false),
+            // The entry adds the edited marker itself
+            ChatEvent::History { id, deleted: true, .. } => Entry::message(*id, line, TOMBSTONE, false),
+            ChatEvent::History { id, text, edited, .. } => {
+                let line = line.strip_suffix(edited_marker(*edited)).unwrap_or(&line).to_string();
+                Entry::message(*id, line, &body(text), *edited)
+            }
+            _ => Entry::new(None, line),
+        };
+        entry.quote = quote;
+        entry.code = code_of(&event).map(str::to_string);
+        entry.own = own;
+        entry.sender = sender.map(|(from, name)| (name, name_color(&from).1));
+        entry.grouped = grouped;
+        entry.stamp = stamp;
+        if self.markdown && let Some(at) = markdown_at(&event, &entry.text) {
+            (entry.markdown, entry.body) = (true, at);
+        }
+        if let ChatEvent::FileOffered { id, .. } | ChatEvent::DirectoryOffered { id, .. } = &event {
+            entry.offer = Some(*id);
+        }
+        match matches!(event, ChatEvent::DirectMessage { .. }) && self.direct_tiled() {
+            true => self.push_direct(entry),
+            false => self.push(room, entry),
+        }
+    }
+
+    // "alice is typing…" for the active room, if anyone is
+    fn typing_line(&mut self, active: Option<TopicId>) -> String {
+        self.typing.retain(|_, (_, _, at)| at.elapsed() < TYPING_TIMEOUT);
+        let mut names: Vec<&str> = self
+            .typing
+            .values()
+            .filter(|(room, _, _)| Some(*room) == active)
+            .map(|(_, name, _)| name.as_str())
+            .collect();
+        names.sort();
+        match names.as_slice() {
+            [] => String::new(),
+            [name] => format!(" {} is typing… ", name),
+            _ => format!(" {} are typing… ", names.join(", ")),
+        }
+    }
+
+    // Returns a line to send once the user presses enter
+    fn on_event(&mut self, event: Event) -> Option<String> {
+        match event {
+            Event::FocusGained => self.focused = true,
+            Event::FocusLost => self.focused = false,
+            _ => {}
+        }
+        let Event::Key(KeyEvent { code, modifiers, kind: KeyEventKind::Press, .. }) = event else {
+            return None;
+        };
+        let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+        match code {
+            // With modal keys Esc stops typing instead, and drops half a binding in normal mode
+            KeyCode::Esc if self.keymap.is_some() => {
+                self.normal = true;
+                self.pending.clear();
+            }
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if ctrl => self.quit = true,
+            KeyCode::Char('w') if ctrl => self.cycle(),
+            KeyCode::Up => self.scroll += 1,
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ if self.normal => return self.on_normal(KeyEvent::new(code, modifiers)),
+            KeyCode::Char('d') if ctrl && self.input.is_empty() => self.quit = true,
+            KeyCode::Char(c) if !ctrl => {
+                self.input.push(c);
+                self.composing = !self.input.starts_with('/');
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            // Completing as far as it goes, or listing the choices when it can't go further
+            KeyCode::Tab => {
+                let (input, choices) = complete::complete(&self.input, &self.names);
+                if input == self.input && !choices.is_empty() {
+                    self.push(None, Entry::new(None, format!("> {}", choices.join("  "))));
+                    self.scroll = 0;
+                }
+                self.input = input;
+            }
+            KeyCode::Enter if self.writing_code || !self.input.trim().is_empty() => {
+                self.scroll = 0;
+                return Some(std::mem::take(&mut self.input));
+            }
+            _ => {}
+        }
+        None
+    }
+
+    // A key in normal mode, which may finish a binding. Moving between rooms goes through /switch
+    // like typing it would.
+    fn on_normal(&mut self, key: KeyEvent) -> Option<String> {
+        let keymap = self.keymap.as_ref()?;
+        let Lookup::Action(action) = keymap.lookup(&mut self.pending, key) else {
+            return None;
+        };
+        let half = (self.height / 2).max(1);
+        let (room, rooms) = self.room;
+        match action {
+            Action::Down => self.scroll = self.scroll.saturating_sub(1),
+            Action::Up => self.scroll += 1,
+            Action::HalfPageDown => self.scroll = self.scroll.saturating_sub(half),
+            Action::HalfPageUp => self.scroll += half,
+            // Drawing stops it at the first line
+            Action::Top => self.scroll = usize::MAX / 2,
+            Action::Bottom => self.scroll = 0,
+            Action::Search => (self.input, self.normal) = ("/search ".to_string(), false),
+            Action::Command => (self.input, self.normal) = ("/".to_string(), false),
+            Action::Insert => self.normal = false,
+            Action::NextRoom if rooms > 1 => return Some(format!("/switch {}", (room + 1) % rooms + 1)),
+            Action::PreviousRoom if rooms > 1 => return Some(format!("/switch {}", (room + rooms - 1) % rooms + 1)),
+            Action::NextRoom | Action::PreviousRoom => {}
+            Action::Quit => self.quit = true,
+        }
+        None
+    }
+
+    fn draw(
+        &mut self,
+        frame: &mut Frame,
+        titles: &[String],
+        prompt: &str,
+        rooms: &[String],
+        peers: &[String],
+        typing: &str,
+    ) {
+        let [body, status] = Layout::vertical([Constraint::Min(6), Constraint::Length(1)]).areas(frame.area());
+        let [main, sidebar] = Layout::horizontal([Constraint::Min(20), Constraint::Length(28)]).areas(body);
+        let [pane, input] = Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(main);
+        let [joined, online] =
+            Layout::vertical([Constraint::Length(rooms.len() as u16 + 2), Constraint::Min(3)]).areas(sidebar);
+
+        self.draw_tiles(frame, pane, titles);
+
+        // Keep the end of long input visible
+        let width = Line::raw(self.input.as_str()).width() as u16;
+        let visible = input.width.saturating_sub(3);
+        let offset = width.saturating_sub(visible);
+        let prompt = Paragraph::new(self.input.as_str())
+            .scroll((0, offset))
+            .block(Block::bordered().title(prompt).title_bottom(typing));
+        frame.render_widget(prompt, input);
+        frame.set_cursor_position((input.x + 1 + width - offset, input.y + 1));
+
+        let rooms = List::new(rooms.iter().map(String::as_str)).block(Block::bordered().title(" Rooms "));
+        frame.render_widget(rooms, joined);
+        let peers = List::new(peers.iter().map(String::as_str))
+            .block(Block::bordered().title(format!(" Online ({}) ", peers.len())));
+        frame.render_widget(peers, online);
+        let style = Style::default().add_modifier(Modifier::REVERSED);
+        frame.render_widget(Paragraph::new(self.status.as_str()).style(style), status);
+    }
+
+    // The active room alone, or the tiles side by side with the one in focus drawn thicker
+    fn draw_tiles(&mut self, frame: &mut Frame, pane: Rect, titles: &[String]) {
+        let tiles: Vec<Option<Tile>> = match self.tiles.is_empty() {
+            true => vec![None],
+            false => self.tiles.iter().map(|(tile, _)| Some(*tile)).collect(),
+        };
+        let areas = Layout::horizontal(vec![Constraint::Fill(1); tiles.len()]).split(pane);
+        let is_us = |mention: &str| self.is_us.as_ref().is_some_and(|is_us| is_us(mention));
+        for (at, ((tile, area), title)) in tiles.iter().zip(areas.iter()).zip(titles).enumerate() {
+            let entries = match tile {
+                Some(Tile::Direct) => Some(&self.direct),
+                Some(Tile::Room(topic_id)) => self.panes.get(&Some(*topic_id)),
+                None => self.panes.get(&self.active),
+            };
+            let entries = entries.into_iter().flatten();
+            let text: Vec<Line> = entries.flat_map(|entry| entry.lines(self.color, &self.theme, &is_us)).collect();
+            let focused = at == self.focus;
+            let border = if focused && tile.is_some() { BorderType::Thick } else { BorderType::Plain };
+            let messages = Paragraph::new(text)
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().border_type(border).title(title.as_str()));
+            // Stick to the bottom unless the user scrolled up
+            let max_scroll = messages.line_count(area.width).saturating_sub(area.height as usize);
+            let scroll = match focused {
+                true => &mut self.scroll,
+                false => &mut self.tiles[at].1,
+            };
+            *scroll = (*scroll).min(max_scroll);
+            let top = (max_scroll - *scroll) as u16;
+            if focused {
+                self.height = area.height.saturating_sub(2) as usize;
+            }
+            frame.render_widget(messages.scroll((top, 0)), *area);
+        }
+    }
+}
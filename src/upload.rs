@@ -0,0 +1,122 @@
+use anyhow::{bail, Result};
+use futures_lite::StreamExt;
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh_blobs::api::Store;
+use iroh_blobs::hashseq::HashSeq;
+use iroh_blobs::protocol::{ChunkRanges, Request};
+use iroh_blobs::provider::CountingReader;
+use iroh_blobs::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+// A cap on bytes per second, shared by everything paced with it. Up to a second's worth goes out at
+// once after a pause; beyond that senders wait their turn.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    per_second: f64,
+    // What may still go out, negative while senders are waiting for the time they took ahead
+    bucket: Arc<Mutex<(f64, Instant)>>,
+}
+
+impl Throttle {
+    pub fn new(per_second: u64) -> Self {
+        let per_second = per_second.max(1) as f64;
+        Self { per_second, bucket: Arc::new(Mutex::new((per_second, Instant::now()))) }
+    }
+
+    // Wait until `bytes` more may go out
+    async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("throttle lock poisoned");
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.per_second).min(self.per_second);
+            *last = now;
+            *tokens -= bytes as f64;
+            Duration::from_secs_f64((-*tokens).max(0.0) / self.per_second)
+        };
+        sleep(wait).await;
+    }
+}
+
+// Serves blobs like iroh-blobs' own provider, but no faster than the throttle lets all uploads
+// together. Gossip has connections of its own, so chat messages never queue behind a file. Only
+// gets are answered, which is all our downloader asks for.
+#[derive(Debug, Clone)]
+pub struct ThrottledBlobs {
+    store: Store,
+    throttle: Throttle,
+}
+
+impl ThrottledBlobs {
+    pub fn new(store: Store, throttle: Throttle) -> Self {
+        Self { store, throttle }
+    }
+
+    async fn handle(&self, conn: Connection) -> Result<()> {
+        // One request per stream, until the fetcher closes the connection
+        while let Ok((send, recv)) = conn.accept_bi().await {
+            let provider = self.clone();
+            // A failed request only concerns the fetcher, which sees its stream end early
+            tokio::spawn(async move { provider.serve(send, recv).await });
+        }
+        Ok(())
+    }
+
+    async fn serve(&self, mut send: SendStream, mut recv: RecvStream) -> Result<()> {
+        let request = Request::read_async(&mut CountingReader::new(&mut recv)).await?;
+        recv.read_to_end(0).await?;
+        match request {
+            Request::Get(request) => {
+                // Collections list their children in a hash sequence, the root blob
+                let mut children = None;
+                for (offset, ranges) in request.ranges.iter_non_empty_infinite() {
+                    let hash = match offset {
+                        0 => request.hash,
+                        _ => {
+                            if children.is_none() {
+                                children = Some(HashSeq::try_from(self.store.get_bytes(request.hash).await?)?);
+                            }
+                            let child = children.as_ref().and_then(|children| children.get(offset as usize - 1));
+                            let Some(hash) = child else {
+                                break;
+                            };
+                            hash
+                        }
+                    };
+                    self.send_blob(&mut send, hash, ranges.clone()).await?;
+                }
+            }
+            Request::GetMany(request) => {
+                for (hash, ranges) in request.hashes.iter().zip(request.ranges.iter_infinite()) {
+                    if !ranges.is_empty() {
+                        self.send_blob(&mut send, *hash, ranges.clone()).await?;
+                    }
+                }
+            }
+            _ => bail!("only get requests are served under an upload limit"),
+        }
+        send.finish()?;
+        Ok(())
+    }
+
+    async fn send_blob(&self, send: &mut SendStream, hash: Hash, ranges: ChunkRanges) -> Result<()> {
+        let mut encoded = self.store.export_bao(hash, ranges).into_byte_stream();
+        while let Some(bytes) = encoded.next().await {
+            let bytes = bytes?;
+            self.throttle.take(bytes.len()).await;
+            send.write_chunk(bytes).await?;
+        }
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for ThrottledBlobs {
+    async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
+        self.handle(conn)
+            .await
+            .map_err(|err| AcceptError::from(Box::<dyn std::error::Error + Send + Sync>::from(err)))
+    }
+}
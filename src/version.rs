@@ -0,0 +1,65 @@
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// The protocol we speak, announced in Hello when joining a room. Bump it whenever peers on the
+// previous version would no longer understand us. Version 3 sends large messages compressed.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+// Optional parts of the protocol we support, also announced in Hello. Add one with every new
+// MessageBody variant or Message field, and bump PROTOCOL_VERSION along with it when older peers
+// can't skip what they don't know.
+pub const FEATURES: &[&str] = &[
+    "receipts", "reactions", "edits", "replies", "clock", "chunks", "leave", "allowlist", "moderation",
+    "room-info", "directory", "migrate", "read-only", "transfer", "heads", "devices", "directories",
+    "long-text", "code", "contacts", "location", "status", "peers", "parents", "timestamps", "compression",
+    "automated", "signed-history",
+];
+
+// Announced on top of FEATURES by peers keeping history for members that were away
+pub const MAILBOX: &str = "mailbox";
+
+// What a peer announced in its Hello
+#[derive(Debug)]
+struct Announced {
+    version: u32,
+    features: Vec<String>,
+}
+
+// The protocol version and features each peer announced, per room: a peer we share several rooms
+// with says Hello in each, and expects an answer in each
+#[derive(Debug, Clone, Default)]
+pub struct Versions(Arc<Mutex<HashMap<(TopicId, NodeId), Announced>>>);
+
+impl Versions {
+    // Record a peer's Hello in a room. Returns whether it is the first one we got from them there.
+    pub fn hello(&self, room: TopicId, from: NodeId, version: u32, features: Vec<String>) -> bool {
+        let mut peers = self.0.lock().expect("versions lock poisoned");
+        peers.insert((room, from), Announced { version, features }).is_none()
+    }
+
+    // A peer's protocol version and features in a room, if it sent a Hello there
+    pub fn get(&self, room: &TopicId, node_id: &NodeId) -> Option<(u32, Vec<String>)> {
+        let peers = self.0.lock().expect("versions lock poisoned");
+        peers.get(&(*room, *node_id)).map(|peer| (peer.version, peer.features.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hellos_are_per_room() {
+        let versions = Versions::default();
+        let peer = iroh::SecretKey::from_bytes(&[1; 32]).public();
+        let (first, second) = (TopicId::from_bytes([1; 32]), TopicId::from_bytes([2; 32]));
+        assert!(versions.hello(first, peer, 2, Vec::new()));
+        assert!(!versions.hello(first, peer, 2, Vec::new()));
+        // The same peer in another room still gets its answer
+        assert!(versions.hello(second, peer, 3, Vec::new()));
+        assert_eq!(versions.get(&first, &peer).map(|(version, _)| version), Some(2));
+        assert_eq!(versions.get(&second, &peer).map(|(version, _)| version), Some(3));
+    }
+}
@@ -0,0 +1,286 @@
+// Voice clips, recorded from the default microphone with cpal and encoded as Opus when built with
+// the `voice` feature. A clip is a little container of our own, so what peers send is only ever
+// decoded by libopus here, never handed to a media player.
+use anyhow::{bail, Result};
+#[cfg(any(feature = "voice", test))]
+use anyhow::Context;
+use std::time::Duration;
+
+// How long a clip may be, and how long one is unless asked otherwise
+pub const MAX_LENGTH: Duration = Duration::from_secs(2 * 60);
+pub const DEFAULT_LENGTH: Duration = Duration::from_secs(10);
+
+// What voice clips are offered as, followed by when they were recorded
+pub const PREFIX: &str = "voice-";
+pub const EXTENSION: &str = ".voice";
+
+// What a clip starts with, followed by its sample rate and its Opus packets, each after its length
+#[cfg(any(feature = "voice", test))]
+const MAGIC: &[u8; 4] = b"ICV1";
+
+// One Opus frame, and as many as MAX_LENGTH holds
+#[cfg(any(feature = "voice", test))]
+const FRAME: Duration = Duration::from_millis(20);
+#[cfg(any(feature = "voice", test))]
+const MAX_PACKETS: usize = (MAX_LENGTH.as_millis() / FRAME.as_millis()) as usize;
+
+// The rates Opus encodes at, best first
+#[cfg(any(feature = "voice", test))]
+const RATES: [u32; 5] = [48_000, 24_000, 16_000, 12_000, 8_000];
+
+#[cfg(not(feature = "voice"))]
+const OFF: &str = "voice clips need the `voice` feature, which this build doesn't have";
+
+// Whether an offered file is a voice clip, going by its name
+pub fn is_clip(name: &str) -> bool {
+    name.starts_with(PREFIX) && name.ends_with(EXTENSION)
+}
+
+// Fails unless this build can record and play clips
+pub fn available() -> Result<()> {
+    #[cfg(not(feature = "voice"))]
+    bail!(OFF);
+    #[cfg(feature = "voice")]
+    Ok(())
+}
+
+// Record `length` of the default microphone
+pub async fn record(length: Duration) -> Result<Vec<u8>> {
+    #[cfg(feature = "voice")]
+    return tokio::task::spawn_blocking(move || audio::record(length)).await?;
+    #[cfg(not(feature = "voice"))]
+    {
+        let _ = length;
+        bail!(OFF)
+    }
+}
+
+// Play a clip on the default speakers until it ends
+pub async fn play(clip: Vec<u8>) -> Result<()> {
+    #[cfg(feature = "voice")]
+    return tokio::task::spawn_blocking(move || audio::play(&clip)).await?;
+    #[cfg(not(feature = "voice"))]
+    {
+        let _ = clip;
+        bail!(OFF)
+    }
+}
+
+// Samples per frame at `rate`
+#[cfg(any(feature = "voice", test))]
+fn frame_len(rate: u32) -> usize {
+    (rate as u128 * FRAME.as_millis() / 1000) as usize
+}
+
+#[cfg(any(feature = "voice", test))]
+fn pack(rate: u32, packets: &[Vec<u8>]) -> Vec<u8> {
+    let mut clip = MAGIC.to_vec();
+    clip.extend_from_slice(&rate.to_le_bytes());
+    for packet in packets {
+        clip.extend_from_slice(&(packet.len() as u16).to_le_bytes());
+        clip.extend_from_slice(packet);
+    }
+    clip
+}
+
+// A clip's sample rate and Opus packets, checked before any of it goes near the decoder
+#[cfg(any(feature = "voice", test))]
+fn unpack(clip: &[u8]) -> Result<(u32, Vec<&[u8]>)> {
+    let Some(rest) = clip.strip_prefix(MAGIC.as_slice()) else {
+        bail!("not a voice clip");
+    };
+    let (rate, mut rest) = rest.split_first_chunk::<4>().context("the clip is cut short")?;
+    let rate = u32::from_le_bytes(*rate);
+    if !RATES.contains(&rate) {
+        bail!("the clip is at {} Hz, which Opus doesn't take", rate);
+    }
+    let mut packets = Vec::new();
+    while !rest.is_empty() {
+        let (len, after) = rest.split_first_chunk::<2>().context("the clip is cut short")?;
+        let len = u16::from_le_bytes(*len) as usize;
+        if after.len() < len {
+            bail!("the clip is cut short");
+        }
+        if packets.len() == MAX_PACKETS {
+            bail!("the clip is longer than {}s", MAX_LENGTH.as_secs());
+        }
+        let (packet, after) = after.split_at(len);
+        packets.push(packet);
+        rest = after;
+    }
+    Ok((rate, packets))
+}
+
+#[cfg(feature = "voice")]
+mod audio {
+    use super::*;
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{Device, FromSample, Sample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig};
+    use cpal::{SupportedStreamConfig, SupportedStreamConfigRange};
+    use opus::{Application, Channels, Decoder, Encoder};
+    use std::sync::{Arc, Mutex};
+
+    // More than Opus makes of one mono 20 ms frame
+    const MAX_PACKET: usize = 4000;
+    // The longest frame a packet can hold, which is what decoding has to have room for
+    const MAX_FRAME: Duration = Duration::from_millis(120);
+
+    // A config of the device's at the best of `rates` it has
+    fn config(
+        configs: impl Iterator<Item = SupportedStreamConfigRange>,
+        rates: &[u32],
+    ) -> Option<SupportedStreamConfig> {
+        let configs: Vec<_> = configs.collect();
+        rates.iter().find_map(|rate| {
+            let range = configs
+                .iter()
+                .find(|range| (range.min_sample_rate().0..=range.max_sample_rate().0).contains(rate))?;
+            Some(range.clone().with_sample_rate(SampleRate(*rate)))
+        })
+    }
+
+    pub fn record(length: Duration) -> Result<Vec<u8>> {
+        let device = cpal::default_host().default_input_device().context("no microphone to record from")?;
+        let supported = config(device.supported_input_configs()?, &RATES);
+        let supported = supported.context("the microphone records at no rate Opus takes")?;
+        let format = supported.sample_format();
+        let config: StreamConfig = supported.into();
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let stream = match format {
+            SampleFormat::I16 => capture::<i16>(&device, &config, samples.clone())?,
+            SampleFormat::U16 => capture::<u16>(&device, &config, samples.clone())?,
+            SampleFormat::F32 => capture::<f32>(&device, &config, samples.clone())?,
+            format => bail!("the microphone records {} samples, which we don't take", format),
+        };
+        stream.play()?;
+        std::thread::sleep(length);
+        drop(stream);
+        let samples = std::mem::take(&mut *samples.lock().expect("samples lock poisoned"));
+        encode(config.sample_rate.0, &samples)
+    }
+
+    // Collect what the microphone hears into `samples`, mixed down to mono
+    fn capture<T>(device: &Device, config: &StreamConfig, samples: Arc<Mutex<Vec<f32>>>) -> Result<Stream>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        let channels = config.channels as usize;
+        let stream = device.build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                let mut samples = samples.lock().expect("samples lock poisoned");
+                for frame in data.chunks(channels) {
+                    let sum: f32 = frame.iter().map(|sample| sample.to_sample::<f32>()).sum();
+                    samples.push(sum / channels as f32);
+                }
+            },
+            // A glitch loses a few samples, which isn't worth giving up the clip for
+            |_| {},
+            None,
+        )?;
+        Ok(stream)
+    }
+
+    fn encode(rate: u32, samples: &[f32]) -> Result<Vec<u8>> {
+        let mut encoder = Encoder::new(rate, Channels::Mono, Application::Voip)?;
+        let frame = frame_len(rate);
+        let mut packets = Vec::new();
+        for chunk in samples.chunks(frame) {
+            // The last frame is padded with silence
+            let mut pcm = chunk.to_vec();
+            pcm.resize(frame, 0.0);
+            packets.push(encoder.encode_vec_float(&pcm, MAX_PACKET)?);
+        }
+        Ok(pack(rate, &packets))
+    }
+
+    pub fn play(clip: &[u8]) -> Result<()> {
+        let (rate, packets) = unpack(clip)?;
+        let mut decoder = Decoder::new(rate, Channels::Mono)?;
+        let most = (MAX_LENGTH.as_secs() * rate as u64) as usize;
+        let mut pcm = vec![0.0; (rate as u128 * MAX_FRAME.as_millis() / 1000) as usize];
+        let mut samples = Vec::new();
+        for packet in packets {
+            let decoded = decoder.decode_float(packet, &mut pcm, false)?;
+            samples.extend_from_slice(&pcm[..decoded]);
+            // Long frames can make a clip of few packets long too
+            if samples.len() >= most {
+                samples.truncate(most);
+                break;
+            }
+        }
+        let device = cpal::default_host().default_output_device().context("no speakers to play on")?;
+        let supported = config(device.supported_output_configs()?, &[rate]);
+        let supported = supported.with_context(|| format!("the speakers don't play at {} Hz", rate))?;
+        let format = supported.sample_format();
+        let config: StreamConfig = supported.into();
+        let length = Duration::from_secs_f64(samples.len() as f64 / rate as f64);
+        let samples = Arc::new(Mutex::new(samples.into_iter()));
+        let stream = match format {
+            SampleFormat::I16 => playback::<i16>(&device, &config, samples)?,
+            SampleFormat::U16 => playback::<u16>(&device, &config, samples)?,
+            SampleFormat::F32 => playback::<f32>(&device, &config, samples)?,
+            format => bail!("the speakers play {} samples, which we don't make", format),
+        };
+        stream.play()?;
+        // A little longer, so what the device buffered plays out too
+        std::thread::sleep(length + Duration::from_millis(200));
+        Ok(())
+    }
+
+    // Play `samples` on every channel, then silence
+    fn playback<T>(
+        device: &Device,
+        config: &StreamConfig,
+        samples: Arc<Mutex<std::vec::IntoIter<f32>>>,
+    ) -> Result<Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let channels = config.channels as usize;
+        let stream = device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let mut samples = samples.lock().expect("samples lock poisoned");
+                for frame in data.chunks_mut(channels) {
+                    frame.fill(T::from_sample(samples.next().unwrap_or(0.0)));
+                }
+            },
+            |_| {},
+            None,
+        )?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_clips_by_name() {
+        assert!(is_clip("voice-20261014-101500.voice"));
+        assert!(!is_clip("notes.voice") && !is_clip("voice-memo.txt"));
+    }
+
+    #[test]
+    fn unpacks_what_it_packed() {
+        let packets = vec![vec![1, 2, 3], Vec::new(), vec![7; 300]];
+        let clip = pack(24_000, &packets);
+        let (rate, unpacked) = unpack(&clip).unwrap();
+        assert_eq!(rate, 24_000);
+        assert_eq!(unpacked, packets.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        assert_eq!(frame_len(48_000), 960);
+    }
+
+    #[test]
+    fn refuses_clips_that_dont_check_out() {
+        let clip = pack(48_000, &[vec![1, 2, 3]]);
+        assert!(unpack(&clip[..clip.len() - 1]).is_err());
+        assert!(unpack(b"OggS\0\0\0\0").is_err());
+        assert!(unpack(&pack(44_100, &[])).is_err());
+        let too_long = vec![Vec::new(); MAX_PACKETS + 1];
+        assert!(unpack(&pack(48_000, &too_long)).unwrap_err().to_string().contains("longer than"));
+    }
+}